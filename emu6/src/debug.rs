@@ -0,0 +1,452 @@
+use crate::machine::{Machine, Step};
+use libemu6::{
+    riscv::{csr_from_name, csr_name, disassemble, x_from_name, x_name, Instruction},
+    size::Usize,
+};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step|s [N]              execute N instructions (default 1)
+continue|c              run until a breakpoint is hit
+break|b [ADDR|SYMBOL]   set a breakpoint, or list breakpoints
+delete|d [N]            delete breakpoint N, or all breakpoints
+regs|r                  print pc and all integer registers
+print|p REG|CSR         print an integer register, a CSR or pc
+set REG|CSR|pc VALUE    modify an integer register, a CSR or pc
+x/[N][bhwg] ADDR        examine N units of memory at ADDR
+mw/[bhwg] ADDR VALUE    write one unit of memory at ADDR
+disas [N]               disassemble N instructions around pc (default 5)
+ins|i                   show the current instruction
+history                 list previous commands; !N repeats command N
+help|h                  show this message
+quit|q                  exit emu6
+
+Numbers are decimal, or hexadecimal with 0x; addresses may be symbol names.
+An empty line repeats the previous command.";
+
+// Interactive console controlling a machine, reading commands from stdin
+pub struct Debugger {
+    symbols: Vec<(String, u64)>,
+    breakpoints: Vec<u64>,
+    history: Vec<String>,
+}
+
+impl Debugger {
+    pub fn new(mut symbols: Vec<(String, u64)>) -> Debugger {
+        symbols.sort_by_key(|&(_, addr)| addr);
+        Debugger {
+            symbols,
+            breakpoints: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    pub fn run(&mut self, machine: &mut Machine) {
+        println!("Debug console; type help for a list of commands");
+        self.show_current(machine);
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(emu6) ");
+            io::stdout().flush().ok();
+            let line = match lines.next() {
+                Some(Ok(line)) => line.trim().to_string(),
+                _ => return,
+            };
+            let line = match self.expand(&line) {
+                Some(line) => line,
+                None => continue,
+            };
+            if line == "quit" || line == "q" {
+                return;
+            }
+            self.command(machine, &line);
+        }
+    }
+
+    // resolves empty lines and `!N` to previous commands, and records history
+    fn expand(&mut self, line: &str) -> Option<String> {
+        let line = if line.is_empty() {
+            self.history.last()?.clone()
+        } else if let Some(idx) = line.strip_prefix('!') {
+            match idx.parse::<usize>().ok().and_then(|i| self.history.get(i)) {
+                Some(line) => line.clone(),
+                None => {
+                    println!("No command {} in history", idx);
+                    return None;
+                }
+            }
+        } else {
+            line.to_string()
+        };
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+
+    fn command(&mut self, machine: &mut Machine, line: &str) {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        let (cmd, format) = match cmd.find('/') {
+            Some(idx) => (&cmd[..idx], &cmd[idx + 1..]),
+            None => (cmd, ""),
+        };
+        let result = match cmd {
+            "step" | "s" => self.step(machine, &args),
+            "continue" | "c" => self.cont(machine),
+            "break" | "b" => self.set_breakpoint(&args),
+            "delete" | "d" => self.delete_breakpoint(&args),
+            "regs" | "r" => {
+                self.print_regs(machine);
+                Ok(())
+            }
+            "print" | "p" => self.print(machine, &args),
+            "set" => self.set(machine, &args),
+            "x" => self.examine(machine, format, &args),
+            "mw" => self.write_mem(machine, format, &args),
+            "disas" => self.disas(machine, &args),
+            "ins" | "i" => {
+                self.show_current(machine);
+                Ok(())
+            }
+            "history" => {
+                for (idx, line) in self.history.iter().enumerate() {
+                    println!("{:>4}  {}", idx, line);
+                }
+                Ok(())
+            }
+            "help" | "h" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            _ => Err(format!("unknown command {}; type help for a list", cmd)),
+        };
+        if let Err(msg) = result {
+            println!("{}", msg);
+        }
+    }
+
+    fn step(&mut self, machine: &mut Machine, args: &[&str]) -> Result<(), String> {
+        let count = match args.first() {
+            Some(arg) => parse_number(arg)?,
+            None => 1,
+        };
+        for _ in 0..count {
+            self.step_one(machine)?;
+        }
+        self.show_current(machine);
+        Ok(())
+    }
+
+    fn cont(&mut self, machine: &mut Machine) -> Result<(), String> {
+        loop {
+            self.step_one(machine)?;
+            let pc = machine.pc.to_u64();
+            if let Some(idx) = self.breakpoints.iter().position(|&addr| addr == pc) {
+                println!("Breakpoint {} at {}", idx, self.describe(pc));
+                break;
+            }
+        }
+        self.show_current(machine);
+        Ok(())
+    }
+
+    fn step_one(&mut self, machine: &mut Machine) -> Result<(), String> {
+        let pc = machine.pc.to_u64();
+        match machine.step() {
+            Ok(Step::Retired(_)) | Ok(Step::Trapped) => Ok(()),
+            Ok(Step::Exited(code)) => Err(format!("program exited with code {}", code)),
+            Err(e) => Err(format!("execute instruction at {:#x}: {:?}", pc, e)),
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let arg = match args.first() {
+            Some(arg) => arg,
+            None => {
+                for (idx, &addr) in self.breakpoints.iter().enumerate() {
+                    println!("{:>4}  {}", idx, self.describe(addr));
+                }
+                return Ok(());
+            }
+        };
+        let addr = self.parse_addr(arg)?;
+        let idx = match self.breakpoints.iter().position(|&a| a == addr) {
+            Some(idx) => idx,
+            None => {
+                self.breakpoints.push(addr);
+                self.breakpoints.len() - 1
+            }
+        };
+        println!("Breakpoint {} at {}", idx, self.describe(addr));
+        Ok(())
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        match args.first() {
+            Some(arg) => {
+                let idx = parse_number(arg)? as usize;
+                if idx >= self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", idx));
+                }
+                self.breakpoints.remove(idx);
+            }
+            None => self.breakpoints.clear(),
+        }
+        Ok(())
+    }
+
+    fn print_regs(&self, machine: &Machine) {
+        println!("pc   {:#018x}  ({:?})", machine.pc.to_u64(), machine.exec.privilege());
+        for idx in 0..32 {
+            let val = machine.exec.read_x(idx).to_u64();
+            print!("{:<4} {:#018x}", x_name(idx), val);
+            print!("{}", if idx % 4 == 3 { "\n" } else { "  " });
+        }
+    }
+
+    fn print(&self, machine: &Machine, args: &[&str]) -> Result<(), String> {
+        let name = args.first().ok_or("usage: print REG|CSR")?;
+        let val = if *name == "pc" {
+            machine.pc
+        } else if let Some(idx) = x_from_name(name) {
+            machine.exec.read_x(idx)
+        } else if let Some(csr) = csr_from_name(name) {
+            machine
+                .exec
+                .read_csr(csr)
+                .map_err(|_| format!("cannot read CSR {}", csr_name(csr)))?
+        } else {
+            return Err(format!("unknown register {}", name));
+        };
+        let val = val.to_u64();
+        println!("{} = {:#x} ({})", name, val, val);
+        Ok(())
+    }
+
+    fn set(&self, machine: &mut Machine, args: &[&str]) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err("usage: set REG|CSR|pc VALUE".to_string());
+        }
+        let (name, val) = (args[0], self.parse_addr(args[1])?);
+        let val = Usize::from_u64(val, machine.exec.xlen());
+        if name == "pc" {
+            machine.pc = val;
+        } else if let Some(idx) = x_from_name(name) {
+            // writes to x0 are ignored by the register file
+            machine.exec.write_x(idx, val);
+        } else if let Some(csr) = csr_from_name(name) {
+            machine
+                .exec
+                .write_csr(csr, val)
+                .map_err(|_| format!("cannot write CSR {}", csr_name(csr)))?;
+        } else {
+            return Err(format!("unknown register {}", name));
+        }
+        Ok(())
+    }
+
+    fn examine(&self, machine: &mut Machine, format: &str, args: &[&str]) -> Result<(), String> {
+        let (count, width) = parse_format(format)?;
+        let addr = self.parse_addr(args.first().ok_or("usage: x/[N][bhwg] ADDR")?)?;
+        let per_line = 16 / width;
+        for i in 0..count {
+            let at = addr.wrapping_add(i * width);
+            if i % per_line == 0 {
+                if i != 0 {
+                    println!();
+                }
+                print!("{:#018x}:", at);
+            }
+            let vaddr = Usize::from_u64(at, machine.exec.xlen());
+            match machine.exec.read_mem(vaddr, width) {
+                Ok(val) => print!(" {:0width$x}", val, width = 2 * width as usize),
+                Err(_) => {
+                    println!();
+                    return Err(format!("cannot access memory at {:#x}", at));
+                }
+            }
+        }
+        println!();
+        Ok(())
+    }
+
+    fn write_mem(&self, machine: &mut Machine, format: &str, args: &[&str]) -> Result<(), String> {
+        let (_, width) = parse_format(format)?;
+        if args.len() != 2 {
+            return Err("usage: mw/[bhwg] ADDR VALUE".to_string());
+        }
+        let addr = self.parse_addr(args[0])?;
+        let val = parse_number(args[1])?;
+        let vaddr = Usize::from_u64(addr, machine.exec.xlen());
+        machine
+            .exec
+            .write_mem(vaddr, val, width)
+            .map_err(|_| format!("cannot access memory at {:#x}", addr))
+    }
+
+    fn disas(&self, machine: &mut Machine, args: &[&str]) -> Result<(), String> {
+        let count = match args.first() {
+            Some(arg) => parse_number(arg)?,
+            None => 5,
+        };
+        let pc = machine.pc.to_u64();
+        // Instructions have variable length, so look for the furthest start address
+        // before pc from which decoding lands exactly on pc
+        let before = count / 2;
+        let mut start = pc;
+        for back in (1..=before * 2).rev().map(|n| n * 2) {
+            let candidate = pc.wrapping_sub(back);
+            if self.lands_on(machine, candidate, pc, before) {
+                start = candidate;
+                break;
+            }
+        }
+        let mut addr = start;
+        for _ in 0..count {
+            let vaddr = Usize::from_u64(addr, machine.exec.xlen());
+            let marker = if addr == pc { "=>" } else { "  " };
+            match machine.decode(vaddr) {
+                Ok(ins) => {
+                    println!("{} {}  {}", marker, self.describe(addr), disassemble(&ins, addr));
+                    addr += ins_len(&ins);
+                }
+                Err(_) => {
+                    println!("{} {}  <cannot decode>", marker, self.describe(addr));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // whether decoding at most `max` instructions from `from` reaches `to`
+    fn lands_on(&self, machine: &mut Machine, from: u64, to: u64, max: u64) -> bool {
+        let mut addr = from;
+        for _ in 0..max {
+            if addr >= to {
+                break;
+            }
+            match machine.decode(Usize::from_u64(addr, machine.exec.xlen())) {
+                Ok(ins) => addr += ins_len(&ins),
+                Err(_) => return false,
+            }
+        }
+        addr == to
+    }
+
+    fn show_current(&self, machine: &mut Machine) {
+        let pc = machine.pc.to_u64();
+        match machine.decode(machine.pc) {
+            Ok(ins) => println!("=> {}  {}", self.describe(pc), disassemble(&ins, pc)),
+            Err(e) => println!("=> {}  <cannot fetch: {:?}>", self.describe(pc), e),
+        }
+    }
+
+    // formats an address, with the nearest preceding symbol if there is one
+    fn describe(&self, addr: u64) -> String {
+        let idx = self.symbols.partition_point(|&(_, a)| a <= addr);
+        match idx.checked_sub(1).map(|i| &self.symbols[i]) {
+            Some((name, base)) if addr == *base => format!("{:#x} <{}>", addr, name),
+            Some((name, base)) => format!("{:#x} <{}+{}>", addr, name, addr - base),
+            None => format!("{:#x}", addr),
+        }
+    }
+
+    fn parse_addr(&self, s: &str) -> Result<u64, String> {
+        if let Some((_, addr)) = self.symbols.iter().find(|(name, _)| name == s) {
+            return Ok(*addr);
+        }
+        parse_number(s).map_err(|_| format!("no symbol or address {}", s))
+    }
+}
+
+// length in bytes of a decoded instruction
+fn ins_len(ins: &Instruction) -> u64 {
+    match ins {
+        Instruction::RVC(_) => 2,
+        _ => 4,
+    }
+}
+
+// parses `[N][bhwg]`, returning the count and the width in bytes
+fn parse_format(format: &str) -> Result<(u64, u64), String> {
+    let digits = format.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let count = if digits.is_empty() { 1 } else { parse_number(digits)? };
+    let width = match &format[digits.len()..] {
+        "b" => 1,
+        "h" => 2,
+        "w" | "" => 4,
+        "g" => 8,
+        unit => return Err(format!("unknown unit size {}", unit)),
+    };
+    Ok((count, width))
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    let ans = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    };
+    ans.map_err(|_| format!("invalid number {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger() -> Debugger {
+        Debugger::new(vec![("loop".to_string(), 0x8000_0010), ("_start".to_string(), 0x8000_0000)])
+    }
+
+    #[test]
+    fn numbers_and_formats() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert!(parse_number("0x8000_0000").is_err());
+        assert_eq!(parse_number("0x80000000"), Ok(0x8000_0000));
+        assert_eq!(parse_format(""), Ok((1, 4)));
+        assert_eq!(parse_format("4g"), Ok((4, 8)));
+        assert_eq!(parse_format("b"), Ok((1, 1)));
+        assert!(parse_format("2q").is_err());
+    }
+
+    #[test]
+    fn addresses_and_symbols() {
+        let debugger = debugger();
+        assert_eq!(debugger.parse_addr("loop"), Ok(0x8000_0010));
+        assert_eq!(debugger.parse_addr("0x10"), Ok(0x10));
+        assert!(debugger.parse_addr("missing").is_err());
+        assert_eq!(debugger.describe(0x8000_0000), "0x80000000 <_start>");
+        assert_eq!(debugger.describe(0x8000_0014), "0x80000014 <loop+4>");
+        assert_eq!(debugger.describe(0x10), "0x10");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger();
+        debugger.set_breakpoint(&["loop"]).unwrap();
+        debugger.set_breakpoint(&["0x80000004"]).unwrap();
+        // setting a breakpoint twice keeps one
+        debugger.set_breakpoint(&["0x80000010"]).unwrap();
+        assert_eq!(debugger.breakpoints, [0x8000_0010, 0x8000_0004]);
+        debugger.delete_breakpoint(&["0"]).unwrap();
+        assert_eq!(debugger.breakpoints, [0x8000_0004]);
+        assert!(debugger.delete_breakpoint(&["1"]).is_err());
+        debugger.delete_breakpoint(&[]).unwrap();
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn history_repeats_commands() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.expand(""), None);
+        assert_eq!(debugger.expand("s 2").as_deref(), Some("s 2"));
+        assert_eq!(debugger.expand("").as_deref(), Some("s 2"));
+        assert_eq!(debugger.expand("regs").as_deref(), Some("regs"));
+        assert_eq!(debugger.expand("!0").as_deref(), Some("s 2"));
+        assert_eq!(debugger.expand("!9"), None);
+        assert_eq!(debugger.history, ["s 2", "regs", "s 2"]);
+    }
+}
@@ -0,0 +1,295 @@
+// header fields and structure block tokens of the devicetree specification
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Writer of flattened device tree blobs. Nodes are opened and closed in order,
+// with their properties written in between; values are big-endian cells
+#[derive(Debug, Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+    // address and size of each memory reservation
+    reservations: Vec<(u64, u64)>,
+}
+
+impl Fdt {
+    pub fn new() -> Fdt {
+        Fdt::default()
+    }
+
+    // keeps the operating system from allocating the memory, as /memreserve/ does
+    pub fn reserve(&mut self, addr: u64, size: u64) {
+        self.reservations.push((addr, size));
+    }
+
+    // the root node has an empty name
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    // a property without value, such as `interrupt-controller`
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &value);
+    }
+
+    // address and size pairs, each two cells wide
+    pub fn property_reg(&mut self, name: &str, regions: &[(u64, u64)]) {
+        let cells: Vec<u32> = regions
+            .iter()
+            .flat_map(|&(addr, size)| {
+                vec![
+                    (addr >> 32) as u32,
+                    addr as u32,
+                    (size >> 32) as u32,
+                    size as u32,
+                ]
+            })
+            .collect();
+        self.property_cells(name, &cells);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    // Returns the blob, with the memory reservations before the structure block
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unclosed device tree node");
+        self.push_u32(FDT_END);
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16 * (self.reservations.len() + 1);
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut ans = Vec::with_capacity(total_size);
+        for field in header.iter() {
+            ans.extend_from_slice(&field.to_be_bytes());
+        }
+        for &(addr, size) in self.reservations.iter() {
+            ans.extend_from_slice(&addr.to_be_bytes());
+            ans.extend_from_slice(&size.to_be_bytes());
+        }
+        // the reservation block ends with an entry of zero address and size
+        ans.extend_from_slice(&[0; 16]);
+        ans.extend_from_slice(&self.structure);
+        ans.extend_from_slice(&self.strings);
+        ans
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() & 3 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    // property names are stored once in the strings block
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&blob[offset..offset + 4]);
+        u32::from_be_bytes(bytes)
+    }
+
+    // Walks the structure block, returning the property names with their node
+    // paths and values
+    fn properties(blob: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let off_struct = be32(blob, 8) as usize;
+        let off_strings = be32(blob, 12) as usize;
+        let name_at = |offset: usize| {
+            let start = off_strings + offset;
+            let len = blob[start..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[start..start + len].to_vec()).unwrap()
+        };
+        let mut path: Vec<String> = Vec::new();
+        let mut ans = Vec::new();
+        let mut pos = off_struct;
+        loop {
+            let token = be32(blob, pos);
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let len = blob[pos..].iter().position(|&b| b == 0).unwrap();
+                    path.push(String::from_utf8(blob[pos..pos + len].to_vec()).unwrap());
+                    pos += (len + 4) & !3;
+                }
+                FDT_END_NODE => {
+                    path.pop();
+                }
+                FDT_PROP => {
+                    let len = be32(blob, pos) as usize;
+                    let name = name_at(be32(blob, pos + 4) as usize);
+                    pos += 8;
+                    let value = blob[pos..pos + len].to_vec();
+                    ans.push((format!("{}/{}", path.join("/"), name), value));
+                    pos += (len + 3) & !3;
+                }
+                FDT_END => return ans,
+                _ => panic!("bad token {:#x} at {:#x}", token, pos - 4),
+            }
+        }
+    }
+
+    #[test]
+    fn header_and_blocks() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.end_node();
+        let blob = fdt.finish();
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        // the structure block follows the empty reservation block
+        assert_eq!(be32(&blob, 16), FDT_HEADER_SIZE as u32);
+        assert_eq!(be32(&blob, 8), FDT_HEADER_SIZE as u32 + 16);
+        assert_eq!(&blob[40..56], &[0; 16]);
+        assert_eq!(be32(&blob, 20), 17);
+        assert_eq!(be32(&blob, 24), 16);
+        let structure = [
+            FDT_BEGIN_NODE,
+            0,
+            FDT_PROP,
+            4,
+            0,
+            2,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        let expected: Vec<u8> = structure.iter().flat_map(|w| w.to_be_bytes().to_vec()).collect();
+        assert_eq!(be32(&blob, 36) as usize, expected.len());
+        assert_eq!(&blob[56..56 + expected.len()], &expected[..]);
+        assert_eq!(&blob[56 + expected.len()..], b"#size-cells\0");
+    }
+
+    #[test]
+    fn memory_reservations() {
+        let mut fdt = Fdt::new();
+        fdt.reserve(0x8020_0000, 0x1_0000);
+        fdt.begin_node("");
+        fdt.end_node();
+        let blob = fdt.finish();
+        let entries: Vec<u8> = [0x8020_0000u64, 0x1_0000, 0, 0]
+            .iter()
+            .flat_map(|n| n.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(&blob[40..72], &entries[..]);
+        assert_eq!(be32(&blob, 8), 72);
+        assert_eq!(be32(&blob, 72), FDT_BEGIN_NODE);
+    }
+
+    #[test]
+    fn nodes_and_property_values() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_string("model", "emu6");
+        fdt.begin_node("soc");
+        fdt.begin_node("serial@10000000");
+        fdt.property_strings("compatible", &["a", "bc"]);
+        fdt.property_reg("reg", &[(0x1_1000_0000, 0x100)]);
+        fdt.property_empty("interrupt-controller");
+        fdt.end_node();
+        fdt.end_node();
+        fdt.property_string("compatible", "emu6");
+        fdt.end_node();
+        let blob = fdt.finish();
+        let reg = [0, 0, 0, 1, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let expected: Vec<(String, Vec<u8>)> = vec![
+            ("/model".into(), b"emu6\0".to_vec()),
+            ("/soc/serial@10000000/compatible".into(), b"a\0bc\0".to_vec()),
+            ("/soc/serial@10000000/reg".into(), reg.to_vec()),
+            ("/soc/serial@10000000/interrupt-controller".into(), Vec::new()),
+            ("/compatible".into(), b"emu6\0".to_vec()),
+        ];
+        assert_eq!(properties(&blob), expected);
+        // property names are stored once
+        let strings = &blob[be32(&blob, 12) as usize..];
+        assert_eq!(strings, &b"model\0compatible\0reg\0interrupt-controller\0"[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unclosed device tree node")]
+    fn nodes_must_be_closed() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.finish();
+    }
+}
@@ -0,0 +1,591 @@
+use crate::machine::{Machine, Step};
+use libemu6::riscv::{csr_names, x_name, Watch, Xlen};
+use libemu6::size::Usize;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// GDB register numbers of RISC-V targets
+const REG_PC: usize = 32;
+const REG_F0: usize = 33;
+const REG_CSR0: usize = 65;
+
+// signals reported in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const CTRL_C: u8 = 0x03;
+// instructions executed between polls for Ctrl-C while continuing
+const POLL_INTERVAL: u64 = 4096;
+
+// Remote serial protocol stub, serving one GDB connection
+pub struct GdbServer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // software and hardware breakpoints alike
+    breakpoints: Vec<u64>,
+    xlen_bytes: usize,
+    flen_bytes: usize,
+    target_xml: String,
+}
+
+impl GdbServer {
+    // Waits for GDB to connect on `addr`; a bare `:PORT` listens on localhost
+    pub fn listen(addr: &str, machine: &mut Machine) -> io::Result<GdbServer> {
+        let addr = match addr.strip_prefix(':') {
+            Some(port) => format!("127.0.0.1:{}", port),
+            None => addr.to_string(),
+        };
+        let listener = TcpListener::bind(&addr)?;
+        eprintln!("Waiting for GDB connection on {}", addr);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("GDB connected from {}", peer);
+        stream.set_nodelay(true)?;
+        let xlen_bytes = match machine.exec.xlen() {
+            Xlen::X32 => 4,
+            _ => 8,
+        };
+        let flen_bytes = if machine.exec.has_extension('D') {
+            8
+        } else if machine.exec.has_extension('F') {
+            4
+        } else {
+            0
+        };
+        Ok(GdbServer {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            breakpoints: Vec::new(),
+            xlen_bytes,
+            flen_bytes,
+            target_xml: target_xml(xlen_bytes, flen_bytes),
+        })
+    }
+
+    // Serves requests until GDB detaches, kills the target or disconnects
+    pub fn run(&mut self, machine: &mut Machine) -> io::Result<()> {
+        self.serve(machine).or_else(|e| match e.kind() {
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => Ok(()),
+            _ => Err(e),
+        })
+    }
+
+    fn serve(&mut self, machine: &mut Machine) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => return self.write_packet("OK"),
+                _ => {}
+            }
+            let reply = self.handle(machine, &packet)?;
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, machine: &mut Machine, packet: &str) -> io::Result<String> {
+        let (cmd, args) = match packet.get(..1) {
+            Some(cmd) => (cmd, &packet[1..]),
+            None => return Ok(String::new()),
+        };
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(machine),
+            "G" => self.write_registers(machine, args),
+            "p" => self.read_register(machine, args),
+            "P" => self.write_register(machine, args),
+            "m" => read_memory(machine, args),
+            "M" => write_memory(machine, args),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    machine.pc = Usize::from_u64(addr, machine.exec.xlen());
+                }
+                return self.resume(machine, cmd == "s");
+            }
+            "Z" | "z" => self.breakpoint(machine, cmd == "Z", args),
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            // vCont, binary writes with X and anything else unsupported
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = match parse_pair(annex) {
+                Some(pair) => pair,
+                None => return "E01".to_string(),
+            };
+            let xml = self.target_xml.as_bytes();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            let kind = if end == xml.len() { 'l' } else { 'm' };
+            format!("{}{}", kind, String::from_utf8_lossy(&xml[start..end]))
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    // Runs until a breakpoint or watchpoint is hit, the machine fails, or GDB
+    // interrupts; returns the stop reply
+    fn resume(&mut self, machine: &mut Machine, step: bool) -> io::Result<String> {
+        let mut count = 0u64;
+        loop {
+            match machine.step() {
+                Ok(Step::Exited(code)) => return Ok(format!("W{:02x}", code as u8)),
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Execute instruction at {:#x}: {:?}", machine.pc.to_u64(), e);
+                    return Ok(format!("S{:02x}", SIGSEGV));
+                }
+            }
+            if let Some((kind, addr)) = machine.exec.watchpoints().take_hit() {
+                let reason = match kind {
+                    Watch::Write => "watch",
+                    Watch::Read => "rwatch",
+                    Watch::Access => "awatch",
+                };
+                return Ok(format!("T{:02x}{}:{:x};", SIGTRAP, reason, addr));
+            }
+            if step || self.breakpoints.contains(&machine.pc.to_u64()) {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2 to Z4 write, read and access watchpoints
+    fn breakpoint(&mut self, machine: &mut Machine, insert: bool, args: &str) -> String {
+        let mut parts = args.splitn(3, ',');
+        let (kind, addr, len) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return "E01".to_string(),
+        };
+        let (addr, len) = match (parse_hex(addr), parse_hex(len)) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.push(addr);
+                } else if let Some(idx) = self.breakpoints.iter().position(|&a| a == addr) {
+                    self.breakpoints.remove(idx);
+                }
+                return "OK".to_string();
+            }
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return String::new(),
+        };
+        let watchpoints = machine.exec.watchpoints();
+        if insert {
+            watchpoints.insert(addr, len, watch);
+        } else if !watchpoints.remove(addr, len, watch) {
+            return "E01".to_string();
+        }
+        "OK".to_string()
+    }
+
+    // the `g` packet holds the integer registers and pc; GDB reads the others
+    // one by one with `p`
+    fn read_registers(&self, machine: &Machine) -> String {
+        let mut ans = String::new();
+        for regno in 0..=REG_PC {
+            let val = self.register(machine, regno).unwrap_or(0);
+            push_le(&mut ans, val, self.xlen_bytes);
+        }
+        ans
+    }
+
+    fn write_registers(&self, machine: &mut Machine, args: &str) -> String {
+        let digits = 2 * self.xlen_bytes;
+        for (regno, chunk) in args.as_bytes().chunks(digits).take(REG_PC + 1).enumerate() {
+            let val = match std::str::from_utf8(chunk).ok().and_then(parse_le) {
+                Some(val) => val,
+                None => return "E01".to_string(),
+            };
+            self.set_register(machine, regno, val);
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, machine: &Machine, args: &str) -> String {
+        let regno = match parse_hex(args) {
+            Some(regno) => regno as usize,
+            None => return "E01".to_string(),
+        };
+        let size = self.register_size(regno);
+        match self.register(machine, regno) {
+            Some(val) => {
+                let mut ans = String::new();
+                push_le(&mut ans, val, size);
+                ans
+            }
+            // registers which cannot be read are reported as unavailable
+            None => "xx".repeat(size),
+        }
+    }
+
+    fn write_register(&self, machine: &mut Machine, args: &str) -> String {
+        let (regno, val) = match args.split_once('=') {
+            Some((regno, val)) => (parse_hex(regno), parse_le(val)),
+            None => return "E01".to_string(),
+        };
+        match (regno, val) {
+            (Some(regno), Some(val)) if self.set_register(machine, regno as usize, val) => {
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn register_size(&self, regno: usize) -> usize {
+        if (REG_F0..REG_CSR0).contains(&regno) {
+            self.flen_bytes
+        } else {
+            self.xlen_bytes
+        }
+    }
+
+    fn register(&self, machine: &Machine, regno: usize) -> Option<u64> {
+        let exec = &machine.exec;
+        let ans = match regno {
+            0..=31 => exec.read_x(regno as u8).to_u64(),
+            REG_PC => machine.pc.to_u64(),
+            REG_F0..=64 if self.flen_bytes == 8 => exec.read_f((regno - REG_F0) as u8),
+            REG_F0..=64 if self.flen_bytes == 4 => exec.read_f((regno - REG_F0) as u8) & 0xFFFF_FFFF,
+            REG_F0..=64 => return None,
+            _ => exec.read_csr(csr_of(regno)?).ok()?.to_u64(),
+        };
+        Some(ans)
+    }
+
+    // returns false if the register does not exist or cannot be written
+    fn set_register(&self, machine: &mut Machine, regno: usize, val: u64) -> bool {
+        let xlen = machine.exec.xlen();
+        match regno {
+            0..=31 => machine.exec.write_x(regno as u8, Usize::from_u64(val, xlen)),
+            REG_PC => machine.pc = Usize::from_u64(val, xlen),
+            REG_F0..=64 if self.flen_bytes == 8 => machine.exec.write_f((regno - REG_F0) as u8, val),
+            REG_F0..=64 if self.flen_bytes == 4 => {
+                // single precision values are NaN-boxed in the register file
+                let boxed = val | 0xFFFF_FFFF_0000_0000;
+                machine.exec.write_f((regno - REG_F0) as u8, boxed)
+            }
+            REG_F0..=64 => return false,
+            _ => match csr_of(regno) {
+                Some(csr) => return machine.exec.write_csr(csr, Usize::from_u64(val, xlen)).is_ok(),
+                None => return false,
+            },
+        }
+        true
+    }
+
+    // checks, without blocking, whether GDB has sent a Ctrl-C
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_nonblocking(true)?;
+            let filled = self.reader.fill_buf().map(|buf| buf.is_empty());
+            self.reader.get_ref().set_nonblocking(false)?;
+            match filled {
+                // disconnected; stop and let the next read notice
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.reader.buffer().first() == Some(&CTRL_C) {
+            self.reader.consume(1);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Reads the next packet, acknowledging it; returns None when GDB disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acknowledgements and interrupts sent while stopped
+            let mut byte = [0u8];
+            loop {
+                if self.reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            if self.reader.read_until(b'#', &mut data)? == 0 || data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut checksum = [0u8; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+            if expected != Some(sum) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.writer, "${}#{:02x}", data, sum)?;
+        self.writer.flush()
+    }
+}
+
+fn csr_of(regno: usize) -> Option<u16> {
+    regno.checked_sub(REG_CSR0).filter(|&csr| csr < 4096).map(|csr| csr as u16)
+}
+
+fn read_memory(machine: &mut Machine, args: &str) -> String {
+    let (addr, len) = match parse_pair(args) {
+        Some(pair) => pair,
+        None => return "E01".to_string(),
+    };
+    let xlen = machine.exec.xlen();
+    let mut ans = String::new();
+    for i in 0..len {
+        match machine.exec.read_mem(Usize::from_u64(addr.wrapping_add(i), xlen), 1) {
+            Ok(byte) => write!(ans, "{:02x}", byte).unwrap(),
+            // a partial read is reported as the bytes read so far
+            Err(_) if i != 0 => break,
+            Err(_) => return "E14".to_string(),
+        }
+    }
+    ans
+}
+
+fn write_memory(machine: &mut Machine, args: &str) -> String {
+    let (range, data) = match args.split_once(':') {
+        Some(parts) => parts,
+        None => return "E01".to_string(),
+    };
+    let (addr, len) = match parse_pair(range) {
+        Some(pair) => pair,
+        None => return "E01".to_string(),
+    };
+    if len.checked_mul(2) != Some(data.len() as u64) {
+        return "E01".to_string();
+    }
+    let bytes = match parse_bytes(data) {
+        Some(bytes) => bytes,
+        None => return "E01".to_string(),
+    };
+    let xlen = machine.exec.xlen();
+    for (i, byte) in bytes.into_iter().enumerate() {
+        let vaddr = Usize::from_u64(addr.wrapping_add(i as u64), xlen);
+        if machine.exec.write_mem(vaddr, byte as u64, 1).is_err() {
+            return "E14".to_string();
+        }
+    }
+    "OK".to_string()
+}
+
+// Describes the registers in GDB register number order: integer registers and
+// pc, floating point registers if F is present, then the CSRs
+fn target_xml(xlen_bytes: usize, flen_bytes: usize) -> String {
+    let xlen = 8 * xlen_bytes;
+    let mut ans = String::new();
+    ans.push_str("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n");
+    ans.push_str("<target version=\"1.0\">\n");
+    writeln!(ans, "<architecture>riscv:rv{}</architecture>", xlen).unwrap();
+    ans.push_str("<feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+    for idx in 0..32 {
+        let ty = match idx {
+            1 => "code_ptr",
+            2 | 8 => "data_ptr",
+            _ => "int",
+        };
+        push_reg(&mut ans, x_name(idx), xlen, ty, idx as usize);
+    }
+    push_reg(&mut ans, "pc", xlen, "code_ptr", REG_PC);
+    ans.push_str("</feature>\n");
+    let float_csrs = ["fflags", "frm", "fcsr"];
+    if flen_bytes != 0 {
+        let ty = if flen_bytes == 8 { "ieee_double" } else { "ieee_single" };
+        ans.push_str("<feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+        for idx in 0..32 {
+            push_reg(&mut ans, &format!("f{}", idx), 8 * flen_bytes, ty, REG_F0 + idx);
+        }
+        for (name, csr) in csr_names().filter(|(name, _)| float_csrs.contains(&name.as_str())) {
+            push_reg(&mut ans, &name, xlen, "int", REG_CSR0 + csr as usize);
+        }
+        ans.push_str("</feature>\n");
+    }
+    ans.push_str("<feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for (name, csr) in csr_names().filter(|(name, _)| !float_csrs.contains(&name.as_str())) {
+        push_reg(&mut ans, &name, xlen, "int", REG_CSR0 + csr as usize);
+    }
+    ans.push_str("</feature>\n</target>\n");
+    ans
+}
+
+fn push_reg(xml: &mut String, name: &str, bitsize: usize, ty: &str, regnum: usize) {
+    writeln!(
+        xml,
+        "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>",
+        name, bitsize, ty, regnum
+    )
+    .unwrap();
+}
+
+// register values are sent in target byte order, which is little endian
+fn push_le(ans: &mut String, val: u64, nbytes: usize) {
+    for i in 0..nbytes {
+        write!(ans, "{:02x}", (val >> (8 * i)) as u8).unwrap();
+    }
+}
+
+fn parse_le(hex: &str) -> Option<u64> {
+    let bytes = parse_bytes(hex)?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(bytes.iter().rev().fold(0, |ans, &byte| ans << 8 | byte as u64))
+}
+
+// decodes pairs of hex digits; the packet may hold any character, so it is
+// parsed byte by byte rather than sliced
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    hex.chunks(2).map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+// parses `ADDR,LENGTH`
+fn parse_pair(s: &str) -> Option<(u64, u64)> {
+    let (a, b) = s.split_once(',')?;
+    Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libemu6::{
+        device::{Clint, MtimeSource, Plic, TestFinisher},
+        mem64::{Config, Endian, Physical, Protect},
+        riscv::{Execute, Fetch},
+    };
+
+    const BASE: u64 = 0x8000_0000;
+
+    // A server connected to a local client socket standing in for GDB
+    fn connect() -> (GdbServer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = GdbServer {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            breakpoints: Vec::new(),
+            xlen_bytes: 8,
+            flen_bytes: 8,
+            target_xml: target_xml(8, 8),
+        };
+        (server, client)
+    }
+
+    #[test]
+    fn packets_are_checked_and_acknowledged() {
+        let (mut server, mut client) = connect();
+        // a stray acknowledgement, a packet with a bad checksum and a good one
+        client.write_all(b"+$g#00$m80000000,4#55").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(server.read_packet().unwrap().as_deref(), Some("m80000000,4"));
+        assert_eq!(server.read_packet().unwrap(), None);
+        server.write_packet("OK").unwrap();
+        drop(server);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "-+$OK#9a");
+    }
+
+    // An RV64 machine with a page of RAM at BASE
+    fn machine() -> Machine<'static> {
+        let mut mem = Box::new(Physical::new());
+        mem.push_zeroed(Config {
+            range: BASE..(BASE + 0x1000),
+            protect: Protect::READ | Protect::WRITE,
+            endian: Endian::Little,
+        });
+        let exec = Execute::new(Box::leak(mem), Xlen::X64);
+        let clint = Clint::new(1, MtimeSource::Instret);
+        let (plic, finisher) = (Plic::new(1, 1), TestFinisher::new());
+        Machine::new(Fetch::new(Xlen::X64), exec, clint, plic, finisher, Usize::U64(BASE))
+    }
+
+    #[test]
+    fn hex_encodings() {
+        let mut ans = String::new();
+        push_le(&mut ans, 0x8000_1234, 8);
+        assert_eq!(ans, "3412008000000000");
+        assert_eq!(parse_le(&ans), Some(0x8000_1234));
+        assert_eq!(parse_le("123"), None);
+        assert_eq!(parse_le("00112233445566778899"), None);
+        assert_eq!(parse_bytes("00fF7a"), Some(vec![0x00, 0xFF, 0x7A]));
+        assert_eq!(parse_bytes("+1"), None);
+        // multibyte characters must not split a pair
+        assert_eq!(parse_bytes("aé"), None);
+        assert_eq!(parse_le("éa"), None);
+        assert_eq!(parse_pair("80000000,10"), Some((0x8000_0000, 0x10)));
+        assert_eq!(parse_pair("80000000"), None);
+    }
+
+    #[test]
+    fn memory_writes() {
+        let mut machine = machine();
+        assert_eq!(write_memory(&mut machine, "80000002,2:aBcd"), "OK");
+        assert_eq!(read_memory(&mut machine, "80000000,4"), "0000abcd");
+        // a length whose digit count overflows, and a pair split by a multibyte character
+        assert_eq!(write_memory(&mut machine, "80000000,8000000000000000:"), "E01");
+        assert_eq!(write_memory(&mut machine, "80000000,1:é"), "E01");
+        assert_eq!(write_memory(&mut machine, "80000000,2:0é0"), "E01");
+        assert_eq!(write_memory(&mut machine, "80001000,1:00"), "E14");
+    }
+
+    #[test]
+    fn register_numbers() {
+        assert_eq!(csr_of(REG_CSR0 + 0x300), Some(0x300));
+        assert_eq!(csr_of(REG_PC), None);
+        assert_eq!(csr_of(REG_CSR0 + 4096), None);
+        let xml = target_xml(8, 4);
+        assert!(xml.contains("<architecture>riscv:rv64</architecture>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>"));
+        let f0 = "<reg name=\"f0\" bitsize=\"32\" type=\"ieee_single\" regnum=\"33\"/>";
+        assert!(xml.contains(f0));
+        assert!(xml.contains("<reg name=\"mstatus\" bitsize=\"64\" type=\"int\" regnum=\"833\"/>"));
+        assert!(!target_xml(8, 0).contains("org.gnu.gdb.riscv.fpu"));
+    }
+}
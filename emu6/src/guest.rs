@@ -0,0 +1,54 @@
+use libemu6::riscv::{Execute, Xlen};
+use libemu6::size::Usize;
+
+// Guest memory as seen by the hart, for services the host provides to the
+// guest; words and fields of parameter blocks are XLEN bits wide
+pub struct Guest<'e, 'a> {
+    pub exec: &'e mut Execute<'a>,
+}
+
+impl<'e, 'a> Guest<'e, 'a> {
+    pub fn word_bytes(&self) -> u64 {
+        match self.exec.xlen() {
+            Xlen::X32 => 4,
+            _ => 8,
+        }
+    }
+
+    pub fn read(&mut self, addr: u64, nbytes: u64) -> Option<u64> {
+        let addr = Usize::from_u64(addr, self.exec.xlen());
+        self.exec.read_mem(addr, nbytes).ok()
+    }
+
+    // returns false if the memory cannot be written
+    pub fn write(&mut self, addr: u64, val: u64, nbytes: u64) -> bool {
+        let addr = Usize::from_u64(addr, self.exec.xlen());
+        self.exec.write_mem(addr, val, nbytes).is_ok()
+    }
+
+    // unreadable fields read as zero
+    pub fn field(&mut self, block: u64, idx: u64) -> u64 {
+        let nbytes = self.word_bytes();
+        self.read(block.wrapping_add(idx * nbytes), nbytes).unwrap_or(0)
+    }
+
+    pub fn set_field(&mut self, block: u64, idx: u64, val: u64) {
+        let nbytes = self.word_bytes();
+        self.write(block.wrapping_add(idx * nbytes), val, nbytes);
+    }
+
+    pub fn byte(&mut self, addr: u64) -> Option<u8> {
+        self.read(addr, 1).map(|b| b as u8)
+    }
+
+    pub fn bytes(&mut self, addr: u64, len: u64) -> Option<Vec<u8>> {
+        (0..len).map(|i| self.byte(addr.wrapping_add(i))).collect()
+    }
+
+    // returns false if any byte cannot be written
+    pub fn set_bytes(&mut self, addr: u64, data: &[u8]) -> bool {
+        data.iter()
+            .enumerate()
+            .all(|(i, &b)| self.write(addr.wrapping_add(i as u64), b as u64, 1))
+    }
+}
@@ -0,0 +1,177 @@
+use libemu6::memory::Bus;
+use std::io::{self, Write};
+
+// devices and commands of the host-target interface
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+// proxied system calls, numbered as in riscv-pk
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+// largest piece of a guest buffer copied at once
+const CHUNK_SIZE: u64 = 4096;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+// Host-target interface of riscv-tests and riscv-pk, through the `tohost` and
+// `fromhost` words in guest memory. The host polls `tohost` after every
+// instruction, serves the request and acknowledges it through `fromhost`
+#[derive(Debug)]
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Htif {
+        Htif { tohost, fromhost }
+    }
+
+    // Serves a pending request; returns the exit code once the guest exits
+    pub fn poll(&mut self, mem: &mut dyn Bus) -> Option<u32> {
+        let tohost = match mem.read_u64(self.tohost) {
+            Ok(0) | Err(_) => return None,
+            Ok(value) => value,
+        };
+        let device = tohost >> 56;
+        let cmd = (tohost >> 48) & 0xFF;
+        let payload = tohost & 0xFFFF_FFFF_FFFF;
+        mem.write_u64(self.tohost, 0).ok();
+        match (device, cmd) {
+            // the lowest bit marks an exit, with the code in the upper bits
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => return Some((payload >> 1) as u32),
+            (DEVICE_SYSCALL, 0) => {
+                if let Some(code) = self.syscall(mem, payload) {
+                    return Some(code);
+                }
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(&[payload as u8]).ok();
+                stdout.flush().ok();
+            }
+            // console input and other devices are not supported
+            _ => return None,
+        }
+        self.reply(mem, device, cmd, 1);
+        None
+    }
+
+    // Proxies the system call in the eight words at `magic_mem`: the number
+    // followed by the arguments. The result replaces the number
+    fn syscall(&mut self, mem: &mut dyn Bus, magic_mem: u64) -> Option<u32> {
+        let mut args = [0u64; 8];
+        for (i, arg) in args.iter_mut().enumerate() {
+            match mem.read_u64(magic_mem + 8 * i as u64) {
+                Ok(value) => *arg = value,
+                Err(_) => return None,
+            }
+        }
+        let ans = match args[0] {
+            SYS_WRITE => write(mem, args[1], args[2], args[3]),
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(args[1] as u32),
+            _ => -ENOSYS,
+        };
+        mem.write_u64(magic_mem, ans as u64).ok();
+        None
+    }
+
+    fn reply(&mut self, mem: &mut dyn Bus, device: u64, cmd: u64, payload: u64) {
+        if let Some(fromhost) = self.fromhost {
+            mem.write_u64(fromhost, (device << 56) | (cmd << 48) | payload).ok();
+        }
+    }
+}
+
+// Copies the buffer to the host in bounded chunks, so a large length from the
+// guest never allocates more than a chunk. A fault after some bytes were
+// written returns the count so far, as a short write
+fn write(mem: &dyn Bus, fd: u64, buf: u64, len: u64) -> i64 {
+    let mut out: Box<dyn Write> = match fd {
+        1 => Box::new(io::stdout()),
+        2 => Box::new(io::stderr()),
+        _ => return -EBADF,
+    };
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    let mut done = 0;
+    while done < len {
+        chunk.clear();
+        let mut fault = false;
+        for i in done..len.min(done + CHUNK_SIZE) {
+            match mem.read_u8(buf.wrapping_add(i)) {
+                Ok(byte) => chunk.push(byte),
+                Err(_) => {
+                    fault = true;
+                    break;
+                }
+            }
+        }
+        if out.write_all(&chunk).and_then(|_| out.flush()).is_err() {
+            return if done == 0 { -EFAULT } else { done as i64 };
+        }
+        done += chunk.len() as u64;
+        if fault {
+            return if done == 0 { -EFAULT } else { done as i64 };
+        }
+    }
+    done as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libemu6::mem64::{Config, Endian, Physical, Protect};
+
+    const TOHOST: u64 = 0x8000_1000;
+    const FROMHOST: u64 = 0x8000_1040;
+    const MAGIC_MEM: u64 = 0x8000_2000;
+
+    fn memory() -> Physical<'static> {
+        let mut mem = Physical::new();
+        mem.push_zeroed(Config {
+            range: 0x8000_0000..0x8000_4000,
+            protect: Protect::READ | Protect::WRITE,
+            endian: Endian::Little,
+        });
+        mem
+    }
+
+    fn syscall(mem: &mut Physical, args: &[u64]) {
+        for (i, &arg) in args.iter().enumerate() {
+            mem.write_u64(MAGIC_MEM + 8 * i as u64, arg).unwrap();
+        }
+        mem.write_u64(TOHOST, MAGIC_MEM).unwrap();
+    }
+
+    #[test]
+    fn exit_through_tohost() {
+        let mut mem = memory();
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        assert_eq!(htif.poll(&mut mem), None);
+        // riscv-tests report failures as (test number << 1) | 1
+        mem.write_u64(TOHOST, 3 << 1 | 1).unwrap();
+        assert_eq!(htif.poll(&mut mem), Some(3));
+        assert_eq!(mem.read_u64(TOHOST).unwrap(), 0);
+    }
+
+    #[test]
+    fn proxied_system_calls() {
+        let mut mem = memory();
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        syscall(&mut mem, &[SYS_WRITE, 3, 0x8000_3000, 4]);
+        assert_eq!(htif.poll(&mut mem), None);
+        assert_eq!(mem.read_u64(MAGIC_MEM).unwrap() as i64, -EBADF);
+        assert_eq!(mem.read_u64(TOHOST).unwrap(), 0);
+        assert_eq!(mem.read_u64(FROMHOST).unwrap(), 1);
+        syscall(&mut mem, &[2000]);
+        assert_eq!(htif.poll(&mut mem), None);
+        assert_eq!(mem.read_u64(MAGIC_MEM).unwrap() as i64, -ENOSYS);
+        syscall(&mut mem, &[SYS_EXIT_GROUP, 7]);
+        assert_eq!(htif.poll(&mut mem), Some(7));
+    }
+}
@@ -0,0 +1,830 @@
+use crate::guest::Guest;
+use libemu6::riscv::Execute;
+use libemu6::size::Usize;
+use std::ffi::CString;
+use std::io;
+use std::ops::Range;
+
+// Guest address space layout; stack and mmap regions sit below 2 GiB so that
+// RV32 programs can address them as well
+pub const STACK_TOP: u64 = 0x7FFF_0000;
+pub const STACK_SIZE: u64 = 8 << 20;
+pub const MMAP_BASE: u64 = 0x4000_0000;
+pub const MMAP_END: u64 = STACK_TOP - STACK_SIZE;
+pub const BRK_SIZE: u64 = 256 << 20;
+const PAGE_SIZE: u64 = 4096;
+// most bytes a single transfer copies through a host buffer; larger requests
+// complete short, as Linux allows
+const IO_LIMIT: u64 = 1 << 20;
+// longest path a symbolic link resolves to
+const PATH_MAX: u64 = 4096;
+
+// system call numbers of the generic Linux ABI used by RISC-V
+const SYS_GETCWD: u64 = 17;
+const SYS_IOCTL: u64 = 29;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+// numbered after the generic ABI by libgloss
+const SYS_OPEN: u64 = 1024;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOMEM: i64 = 12;
+const ENOSYS: i64 = 38;
+const ENOTTY: i64 = 25;
+// newlib numbers ENOSYS differently; its other common values are those of Linux
+const NEWLIB_ENOSYS: i64 = 88;
+
+const AT_FDCWD: i64 = -100;
+
+// open flags of the generic ABI, translated to the host's
+const OPEN_FLAGS: [(u64, i32); 11] = [
+    (0o100, libc::O_CREAT),
+    (0o200, libc::O_EXCL),
+    (0o400, libc::O_NOCTTY),
+    (0o1000, libc::O_TRUNC),
+    (0o2000, libc::O_APPEND),
+    (0o4000, libc::O_NONBLOCK),
+    (0o10000, libc::O_DSYNC),
+    (0o200000, libc::O_DIRECTORY),
+    (0o400000, libc::O_NOFOLLOW),
+    (0o2000000, libc::O_CLOEXEC),
+    (0o10000000, libc::O_PATH),
+];
+
+// newlib open flags, translated to those of the generic ABI
+const NEWLIB_OPEN_FLAGS: [(u64, u64); 9] = [
+    (0x0008, 0o2000),
+    (0x0200, 0o100),
+    (0x0400, 0o1000),
+    (0x0800, 0o200),
+    (0x4000, 0o4000),
+    (0x8000, 0o400),
+    (0x40000, 0o2000000),
+    (0x100000, 0o400000),
+    (0x200000, 0o200000),
+];
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+// Where the program was loaded, for the auxiliary vector
+#[derive(Clone, Copy, Debug)]
+pub struct Image {
+    pub entry: u64,
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+}
+
+// System call interfaces served to the program
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Profile {
+    // the system calls of Linux C libraries
+    Linux,
+    // the subset newlib makes through libgloss: write, read, open, close,
+    // lseek, fstat, exit, gettimeofday and brk for sbrk
+    Newlib,
+}
+
+// Linux user-mode emulation: ECALLs are system calls with the number in a7 and
+// arguments in a0 to a5, served by the host; the result or a negated errno is
+// returned in a0. File descriptors are the host's own. Memory comes from two
+// regions set aside when loading: the brk heap after the program, and an
+// anonymous mapping region which is handed out from the bottom up. Bare-metal
+// newlib programs get the same service for their ECALLs, without being a process
+#[derive(Debug)]
+pub struct Linux {
+    profile: Profile,
+    process: bool,
+    brk_start: u64,
+    brk: u64,
+    brk_end: u64,
+    mmap_top: u64,
+}
+
+impl Linux {
+    pub fn new(profile: Profile, heap: Range<u64>) -> Linux {
+        Linux {
+            profile,
+            process: true,
+            brk_start: heap.start,
+            brk: heap.start,
+            brk_end: heap.end,
+            mmap_top: MMAP_BASE,
+        }
+    }
+
+    // Serves the newlib calls of a bare-metal program with the brk heap in `heap`
+    pub fn bare_metal(heap: Range<u64>) -> Linux {
+        Linux {
+            process: false,
+            ..Linux::new(Profile::Newlib, heap)
+        }
+    }
+
+    // Whether the program is a process, which ends on any trap but a system call
+    pub fn is_process(&self) -> bool {
+        self.process
+    }
+
+    // Builds the initial stack of argc, argv, envp and the auxiliary vector
+    // below `top`; returns the stack pointer
+    pub fn setup_stack(
+        exec: &mut Execute,
+        top: u64,
+        args: &[String],
+        env: &[String],
+        image: Image,
+    ) -> u64 {
+        let mut mem = Guest { exec };
+        let word = mem.word_bytes();
+        let mut sp = top;
+        let mut push_bytes = |mem: &mut Guest, data: &[u8]| {
+            sp -= data.len() as u64;
+            mem.set_bytes(sp, data);
+            sp
+        };
+        let mut random = [0u8; 16];
+        unsafe { libc::getrandom(random.as_mut_ptr() as *mut _, random.len(), 0) };
+        let random = push_bytes(&mut mem, &random);
+        let mut push_str = |mem: &mut Guest, s: &str| {
+            let mut data = s.as_bytes().to_vec();
+            data.push(0);
+            push_bytes(mem, &data)
+        };
+        let argv: Vec<u64> = args.iter().map(|s| push_str(&mut mem, s)).collect();
+        let envp: Vec<u64> = env.iter().map(|s| push_str(&mut mem, s)).collect();
+        let execfn = argv[0];
+        let hwcap = "IMAFDC"
+            .chars()
+            .filter(|&c| mem.exec.has_extension(c))
+            .fold(0, |bits, c| bits | 1 << (c as u8 - b'A'));
+        let (uid, gid) = unsafe { (libc::getuid() as u64, libc::getgid() as u64) };
+        let auxv = [
+            (AT_PHDR, image.phdr),
+            (AT_PHENT, image.phent),
+            (AT_PHNUM, image.phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_ENTRY, image.entry),
+            (AT_UID, uid),
+            (AT_EUID, uid),
+            (AT_GID, gid),
+            (AT_EGID, gid),
+            (AT_HWCAP, hwcap),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![args.len() as u64];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for (key, val) in auxv.iter() {
+            words.extend(&[*key, *val]);
+        }
+        // the stack pointer is 16-byte aligned at entry
+        let sp = (sp - words.len() as u64 * word) & !0xF;
+        for (i, &val) in words.iter().enumerate() {
+            mem.write(sp + i as u64 * word, val, word);
+        }
+        sp
+    }
+
+    // Serves the system call; returns the exit code if the program exits
+    pub fn call(&mut self, exec: &mut Execute) -> Option<u32> {
+        let num = exec.read_x(17).to_u64();
+        let mut args = [0u64; 6];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = exec.read_x(10 + i as u8).to_u64();
+        }
+        if num == SYS_EXIT || num == SYS_EXIT_GROUP {
+            return Some(args[0] as u32 & 0xFF);
+        }
+        let mut mem = Guest { exec };
+        let ans = match self.profile {
+            Profile::Linux => self.linux_call(&mut mem, num, &args),
+            Profile::Newlib => self.newlib_call(&mut mem, num, &args),
+        };
+        let xlen = mem.exec.xlen();
+        mem.exec.write_x(10, Usize::from_u64(ans as u64, xlen));
+        None
+    }
+
+    fn linux_call(&mut self, mem: &mut Guest, num: u64, args: &[u64; 6]) -> i64 {
+        match num {
+            SYS_READ => read(mem, args[0], args[1], args[2]),
+            SYS_WRITE => write(mem, args[0], args[1], args[2]),
+            SYS_READV | SYS_WRITEV => self.vectored(mem, num, args[0], args[1], args[2]),
+            SYS_OPENAT => match c_string(mem, args[1]) {
+                Some(path) => {
+                    let flags = open_flags(args[2]);
+                    let ans = unsafe {
+                        libc::openat(args[0] as i32, path.as_ptr(), flags, args[3] as libc::c_uint)
+                    };
+                    host_result(ans as i64)
+                }
+                None => -EFAULT,
+            },
+            SYS_CLOSE => host_result(unsafe { libc::close(args[0] as i32) } as i64),
+            SYS_LSEEK => {
+                // offsets are signed words
+                let offset = match mem.word_bytes() {
+                    4 => args[1] as i32 as i64,
+                    _ => args[1] as i64,
+                };
+                let ans = unsafe { libc::lseek(args[0] as i32, offset, args[2] as i32) };
+                host_result(ans)
+            }
+            SYS_FSTAT => {
+                let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+                let ans = unsafe { libc::fstat(args[0] as i32, &mut stat) };
+                write_stat(mem, ans, &stat, args[1])
+            }
+            SYS_NEWFSTATAT => match c_string(mem, args[1]) {
+                Some(path) => {
+                    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+                    let (dirfd, flags) = (args[0] as i32, args[3] as i32);
+                    let ans = unsafe { libc::fstatat(dirfd, path.as_ptr(), &mut stat, flags) };
+                    write_stat(mem, ans, &stat, args[2])
+                }
+                None => -EFAULT,
+            },
+            SYS_FACCESSAT => match c_string(mem, args[1]) {
+                Some(path) => {
+                    let ans = unsafe {
+                        libc::faccessat(args[0] as i32, path.as_ptr(), args[2] as i32, 0)
+                    };
+                    host_result(ans as i64)
+                }
+                None => -EFAULT,
+            },
+            SYS_READLINKAT => match c_string(mem, args[1]) {
+                // the size is an int; links longer than the buffer are truncated
+                Some(_) if args[3] as i32 <= 0 => -EINVAL,
+                Some(path) => {
+                    let mut buf = vec![0u8; (args[3] as i32 as u64).min(PATH_MAX) as usize];
+                    let ans = unsafe {
+                        let buf_ptr = buf.as_mut_ptr() as *mut libc::c_char;
+                        libc::readlinkat(args[0] as i32, path.as_ptr(), buf_ptr, buf.len())
+                    };
+                    copy_out(mem, host_result(ans as i64), &buf, args[2])
+                }
+                None => -EFAULT,
+            },
+            SYS_GETCWD => match std::env::current_dir() {
+                Ok(dir) => {
+                    let mut data = dir.to_string_lossy().into_owned().into_bytes();
+                    data.push(0);
+                    if data.len() as u64 > args[1] {
+                        -(libc::ERANGE as i64)
+                    } else {
+                        copy_out(mem, data.len() as i64, &data, args[0])
+                    }
+                }
+                Err(e) => -io_errno(e),
+            },
+            // there are no terminals; C libraries then buffer output fully
+            SYS_IOCTL => -ENOTTY,
+            SYS_BRK => self.brk(mem, args[0]) as i64,
+            SYS_MMAP => self.mmap(mem, args),
+            // unmapped memory is only reclaimed at the top of the mapping region
+            SYS_MUNMAP => match page_align(args[1]) {
+                Some(len) => {
+                    let end = args[0].wrapping_add(len);
+                    if end == self.mmap_top && args[0] >= MMAP_BASE {
+                        self.mmap_top = args[0];
+                    }
+                    0
+                }
+                None => -EINVAL,
+            },
+            // all memory is readable, writable and executable
+            SYS_MPROTECT | SYS_MADVISE => 0,
+            SYS_CLOCK_GETTIME => {
+                let mut ts = unsafe { std::mem::zeroed::<libc::timespec>() };
+                let ans = unsafe { libc::clock_gettime(args[0] as libc::clockid_t, &mut ts) };
+                let ans = host_result(ans as i64);
+                if ans == 0 && !write_pair(mem, args[1], ts.tv_sec as u64, ts.tv_nsec as u64) {
+                    -EFAULT
+                } else {
+                    ans
+                }
+            }
+            SYS_GETTIMEOFDAY => {
+                let mut ts = unsafe { std::mem::zeroed::<libc::timespec>() };
+                unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+                let usec = ts.tv_nsec as u64 / 1000;
+                if args[0] != 0 && !write_pair(mem, args[0], ts.tv_sec as u64, usec) {
+                    -EFAULT
+                } else {
+                    0
+                }
+            }
+            SYS_GETRANDOM => {
+                let mut buf = vec![0u8; args[1].min(IO_LIMIT) as usize];
+                let ans = unsafe {
+                    libc::getrandom(buf.as_mut_ptr() as *mut _, buf.len(), args[2] as u32)
+                };
+                copy_out(mem, host_result(ans as i64), &buf, args[0])
+            }
+            SYS_UNAME => uname(mem, args[0]),
+            // signals are never delivered; the previous actions and masks are empty
+            SYS_RT_SIGACTION => zero_out(mem, args[2], 24),
+            SYS_RT_SIGPROCMASK => zero_out(mem, args[2], 8),
+            SYS_PRLIMIT64 => {
+                let limit = if args[1] == RLIMIT_STACK { STACK_SIZE } else { RLIM_INFINITY };
+                if args[3] != 0 && !write_pair(mem, args[3], limit, limit) {
+                    -EFAULT
+                } else {
+                    0
+                }
+            }
+            // there is a single thread, whose id is the process id
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => unsafe { libc::getpid() as i64 },
+            SYS_GETPPID => unsafe { libc::getppid() as i64 },
+            SYS_GETUID => unsafe { libc::getuid() as i64 },
+            SYS_GETEUID => unsafe { libc::geteuid() as i64 },
+            SYS_GETGID => unsafe { libc::getgid() as i64 },
+            SYS_GETEGID => unsafe { libc::getegid() as i64 },
+            SYS_FUTEX | SYS_SET_ROBUST_LIST => 0,
+            _ => {
+                eprintln!("Unsupported system call {}", num);
+                -ENOSYS
+            }
+        }
+    }
+
+    // Serves the libgloss subset through the Linux calls, after translating
+    // open flags
+    fn newlib_call(&mut self, mem: &mut Guest, num: u64, args: &[u64; 6]) -> i64 {
+        let mut args = *args;
+        let num = match num {
+            SYS_OPEN => {
+                let (path, flags, mode) = (args[0], args[1], args[2]);
+                args = [AT_FDCWD as u64, path, newlib_open_flags(flags), mode, 0, 0];
+                SYS_OPENAT
+            }
+            SYS_OPENAT => {
+                args[2] = newlib_open_flags(args[2]);
+                SYS_OPENAT
+            }
+            SYS_READ | SYS_WRITE | SYS_CLOSE | SYS_LSEEK | SYS_FSTAT | SYS_GETTIMEOFDAY
+            | SYS_BRK => num,
+            _ => {
+                eprintln!("Unsupported system call {}", num);
+                return -NEWLIB_ENOSYS;
+            }
+        };
+        self.linux_call(mem, num, &args)
+    }
+
+    // returns the new break; requests outside the heap region leave it unchanged
+    fn brk(&mut self, mem: &mut Guest, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > self.brk_end {
+            return self.brk;
+        }
+        // memory given back and taken again reads as zero
+        if addr > self.brk {
+            zero_out(mem, self.brk, addr - self.brk);
+        }
+        self.brk = addr;
+        addr
+    }
+
+    fn mmap(&mut self, mem: &mut Guest, args: &[u64; 6]) -> i64 {
+        let (addr, len, flags, fd, offset) = (args[0], args[1], args[3], args[4], args[5]);
+        if len == 0 {
+            return -EINVAL;
+        }
+        let len = match page_align(len) {
+            Some(len) => len,
+            None => return -ENOMEM,
+        };
+        let start = if flags & MAP_FIXED != 0 {
+            if addr < MMAP_BASE || addr.saturating_add(len) > MMAP_END {
+                return -ENOMEM;
+            }
+            self.mmap_top = self.mmap_top.max(addr + len);
+            addr
+        } else {
+            match self.mmap_top.checked_add(len) {
+                Some(end) if end <= MMAP_END => {
+                    self.mmap_top = end;
+                    end - len
+                }
+                _ => return -ENOMEM,
+            }
+        };
+        zero_out(mem, start, len);
+        if flags & MAP_ANONYMOUS == 0 {
+            // file mappings are private copies of the file contents, read in
+            // bounded pieces up to the end of the file
+            let mut buf = vec![0u8; len.min(IO_LIMIT) as usize];
+            let mut done = 0;
+            while done < len {
+                let want = (len - done).min(IO_LIMIT) as usize;
+                let ans = unsafe {
+                    let pos = offset.wrapping_add(done) as i64;
+                    libc::pread(fd as i32, buf.as_mut_ptr() as *mut _, want, pos)
+                };
+                let count = host_result(ans as i64);
+                if count < 0 {
+                    return count;
+                }
+                if count == 0 || !mem.set_bytes(start + done, &buf[..count as usize]) {
+                    break;
+                }
+                done += count as u64;
+            }
+        }
+        start as i64
+    }
+
+    fn vectored(&self, mem: &mut Guest, num: u64, fd: u64, iov: u64, iovcnt: u64) -> i64 {
+        let mut total = 0;
+        for i in 0..iovcnt {
+            let base = mem.field(iov, 2 * i);
+            let len = mem.field(iov, 2 * i + 1);
+            if len == 0 {
+                continue;
+            }
+            let ans = if num == SYS_READV {
+                read(mem, fd, base, len)
+            } else {
+                write(mem, fd, base, len)
+            };
+            if ans < 0 {
+                return if total == 0 { ans } else { total };
+            }
+            total += ans;
+            // a short transfer ends the call
+            if (ans as u64) < len {
+                break;
+            }
+        }
+        total
+    }
+}
+
+fn read(mem: &mut Guest, fd: u64, buf: u64, len: u64) -> i64 {
+    let mut data = vec![0u8; len.min(IO_LIMIT) as usize];
+    let ans = unsafe { libc::read(fd as i32, data.as_mut_ptr() as *mut _, data.len()) };
+    copy_out(mem, host_result(ans as i64), &data, buf)
+}
+
+fn write(mem: &mut Guest, fd: u64, buf: u64, len: u64) -> i64 {
+    let data = match mem.bytes(buf, len.min(IO_LIMIT)) {
+        Some(data) => data,
+        None => return -EFAULT,
+    };
+    let ans = unsafe { libc::write(fd as i32, data.as_ptr() as *const _, data.len()) };
+    host_result(ans as i64)
+}
+
+// copies the first `count` bytes of `data` to the guest, if the call succeeded
+fn copy_out(mem: &mut Guest, count: i64, data: &[u8], addr: u64) -> i64 {
+    if count > 0 && !mem.set_bytes(addr, &data[..count as usize]) {
+        return -EFAULT;
+    }
+    count
+}
+
+fn zero_out(mem: &mut Guest, addr: u64, len: u64) -> i64 {
+    if addr == 0 {
+        return 0;
+    }
+    let mut offset = 0;
+    while offset < len {
+        let nbytes = if len - offset >= 8 && (addr + offset) % 8 == 0 { 8 } else { 1 };
+        if !mem.write(addr + offset, 0, nbytes) {
+            return -EFAULT;
+        }
+        offset += nbytes;
+    }
+    0
+}
+
+// writes two 64-bit values, as in struct timespec or struct rlimit
+fn write_pair(mem: &mut Guest, addr: u64, first: u64, second: u64) -> bool {
+    mem.write(addr, first, 8) && mem.write(addr + 8, second, 8)
+}
+
+// struct stat of the generic ABI
+fn write_stat(mem: &mut Guest, ans: i32, stat: &libc::stat, addr: u64) -> i64 {
+    let ans = host_result(ans as i64);
+    if ans < 0 {
+        return ans;
+    }
+    let fields: [(u64, u64); 18] = [
+        (stat.st_dev as u64, 8),
+        (stat.st_ino as u64, 8),
+        (stat.st_mode as u64, 4),
+        (stat.st_nlink as u64, 4),
+        (stat.st_uid as u64, 4),
+        (stat.st_gid as u64, 4),
+        (stat.st_rdev as u64, 8),
+        (0, 8),
+        (stat.st_size as u64, 8),
+        (stat.st_blksize as u64, 4),
+        (0, 4),
+        (stat.st_blocks as u64, 8),
+        (stat.st_atime as u64, 8),
+        (stat.st_atime_nsec as u64, 8),
+        (stat.st_mtime as u64, 8),
+        (stat.st_mtime_nsec as u64, 8),
+        (stat.st_ctime as u64, 8),
+        (stat.st_ctime_nsec as u64, 8),
+    ];
+    let mut offset = 0;
+    for &(val, nbytes) in fields.iter() {
+        if !mem.write(addr + offset, val, nbytes) {
+            return -EFAULT;
+        }
+        offset += nbytes;
+    }
+    0
+}
+
+// struct utsname of six 65-byte strings
+fn uname(mem: &mut Guest, addr: u64) -> i64 {
+    let fields = ["Linux", "emu6", "6.1.0", "#1", "riscv64", ""];
+    for (i, field) in fields.iter().enumerate() {
+        let mut data = field.as_bytes().to_vec();
+        data.resize(65, 0);
+        if !mem.set_bytes(addr + 65 * i as u64, &data) {
+            return -EFAULT;
+        }
+    }
+    0
+}
+
+fn c_string(mem: &mut Guest, addr: u64) -> Option<CString> {
+    let mut data = Vec::new();
+    loop {
+        match mem.byte(addr + data.len() as u64)? {
+            0 => return CString::new(data).ok(),
+            b => data.push(b),
+        }
+    }
+}
+
+fn newlib_open_flags(flags: u64) -> u64 {
+    let access = flags & 0b11;
+    NEWLIB_OPEN_FLAGS
+        .iter()
+        .filter(|&&(newlib, _)| flags & newlib != 0)
+        .fold(access, |ans, &(_, generic)| ans | generic)
+}
+
+fn open_flags(flags: u64) -> i32 {
+    let access = (flags & 0b11) as i32;
+    OPEN_FLAGS
+        .iter()
+        .filter(|&&(guest, _)| flags & guest != 0)
+        .fold(access, |ans, &(_, host)| ans | host)
+}
+
+// host calls return -1 and set errno on failure
+fn host_result(ans: i64) -> i64 {
+    if ans < 0 {
+        -io_errno(io::Error::last_os_error())
+    } else {
+        ans
+    }
+}
+
+// errno values of Linux hosts equal those of the guest
+fn io_errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(EBADF, |errno| errno as i64)
+}
+
+// The region of the brk heap, from the first page after the program
+pub fn heap(image_end: u64) -> Range<u64> {
+    let start = page_align(image_end).unwrap_or(u64::MAX);
+    start..start.saturating_add(BRK_SIZE).min(MMAP_BASE).max(start)
+}
+
+// None if rounding up overflows the address space
+fn page_align(n: u64) -> Option<u64> {
+    n.checked_add(PAGE_SIZE - 1).map(|n| n & !(PAGE_SIZE - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libemu6::{
+        mem64::{Config, Endian, Physical, Protect},
+        riscv::Xlen,
+        size::Usize,
+    };
+
+    const HEAP: u64 = 0x0002_0000;
+
+    // An RV64 hart with memory for a small heap, the first pages of the mapping
+    // region and the top of the stack
+    fn hart() -> Execute<'static> {
+        let mut mem = Box::new(Physical::new());
+        let regions = [
+            HEAP..(HEAP + 0x4000),
+            MMAP_BASE..(MMAP_BASE + 0x4000),
+            (STACK_TOP - 0x4000)..STACK_TOP,
+        ];
+        for range in regions.iter() {
+            mem.push_zeroed(Config {
+                range: range.clone(),
+                protect: Protect::READ | Protect::WRITE,
+                endian: Endian::Little,
+            });
+        }
+        Execute::new(Box::leak(mem), Xlen::X64)
+    }
+
+    fn syscall(linux: &mut Linux, exec: &mut Execute, num: u64, args: &[u64]) -> i64 {
+        exec.write_x(17, Usize::U64(num));
+        for (i, &arg) in args.iter().enumerate() {
+            exec.write_x(10 + i as u8, Usize::U64(arg));
+        }
+        assert_eq!(linux.call(exec), None);
+        exec.read_x(10).to_u64() as i64
+    }
+
+    fn read(exec: &mut Execute, addr: u64) -> u64 {
+        exec.read_mem(Usize::U64(addr), 8).unwrap()
+    }
+
+    #[test]
+    fn heap_follows_the_program() {
+        assert_eq!(page_align(0), Some(0));
+        assert_eq!(page_align(1), Some(PAGE_SIZE));
+        assert_eq!(page_align(PAGE_SIZE), Some(PAGE_SIZE));
+        assert_eq!(page_align(u64::MAX), None);
+        assert_eq!(heap(0x1_0001), 0x1_1000..(0x1_1000 + BRK_SIZE));
+        assert!(heap(u64::MAX).is_empty());
+        // the heap never reaches into the mapping region
+        assert_eq!(heap(MMAP_BASE - 0x1000), (MMAP_BASE - 0x1000)..MMAP_BASE);
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks_within_the_heap() {
+        let mut exec = hart();
+        let mut linux = Linux::new(Profile::Linux, HEAP..(HEAP + 0x4000));
+        let mut brk = |exec: &mut Execute, addr| syscall(&mut linux, exec, SYS_BRK, &[addr]) as u64;
+        assert_eq!(brk(&mut exec, 0), HEAP);
+        assert_eq!(brk(&mut exec, HEAP + 0x2000), HEAP + 0x2000);
+        exec.write_mem(Usize::U64(HEAP + 0x1000), 0x55, 8).unwrap();
+        // requests outside the heap leave the break unchanged
+        assert_eq!(brk(&mut exec, HEAP + 0x8000), HEAP + 0x2000);
+        assert_eq!(brk(&mut exec, HEAP + 0x1000), HEAP + 0x1000);
+        brk(&mut exec, HEAP + 0x2000);
+        assert_eq!(read(&mut exec, HEAP + 0x1000), 0);
+    }
+
+    #[test]
+    fn anonymous_mappings() {
+        let mut exec = hart();
+        let mut linux = Linux::new(Profile::Linux, HEAP..(HEAP + 0x4000));
+        let anonymous = |len| [0, len, 3, MAP_ANONYMOUS, u64::MAX, 0];
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &anonymous(10)), MMAP_BASE as i64);
+        let second = syscall(&mut linux, &mut exec, SYS_MMAP, &anonymous(0x1000));
+        assert_eq!(second, (MMAP_BASE + 0x1000) as i64);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &anonymous(0)), -EINVAL);
+        // sizes which overflow when rounded up to pages
+        let huge = u64::MAX - 0x10;
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &anonymous(huge)), -ENOMEM);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MUNMAP, &[MMAP_BASE, huge]), -EINVAL);
+        let fixed = [0x1000, 0x1000, 3, MAP_ANONYMOUS | MAP_FIXED, u64::MAX, 0];
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &fixed), -ENOMEM);
+        // unmapping the top mapping hands its pages out again
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MUNMAP, &[second as u64, 0x1000]), 0);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &anonymous(0x1000)), second);
+    }
+
+    #[test]
+    fn system_calls() {
+        let mut exec = hart();
+        let mut linux = Linux::new(Profile::Linux, HEAP..(HEAP + 0x4000));
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_WRITE, &[1, 0x10, 4]), -EFAULT);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_WRITE, &[u32::MAX as u64, HEAP, 4]), -EBADF);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_IOCTL, &[1, 0x5401, 0]), -ENOTTY);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_GETPID, &[]), std::process::id() as i64);
+        assert_eq!(syscall(&mut linux, &mut exec, 1000, &[]), -ENOSYS);
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_UNAME, &[HEAP]), 0);
+        assert_eq!(read(&mut exec, HEAP) & 0xFF_FFFF_FFFF, u64::from_le_bytes(*b"Linux\0\0\0"));
+        exec.write_x(17, Usize::U64(SYS_EXIT_GROUP));
+        exec.write_x(10, Usize::U64(0x1_0003));
+        assert_eq!(linux.call(&mut exec), Some(3));
+    }
+
+    #[test]
+    fn initial_stack() {
+        let mut exec = hart();
+        let args = ["prog".to_string(), "-v".to_string()];
+        let env = ["HOME=/".to_string()];
+        let image = Image { entry: 0x1_0000, phdr: 0x1_0040, phent: 56, phnum: 2 };
+        let sp = Linux::setup_stack(&mut exec, STACK_TOP, &args, &env, image);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(read(&mut exec, sp), 2);
+        let c_str = |exec: &mut Execute, addr: u64| {
+            let bytes: Vec<u8> = (addr..)
+                .map(|a| exec.read_mem(Usize::U64(a), 1).unwrap() as u8)
+                .take_while(|&b| b != 0)
+                .collect();
+            String::from_utf8(bytes).unwrap()
+        };
+        let argv1 = read(&mut exec, sp + 16);
+        assert_eq!(c_str(&mut exec, argv1), "-v");
+        assert_eq!(read(&mut exec, sp + 24), 0);
+        let envp0 = read(&mut exec, sp + 32);
+        assert_eq!(c_str(&mut exec, envp0), "HOME=/");
+        assert_eq!(read(&mut exec, sp + 40), 0);
+        let auxv: Vec<(u64, u64)> = (0..)
+            .map(|i| (read(&mut exec, sp + 48 + 16 * i), read(&mut exec, sp + 56 + 16 * i)))
+            .take_while(|&(key, _)| key != AT_NULL)
+            .collect();
+        assert!(auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+        assert!(auxv.contains(&(AT_ENTRY, 0x1_0000)));
+        assert!(auxv.contains(&(AT_PHNUM, 2)));
+    }
+
+    #[test]
+    fn newlib_write_and_exit() {
+        let mut exec = hart();
+        let mut linux = Linux::bare_metal(HEAP..(HEAP + 0x4000));
+        assert!(!linux.is_process());
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        for (i, &b) in b"hi\n".iter().enumerate() {
+            exec.write_mem(Usize::U64(HEAP + i as u64), b as u64, 1).unwrap();
+        }
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_WRITE, &[fds[1] as u64, HEAP, 3]), 3);
+        let mut buf = [0u8; 3];
+        let count = unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut _, 3) };
+        assert_eq!((count, &buf), (3, b"hi\n"));
+        unsafe { libc::close(fds[0]) };
+        unsafe { libc::close(fds[1]) };
+        // calls outside the libgloss subset fail with newlib's ENOSYS
+        assert_eq!(syscall(&mut linux, &mut exec, SYS_MMAP, &[0, 0x1000]), -NEWLIB_ENOSYS);
+        exec.write_x(17, Usize::U64(SYS_EXIT));
+        exec.write_x(10, Usize::U64(3));
+        assert_eq!(linux.call(&mut exec), Some(3));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::htif::Htif;
+#[cfg(target_os = "linux")]
+use crate::linux::Linux;
+use crate::sbi::Sbi;
+use crate::semihosting::Semihosting;
+use libemu6::{
+    device::{Clint, Plic, TestFinisher},
+    riscv::{Exception, Execute, Fetch, Instruction, Interrupt, Trap},
+    size::Usize,
+    Error,
+};
+
+// A single hart together with its memory and interrupt controllers
+pub struct Machine<'a> {
+    pub fetch: Fetch,
+    pub exec: Execute<'a>,
+    pub pc: Usize,
+    // print interrupts and traps as they are taken
+    pub trace: bool,
+    // polled after every instruction when the program has a `tohost` symbol
+    pub htif: Option<Htif>,
+    // serves semihosting calls instead of entering the guest breakpoint handler
+    pub semihosting: Option<Semihosting>,
+    // serves SBI calls from S-mode in place of firmware
+    pub sbi: Option<Sbi>,
+    // serves the system calls of a Linux process, or of a bare-metal newlib program
+    #[cfg(target_os = "linux")]
+    pub linux: Option<Linux>,
+    clint: Clint,
+    plic: Plic,
+    finisher: TestFinisher,
+    exit_code: Option<u32>,
+}
+
+// What happened during a single step
+#[derive(Debug)]
+pub enum Step {
+    Retired(Instruction),
+    Trapped,
+    // the guest program has finished; no further instructions are executed
+    Exited(u32),
+}
+
+impl<'a> Machine<'a> {
+    // `exec` owns the memory bus and lends it to `fetch` for each instruction
+    pub fn new(
+        fetch: Fetch,
+        exec: Execute<'a>,
+        clint: Clint,
+        plic: Plic,
+        finisher: TestFinisher,
+        pc: Usize,
+    ) -> Machine<'a> {
+        Machine {
+            fetch,
+            exec,
+            pc,
+            trace: false,
+            htif: None,
+            semihosting: None,
+            sbi: None,
+            #[cfg(target_os = "linux")]
+            linux: None,
+            clint,
+            plic,
+            finisher,
+            exit_code: None,
+        }
+    }
+
+    // Takes a pending interrupt, then executes one instruction. Synchronous
+    // exceptions enter the guest trap handler
+    pub fn step(&mut self) -> Result<Step, Error> {
+        if let Some(code) = self.exit_code.or_else(|| self.finisher.exit_code()) {
+            self.exit_code = Some(code);
+            return Ok(Step::Exited(code));
+        }
+        let exec = &mut self.exec;
+        exec.set_time(self.clint.mtime());
+        exec.set_interrupt_pending(Interrupt::MachineSoftware, self.clint.software_pending(0));
+        exec.set_interrupt_pending(Interrupt::MachineTimer, self.clint.timer_pending(0));
+        exec.set_interrupt_pending(Interrupt::MachineExternal, self.plic.machine_pending(0));
+        exec.set_interrupt_pending(Interrupt::SupervisorExternal, self.plic.supervisor_pending(0));
+        if let Some(sbi) = &self.sbi {
+            exec.set_interrupt_pending(Interrupt::SupervisorTimer, sbi.timer_pending());
+        }
+        if let Some(handler) = exec.take_interrupt(self.pc) {
+            if self.trace {
+                eprintln!("Interrupt at {:#016X}", self.pc);
+            }
+            self.pc = handler;
+        }
+        let pc = self.pc;
+        let result = self.fetch.fetch(exec.bus(), pc, &exec.translate()).and_then(|ins| {
+            let next_pc = exec.execute(ins, pc)?;
+            Ok((ins, next_pc))
+        });
+        let (ins, next_pc) = match result {
+            Ok(ans) => ans,
+            Err(Error::Trap(trap)) => {
+                if let Some(step) = self.host_call(trap, pc) {
+                    return Ok(step);
+                }
+                if self.trace {
+                    eprintln!("Trap at {:#016X}: {}", pc, trap);
+                }
+                self.pc = self.exec.handle_trap(trap, pc);
+                return Ok(Step::Trapped);
+            }
+            Err(e) => return Err(e),
+        };
+        self.retire(next_pc);
+        Ok(Step::Retired(ins))
+    }
+
+    // Serves the system call, SBI call or semihosting call at `pc`; returns
+    // None if the trap enters the guest trap handler instead
+    fn host_call(&mut self, trap: Trap, pc: Usize) -> Option<Step> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(step) = self.system_call(trap, pc) {
+                return Some(step);
+            }
+        }
+        if let Some(sbi) = self.sbi.as_mut() {
+            if trap.cause == Exception::EnvironmentCallFromSMode {
+                let code = sbi.call(&mut self.exec);
+                return self.retire_call(code, pc);
+            }
+        }
+        if trap.cause != Exception::Breakpoint || self.semihosting.is_none() {
+            return None;
+        }
+        let xlen = self.exec.xlen();
+        let decode = |addr| self.decode(Usize::from_u64(addr, xlen)).ok();
+        if !Semihosting::is_call(decode, pc.to_u64()) {
+            return None;
+        }
+        let semihosting = self.semihosting.as_mut()?;
+        let code = semihosting.call(&mut self.exec);
+        self.retire_call(code, pc)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn system_call(&mut self, trap: Trap, pc: Usize) -> Option<Step> {
+        let linux = self.linux.as_mut()?;
+        match trap.cause {
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => {
+                let code = linux.call(&mut self.exec);
+                self.retire_call(code, pc)
+            }
+            // a process has no trap handler; it ends as if killed by the signal
+            cause if linux.is_process() => {
+                eprintln!("{} at {:#016X}", trap, pc);
+                let code = 128 + signal(cause);
+                self.exit_code = Some(code);
+                Some(Step::Exited(code))
+            }
+            _ => None,
+        }
+    }
+
+    // The call at `pc` retires like any other instruction
+    fn retire_call(&mut self, exit_code: Option<u32>, pc: Usize) -> Option<Step> {
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+        }
+        let ins = self.decode(pc).ok()?;
+        self.retire(pc + 4);
+        Some(Step::Retired(ins))
+    }
+
+    fn retire(&mut self, next_pc: Usize) {
+        let mem = self.exec.bus_mut();
+        mem.tick();
+        if let Some(code) = self.htif.as_mut().and_then(|htif| htif.poll(mem)) {
+            self.exit_code = Some(code);
+        }
+        self.pc = next_pc;
+    }
+
+    // Decodes the instruction at `pc` without executing it
+    pub fn decode(&mut self, pc: Usize) -> Result<Instruction, Error> {
+        self.fetch.fetch(self.exec.bus(), pc, &self.exec.translate())
+    }
+}
+
+// the signal Linux sends for an exception in a user process
+#[cfg(target_os = "linux")]
+fn signal(cause: Exception) -> u32 {
+    const SIGILL: u32 = 4;
+    const SIGTRAP: u32 = 5;
+    const SIGBUS: u32 = 7;
+    const SIGSEGV: u32 = 11;
+    match cause {
+        Exception::IllegalInstruction => SIGILL,
+        Exception::Breakpoint => SIGTRAP,
+        Exception::InstructionAddressMisaligned
+        | Exception::LoadAddressMisaligned
+        | Exception::StoreAddressMisaligned => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
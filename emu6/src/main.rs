@@ -1,37 +1,184 @@
+mod debug;
+mod fdt;
+mod gdb;
+mod guest;
+mod htif;
+#[cfg(target_os = "linux")]
+mod linux;
+mod machine;
+mod sbi;
+mod semihosting;
+
+use debug::Debugger;
+use fdt::Fdt;
+use gdb::GdbServer;
+use guest::Guest;
+use htif::Htif;
+#[cfg(target_os = "linux")]
+use linux::{Image, Linux, Profile};
+#[cfg(target_os = "linux")]
+use libemu6::riscv::{Privilege, CSR_MCOUNTEREN, CSR_MSTATUS, CSR_SCOUNTEREN, MSTATUS_FS_INITIAL};
 use libemu6::{
+    device::{
+        Clint, Console, MtimeSource, Plic, TestFinisher, Uart, CLINT_SIZE, FINISHER_SIZE,
+        PLIC_SIZE, UART_SIZE,
+    },
     mem64::{Config, Endian, Physical, Protect},
-    riscv::{Execute, Fetch, Xlen},
-    size::Usize
+    memory::Extension,
+    plugin,
+    riscv::{Execute, Fetch, InsExtension, Xlen},
+    size::Usize,
 };
-use clap::{crate_authors, crate_description, crate_version, App, Arg};
+use machine::{Machine, Step};
+use sbi::Sbi;
+use semihosting::Semihosting;
+use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg, ArgMatches};
+use std::ops::Range;
 use xmas_elf::{
     header,
     program::{self, SegmentData},
+    sections::SectionData,
+    symbol_table::{self, Entry},
     ElfFile,
 };
 
+const FINISHER_BASE: u64 = 0x0010_0000;
+const CLINT_BASE: u64 = 0x0200_0000;
+// mtime frequency when following the host clock
+const CLINT_FREQUENCY: u64 = 10_000_000;
+const PLIC_BASE: u64 = 0x0C00_0000;
+const PLIC_SOURCES: usize = 32;
+const UART_BASE: u64 = 0x1000_0000;
+const UART_IRQ: usize = 10;
+const UART_CLOCK: u32 = 3_686_400;
+// space for the device tree, placed at the first page after the program
+const DTB_SIZE: u64 = 0x1_0000;
+const PAGE_SIZE: u64 = 0x1000;
+// free memory after the device tree and initial ramdisk
+const RAM_SIZE: u64 = 16 << 20;
+// interrupt controller handles in the device tree
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+const FINISHER_PHANDLE: u32 = 3;
+
+// process exit status when the instruction limit is reached, as timeout(1) does
+const EXIT_LIMIT: i32 = 124;
+// process exit status when the emulator cannot continue
+const EXIT_ERROR: i32 = 125;
+
 fn main() {
     let matches = App::new("emu6")
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .setting(AppSettings::TrailingVarArg)
         .arg(
             Arg::with_name("debug")
                 .short("d")
                 .help("Enable an interactive debug console"),
         )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .help("Wait for a GDB remote connection on ADDR:PORT, or :PORT on localhost")
+                .takes_value(true)
+                .conflicts_with("debug"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .help("Run the program as a Linux process, with the arguments after it"),
+        )
+        .arg(
+            Arg::with_name("syscalls")
+                .long("syscalls")
+                .help("System calls served for ECALLs: those of Linux in user mode, or the libgloss subset for newlib programs, also bare-metal")
+                .possible_values(&["linux", "newlib"])
+                .default_value("linux"),
+        )
+        .arg(
+            Arg::with_name("sbi")
+                .long("sbi")
+                .help("Serve SBI calls in place of firmware, starting the program in S-mode")
+                .conflicts_with("user"),
+        )
+        .arg(
+            Arg::with_name("bootargs")
+                .long("bootargs")
+                .help("Kernel command line, passed in the device tree")
+                .takes_value(true)
+                .conflicts_with("user"),
+        )
+        .arg(
+            Arg::with_name("initrd")
+                .long("initrd")
+                .help("Load an initial ramdisk after the device tree")
+                .takes_value(true)
+                .conflicts_with("user"),
+        )
+        .arg(
+            Arg::with_name("dump-dtb")
+                .long("dump-dtb")
+                .help("Write the generated device tree blob to a file and exit")
+                .takes_value(true)
+                .conflicts_with("user"),
+        )
         .arg(
             Arg::with_name("pc")
                 .long("pc")
                 .help("When using single executable, override ELF entry point")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("max-instructions")
+                .long("max-instructions")
+                .help("Stop after executing this many instructions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Print every instruction, interrupt and trap with the hart state"),
+        )
+        .arg(
+            Arg::with_name("semihosting-files")
+                .long("semihosting-files")
+                .help("Allow semihosting calls to open files on the host"),
+        )
+        .arg(
+            Arg::with_name("mtime")
+                .long("mtime")
+                .help("Time source of the CLINT mtime counter")
+                .possible_values(&["instret", "host"])
+                .default_value("instret"),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .help("Host side of the UART: stdio, pty, or unix:PATH to listen on a Unix socket")
+                .takes_value(true)
+                .default_value("stdio"),
+        )
+        .arg(
+            Arg::with_name("plugin")
+                .long("plugin")
+                .help("Load a device or instruction plugin from a shared library, as PATH or PATH@ADDR to map its device at hexadecimal ADDR instead of its own range")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(
             Arg::with_name("target programs")
                 .help("Input programs; typically one or multiple ELF files")
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("args")
+                .help("Arguments passed to the program in user mode")
+                .multiple(true)
+                .index(2),
+        )
         .get_matches();
 
     let elf_file_name = matches.value_of("target programs").unwrap();
@@ -55,7 +202,17 @@ fn main() {
         header::Class::SixtyFour => Xlen::X64,
         _ => panic!("unsupported xlen"),
     };
+    let user = matches.is_present("user");
+    if user && cfg!(not(target_os = "linux")) {
+        panic!("user mode is only supported on Linux hosts");
+    }
+    // bare-metal programs only have their ECALLs served with the newlib profile
+    let newlib = !user && matches.value_of("syscalls") == Some("newlib");
+    if newlib && cfg!(not(target_os = "linux")) {
+        panic!("system calls are only served on Linux hosts");
+    }
     let mut mem = Physical::new();
+    let mut image_end = 0;
     for program_header in elf_file.program_iter() {
         if program_header.get_type() != Ok(program::Type::Load) {
             continue;
@@ -84,15 +241,114 @@ fn main() {
             protect,
             endian,
         };
+        image_end = image_end.max(vaddr + mem_size);
         if protect.contains(Protect::WRITE) {
-            mem.push_owned(config, data.to_vec());
+            // the rest of the segment up to its memory size is zeroed
+            let mut data = data.to_vec();
+            data.resize(mem_size as usize, 0);
+            mem.push_owned(config, data);
         } else {
             mem.push_slice(config, data);
         }
     }
-    let mem = &mut mem as *mut _; // todo!
-    let mut fetch = Fetch::new(unsafe { &*mem }, xlen);
-    let mut exec = Execute::new(unsafe { &mut *mem }, xlen);
+    let mtime_source = match matches.value_of("mtime") {
+        Some("host") => MtimeSource::HostClock {
+            frequency: CLINT_FREQUENCY,
+        },
+        _ => MtimeSource::Instret,
+    };
+    let finisher = TestFinisher::new();
+    let clint = Clint::new(1, mtime_source);
+    let plic = Plic::new(1, PLIC_SOURCES);
+    // a process sees only its own memory, and the host's standard input
+    let mut uart = None;
+    if user {
+        #[cfg(target_os = "linux")]
+        map_process(&mut mem, image_end, endian);
+    } else {
+        uart = Some(mount_devices(&mut mem, &matches, &finisher, &clint, &plic, endian));
+    }
+    let sbi = match &uart {
+        Some(uart) if matches.is_present("sbi") => Some(Sbi::new(clint.clone(), uart.clone())),
+        _ => None,
+    };
+    let mut ins_extensions = Vec::new();
+    // the device tree leaves out plugin devices, so dumping it loads no plugins
+    let plugins = matches.values_of("plugin").into_iter().flatten();
+    for spec in plugins.filter(|_| !matches.is_present("dump-dtb")) {
+        let (path, base) = match spec.rfind('@') {
+            Some(idx) => {
+                let base = u64::from_str_radix(spec[idx + 1..].trim_start_matches("0x"), 16)
+                    .expect("convert plugin address");
+                (&spec[..idx], Some(base))
+            }
+            None => (spec, None),
+        };
+        let plugin = plugin::load(path).expect("load plugin");
+        let memory_ext = plugin.memory_ext().expect("check plugin");
+        let instruction_ext = plugin.instruction_ext().expect("check plugin");
+        if memory_ext.is_none() && instruction_ext.is_none() {
+            panic!("plugin {} provides no device or instruction extension", path);
+        }
+        if let Some(vtable) = memory_ext {
+            let extension = Extension::new(Box::new(*vtable));
+            let range = extension.get_range();
+            let (start, end) = (range.start.to_u64(), range.end.to_u64());
+            let start_at = base.unwrap_or(start);
+            let config = Config {
+                range: start_at..(start_at + (end - start)),
+                protect: Protect::READ | Protect::WRITE | Protect::EXECUTE,
+                endian,
+            };
+            let (start, end) = (config.range.start, config.range.end);
+            eprintln!("Plugin {} mapped at {:#x}..{:#x}", path, start, end);
+            mem.push_device(config, Box::new(extension.with_base(start_at)));
+        }
+        if let Some(vtable) = instruction_ext {
+            ins_extensions.push(InsExtension::new(vtable));
+        }
+    }
+    // the device tree and initial ramdisk follow the program, then free memory
+    let dtb_base = page_align(image_end);
+    let mut ram_base = dtb_base + DTB_SIZE;
+    let mut initrd = None;
+    if !user {
+        let config = Config {
+            range: dtb_base..(dtb_base + DTB_SIZE),
+            protect: Protect::READ | Protect::WRITE,
+            endian,
+        };
+        mem.push_zeroed(config);
+        if let Some(path) = matches.value_of("initrd") {
+            let data = std::fs::read(path).expect("read initial ramdisk");
+            let start = dtb_base + DTB_SIZE;
+            let config = Config {
+                range: start..(start + data.len() as u64),
+                protect: Protect::READ | Protect::WRITE,
+                endian,
+            };
+            initrd = Some(config.range.clone());
+            ram_base = page_align(config.range.end);
+            mem.push_owned(config, data);
+        }
+    }
+    // free memory for the heap and stack of bare-metal programs, left out when
+    // something else is mapped there
+    let mut ram = None;
+    if !user {
+        let range = ram_base..(ram_base + RAM_SIZE);
+        if mem.is_unmapped(&range) {
+            let protect = Protect::READ | Protect::WRITE | Protect::EXECUTE;
+            mem.push_zeroed(Config { range: range.clone(), protect, endian });
+            ram = Some(range);
+        }
+    }
+    let memory = mem.ram_regions();
+    let mut fetch = Fetch::new(xlen);
+    for extension in ins_extensions {
+        fetch.push_extension(extension);
+    }
+    let exec = Execute::new(&mut mem, xlen);
     let entry_addr = matches
         .value_of("pc")
         .map(|s| match xlen {
@@ -105,13 +361,409 @@ fn main() {
             Xlen::X64 => Usize::U64(elf_file.header.pt2.entry_point()),
             Xlen::X128 => panic!("Unsupported"),
         });
-    println!("Entry point: {:#016X}", entry_addr);
-    let mut pc = entry_addr;
-    for _ in 0..10 {
-        let ins = fetch.fetch(pc).unwrap();
-        println!("{:?}", ins);
-        let next_pc = exec.execute(ins, pc).unwrap();
-        println!("{:?}", exec);
-        pc = next_pc;
+    if !user {
+        eprintln!("Entry point: {:#016X}", entry_addr);
+    }
+    let mut machine = Machine::new(fetch, exec, clint, plic, finisher, entry_addr);
+    machine.trace = matches.is_present("trace") || matches.is_present("debug");
+    let symbols = read_symbols(&elf_file);
+    if user {
+        #[cfg(target_os = "linux")]
+        start_process(&mut machine, &matches, &elf_file, image_end);
+    } else {
+        let allow_files = matches.is_present("semihosting-files");
+        let semihosting = Semihosting::new(elf_file_name.to_string(), allow_files, ram.clone());
+        machine.semihosting = Some(semihosting);
+        let symbol = |name: &str| symbols.iter().find(|(n, _)| n == name).map(|&(_, addr)| addr);
+        if let Some(tohost) = symbol("tohost") {
+            machine.htif = Some(Htif::new(tohost, symbol("fromhost")));
+        }
+        let bootargs = matches.value_of("bootargs");
+        let dtb_range = dtb_base..(dtb_base + DTB_SIZE);
+        let dtb = device_tree(&machine.exec, &memory, dtb_range, bootargs, initrd);
+        if let Some(path) = matches.value_of("dump-dtb") {
+            std::fs::write(path, &dtb).expect("write device tree");
+            return;
+        }
+        assert!(dtb.len() as u64 <= DTB_SIZE, "device tree too large");
+        Guest { exec: &mut machine.exec }.set_bytes(dtb_base, &dtb);
+        // the hart ID in a0 and the device tree in a1, as boot loaders pass them
+        if let Some(sbi) = sbi {
+            Sbi::boot(&mut machine.exec, dtb_base);
+            machine.sbi = Some(sbi);
+        } else {
+            machine.exec.write_x(11, Usize::from_u64(dtb_base, xlen));
+        }
+        #[cfg(target_os = "linux")]
+        if newlib {
+            let ram = ram.expect("map free memory after the program");
+            start_newlib(&mut machine, &matches, &elf_file, ram);
+        }
+    }
+    if matches.is_present("debug") {
+        Debugger::new(symbols).run(&mut machine);
+        return;
+    }
+    if let Some(addr) = matches.value_of("gdb") {
+        let mut server = GdbServer::listen(addr, &mut machine).expect("wait for gdb connection");
+        server.run(&mut machine).expect("serve gdb connection");
+        return;
+    }
+    let max_instructions = matches
+        .value_of("max-instructions")
+        .map(|s| s.parse::<u64>().expect("convert instruction limit"));
+    std::process::exit(run(&mut machine, max_instructions));
+}
+
+// Runs until the guest exits, the limit is reached or execution fails; returns
+// the process exit status
+fn run(machine: &mut Machine, max_instructions: Option<u64>) -> i32 {
+    let mut count = 0;
+    loop {
+        if max_instructions.map_or(false, |max| count >= max) {
+            eprintln!("Stopped after {} instructions at {:#016X}", count, machine.pc);
+            return EXIT_LIMIT;
+        }
+        let pc = machine.pc;
+        match machine.step() {
+            Ok(Step::Retired(ins)) if machine.trace => {
+                eprintln!("{:#016X}: {:?}", pc, ins);
+                eprintln!("{:?}", machine.exec);
+            }
+            Ok(Step::Retired(_)) | Ok(Step::Trapped) => {}
+            Ok(Step::Exited(code)) => return exit_status(code),
+            Err(e) => {
+                eprintln!("Execute instruction at {:#016X}: {:?}", pc, e);
+                return EXIT_ERROR;
+            }
+        }
+        count += 1;
+    }
+}
+
+// Only the low byte of the exit status reaches the parent, so a failure code
+// that is a multiple of 256 exits with 1 rather than reading as success
+fn exit_status(code: u32) -> i32 {
+    if code != 0 && code & 0xFF == 0 {
+        1
+    } else {
+        code as i32
+    }
+}
+
+// Maps the test finisher, interrupt controllers and UART into guest memory;
+// returns the UART for firmware services
+fn mount_devices(
+    mem: &mut Physical,
+    matches: &ArgMatches,
+    finisher: &TestFinisher,
+    clint: &Clint,
+    plic: &Plic,
+    endian: Endian,
+) -> Uart {
+    let finisher_config = Config {
+        range: FINISHER_BASE..(FINISHER_BASE + FINISHER_SIZE),
+        protect: Protect::READ | Protect::WRITE,
+        endian,
+    };
+    mem.push_device(finisher_config, Box::new(finisher.clone()));
+    let clint_config = Config {
+        range: CLINT_BASE..(CLINT_BASE + CLINT_SIZE),
+        protect: Protect::READ | Protect::WRITE,
+        endian,
+    };
+    mem.push_device(clint_config, Box::new(clint.clone()));
+    let plic_config = Config {
+        range: PLIC_BASE..(PLIC_BASE + PLIC_SIZE),
+        protect: Protect::READ | Protect::WRITE,
+        endian,
+    };
+    mem.push_device(plic_config, Box::new(plic.clone()));
+    // dumping the device tree runs nothing, so it opens no serial backend
+    let console = if matches.is_present("dump-dtb") {
+        Console::new(std::io::empty(), std::io::sink())
+    } else {
+        open_console(matches.value_of("serial").unwrap(), matches.is_present("debug"))
+    };
+    let uart = Uart::new(console, plic.line(UART_IRQ));
+    let uart_config = Config {
+        range: UART_BASE..(UART_BASE + UART_SIZE),
+        protect: Protect::READ | Protect::WRITE,
+        endian,
+    };
+    mem.push_device(uart_config, Box::new(uart.clone()));
+    uart
+}
+
+// Maps the heap after the program, and the anonymous mapping region with the
+// stack at its top
+#[cfg(target_os = "linux")]
+fn map_process(mem: &mut Physical, image_end: u64, endian: Endian) {
+    let protect = Protect::READ | Protect::WRITE | Protect::EXECUTE;
+    let heap = linux::heap(image_end);
+    if !heap.is_empty() {
+        mem.push_zeroed(Config { range: heap, protect, endian });
+    }
+    let range = linux::MMAP_BASE..linux::STACK_TOP;
+    mem.push_zeroed(Config { range, protect, endian });
+}
+
+// Sets up the initial stack and registers of the process
+#[cfg(target_os = "linux")]
+fn start_process(machine: &mut Machine, matches: &ArgMatches, elf_file: &ElfFile, image_end: u64) {
+    let args = program_args(matches);
+    let env: Vec<String> = std::env::vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let image = process_image(machine, elf_file);
+    let sp = Linux::setup_stack(&mut machine.exec, linux::STACK_TOP, &args, &env, image);
+    let xlen = machine.exec.xlen();
+    machine.exec.write_x(2, Usize::from_u64(sp, xlen));
+    enable_float(&mut machine.exec);
+    // the process runs in U-mode over all of memory, and may read the counters
+    for &csr in [CSR_MCOUNTEREN, CSR_SCOUNTEREN].iter() {
+        let val = Usize::from_u64(0b111, xlen);
+        machine.exec.write_csr(csr, val).expect("set up hart for process");
+    }
+    machine.exec.open_pmp();
+    machine.exec.set_privilege(Privilege::User);
+    let profile = match matches.value_of("syscalls") {
+        Some("newlib") => Profile::Newlib,
+        _ => Profile::Linux,
+    };
+    machine.linux = Some(Linux::new(profile, linux::heap(image_end)));
+}
+
+// Serves the ECALLs of a bare-metal newlib program, which stays in M-mode. Its
+// stack with the arguments is at the top of free memory, the brk heap below
+#[cfg(target_os = "linux")]
+fn start_newlib(machine: &mut Machine, matches: &ArgMatches, elf_file: &ElfFile, ram: Range<u64>) {
+    let args = program_args(matches);
+    let image = process_image(machine, elf_file);
+    let sp = Linux::setup_stack(&mut machine.exec, ram.end, &args, &[], image);
+    let xlen = machine.exec.xlen();
+    machine.exec.write_x(2, Usize::from_u64(sp, xlen));
+    enable_float(&mut machine.exec);
+    let heap_end = ram.end.saturating_sub(linux::STACK_SIZE).max(ram.start);
+    machine.linux = Some(Linux::bare_metal(ram.start..heap_end));
+}
+
+// the program name followed by the arguments given after it
+#[cfg(target_os = "linux")]
+fn program_args(matches: &ArgMatches) -> Vec<String> {
+    let mut args = vec![matches.value_of("target programs").unwrap().to_string()];
+    args.extend(matches.values_of("args").into_iter().flatten().map(String::from));
+    args
+}
+
+#[cfg(target_os = "linux")]
+fn process_image(machine: &Machine, elf_file: &ElfFile) -> Image {
+    // the program headers are found through the segment which loads them
+    let header = &elf_file.header.pt2;
+    let ph_offset = header.ph_offset();
+    let phdr = elf_file
+        .program_iter()
+        .filter(|ph| ph.get_type() == Ok(program::Type::Load))
+        .find(|ph| ph.offset() <= ph_offset && ph_offset < ph.offset() + ph.file_size())
+        .map_or(0, |ph| ph.virtual_addr() + (ph_offset - ph.offset()));
+    Image {
+        entry: machine.pc.to_u64(),
+        phdr,
+        phent: header.ph_entry_size() as u64,
+        phnum: header.ph_count() as u64,
+    }
+}
+
+// floating point instructions are enabled, as Linux does on first use and
+// proxy kernels do before entering the program
+#[cfg(target_os = "linux")]
+fn enable_float(exec: &mut Execute) {
+    let xlen = exec.xlen();
+    let mstatus = exec.read_csr(CSR_MSTATUS).expect("read mstatus");
+    let mstatus = mstatus.to_u64() | MSTATUS_FS_INITIAL;
+    exec.write_csr(CSR_MSTATUS, Usize::from_u64(mstatus, xlen)).expect("write mstatus");
+}
+
+// Describes the hart, memory and devices as mounted by `mount_devices`
+fn device_tree(
+    exec: &Execute,
+    memory: &[Range<u64>],
+    dtb: Range<u64>,
+    bootargs: Option<&str>,
+    initrd: Option<Range<u64>>,
+) -> Vec<u8> {
+    let (bits, mmu_type) = match exec.xlen() {
+        Xlen::X32 => (32, "riscv,sv32"),
+        _ => (64, "riscv,sv48"),
+    };
+    let extensions: Vec<String> = "IMAFDQCV"
+        .chars()
+        .filter(|&ext| exec.has_extension(ext))
+        .map(|ext| ext.to_ascii_lowercase().to_string())
+        .collect();
+    let isa = format!("rv{}{}", bits, extensions.concat());
+    let mut regions: Vec<Range<u64>> = memory.to_vec();
+    regions.sort_by_key(|range| range.start);
+    let mut reg: Vec<(u64, u64)> = Vec::new();
+    for range in regions {
+        match reg.last_mut() {
+            Some((start, size)) if *start + *size == range.start => *size += range.end - range.start,
+            _ => reg.push((range.start, range.end - range.start)),
+        }
+    }
+    let serial = format!("serial@{:x}", UART_BASE);
+    let mut fdt = Fdt::new();
+    // the device tree and initial ramdisk lie in the memory described below
+    fdt.reserve(dtb.start, dtb.end - dtb.start);
+    if let Some(initrd) = &initrd {
+        fdt.reserve(initrd.start, initrd.end - initrd.start);
+    }
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "emu6");
+    fdt.property_string("model", "emu6");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/{}", serial));
+    if let Some(bootargs) = bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some(initrd) = initrd {
+        fdt.property_cells("linux,initrd-start", &[(initrd.start >> 32) as u32, initrd.start as u32]);
+        fdt.property_cells("linux,initrd-end", &[(initrd.end >> 32) as u32, initrd.end as u32]);
+    }
+    fdt.end_node();
+
+    if let Some(&(start, _)) = reg.first() {
+        fdt.begin_node(&format!("memory@{:x}", start));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg("reg", &reg);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    // mtime counts at this nominal rate when following instructions as well
+    fdt.property_u32("timebase-frequency", CLINT_FREQUENCY as u32);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &isa);
+    fdt.property_string("riscv,isa-base", &format!("rv{}i", bits));
+    let mut isa_extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+    isa_extensions.push("zicsr");
+    fdt.property_strings("riscv,isa-extensions", &isa_extensions);
+    fdt.property_string("mmu-type", mmu_type);
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    fdt.begin_node(&format!("test@{:x}", FINISHER_BASE));
+    fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.property_reg("reg", &[(FINISHER_BASE, FINISHER_SIZE)]);
+    fdt.property_u32("phandle", FINISHER_PHANDLE);
+    fdt.end_node();
+
+    // software and timer interrupts of M-mode
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_reg("reg", &[(CLINT_BASE, CLINT_SIZE)]);
+    fdt.property_cells("interrupts-extended", &[CPU_INTC_PHANDLE, 3, CPU_INTC_PHANDLE, 7]);
+    fdt.end_node();
+
+    // the M-mode context, then the S-mode one
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_reg("reg", &[(PLIC_BASE, PLIC_SIZE)]);
+    fdt.property_cells("interrupts-extended", &[CPU_INTC_PHANDLE, 11, CPU_INTC_PHANDLE, 9]);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&serial);
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_reg("reg", &[(UART_BASE, UART_SIZE)]);
+    fdt.property_u32("clock-frequency", UART_CLOCK);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.property_u32("interrupts", UART_IRQ as u32);
+    fdt.end_node();
+    fdt.end_node();
+
+    // writing 0x5555 to the test finisher powers off
+    fdt.begin_node("poweroff");
+    fdt.property_string("compatible", "syscon-poweroff");
+    fdt.property_u32("regmap", FINISHER_PHANDLE);
+    fdt.property_u32("offset", 0);
+    fdt.property_u32("value", 0x5555);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+fn page_align(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// With the debug console, stdin belongs to the debugger
+fn open_console(serial: &str, debug: bool) -> Console {
+    match serial {
+        "stdio" if debug => Console::new(std::io::empty(), std::io::stdout()),
+        "stdio" => Console::stdio(),
+        #[cfg(unix)]
+        "pty" => {
+            let (console, name) = Console::pty().expect("open pseudo-terminal");
+            eprintln!("UART connected to {}", name);
+            console
+        }
+        #[cfg(unix)]
+        _ if serial.starts_with("unix:") => {
+            let path = &serial["unix:".len()..];
+            eprintln!("Waiting for connection on {}", path);
+            Console::unix_socket(path).expect("listen on unix socket")
+        }
+        _ => panic!("unsupported serial backend: {}", serial),
+    }
+}
+
+// Named code and data symbols of the program
+fn read_symbols(elf_file: &ElfFile) -> Vec<(String, u64)> {
+    let mut ans = Vec::new();
+    let mut push = |entry: &dyn Entry| {
+        match entry.get_type() {
+            Ok(symbol_table::Type::NoType)
+            | Ok(symbol_table::Type::Object)
+            | Ok(symbol_table::Type::Func) => {}
+            _ => return,
+        }
+        if let Ok(name) = entry.get_name(elf_file) {
+            // skip assembler local labels
+            if !name.is_empty() && !name.starts_with(".L") && !name.starts_with('$') {
+                ans.push((name.to_string(), entry.value()));
+            }
+        }
+    };
+    for section in elf_file.section_iter() {
+        match section.get_data(elf_file) {
+            Ok(SectionData::SymbolTable32(entries)) => entries.iter().for_each(|e| push(e)),
+            Ok(SectionData::SymbolTable64(entries)) => entries.iter().for_each(|e| push(e)),
+            _ => {}
+        }
     }
+    ans
 }
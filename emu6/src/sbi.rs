@@ -0,0 +1,398 @@
+use libemu6::{
+    device::{Clint, Uart},
+    riscv::{Execute, Interrupt, Privilege, Xlen, CSR_MCOUNTEREN, CSR_MEDELEG, CSR_MIDELEG},
+    size::Usize,
+};
+
+// extension IDs, passed in a7
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4D45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4E43;
+const EXT_HSM: u64 = 0x0048_534D;
+const EXT_SRST: u64 = 0x5352_5354;
+// legacy extensions, each a single function
+const LEGACY_SET_TIMER: u64 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const LEGACY_CLEAR_IPI: u64 = 0x03;
+const LEGACY_SEND_IPI: u64 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const LEGACY_SHUTDOWN: u64 = 0x08;
+
+// functions of the base extension, passed in a6
+const BASE_GET_SPEC_VERSION: u64 = 0;
+const BASE_GET_IMPL_ID: u64 = 1;
+const BASE_GET_IMPL_VERSION: u64 = 2;
+const BASE_PROBE_EXTENSION: u64 = 3;
+const BASE_GET_MVENDORID: u64 = 4;
+const BASE_GET_MARCHID: u64 = 5;
+const BASE_GET_MIMPID: u64 = 6;
+const HSM_HART_START: u64 = 0;
+const HSM_HART_STOP: u64 = 1;
+const HSM_HART_GET_STATUS: u64 = 2;
+const HSM_HART_SUSPEND: u64 = 3;
+const SRST_SYSTEM_RESET: u64 = 0;
+
+// SBI 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+// not a registered implementation ID; "emu6" in ASCII
+const IMPL_ID: u64 = 0x656D_7536;
+const HSM_STARTED: u64 = 0;
+const RESET_REASON_NONE: u64 = 0;
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// misaligned, access fault and illegal instruction exceptions, breakpoints,
+// environment calls from U-mode and page faults
+const MEDELEG: u64 = 0xB1FF;
+// supervisor software, timer and external interrupts
+const MIDELEG: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+
+// Built-in supervisor binary interface, so that S-mode payloads boot without
+// firmware: ECALLs from S-mode are served on the host. The timer and IPIs
+// raise the supervisor interrupts directly, and the console goes through the
+// UART. There is a single hart, so remote fences have nothing to do
+#[derive(Debug)]
+pub struct Sbi {
+    clint: Clint,
+    uart: Uart,
+}
+
+impl Sbi {
+    pub fn new(clint: Clint, uart: Uart) -> Sbi {
+        Sbi { clint, uart }
+    }
+
+    // Sets up delegation and memory protection as firmware does, then enters
+    // the payload in S-mode with the hart ID in a0 and the device tree in a1
+    pub fn boot(exec: &mut Execute, fdt: u64) {
+        let xlen = exec.xlen();
+        let csrs = [(CSR_MEDELEG, MEDELEG), (CSR_MIDELEG, MIDELEG), (CSR_MCOUNTEREN, 0b111)];
+        for &(csr, val) in csrs.iter() {
+            exec.write_csr(csr, Usize::from_u64(val, xlen))
+                .expect("set up hart for payload");
+        }
+        exec.open_pmp();
+        exec.write_x(10, Usize::from_u64(0, xlen));
+        exec.write_x(11, Usize::from_u64(fdt, xlen));
+        exec.set_privilege(Privilege::Supervisor);
+    }
+
+    // the supervisor timer interrupt, which firmware would forward from mtimecmp
+    pub fn timer_pending(&self) -> bool {
+        self.clint.timer_pending(0)
+    }
+
+    // Serves the call with the extension in a7 and function in a6, returning
+    // the error in a0 and the value in a1; legacy calls return in a0 only.
+    // Returns the exit code if the payload shuts down the system
+    pub fn call(&mut self, exec: &mut Execute) -> Option<u32> {
+        let ext = exec.read_x(17).to_u64();
+        let func = exec.read_x(16).to_u64();
+        let a0 = exec.read_x(10).to_u64();
+        let a1 = exec.read_x(11).to_u64();
+        let xlen = exec.xlen();
+        let legacy = match ext {
+            LEGACY_SET_TIMER => Some(self.set_timer(exec, a0, a1)),
+            LEGACY_CONSOLE_PUTCHAR => {
+                self.uart.putchar(a0 as u8);
+                Some(0)
+            }
+            LEGACY_CONSOLE_GETCHAR => Some(self.uart.getchar().map_or(-1, |b| b as i64)),
+            LEGACY_CLEAR_IPI => {
+                exec.set_interrupt_pending(Interrupt::SupervisorSoftware, false);
+                Some(0)
+            }
+            // the hart mask is passed by address
+            LEGACY_SEND_IPI => {
+                let addr = Usize::from_u64(a0, xlen);
+                if exec.read_mem(addr, 1).map_or(false, |mask| mask & 1 != 0) {
+                    exec.set_interrupt_pending(Interrupt::SupervisorSoftware, true);
+                }
+                Some(0)
+            }
+            LEGACY_REMOTE_FENCE_I | LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => {
+                Some(0)
+            }
+            LEGACY_SHUTDOWN => return Some(0),
+            _ => None,
+        };
+        if let Some(ans) = legacy {
+            exec.write_x(10, Usize::from_u64(ans as u64, xlen));
+            return None;
+        }
+        let (error, value) = match (ext, func) {
+            (EXT_BASE, BASE_GET_SPEC_VERSION) => (SBI_SUCCESS, SPEC_VERSION),
+            (EXT_BASE, BASE_GET_IMPL_ID) => (SBI_SUCCESS, IMPL_ID),
+            (EXT_BASE, BASE_GET_IMPL_VERSION) => (SBI_SUCCESS, impl_version()),
+            (EXT_BASE, BASE_PROBE_EXTENSION) => (SBI_SUCCESS, is_supported(a0) as u64),
+            (EXT_BASE, BASE_GET_MVENDORID)
+            | (EXT_BASE, BASE_GET_MARCHID)
+            | (EXT_BASE, BASE_GET_MIMPID) => {
+                let csr = 0xF11 + (func - BASE_GET_MVENDORID) as u16;
+                (SBI_SUCCESS, exec.read_csr(csr).map_or(0, Usize::to_u64))
+            }
+            (EXT_TIME, 0) => (self.set_timer(exec, a0, a1), 0),
+            // a0 is the hart mask and a1 its base; hart 0 is the only hart
+            (EXT_IPI, 0) => {
+                if targets_hart(a0, a1, xlen) {
+                    exec.set_interrupt_pending(Interrupt::SupervisorSoftware, true);
+                }
+                (SBI_SUCCESS, 0)
+            }
+            (EXT_RFENCE, 0..=6) => (SBI_SUCCESS, 0),
+            (EXT_HSM, HSM_HART_START) if a0 == 0 => (SBI_ERR_ALREADY_AVAILABLE, 0),
+            (EXT_HSM, HSM_HART_GET_STATUS) if a0 == 0 => (SBI_SUCCESS, HSM_STARTED),
+            (EXT_HSM, HSM_HART_START) | (EXT_HSM, HSM_HART_GET_STATUS) => {
+                (SBI_ERR_INVALID_PARAM, 0)
+            }
+            // stopping the only hart ends the emulation
+            (EXT_HSM, HSM_HART_STOP) => return Some(0),
+            // suspending waits for an interrupt, which the next step takes
+            (EXT_HSM, HSM_HART_SUSPEND) => (SBI_SUCCESS, 0),
+            // reboots are not supported and end the emulation as a shutdown does
+            (EXT_SRST, SRST_SYSTEM_RESET) if a0 <= 2 => {
+                return Some((a1 != RESET_REASON_NONE) as u32);
+            }
+            (EXT_SRST, SRST_SYSTEM_RESET) => (SBI_ERR_INVALID_PARAM, 0),
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        };
+        exec.write_x(10, Usize::from_u64(error as u64, xlen));
+        exec.write_x(11, Usize::from_u64(value, xlen));
+        None
+    }
+
+    fn set_timer(&mut self, exec: &mut Execute, a0: u64, a1: u64) -> i64 {
+        // RV32 passes the 64-bit value in a0 and a1
+        let value = match exec.xlen() {
+            Xlen::X32 => a0 | a1 << 32,
+            _ => a0,
+        };
+        self.clint.set_timer(0, value);
+        SBI_SUCCESS
+    }
+}
+
+fn is_supported(ext: u64) -> bool {
+    match ext {
+        EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST => true,
+        LEGACY_SET_TIMER..=LEGACY_SHUTDOWN => true,
+        _ => false,
+    }
+}
+
+// whether the hart mask starting at hart `base` includes hart 0; a base of all
+// ones selects every hart
+fn targets_hart(mask: u64, base: u64, xlen: Xlen) -> bool {
+    let all = match xlen {
+        Xlen::X32 => u32::MAX as u64,
+        _ => u64::MAX,
+    };
+    base == all || (base == 0 && mask & 1 != 0)
+}
+
+// the crate version as major and minor number
+fn impl_version() -> u64 {
+    let mut parts = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|n| n.parse().unwrap_or(0));
+    let major: u64 = parts.next().unwrap_or(0);
+    let minor: u64 = parts.next().unwrap_or(0);
+    major << 16 | minor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libemu6::{
+        device::{Console, MtimeSource, Plic},
+        mem64::{Config, Device, Endian, Physical, Protect},
+        riscv::Exception,
+    };
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Write};
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    const BASE: u64 = 0x8000_0000;
+    const MTIME: u64 = 0xBFF8;
+
+    // a console output the test can look into
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // An SBI over a CLINT counting instructions and a UART with the given input
+    fn sbi(input: &[u8]) -> (Sbi, Clint, Output) {
+        let plic = Plic::new(1, 2);
+        let output = Output::default();
+        let console = Console::new(Cursor::new(input.to_vec()), output.clone());
+        let clint = Clint::new(1, MtimeSource::Instret);
+        let uart = Uart::new(console, plic.line(1));
+        (Sbi::new(clint.clone(), uart), clint, output)
+    }
+
+    // A hart of the given width with the instructions at BASE
+    fn hart(xlen: Xlen, code: &[u32]) -> Execute<'static> {
+        let mut bytes: Vec<u8> = code.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+        bytes.resize(0x1000, 0);
+        let mut mem = Box::new(Physical::new());
+        mem.push_owned(
+            Config {
+                range: BASE..(BASE + 0x1000),
+                protect: Protect::READ | Protect::WRITE | Protect::EXECUTE,
+                endian: Endian::Little,
+            },
+            bytes,
+        );
+        Execute::new(Box::leak(mem), xlen)
+    }
+
+    fn mip(exec: &Execute) -> u64 {
+        exec.read_csr(0x344).unwrap().to_u64()
+    }
+
+    // Makes the call with extension `ext` and function `func`; returns a0 and a1
+    fn call(sbi: &mut Sbi, exec: &mut Execute, ext: u64, func: u64, args: &[u64]) -> (i64, u64) {
+        let xlen = exec.xlen();
+        exec.write_x(17, Usize::from_u64(ext, xlen));
+        exec.write_x(16, Usize::from_u64(func, xlen));
+        for (i, &arg) in args.iter().enumerate() {
+            exec.write_x(10 + i as u8, Usize::from_u64(arg, xlen));
+        }
+        assert_eq!(sbi.call(exec), None);
+        let error = exec.read_x(10).to_u64() as i64;
+        (error, exec.read_x(11).to_u64())
+    }
+
+    #[test]
+    fn base_extension() {
+        let (mut sbi, _, _) = sbi(b"");
+        let mut exec = hart(Xlen::X64, &[]);
+        let version = call(&mut sbi, &mut exec, EXT_BASE, BASE_GET_SPEC_VERSION, &[]);
+        assert_eq!(version, (SBI_SUCCESS, 2 << 24));
+        let id = call(&mut sbi, &mut exec, EXT_BASE, BASE_GET_IMPL_ID, &[]);
+        assert_eq!(id, (SBI_SUCCESS, IMPL_ID));
+        for &ext in [EXT_TIME, EXT_SRST, LEGACY_CONSOLE_PUTCHAR].iter() {
+            let probe = call(&mut sbi, &mut exec, EXT_BASE, BASE_PROBE_EXTENSION, &[ext]);
+            assert_eq!(probe, (SBI_SUCCESS, 1));
+        }
+        // the debug console is not provided
+        let probe = call(&mut sbi, &mut exec, EXT_BASE, BASE_PROBE_EXTENSION, &[0x4442_434E]);
+        assert_eq!(probe, (SBI_SUCCESS, 0));
+        let unknown = call(&mut sbi, &mut exec, 0x0A00_0000, 0, &[]);
+        assert_eq!(unknown.0, SBI_ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn timer_and_ipis() {
+        let (mut sbi, mut clint, _) = sbi(b"");
+        let mut exec = hart(Xlen::X64, &[]);
+        clint.write(MTIME, 8, 100).unwrap();
+        assert!(!sbi.timer_pending());
+        assert_eq!(call(&mut sbi, &mut exec, EXT_TIME, 0, &[150]).0, SBI_SUCCESS);
+        assert!(!sbi.timer_pending());
+        clint.write(MTIME, 8, 150).unwrap();
+        assert!(sbi.timer_pending());
+        call(&mut sbi, &mut exec, LEGACY_SET_TIMER, 0, &[u64::MAX]);
+        assert!(!sbi.timer_pending());
+
+        let ssip = 1 << 1;
+        // the mask selects other harts only
+        call(&mut sbi, &mut exec, EXT_IPI, 0, &[1, 1]);
+        assert_eq!(mip(&exec) & ssip, 0);
+        call(&mut sbi, &mut exec, EXT_IPI, 0, &[0, u64::MAX]);
+        assert_eq!(mip(&exec) & ssip, ssip);
+        call(&mut sbi, &mut exec, LEGACY_CLEAR_IPI, 0, &[]);
+        assert_eq!(mip(&exec) & ssip, 0);
+    }
+
+    #[test]
+    fn rv32_timer_is_split_across_registers() {
+        let (mut sbi, mut clint, _) = sbi(b"");
+        let mut exec = hart(Xlen::X32, &[]);
+        clint.write(MTIME, 8, 0x1_0000_0000).unwrap();
+        call(&mut sbi, &mut exec, EXT_TIME, 0, &[0, 1]);
+        assert!(sbi.timer_pending());
+        call(&mut sbi, &mut exec, EXT_TIME, 0, &[1, 1]);
+        assert!(!sbi.timer_pending());
+    }
+
+    #[test]
+    fn legacy_console() {
+        let (mut sbi, _, output) = sbi(b"k");
+        let mut exec = hart(Xlen::X64, &[]);
+        for &byte in b"ok\n".iter() {
+            call(&mut sbi, &mut exec, LEGACY_CONSOLE_PUTCHAR, 0, &[byte as u64]);
+        }
+        assert_eq!(*output.0.borrow(), b"ok\n");
+        // input arrives from the reader thread of the console
+        let mut byte = -1;
+        for _ in 0..100 {
+            byte = call(&mut sbi, &mut exec, LEGACY_CONSOLE_GETCHAR, 0, &[]).0;
+            if byte != -1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(byte, b'k' as i64);
+        assert_eq!(call(&mut sbi, &mut exec, LEGACY_CONSOLE_GETCHAR, 0, &[]).0, -1);
+    }
+
+    #[test]
+    fn harts_and_reset() {
+        let (mut sbi, _, _) = sbi(b"");
+        let mut exec = hart(Xlen::X64, &[]);
+        let status = call(&mut sbi, &mut exec, EXT_HSM, HSM_HART_GET_STATUS, &[0]);
+        assert_eq!(status, (SBI_SUCCESS, HSM_STARTED));
+        let start = call(&mut sbi, &mut exec, EXT_HSM, HSM_HART_START, &[0]);
+        assert_eq!(start.0, SBI_ERR_ALREADY_AVAILABLE);
+        let other = call(&mut sbi, &mut exec, EXT_HSM, HSM_HART_START, &[1]);
+        assert_eq!(other.0, SBI_ERR_INVALID_PARAM);
+        let bad_type = call(&mut sbi, &mut exec, EXT_SRST, SRST_SYSTEM_RESET, &[3, 0]);
+        assert_eq!(bad_type.0, SBI_ERR_INVALID_PARAM);
+
+        // shutdown, then a cold reboot for a system failure
+        exec.write_x(17, Usize::U64(EXT_SRST));
+        exec.write_x(16, Usize::U64(SRST_SYSTEM_RESET));
+        exec.write_x(10, Usize::U64(0));
+        exec.write_x(11, Usize::U64(RESET_REASON_NONE));
+        assert_eq!(sbi.call(&mut exec), Some(0));
+        exec.write_x(10, Usize::U64(1));
+        exec.write_x(11, Usize::U64(1));
+        assert_eq!(sbi.call(&mut exec), Some(1));
+        exec.write_x(17, Usize::U64(LEGACY_SHUTDOWN));
+        assert_eq!(sbi.call(&mut exec), Some(0));
+    }
+
+    #[test]
+    fn boot_enters_the_payload_in_s_mode() {
+        let mut exec = hart(Xlen::X64, &[]);
+        Sbi::boot(&mut exec, 0x8220_0000);
+        assert_eq!(exec.privilege(), Privilege::Supervisor);
+        assert_eq!(exec.read_x(10), Usize::U64(0));
+        assert_eq!(exec.read_x(11), Usize::U64(0x8220_0000));
+        assert_eq!(exec.read_csr(CSR_MEDELEG).unwrap(), Usize::U64(MEDELEG));
+        assert_eq!(exec.read_csr(CSR_MIDELEG).unwrap(), Usize::U64(MIDELEG));
+        // S-mode ECALLs stay in M-mode, where the host serves them
+        let code = Exception::EnvironmentCallFromSMode.code();
+        assert_eq!(MEDELEG >> code & 1, 0);
+    }
+}
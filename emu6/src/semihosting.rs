@@ -0,0 +1,480 @@
+use crate::guest::Guest;
+use libemu6::riscv::{Execute, Instruction, Xlen, RV32I, RV64I};
+use libemu6::size::Usize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// operation numbers, passed in a0
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0A;
+const SYS_FLEN: u64 = 0x0C;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+// reason code of SYS_EXIT for a normal exit
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// the stack at the top of free memory reported by SYS_HEAPINFO; the heap
+// takes the rest
+const STACK_SIZE: u64 = 1 << 20;
+
+// most bytes SYS_READ transfers in one call
+const READ_LIMIT: u64 = 0x1_0000;
+
+const EACCES: u64 = 13;
+const EBADF: u64 = 9;
+const EINVAL: u64 = 22;
+const EIO: u64 = 5;
+
+#[derive(Debug)]
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+// RISC-V semihosting: an EBREAK between `slli zero, zero, 0x1f` and
+// `srai zero, zero, 7` requests the operation in a0 from the host, with a1
+// pointing to its parameter block; the result is returned in a0. Files on the
+// host are only opened when `allow_files` is set. The heap and stack are placed
+// in `ram`, free memory after the program
+#[derive(Debug)]
+pub struct Semihosting {
+    cmdline: String,
+    allow_files: bool,
+    ram: Option<Range<u64>>,
+    handles: Vec<Option<Handle>>,
+    errno: u64,
+    epoch: Instant,
+}
+
+impl Semihosting {
+    pub fn new(cmdline: String, allow_files: bool, ram: Option<Range<u64>>) -> Semihosting {
+        Semihosting {
+            cmdline,
+            allow_files,
+            ram,
+            handles: Vec::new(),
+            errno: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    // Whether the breakpoint at `pc` is a semihosting call, an uncompressed
+    // EBREAK between `slli zero, zero, 0x1f` and `srai zero, zero, 7`;
+    // `decode` returns the instruction at an address
+    pub fn is_call(mut decode: impl FnMut(u64) -> Option<Instruction>, pc: u64) -> bool {
+        matches!(decode(pc), Some(Instruction::RV32I(RV32I::Ebreak(_))))
+            && zero_shift(decode(pc.wrapping_sub(4)), true) == Some(0x1F)
+            && zero_shift(decode(pc.wrapping_add(4)), false) == Some(7)
+    }
+
+    // Performs the requested operation; returns the exit code if the guest exits
+    pub fn call(&mut self, exec: &mut Execute) -> Option<u32> {
+        let op = exec.read_x(10).to_u64();
+        let args = exec.read_x(11).to_u64();
+        let mut mem = Guest { exec };
+        let ans = match op {
+            SYS_OPEN => self.open(&mut mem, args),
+            SYS_CLOSE => self.close(&mut mem, args),
+            SYS_WRITEC => self.write_console(&mut mem, args, 1),
+            SYS_WRITE0 => {
+                let len = (0..)
+                    .take_while(|&i| matches!(mem.byte(args + i), Some(b) if b != 0))
+                    .count();
+                self.write_console(&mut mem, args, len as u64)
+            }
+            SYS_WRITE => self.write(&mut mem, args),
+            SYS_READ => self.read(&mut mem, args),
+            SYS_ISTTY => match self.handle(mem.field(args, 0)) {
+                Some(Handle::File(_)) => 0,
+                Some(_) => 1,
+                None => self.fail(EBADF),
+            },
+            SYS_SEEK => self.seek(&mut mem, args),
+            SYS_FLEN => self.flen(&mut mem, args),
+            SYS_CLOCK => (self.epoch.elapsed().as_millis() / 10) as u64,
+            SYS_TIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => self.get_cmdline(&mut mem, args),
+            SYS_HEAPINFO => self.heap_info(&mut mem, args),
+            // RV32 passes the reason in a1; RV64 a block of reason and exit code
+            SYS_EXIT if mem.exec.xlen() == Xlen::X32 => {
+                return Some((args != ADP_STOPPED_APPLICATION_EXIT) as u32);
+            }
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let (reason, code) = (mem.field(args, 0), mem.field(args, 1));
+                if reason != ADP_STOPPED_APPLICATION_EXIT {
+                    return Some(1);
+                }
+                return Some(code as u32);
+            }
+            _ => {
+                eprintln!("Unsupported semihosting operation {:#x}", op);
+                self.fail(EINVAL)
+            }
+        };
+        let xlen = mem.exec.xlen();
+        mem.exec.write_x(10, Usize::from_u64(ans, xlen));
+        None
+    }
+
+    fn open(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let (name, mode, len) = (mem.field(args, 0), mem.field(args, 1), mem.field(args, 2));
+        let name = match mem.bytes(name, len) {
+            Some(name) => String::from_utf8_lossy(&name).into_owned(),
+            None => return self.fail(EINVAL),
+        };
+        // modes follow fopen: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+        let handle = if name == ":tt" {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else if !self.allow_files {
+            return self.fail(EACCES);
+        } else {
+            let mut options = OpenOptions::new();
+            let plus = mode & 0b10 != 0;
+            match mode {
+                0..=3 => options.read(true).write(plus),
+                4..=7 => options.write(true).create(true).truncate(true).read(plus),
+                8..=11 => options.append(true).create(true).read(plus),
+                _ => return self.fail(EINVAL),
+            };
+            match options.open(&name) {
+                Ok(file) => Handle::File(file),
+                Err(e) => return self.fail_io(e),
+            }
+        };
+        let idx = match self.handles.iter().position(|h| h.is_none()) {
+            Some(idx) => idx,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+        };
+        self.handles[idx] = Some(handle);
+        // handles are nonzero
+        idx as u64 + 1
+    }
+
+    fn close(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let idx = mem.field(args, 0).wrapping_sub(1) as usize;
+        match self.handles.get_mut(idx).and_then(Option::take) {
+            Some(_) => 0,
+            None => self.fail(EBADF),
+        }
+    }
+
+    // returns the number of bytes not written
+    fn write(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let (fd, buf, len) = (mem.field(args, 0), mem.field(args, 1), mem.field(args, 2));
+        let data = match mem.bytes(buf, len) {
+            Some(data) => data,
+            None => return len,
+        };
+        let ans = match self.handle(fd) {
+            Some(Handle::Stdout) => {
+                io::stdout().write_all(&data).and_then(|_| io::stdout().flush())
+            }
+            Some(Handle::Stderr) => io::stderr().write_all(&data),
+            Some(Handle::File(file)) => file.write_all(&data),
+            Some(Handle::Stdin) | None => {
+                self.errno = EBADF;
+                return len;
+            }
+        };
+        match ans {
+            Ok(()) => 0,
+            Err(e) => {
+                self.fail_io(e);
+                len
+            }
+        }
+    }
+
+    // returns the number of bytes not read
+    fn read(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let (fd, buf, len) = (mem.field(args, 0), mem.field(args, 1), mem.field(args, 2));
+        // a short read is allowed, so large requests are cut to a bounded buffer
+        let mut data = vec![0; len.min(READ_LIMIT) as usize];
+        let ans = match self.handle(fd) {
+            Some(Handle::Stdin) => io::stdin().read(&mut data),
+            Some(Handle::File(file)) => file.read(&mut data),
+            _ => {
+                self.errno = EBADF;
+                return len;
+            }
+        };
+        let count = match ans {
+            Ok(count) => count,
+            Err(e) => {
+                self.fail_io(e);
+                return len;
+            }
+        };
+        if !mem.set_bytes(buf, &data[..count]) {
+            self.errno = EIO;
+            return len;
+        }
+        len - count as u64
+    }
+
+    // Fills heap base and limit, then stack base and limit. Without free
+    // memory, zeros let the C library use its defaults
+    fn heap_info(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let block = mem.field(args, 0);
+        let info = match &self.ram {
+            Some(ram) => {
+                let stack_limit = ram.end - STACK_SIZE.min(ram.end - ram.start);
+                [ram.start, stack_limit, ram.end, stack_limit]
+            }
+            None => [0; 4],
+        };
+        for (i, &value) in info.iter().enumerate() {
+            mem.set_field(block, i as u64, value);
+        }
+        0
+    }
+
+    fn seek(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let (fd, pos) = (mem.field(args, 0), mem.field(args, 1));
+        let ans = match self.handle(fd) {
+            Some(Handle::File(file)) => file.seek(SeekFrom::Start(pos)),
+            _ => return self.fail(EBADF),
+        };
+        match ans {
+            Ok(_) => 0,
+            Err(e) => self.fail_io(e),
+        }
+    }
+
+    fn flen(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let ans = match self.handle(mem.field(args, 0)) {
+            Some(Handle::File(file)) => file.metadata(),
+            _ => return self.fail(EBADF),
+        };
+        match ans {
+            Ok(metadata) => metadata.len(),
+            Err(e) => self.fail_io(e),
+        }
+    }
+
+    fn write_console(&mut self, mem: &mut Guest, addr: u64, len: u64) -> u64 {
+        if let Some(data) = mem.bytes(addr, len) {
+            io::stdout().write_all(&data).ok();
+            io::stdout().flush().ok();
+        }
+        0
+    }
+
+    // writes the command line, NUL terminated, and its length into the block
+    fn get_cmdline(&mut self, mem: &mut Guest, args: u64) -> u64 {
+        let (buf, size) = (mem.field(args, 0), mem.field(args, 1));
+        let mut data = self.cmdline.clone().into_bytes();
+        let len = data.len() as u64;
+        data.push(0);
+        if data.len() as u64 > size || !mem.set_bytes(buf, &data) {
+            return self.fail(EINVAL);
+        }
+        mem.set_field(args, 1, len);
+        0
+    }
+
+    fn handle(&mut self, fd: u64) -> Option<&mut Handle> {
+        let idx = fd.wrapping_sub(1) as usize;
+        self.handles.get_mut(idx).and_then(Option::as_mut)
+    }
+
+    // records the error number; returns -1
+    fn fail(&mut self, errno: u64) -> u64 {
+        self.errno = errno;
+        u64::MAX
+    }
+
+    fn fail_io(&mut self, e: io::Error) -> u64 {
+        self.fail(e.raw_os_error().map_or(EIO, |errno| errno as u64))
+    }
+}
+
+// the shift amount of `slli zero, zero, n` if `left`, else of `srai zero, zero, n`
+fn zero_shift(ins: Option<Instruction>, left: bool) -> Option<u32> {
+    let i = match (ins?, left) {
+        (Instruction::RV32I(RV32I::Slli(i)), true)
+        | (Instruction::RV64I(RV64I::Slli(i)), true)
+        | (Instruction::RV32I(RV32I::Srai(i)), false)
+        | (Instruction::RV64I(RV64I::Srai(i)), false) => i,
+        _ => return None,
+    };
+    if i.rd == 0 && i.rs1 == 0 {
+        Some(i.imm.low_u32() & 0x3F)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libemu6::{
+        mem64::{Config, Endian, Physical, Protect},
+        riscv::Fetch,
+    };
+
+    const BASE: u64 = 0x8000_0000;
+    // parameter block and buffers
+    const ARGS: u64 = BASE + 0x1000;
+    const BUF: u64 = BASE + 0x2000;
+    // the instructions around the EBREAK of a semihosting call
+    const SLLI_ZERO_0X1F: u32 = 0x01F0_1013;
+    const EBREAK: u32 = 0x0010_0073;
+    const SRAI_ZERO_7: u32 = 0x4070_5013;
+
+    // An RV64 hart in machine mode with a semihosting call at BASE + 4
+    fn hart() -> Execute<'static> {
+        let code = [SLLI_ZERO_0X1F, EBREAK, SRAI_ZERO_7];
+        let mut bytes: Vec<u8> = code.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+        bytes.resize(0x4000, 0);
+        let mut mem = Box::new(Physical::new());
+        mem.push_owned(
+            Config {
+                range: BASE..(BASE + 0x4000),
+                protect: Protect::READ | Protect::WRITE | Protect::EXECUTE,
+                endian: Endian::Little,
+            },
+            bytes,
+        );
+        Execute::new(Box::leak(mem), Xlen::X64)
+    }
+
+    // Makes the call `op` with the given parameter block; returns a0
+    fn call(semihosting: &mut Semihosting, exec: &mut Execute, op: u64, args: &[u64]) -> u64 {
+        for (i, &arg) in args.iter().enumerate() {
+            exec.write_mem(Usize::U64(ARGS + 8 * i as u64), arg, 8).unwrap();
+        }
+        exec.write_x(10, Usize::U64(op));
+        exec.write_x(11, Usize::U64(ARGS));
+        assert_eq!(semihosting.call(exec), None);
+        exec.read_x(10).to_u64()
+    }
+
+    fn write_bytes(exec: &mut Execute, addr: u64, data: &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            exec.write_mem(Usize::U64(addr + i as u64), b as u64, 1).unwrap();
+        }
+    }
+
+    fn read_bytes(exec: &mut Execute, addr: u64, len: u64) -> Vec<u8> {
+        (0..len).map(|i| exec.read_mem(Usize::U64(addr + i), 1).unwrap() as u8).collect()
+    }
+
+    #[test]
+    fn calls_are_marked_by_surrounding_instructions() {
+        let exec = hart();
+        let mut fetch = Fetch::new(Xlen::X64);
+        let mut decode =
+            |addr| fetch.fetch(exec.bus(), Usize::U64(addr), &exec.translate()).ok();
+        assert!(Semihosting::is_call(&mut decode, BASE + 4));
+        assert!(!Semihosting::is_call(&mut decode, BASE));
+        assert!(!Semihosting::is_call(&mut decode, BASE + 8));
+    }
+
+    #[test]
+    fn command_line_and_errors() {
+        let mut exec = hart();
+        let mut semihosting = Semihosting::new("prog arg".to_string(), false, None);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_GET_CMDLINE, &[BUF, 64]), 0);
+        assert_eq!(read_bytes(&mut exec, BUF, 9), b"prog arg\0");
+        assert_eq!(exec.read_mem(Usize::U64(ARGS + 8), 8).unwrap(), 8);
+        // too small a buffer
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_GET_CMDLINE, &[BUF, 8]), u64::MAX);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_ERRNO, &[]), EINVAL);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_CLOSE, &[1]), u64::MAX);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_ERRNO, &[]), EBADF);
+        // host files need allow_files
+        write_bytes(&mut exec, BUF, b"/tmp/x");
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_OPEN, &[BUF, 4, 6]), u64::MAX);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_ERRNO, &[]), EACCES);
+        write_bytes(&mut exec, BUF, b":tt");
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_OPEN, &[BUF, 0, 3]), 1);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_ISTTY, &[1]), 1);
+    }
+
+    #[test]
+    fn host_files() {
+        let path = std::env::temp_dir().join(format!("emu6-semihosting-{}", std::process::id()));
+        let name = path.to_str().unwrap().as_bytes().to_vec();
+        let mut exec = hart();
+        let mut semihosting = Semihosting::new(String::new(), true, None);
+        write_bytes(&mut exec, BUF, &name);
+        let len = name.len() as u64;
+        // mode "w", then "r"
+        let fd = call(&mut semihosting, &mut exec, SYS_OPEN, &[BUF, 4, len]);
+        write_bytes(&mut exec, BUF + 0x100, b"hello");
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_WRITE, &[fd, BUF + 0x100, 5]), 0);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_ISTTY, &[fd]), 0);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_CLOSE, &[fd]), 0);
+        let fd = call(&mut semihosting, &mut exec, SYS_OPEN, &[BUF, 0, len]);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_FLEN, &[fd]), 5);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_SEEK, &[fd, 1]), 0);
+        // reads report the number of bytes not read
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_READ, &[fd, BUF + 0x200, 8]), 4);
+        assert_eq!(read_bytes(&mut exec, BUF + 0x200, 4), b"ello");
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_CLOSE, &[fd]), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn heap_and_stack_in_free_memory() {
+        let mut exec = hart();
+        let mut semihosting = Semihosting::new(String::new(), false, None);
+        let info = |exec: &mut Execute| -> Vec<u64> {
+            let block = exec.read_mem(Usize::U64(ARGS), 8).unwrap();
+            (0..4).map(|i| exec.read_mem(Usize::U64(block + 8 * i), 8).unwrap()).collect()
+        };
+        write_bytes(&mut exec, BUF, &[0xFF; 32]);
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_HEAPINFO, &[BUF]), 0);
+        assert_eq!(info(&mut exec), [0; 4]);
+        let ram = 0x8100_0000..0x8200_0000;
+        let mut semihosting = Semihosting::new(String::new(), false, Some(ram));
+        assert_eq!(call(&mut semihosting, &mut exec, SYS_HEAPINFO, &[BUF]), 0);
+        let stack_limit = 0x8200_0000 - STACK_SIZE;
+        assert_eq!(info(&mut exec), [0x8100_0000, stack_limit, 0x8200_0000, stack_limit]);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let mut exec = hart();
+        let mut semihosting = Semihosting::new(String::new(), false, None);
+        let exit = |exec: &mut Execute, semihosting: &mut Semihosting, op, reason, code| {
+            exec.write_mem(Usize::U64(ARGS), reason, 8).unwrap();
+            exec.write_mem(Usize::U64(ARGS + 8), code, 8).unwrap();
+            exec.write_x(10, Usize::U64(op));
+            exec.write_x(11, Usize::U64(ARGS));
+            semihosting.call(exec)
+        };
+        let stopped = ADP_STOPPED_APPLICATION_EXIT;
+        assert_eq!(exit(&mut exec, &mut semihosting, SYS_EXIT, stopped, 3), Some(3));
+        assert_eq!(exit(&mut exec, &mut semihosting, SYS_EXIT_EXTENDED, stopped, 0), Some(0));
+        // any other reason is abnormal termination
+        assert_eq!(exit(&mut exec, &mut semihosting, SYS_EXIT, 0x20023, 0), Some(1));
+    }
+}
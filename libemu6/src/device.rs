@@ -0,0 +1,11 @@
+mod clint;
+mod console;
+mod finisher;
+mod plic;
+mod uart;
+
+pub use clint::{Clint, MtimeSource, CLINT_SIZE};
+pub use console::Console;
+pub use finisher::{TestFinisher, FINISHER_SIZE};
+pub use plic::{IrqLine, Plic, PLIC_SIZE};
+pub use uart::{Uart, UART_SIZE};
@@ -0,0 +1,216 @@
+use crate::error::Result;
+use crate::mem64::Device;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+// register layout compatible with the SiFive CLINT
+const MSIP_BASE: u64 = 0x0000;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MtimeSource {
+    // mtime increases by one for each retired instruction
+    Instret,
+    // mtime follows the host clock, counting at `frequency` Hz
+    HostClock { frequency: u64 },
+}
+
+// Core local interruptor, providing the machine software and timer interrupts.
+// Clones share the same registers, so one clone is mounted into the physical
+// memory while the run loop keeps another to drive the harts
+#[derive(Clone, Debug)]
+pub struct Clint {
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    source: MtimeSource,
+    // the current value when counting instructions, or the value at `epoch`
+    mtime: u64,
+    epoch: Instant,
+}
+
+impl Clint {
+    pub fn new(num_harts: usize, source: MtimeSource) -> Clint {
+        let inner = Inner {
+            msip: vec![false; num_harts],
+            // timer interrupts stay off until software programs mtimecmp
+            mtimecmp: vec![u64::MAX; num_harts],
+            source,
+            mtime: 0,
+            epoch: Instant::now(),
+        };
+        Clint {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.inner.borrow().mtime()
+    }
+
+    pub fn software_pending(&self, hart_id: usize) -> bool {
+        self.inner.borrow().msip[hart_id]
+    }
+
+    pub fn timer_pending(&self, hart_id: usize) -> bool {
+        let inner = self.inner.borrow();
+        inner.mtime() >= inner.mtimecmp[hart_id]
+    }
+
+    // programs mtimecmp on behalf of firmware running on the host
+    pub fn set_timer(&self, hart_id: usize, value: u64) {
+        self.inner.borrow_mut().mtimecmp[hart_id] = value;
+    }
+}
+
+impl Inner {
+    fn mtime(&self) -> u64 {
+        match self.source {
+            MtimeSource::Instret => self.mtime,
+            MtimeSource::HostClock { frequency } => {
+                let elapsed = self.epoch.elapsed().as_nanos();
+                let ticks = elapsed * frequency as u128 / 1_000_000_000;
+                self.mtime.wrapping_add(ticks as u64)
+            }
+        }
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = value;
+        self.epoch = Instant::now();
+    }
+
+    // returns (register offset, register width) of the register holding `offset`
+    fn register(&self, offset: u64) -> Option<(u64, u64)> {
+        let harts = self.msip.len() as u64;
+        if (MSIP_BASE..MSIP_BASE + 4 * harts).contains(&offset) {
+            Some((offset & !0b11, 4))
+        } else if (MTIMECMP_BASE..MTIMECMP_BASE + 8 * harts).contains(&offset) {
+            Some((offset & !0b111, 8))
+        } else if (MTIME..MTIME + 8).contains(&offset) {
+            Some((MTIME, 8))
+        } else {
+            None
+        }
+    }
+
+    fn read_register(&self, reg: u64) -> u64 {
+        match reg {
+            MTIME => self.mtime(),
+            _ if reg >= MTIMECMP_BASE => self.mtimecmp[((reg - MTIMECMP_BASE) / 8) as usize],
+            _ => self.msip[((reg - MSIP_BASE) / 4) as usize] as u64,
+        }
+    }
+
+    fn write_register(&mut self, reg: u64, value: u64) {
+        match reg {
+            MTIME => self.set_mtime(value),
+            _ if reg >= MTIMECMP_BASE => {
+                self.mtimecmp[((reg - MTIMECMP_BASE) / 8) as usize] = value
+            }
+            // only the lowest bit of msip is writable
+            _ => self.msip[((reg - MSIP_BASE) / 4) as usize] = value & 1 != 0,
+        }
+    }
+}
+
+// Registers may be accessed in parts, e.g. mtime as two 32-bit halves on RV32.
+// Reserved addresses read as zero and ignore writes
+impl Device for Clint {
+    fn read(&self, offset: u64, nbytes: u64) -> Result<u64> {
+        let inner = self.inner.borrow();
+        let (reg, width) = match inner.register(offset) {
+            Some(reg) => reg,
+            None => return Ok(0),
+        };
+        let shift = 8 * (offset - reg);
+        let value = inner.read_register(reg) >> shift;
+        Ok(value & mask(nbytes.min(width - (offset - reg))))
+    }
+
+    fn write(&mut self, offset: u64, nbytes: u64, value: u64) -> Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let (reg, width) = match inner.register(offset) {
+            Some(reg) => reg,
+            None => return Ok(()),
+        };
+        let shift = 8 * (offset - reg);
+        let bits = mask(nbytes.min(width - (offset - reg))) << shift;
+        let old = inner.read_register(reg);
+        inner.write_register(reg, (old & !bits) | ((value << shift) & bits));
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        if let MtimeSource::Instret = inner.source {
+            inner.mtime = inner.mtime.wrapping_add(1);
+        }
+    }
+}
+
+fn mask(nbytes: u64) -> u64 {
+    if nbytes >= 8 {
+        u64::MAX
+    } else {
+        (1 << (8 * nbytes)) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_pending_follows_mtimecmp() {
+        let mut clint = Clint::new(2, MtimeSource::Instret);
+        assert!(!clint.timer_pending(0));
+        clint.write(MTIMECMP_BASE, 8, 3).unwrap();
+        clint.tick();
+        clint.tick();
+        assert!(!clint.timer_pending(0));
+        clint.tick();
+        assert_eq!(clint.mtime(), 3);
+        assert!(clint.timer_pending(0));
+        assert!(!clint.timer_pending(1));
+        // moving mtimecmp past mtime clears the interrupt
+        clint.write(MTIMECMP_BASE, 8, 4).unwrap();
+        assert!(!clint.timer_pending(0));
+    }
+
+    #[test]
+    fn software_interrupt_keeps_lowest_bit() {
+        // the run loop and the memory bus share registers through clones
+        let mut mounted = Clint::new(2, MtimeSource::Instret);
+        let clint = mounted.clone();
+        mounted.write(MSIP_BASE + 4, 4, 0xFFFF_FFFF).unwrap();
+        assert_eq!(mounted.read(MSIP_BASE + 4, 4).unwrap(), 1);
+        assert!(clint.software_pending(1));
+        assert!(!clint.software_pending(0));
+        mounted.write(MSIP_BASE + 4, 4, 2).unwrap();
+        assert!(!clint.software_pending(1));
+    }
+
+    #[test]
+    fn registers_in_parts() {
+        let mut clint = Clint::new(1, MtimeSource::Instret);
+        clint.write(MTIME, 4, 0x1234_5678).unwrap();
+        clint.write(MTIME + 4, 4, 0x9).unwrap();
+        assert_eq!(clint.read(MTIME, 8).unwrap(), 0x9_1234_5678);
+        assert_eq!(clint.read(MTIME + 4, 4).unwrap(), 0x9);
+        assert_eq!(clint.read(MTIME + 1, 2).unwrap(), 0x3456);
+        clint.write(MTIMECMP_BASE + 2, 1, 0xAB).unwrap();
+        assert_eq!(clint.read(MTIMECMP_BASE, 8).unwrap(), 0xFFFF_FFFF_FFAB_FFFF);
+        // reserved addresses, including the mtimecmp of a missing hart
+        clint.write(MTIMECMP_BASE + 8, 8, 0).unwrap();
+        assert_eq!(clint.read(MTIMECMP_BASE + 8, 8).unwrap(), 0);
+        assert_eq!(clint.read(0x1000, 4).unwrap(), 0);
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// Host side of a serial port. Input is read on a separate thread, so that polling
+// for received bytes never blocks the emulation
+pub struct Console {
+    output: Box<dyn Write>,
+    input: Receiver<u8>,
+}
+
+impl Console {
+    pub fn new<R, W>(reader: R, writer: W) -> Console
+    where
+        R: Read + Send + 'static,
+        W: Write + 'static,
+    {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = reader;
+            let mut buf = [0u8; 64];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if buf[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                            break;
+                        }
+                    }
+                    // e.g. a pty with no terminal attached yet; try again later
+                    Err(_) => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        });
+        Console {
+            output: Box::new(writer),
+            input,
+        }
+    }
+
+    pub fn stdio() -> Console {
+        Console::new(io::stdin(), io::stdout())
+    }
+
+    // Listens on a Unix socket and waits for one client to connect
+    #[cfg(unix)]
+    pub fn unix_socket<P: AsRef<std::path::Path>>(path: P) -> io::Result<Console> {
+        use std::os::unix::net::UnixListener;
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        Ok(Console::new(stream.try_clone()?, stream))
+    }
+
+    // Opens a new pseudo-terminal, returning the console and the path of the
+    // terminal for the user to attach to
+    #[cfg(unix)]
+    pub fn pty() -> io::Result<(Console, String)> {
+        use std::ffi::CStr;
+        use std::fs::File;
+        use std::os::unix::io::FromRawFd;
+        let (master, name) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            (master, CStr::from_ptr(name).to_string_lossy().into_owned())
+        };
+        Ok((Console::new(master.try_clone()?, master), name))
+    }
+
+    pub fn try_read(&mut self) -> Option<u8> {
+        match self.input.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    pub fn write(&mut self, byte: u8) {
+        // a console with nobody listening drops its output like a real line would
+        let _ = self.output.write_all(&[byte]);
+        let _ = self.output.flush();
+    }
+}
+
+impl core::fmt::Debug for Console {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Console").finish()
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::Result;
+use crate::mem64::Device;
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub const FINISHER_SIZE: u64 = 0x1000;
+
+// values of the low 16 bits written to the register
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+// Test finisher compatible with the SiFive test device, through which guest
+// programs end the emulation. Writing 0x5555 passes, and 0x3333 fails with the
+// exit code in the upper 16 bits. Resets are not supported and end the
+// emulation as a pass. Clones share the same exit status
+#[derive(Clone, Debug, Default)]
+pub struct TestFinisher {
+    exit_code: Rc<Cell<Option<u32>>>,
+}
+
+impl TestFinisher {
+    pub fn new() -> TestFinisher {
+        TestFinisher::default()
+    }
+
+    // the exit code, once the guest has finished
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code.get()
+    }
+}
+
+impl Device for TestFinisher {
+    fn read(&self, _offset: u64, _nbytes: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    fn write(&mut self, offset: u64, _nbytes: u64, value: u64) -> Result<()> {
+        if offset != 0 {
+            return Ok(());
+        }
+        let code = match value & 0xFFFF {
+            FINISHER_PASS | FINISHER_RESET => 0,
+            FINISHER_FAIL => (value >> 16) as u32 & 0xFFFF,
+            _ => return Ok(()),
+        };
+        self.exit_code.set(Some(code));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let mut mounted = TestFinisher::new();
+        let finisher = mounted.clone();
+        // writes of other values or to other offsets are ignored
+        mounted.write(0, 4, 0x1234).unwrap();
+        mounted.write(4, 4, FINISHER_PASS).unwrap();
+        assert_eq!(finisher.exit_code(), None);
+        mounted.write(0, 4, 3 << 16 | FINISHER_FAIL).unwrap();
+        assert_eq!(finisher.exit_code(), Some(3));
+        mounted.write(0, 4, FINISHER_PASS).unwrap();
+        assert_eq!(finisher.exit_code(), Some(0));
+        let mut fail = TestFinisher::new();
+        fail.write(0, 4, 0xFFFF_0000 | FINISHER_FAIL).unwrap();
+        assert_eq!(fail.exit_code(), Some(0xFFFF));
+        let mut reset = TestFinisher::new();
+        reset.write(0, 2, FINISHER_RESET).unwrap();
+        assert_eq!(reset.exit_code(), Some(0));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::error::Result;
+use crate::mem64::Device;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// register layout compatible with the SiFive PLIC
+const PRIORITY_BASE: u64 = 0x0000;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+// interrupt source 0 does not exist, leaving at most 1023 usable sources
+const MAX_SOURCES: usize = 1024;
+// priorities and thresholds are 3 bits wide
+const PRIORITY_MASK: u32 = 0b111;
+
+// Platform-level interrupt controller. Each hart has two contexts, the M-mode one
+// numbered 2 * hart_id and the S-mode one numbered 2 * hart_id + 1. Clones share
+// the same state like the CLINT does
+#[derive(Clone, Debug)]
+pub struct Plic {
+    inner: Rc<RefCell<Inner>>,
+}
+
+// An interrupt line from a peripheral into one PLIC source
+#[derive(Clone, Debug)]
+pub struct IrqLine {
+    plic: Plic,
+    source: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    priority: Vec<u32>,
+    // level of the line from the peripheral
+    level: Vec<bool>,
+    pending: Vec<bool>,
+    // claimed and not yet completed; the gateway holds the source until completion
+    claimed: Vec<bool>,
+    enable: Vec<Vec<bool>>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    // `num_sources` counts the sources including the nonexistent source 0
+    pub fn new(num_harts: usize, num_sources: usize) -> Plic {
+        assert!(num_sources <= MAX_SOURCES, "too many interrupt sources");
+        let num_contexts = 2 * num_harts;
+        let inner = Inner {
+            priority: vec![0; num_sources],
+            level: vec![false; num_sources],
+            pending: vec![false; num_sources],
+            claimed: vec![false; num_sources],
+            enable: vec![vec![false; num_sources]; num_contexts],
+            threshold: vec![0; num_contexts],
+        };
+        Plic {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    pub fn line(&self, source: usize) -> IrqLine {
+        assert!(
+            source != 0 && source < self.inner.borrow().priority.len(),
+            "invalid interrupt source {}",
+            source
+        );
+        IrqLine {
+            plic: self.clone(),
+            source,
+        }
+    }
+
+    // whether mip.MEIP should be set on the hart
+    pub fn machine_pending(&self, hart_id: usize) -> bool {
+        self.inner.borrow().best(2 * hart_id).is_some()
+    }
+
+    // whether mip.SEIP should be set on the hart
+    pub fn supervisor_pending(&self, hart_id: usize) -> bool {
+        self.inner.borrow().best(2 * hart_id + 1).is_some()
+    }
+}
+
+impl IrqLine {
+    // sets the level of a level-triggered line
+    pub fn set(&self, level: bool) {
+        let mut inner = self.plic.inner.borrow_mut();
+        inner.level[self.source] = level;
+        if !inner.claimed[self.source] {
+            inner.pending[self.source] = level;
+        }
+    }
+
+    pub fn raise(&self) {
+        self.set(true)
+    }
+
+    pub fn lower(&self) {
+        self.set(false)
+    }
+}
+
+impl Inner {
+    // the enabled pending source with the highest priority above the threshold,
+    // preferring the lowest source number on ties
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best = None;
+        let mut best_priority = self.threshold[context];
+        for source in 1..self.priority.len() {
+            let priority = self.priority[source];
+            if self.pending[source] && self.enable[context][source] && priority > best_priority {
+                best = Some(source);
+                best_priority = priority;
+            }
+        }
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: usize) {
+        // completions of sources not enabled for the context are ignored
+        if source >= self.priority.len() || !self.enable[context][source] {
+            return;
+        }
+        self.claimed[source] = false;
+        self.pending[source] = self.level[source];
+    }
+
+    fn num_contexts(&self) -> u64 {
+        self.threshold.len() as u64
+    }
+
+    // bits of 32 sources starting at `first`
+    fn word(bits: &[bool], first: usize) -> u32 {
+        (0..32)
+            .filter(|i| bits.get(first + i).copied().unwrap_or(false))
+            .fold(0, |ans, i| ans | 1 << i)
+    }
+
+    // Reading the claim register claims the best source, unless `claim` is off
+    // as when merging a partial write, where it reads as zero
+    fn read_u32(&mut self, offset: u64, claim: bool) -> u32 {
+        let num_sources = self.priority.len() as u64;
+        if (PRIORITY_BASE..PRIORITY_BASE + 4 * num_sources).contains(&offset) {
+            self.priority[((offset - PRIORITY_BASE) / 4) as usize]
+        } else if (PENDING_BASE..ENABLE_BASE).contains(&offset) {
+            Self::word(&self.pending, (8 * (offset - PENDING_BASE)) as usize)
+        } else if (ENABLE_BASE..ENABLE_BASE + ENABLE_STRIDE * self.num_contexts())
+            .contains(&offset)
+        {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let first = (8 * ((offset - ENABLE_BASE) % ENABLE_STRIDE)) as usize;
+            Self::word(&self.enable[context], first)
+        } else if (CONTEXT_BASE..CONTEXT_BASE + CONTEXT_STRIDE * self.num_contexts())
+            .contains(&offset)
+        {
+            let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+            match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                CONTEXT_THRESHOLD => self.threshold[context],
+                CONTEXT_CLAIM if claim => self.claim(context),
+                _ => 0,
+            }
+        } else {
+            0
+        }
+    }
+
+    fn write_u32(&mut self, offset: u64, value: u32) {
+        let num_sources = self.priority.len() as u64;
+        if (PRIORITY_BASE + 4..PRIORITY_BASE + 4 * num_sources).contains(&offset) {
+            self.priority[((offset - PRIORITY_BASE) / 4) as usize] = value & PRIORITY_MASK;
+        } else if (ENABLE_BASE..ENABLE_BASE + ENABLE_STRIDE * self.num_contexts())
+            .contains(&offset)
+        {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let first = (8 * ((offset - ENABLE_BASE) % ENABLE_STRIDE)) as usize;
+            for i in 0..32 {
+                // source 0 is hardwired to zero
+                let source = first + i;
+                if source != 0 && source < self.priority.len() {
+                    self.enable[context][source] = value & (1 << i) != 0;
+                }
+            }
+        } else if (CONTEXT_BASE..CONTEXT_BASE + CONTEXT_STRIDE * self.num_contexts())
+            .contains(&offset)
+        {
+            let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+            match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                CONTEXT_THRESHOLD => self.threshold[context] = value & PRIORITY_MASK,
+                CONTEXT_CLAIM => self.complete(context, value as usize),
+                _ => {}
+            }
+        }
+        // pending bits are read-only and other addresses are reserved
+    }
+}
+
+// All registers are 32 bits wide and aligned. Accesses cover the registers they
+// overlap, and writes of part of a register leave its other bytes unchanged.
+// Reserved addresses read as zero and ignore writes
+impl Device for Plic {
+    fn read(&self, offset: u64, nbytes: u64) -> Result<u64> {
+        let mut inner = self.inner.borrow_mut();
+        let base = offset & !0b11;
+        let mut words = 0u128;
+        for (i, reg) in (base..offset + nbytes).step_by(4).enumerate() {
+            words |= (inner.read_u32(reg, true) as u128) << (32 * i);
+        }
+        Ok((words >> (8 * (offset - base))) as u64 & mask(nbytes))
+    }
+
+    fn write(&mut self, offset: u64, nbytes: u64, value: u64) -> Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let base = offset & !0b11;
+        let shift = 8 * (offset - base);
+        let bits = (mask(nbytes) as u128) << shift;
+        let value = (value as u128) << shift;
+        for (i, reg) in (base..offset + nbytes).step_by(4).enumerate() {
+            let reg_bits = (bits >> (32 * i)) as u32;
+            let mut reg_value = (value >> (32 * i)) as u32 & reg_bits;
+            if reg_bits != u32::MAX {
+                reg_value |= inner.read_u32(reg, false) & !reg_bits;
+            }
+            inner.write_u32(reg, reg_value);
+        }
+        Ok(())
+    }
+}
+
+fn mask(nbytes: u64) -> u64 {
+    if nbytes >= 8 {
+        u64::MAX
+    } else {
+        (1 << (8 * nbytes)) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: u64 = CONTEXT_BASE + CONTEXT_CLAIM;
+
+    #[test]
+    fn claim_and_complete() {
+        let mut plic = Plic::new(1, 8);
+        let line = plic.line(3);
+        plic.write(PRIORITY_BASE + 4 * 3, 4, 2).unwrap();
+        plic.write(ENABLE_BASE, 4, 1 << 3).unwrap();
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 0);
+        line.raise();
+        assert!(plic.machine_pending(0));
+        assert!(!plic.supervisor_pending(0));
+        assert_eq!(plic.read(PENDING_BASE, 4).unwrap(), 1 << 3);
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 3);
+        // the gateway holds the source until completion
+        assert!(!plic.machine_pending(0));
+        assert_eq!(plic.read(PENDING_BASE, 4).unwrap(), 0);
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 0);
+        // a line still raised at completion is pending again
+        plic.write(CLAIM, 4, 3).unwrap();
+        assert!(plic.machine_pending(0));
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 3);
+        line.lower();
+        plic.write(CLAIM, 4, 3).unwrap();
+        assert!(!plic.machine_pending(0));
+    }
+
+    #[test]
+    fn priority_threshold_and_enables() {
+        let mut plic = Plic::new(1, 8);
+        let (low, high) = (plic.line(1), plic.line(2));
+        plic.write(PRIORITY_BASE + 4, 4, 1).unwrap();
+        // priorities are 3 bits wide
+        plic.write(PRIORITY_BASE + 8, 4, 0xB).unwrap();
+        assert_eq!(plic.read(PRIORITY_BASE + 8, 4).unwrap(), 3);
+        // source 0 cannot be enabled, and the S-mode context has its own enables
+        plic.write(ENABLE_BASE, 4, 0b111).unwrap();
+        assert_eq!(plic.read(ENABLE_BASE, 4).unwrap(), 0b110);
+        assert_eq!(plic.read(ENABLE_BASE + ENABLE_STRIDE, 4).unwrap(), 0);
+        low.raise();
+        high.raise();
+        assert!(!plic.supervisor_pending(0));
+        plic.write(CONTEXT_BASE + CONTEXT_THRESHOLD, 4, 2).unwrap();
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 2);
+        // only the source above the threshold may be claimed
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 0);
+        plic.write(CONTEXT_BASE + CONTEXT_THRESHOLD, 4, 0).unwrap();
+        assert_eq!(plic.read(CLAIM, 4).unwrap(), 1);
+        // pending bits are read-only
+        plic.write(PENDING_BASE, 4, u64::MAX).unwrap();
+        assert_eq!(plic.read(PENDING_BASE, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn sub_word_accesses() {
+        let mut plic = Plic::new(1, 40);
+        // bytes of an enable word, and enables of sources 8 to 15 alone
+        plic.write(ENABLE_BASE, 4, 0x1234_5678).unwrap();
+        assert_eq!(plic.read(ENABLE_BASE + 1, 1).unwrap(), 0x56);
+        assert_eq!(plic.read(ENABLE_BASE + 2, 2).unwrap(), 0x1234);
+        plic.write(ENABLE_BASE + 1, 1, 0xFF).unwrap();
+        assert_eq!(plic.read(ENABLE_BASE, 4).unwrap(), 0x1234_FF78);
+        plic.write(ENABLE_BASE + 2, 2, 0).unwrap();
+        assert_eq!(plic.read(ENABLE_BASE, 4).unwrap(), 0xFF78);
+        // 64-bit accesses cover two registers
+        plic.write(ENABLE_BASE, 8, 0xAB_0000_0002).unwrap();
+        assert_eq!(plic.read(ENABLE_BASE + 4, 4).unwrap(), 0xAB);
+        assert_eq!(plic.read(ENABLE_BASE, 8).unwrap(), 0xAB_0000_0002);
+        // the priority of source 5 through its low byte
+        plic.write(PRIORITY_BASE + 4 * 5, 1, 6).unwrap();
+        assert_eq!(plic.read(PRIORITY_BASE + 4 * 5, 4).unwrap(), 6);
+        assert_eq!(plic.read(PRIORITY_BASE + 4 * 5 + 1, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn sub_word_claim_and_complete() {
+        let mut plic = Plic::new(1, 8);
+        let line = plic.line(5);
+        plic.write(PRIORITY_BASE + 4 * 5, 4, 1).unwrap();
+        plic.write(ENABLE_BASE, 1, 1 << 5).unwrap();
+        line.raise();
+        // a byte read of the claim register claims the source
+        assert_eq!(plic.read(CLAIM, 1).unwrap(), 5);
+        assert!(!plic.machine_pending(0));
+        line.lower();
+        line.raise();
+        // writing the low byte completes without claiming anything else
+        plic.write(CLAIM, 1, 5).unwrap();
+        assert!(plic.machine_pending(0));
+        assert_eq!(plic.read(PENDING_BASE, 1).unwrap(), 1 << 5);
+        assert_eq!(plic.read(CLAIM, 2).unwrap(), 5);
+    }
+}
@@ -0,0 +1,342 @@
+use super::{Console, IrqLine};
+use crate::error::Result;
+use crate::mem64::Device;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// register offsets; DLL and DLM replace RBR/THR and IER while LCR.DLAB is set
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+pub const UART_SIZE: u64 = 0x100;
+
+const IER_ERBFI: u8 = 1 << 0;
+const IER_ETBEI: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0F;
+// interrupt identification, in decreasing priority
+const IIR_NONE: u8 = 0x01;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_RX_RESET: u8 = 1 << 1;
+const FCR_TX_RESET: u8 = 1 << 2;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1F;
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+// data carrier detect, ring indicator, data set ready and clear to send
+const MSR_CONNECTED: u8 = 0xB0;
+
+const FIFO_DEPTH: usize = 16;
+
+// NS16550A compatible UART. Transmission completes at once, so the transmitter
+// is always empty; received bytes are polled from the console into the FIFO
+#[derive(Clone, Debug)]
+pub struct Uart {
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    console: Console,
+    irq: IrqLine,
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // the transmitter became empty and the interrupt has not been acknowledged
+    thre_interrupt: bool,
+}
+
+impl Uart {
+    pub fn new(console: Console, irq: IrqLine) -> Uart {
+        let inner = Inner {
+            console,
+            irq,
+            rx_fifo: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THRE | LSR_TEMT,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_interrupt: false,
+        };
+        Uart {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    // Console access for firmware running on the host, bypassing the registers
+    pub fn putchar(&self, byte: u8) {
+        self.inner.borrow_mut().console.write(byte);
+    }
+
+    pub fn getchar(&self) -> Option<u8> {
+        let mut inner = self.inner.borrow_mut();
+        inner.poll();
+        inner.rx_fifo.pop_front()
+    }
+}
+
+impl Inner {
+    fn fifo_depth(&self) -> usize {
+        if self.fcr & FCR_FIFO_ENABLE != 0 {
+            FIFO_DEPTH
+        } else {
+            1
+        }
+    }
+
+    fn poll(&mut self) {
+        // in loopback mode the receiver is disconnected from the line
+        if self.mcr & MCR_LOOP != 0 {
+            return;
+        }
+        while self.rx_fifo.len() < self.fifo_depth() {
+            match self.console.try_read() {
+                Some(byte) => self.receive(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() < self.fifo_depth() {
+            self.rx_fifo.push_back(byte);
+        } else {
+            self.lsr |= LSR_OE;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);
+        } else {
+            self.console.write(byte);
+        }
+        self.thre_interrupt = true;
+    }
+
+    fn iir(&self) -> u8 {
+        let id = if self.ier & IER_ERBFI != 0 && !self.rx_fifo.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_ETBEI != 0 && self.thre_interrupt {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NONE
+        };
+        if self.fcr & FCR_FIFO_ENABLE != 0 {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+
+    fn update_irq(&self) {
+        self.irq.set(self.iir() & IIR_NONE == 0);
+    }
+
+    fn read_u8(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => {
+                let byte = self.rx_fifo.pop_front().unwrap_or(0);
+                self.poll();
+                byte
+            }
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                // reading the identification acknowledges a transmitter interrupt
+                if iir & 0x0F == IIR_THR_EMPTY {
+                    self.thre_interrupt = false;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll();
+                let dr = if self.rx_fifo.is_empty() { 0 } else { LSR_DR };
+                let lsr = self.lsr | dr;
+                // overrun is cleared by reading the status
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_u8(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = value,
+            RBR_THR_DLL => self.transmit(value),
+            IER_DLM if dlab => self.dlm = value,
+            IER_DLM => {
+                // enabling the transmitter interrupt fires it, as the transmitter is empty
+                if value & IER_ETBEI != 0 && self.ier & IER_ETBEI == 0 {
+                    self.thre_interrupt = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                if value & FCR_RX_RESET != 0 {
+                    self.rx_fifo.clear();
+                }
+                // the reset bits clear themselves
+                self.fcr = value & !(FCR_RX_RESET | FCR_TX_RESET);
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+// Registers are one byte wide; wider accesses only reach the lowest register
+impl Device for Uart {
+    fn read(&self, offset: u64, _nbytes: u64) -> Result<u64> {
+        let mut inner = self.inner.borrow_mut();
+        let value = inner.read_u8(offset);
+        inner.update_irq();
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, _nbytes: u64, value: u64) -> Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        inner.write_u8(offset, value as u8);
+        inner.update_irq();
+        Ok(())
+    }
+
+    // moves input from the console into the receive FIFO
+    fn tick(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.poll();
+        inner.update_irq();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Plic;
+    use std::io::{self, Cursor, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    // a console output the test can look into
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A UART on source 1 of a PLIC, which signals the M-mode context of hart 0
+    fn uart(input: &[u8]) -> (Uart, Plic, Output) {
+        let mut plic = Plic::new(1, 2);
+        plic.write(4, 4, 1).unwrap();
+        plic.write(0x2000, 4, 1 << 1).unwrap();
+        let output = Output::default();
+        let console = Console::new(Cursor::new(input.to_vec()), output.clone());
+        (Uart::new(console, plic.line(1)), plic, output)
+    }
+
+    // input arrives from the reader thread of the console
+    fn wait_for_data(uart: &Uart) {
+        for _ in 0..100 {
+            if uart.read(LSR, 1).unwrap() as u8 & LSR_DR != 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no input from the console");
+    }
+
+    #[test]
+    fn transmit_and_interrupt_identification() {
+        let (mut uart, plic, output) = uart(b"");
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8, LSR_THRE | LSR_TEMT);
+        uart.write(RBR_THR_DLL, 1, b'h' as u64).unwrap();
+        assert_eq!(*output.0.borrow(), b"h");
+        assert_eq!(uart.read(IIR_FCR, 1).unwrap() as u8, IIR_NONE);
+        // the transmitter is empty, so enabling its interrupt fires it at once
+        uart.write(IER_DLM, 1, IER_ETBEI as u64).unwrap();
+        assert!(plic.machine_pending(0));
+        assert_eq!(uart.read(IIR_FCR, 1).unwrap() as u8, IIR_THR_EMPTY);
+        // which the read has acknowledged
+        assert!(!plic.machine_pending(0));
+        assert_eq!(uart.read(IIR_FCR, 1).unwrap() as u8, IIR_NONE);
+        // the divisor latch shares addresses with the data and IER registers
+        uart.write(LCR, 1, LCR_DLAB as u64).unwrap();
+        uart.write(RBR_THR_DLL, 1, 3).unwrap();
+        assert_eq!(uart.read(RBR_THR_DLL, 1).unwrap(), 3);
+        assert_eq!(uart.read(IER_DLM, 1).unwrap(), 0);
+        uart.write(LCR, 1, 3).unwrap();
+        assert_eq!(uart.read(IER_DLM, 1).unwrap() as u8, IER_ETBEI);
+        assert_eq!(*output.0.borrow(), b"h");
+    }
+
+    #[test]
+    fn loopback_and_overrun() {
+        let (mut uart, _, output) = uart(b"");
+        uart.write(MCR, 1, MCR_LOOP as u64).unwrap();
+        uart.write(RBR_THR_DLL, 1, b'x' as u64).unwrap();
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, LSR_DR);
+        // without the FIFO the receiver holds one byte
+        uart.write(RBR_THR_DLL, 1, b'y' as u64).unwrap();
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_OE, LSR_OE);
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_OE, 0);
+        assert_eq!(uart.read(RBR_THR_DLL, 1).unwrap(), b'x' as u64);
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, 0);
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn receive_from_console() {
+        let (mut uart, plic, _) = uart(b"ab");
+        uart.write(IIR_FCR, 1, FCR_FIFO_ENABLE as u64).unwrap();
+        uart.write(IER_DLM, 1, IER_ERBFI as u64).unwrap();
+        wait_for_data(&uart);
+        uart.tick();
+        assert!(plic.machine_pending(0));
+        assert_eq!(uart.read(IIR_FCR, 1).unwrap() as u8, IIR_RX_AVAILABLE | IIR_FIFO_ENABLED);
+        assert_eq!(uart.read(RBR_THR_DLL, 1).unwrap(), b'a' as u64);
+        wait_for_data(&uart);
+        assert_eq!(uart.read(RBR_THR_DLL, 1).unwrap(), b'b' as u64);
+        uart.tick();
+        assert!(!plic.machine_pending(0));
+    }
+}
@@ -1,15 +1,21 @@
 use crate::mem64::MemError as Mem64Error;
-use crate::riscv::{ExecError, FetchError};
+use crate::memory::MemError as MemoryError;
+use crate::plugin::PluginError;
+use crate::riscv::{ExecError, Trap};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("error in memory module")]
     Mem64(#[from] Mem64Error),
-    #[error("error in instruction fetch")]
-    Fetch(#[from] FetchError),
+    #[error("error in memory module")]
+    Memory(#[from] MemoryError),
     #[error("error in instruction execution")]
     Exec(#[from] ExecError),
+    #[error("synchronous exception")]
+    Trap(#[from] Trap),
+    #[error("error in plugin")]
+    Plugin(#[from] PluginError),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
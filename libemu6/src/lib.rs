@@ -1,3 +1,4 @@
+pub mod device;
 mod error;
 pub mod mem64;
 pub mod riscv;
@@ -1,4 +1,5 @@
 use crate::error::Result;
+use crate::memory::{Bus, Reservations};
 use core::ops::Range;
 use core::ptr::copy_nonoverlapping;
 use thiserror::Error;
@@ -6,12 +7,14 @@ use thiserror::Error;
 #[derive(Debug)]
 pub struct Physical<'a> {
     sections: Vec<Section<'a>>,
+    reservations: Reservations,
 }
 
 impl<'a> Physical<'a> {
     pub fn new() -> Physical<'a> {
         Physical {
             sections: Vec::new(),
+            reservations: Reservations::default(),
         }
     }
 
@@ -43,16 +46,46 @@ impl<'a> Physical<'a> {
         self.sections.push(Section::new_owned(config, owned));
     }
 
-    fn check_overlap(&self, new_config: &Config) -> bool {
-        let start = new_config.range.start;
-        let end = new_config.range.end;
+    pub fn push_device(&mut self, config: Config, device: Box<dyn Device + 'a>) {
+        if !self.check_overlap(&config) {
+            panic!("Section region overlapped")
+        }
+        self.sections.push(Section::new_device(config, device));
+    }
+
+    // address ranges of writable memory sections, leaving out devices and
+    // read-only program segments
+    pub fn ram_regions(&self) -> Vec<Range<u64>> {
+        self.sections
+            .iter()
+            .filter(|section| !matches!(section.inner, SectionInner::Device(_)))
+            .filter(|section| section.config.protect.contains(Protect::WRITE))
+            .map(|section| section.config.range.clone())
+            .collect()
+    }
+
+    // Whether no section, memory or device, covers any part of `range`
+    pub fn is_unmapped(&self, new_range: &Range<u64>) -> bool {
         for section in &self.sections {
-            if section.config.range.contains(&start) || section.config.range.contains(&end) {
+            let range = &section.config.range;
+            if new_range.start < range.end && range.start < new_range.end {
                 return false;
             }
         }
         true
     }
+
+    fn check_overlap(&self, new_config: &Config) -> bool {
+        self.is_unmapped(&new_config.range)
+    }
+
+    pub fn tick(&mut self) {
+        for section in &mut self.sections {
+            if let SectionInner::Device(device) = &mut section.inner {
+                device.tick();
+            }
+        }
+    }
 }
 
 impl<'a> Physical<'a> {
@@ -138,29 +171,31 @@ impl<'a> Physical<'a> {
     }
 
     pub fn write_u8(&mut self, addr: u64, n: u8) -> Result<()> {
-        self.write_any(addr, |section, addr| section.write_u8(addr, n))
+        self.write_any(addr, 1, |section, addr| section.write_u8(addr, n))
     }
 
     pub fn write_u16(&mut self, addr: u64, n: u16) -> Result<()> {
-        self.write_any(addr, |section, addr| section.write_u16(addr, n))
+        self.write_any(addr, 2, |section, addr| section.write_u16(addr, n))
     }
 
     pub fn write_u32(&mut self, addr: u64, n: u32) -> Result<()> {
-        self.write_any(addr, |section, addr| section.write_u32(addr, n))
+        self.write_any(addr, 4, |section, addr| section.write_u32(addr, n))
     }
 
     pub fn write_u64(&mut self, addr: u64, n: u64) -> Result<()> {
-        self.write_any(addr, |section, addr| section.write_u64(addr, n))
+        self.write_any(addr, 8, |section, addr| section.write_u64(addr, n))
     }
 
-    fn write_any<F>(&mut self, addr: u64, f: F) -> Result<()>
+    fn write_any<F>(&mut self, addr: u64, nbytes: u64, f: F) -> Result<()>
     where
         F: Fn(&mut Section, u64) -> Result<()>,
     {
         for mut section in &mut self.sections {
             if section.config.range.contains(&addr) {
                 if section.config.protect.contains(Protect::WRITE) {
-                    return f(&mut section, addr);
+                    f(&mut section, addr)?;
+                    self.reservations.invalidate(addr, nbytes);
+                    return Ok(());
                 } else {
                     return Err(MemError::CannotWrite { addr })?;
                 }
@@ -179,6 +214,67 @@ impl<'a> Physical<'a> {
     }
 }
 
+impl<'a> Bus for Physical<'a> {
+    fn read_u8(&self, addr: u64) -> Result<u8> {
+        Physical::read_u8(self, addr)
+    }
+
+    fn read_u16(&self, addr: u64) -> Result<u16> {
+        Physical::read_u16(self, addr)
+    }
+
+    fn read_u32(&self, addr: u64) -> Result<u32> {
+        Physical::read_u32(self, addr)
+    }
+
+    fn read_u64(&self, addr: u64) -> Result<u64> {
+        Physical::read_u64(self, addr)
+    }
+
+    fn fetch_ins_u16(&self, addr: u64) -> Result<u16> {
+        Physical::fetch_ins_u16(self, addr)
+    }
+
+    fn write_u8(&mut self, addr: u64, n: u8) -> Result<()> {
+        Physical::write_u8(self, addr, n)
+    }
+
+    fn write_u16(&mut self, addr: u64, n: u16) -> Result<()> {
+        Physical::write_u16(self, addr, n)
+    }
+
+    fn write_u32(&mut self, addr: u64, n: u32) -> Result<()> {
+        Physical::write_u32(self, addr, n)
+    }
+
+    fn write_u64(&mut self, addr: u64, n: u64) -> Result<()> {
+        Physical::write_u64(self, addr, n)
+    }
+
+    fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64) {
+        Physical::reserve(self, hart_id, addr, nbytes)
+    }
+
+    fn take_reservation(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool {
+        Physical::take_reservation(self, hart_id, addr, nbytes)
+    }
+
+    fn tick(&mut self) {
+        Physical::tick(self)
+    }
+}
+
+// LR/SC reservations, as kept by `memory::Reservations`
+impl<'a> Physical<'a> {
+    pub fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64) {
+        self.reservations.reserve(hart_id, addr, nbytes)
+    }
+
+    pub fn take_reservation(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool {
+        self.reservations.take(hart_id, addr, nbytes)
+    }
+}
+
 #[derive(Debug)]
 struct Section<'a> {
     config: Config,
@@ -187,9 +283,10 @@ struct Section<'a> {
 
 impl<'a> Section<'a> {
     fn new_zeroed(config: Config) -> Section<'a> {
+        let len = (config.range.end - config.range.start) as usize;
         Section {
             config,
-            inner: SectionInner::Owned(Vec::new()),
+            inner: SectionInner::Owned(vec![0; len]),
         }
     }
 
@@ -216,54 +313,85 @@ impl<'a> Section<'a> {
             inner: SectionInner::Owned(owned),
         }
     }
+
+    fn new_device(config: Config, device: Box<dyn Device + 'a>) -> Section<'a> {
+        Section {
+            config,
+            inner: SectionInner::Device(device),
+        }
+    }
 }
 
 impl<'a> Section<'a> {
     pub fn read_u8(&self, addr: u64) -> Result<u8> {
         self.check_read(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &self.inner {
+            return device.read(offset, 1).map(|n| n as u8);
+        }
         Ok(self.inner.read_u8(offset as usize))
     }
 
     pub fn read_u16(&self, addr: u64) -> Result<u16> {
         self.check_read(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &self.inner {
+            return device.read(offset, 2).map(|n| n as u16);
+        }
         Ok(self.inner.read_u16(offset as usize, self.config.endian))
     }
 
     pub fn read_u32(&self, addr: u64) -> Result<u32> {
         self.check_read(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &self.inner {
+            return device.read(offset, 4).map(|n| n as u32);
+        }
         Ok(self.inner.read_u32(offset as usize, self.config.endian))
     }
 
     pub fn read_u64(&self, addr: u64) -> Result<u64> {
         self.check_read(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &self.inner {
+            return device.read(offset, 8);
+        }
         Ok(self.inner.read_u64(offset as usize, self.config.endian))
     }
 
     pub fn write_u8(&mut self, addr: u64, n: u8) -> Result<()> {
         self.check_write(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &mut self.inner {
+            return device.write(offset, 1, n as u64);
+        }
         Ok(self.inner.write_u8(offset as usize, n))
     }
 
     pub fn write_u16(&mut self, addr: u64, n: u16) -> Result<()> {
         self.check_write(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &mut self.inner {
+            return device.write(offset, 2, n as u64);
+        }
         Ok(self.inner.write_u16(offset as usize, n, self.config.endian))
     }
 
     pub fn write_u32(&mut self, addr: u64, n: u32) -> Result<()> {
         self.check_write(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &mut self.inner {
+            return device.write(offset, 4, n as u64);
+        }
         Ok(self.inner.write_u32(offset as usize, n, self.config.endian))
     }
 
     pub fn write_u64(&mut self, addr: u64, n: u64) -> Result<()> {
         self.check_write(addr)?;
         let offset = self.get_underlying_buf_offset(addr)?;
+        if let SectionInner::Device(device) = &mut self.inner {
+            return device.write(offset, 8, n);
+        }
         Ok(self.inner.write_u64(offset as usize, n, self.config.endian))
     }
 
@@ -289,11 +417,22 @@ impl<'a> Section<'a> {
     }
 }
 
+// A memory-mapped device. Offsets are relative to the start of its section and
+// accesses are 1, 2, 4 or 8 bytes wide, with values passed in host byte order;
+// reads may have side effects, so devices keep their state behind interior mutability
+pub trait Device: core::fmt::Debug {
+    fn read(&self, offset: u64, nbytes: u64) -> Result<u64>;
+    fn write(&mut self, offset: u64, nbytes: u64, value: u64) -> Result<()>;
+    // called once for every retired instruction
+    fn tick(&mut self) {}
+}
+
 #[derive(Debug)]
 enum SectionInner<'a> {
     Borrowed(&'a [u8]),
     BorrowedMut(&'a mut [u8]),
     Owned(Vec<u8>),
+    Device(Box<dyn Device + 'a>),
 }
 
 impl<'a> SectionInner<'a> {
@@ -302,6 +441,7 @@ impl<'a> SectionInner<'a> {
             SectionInner::Borrowed(slice) => slice[offset],
             SectionInner::BorrowedMut(slice) => slice[offset],
             SectionInner::Owned(vec) => vec[offset],
+            SectionInner::Device(_) => unreachable!(),
         }
     }
 
@@ -322,6 +462,7 @@ impl<'a> SectionInner<'a> {
             SectionInner::Borrowed(slice) => slice.as_ptr(),
             SectionInner::BorrowedMut(slice) => slice.as_ptr(),
             SectionInner::Owned(vec) => vec.as_ptr(),
+            SectionInner::Device(_) => unreachable!(),
         };
         let buf_ptr = unsafe { buf_ptr.offset(offset as isize) };
         let mut out = 0u64;
@@ -340,6 +481,7 @@ impl<'a> SectionInner<'a> {
             SectionInner::Borrowed(_slice) => unreachable!(),
             SectionInner::BorrowedMut(slice) => slice[offset] = n,
             SectionInner::Owned(vec) => vec[offset] = n,
+            SectionInner::Device(_) => unreachable!(),
         }
     }
 
@@ -360,6 +502,7 @@ impl<'a> SectionInner<'a> {
             SectionInner::Borrowed(slice) => slice.as_ptr(),
             SectionInner::BorrowedMut(slice) => slice.as_ptr(),
             SectionInner::Owned(vec) => vec.as_ptr(),
+            SectionInner::Device(_) => unreachable!(),
         };
         let buf_ptr = unsafe { buf_ptr.offset(offset as isize) as *mut u8 };
         let in_buf = match endian {
@@ -405,3 +548,98 @@ pub enum MemError {
     #[error("No memory bound for address 0x{addr:016X}")]
     NoMemory { addr: u64 },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // records every access and tick, and reads back the offset
+    #[derive(Debug, Default)]
+    struct Recorder {
+        writes: Rc<RefCell<Vec<(u64, u64, u64)>>>,
+        ticks: Rc<RefCell<usize>>,
+    }
+
+    impl Device for Recorder {
+        fn read(&self, offset: u64, _nbytes: u64) -> Result<u64> {
+            Ok(offset)
+        }
+
+        fn write(&mut self, offset: u64, nbytes: u64, value: u64) -> Result<()> {
+            self.writes.borrow_mut().push((offset, nbytes, value));
+            Ok(())
+        }
+
+        fn tick(&mut self) {
+            *self.ticks.borrow_mut() += 1;
+        }
+    }
+
+    fn config(range: Range<u64>) -> Config {
+        Config {
+            range,
+            protect: Protect::READ | Protect::WRITE,
+            endian: Endian::Little,
+        }
+    }
+
+    #[test]
+    fn zeroed_section_is_allocated() {
+        let mut mem = Physical::new();
+        mem.push_zeroed(config(0x1000..0x2000));
+        assert_eq!(mem.read_u64(0x1FF8).unwrap(), 0);
+        mem.write_u64(0x1FF8, 0x0123_4567_89AB_CDEF).unwrap();
+        assert_eq!(mem.read_u32(0x1FFC).unwrap(), 0x0123_4567);
+    }
+
+    #[test]
+    fn devices_see_offsets_and_widths() {
+        let recorder = Recorder::default();
+        let (writes, ticks) = (recorder.writes.clone(), recorder.ticks.clone());
+        let mut mem = Physical::new();
+        mem.push_zeroed(config(0x8000_0000..0x8000_1000));
+        mem.push_device(config(0x1000_0000..0x1000_0100), Box::new(recorder));
+        mem.write_u32(0x1000_0010, 0xAB).unwrap();
+        mem.write_u8(0x1000_00FF, 1).unwrap();
+        mem.write_u64(0x8000_0000, 2).unwrap();
+        assert_eq!(*writes.borrow(), [(0x10, 4, 0xAB), (0xFF, 1, 1)]);
+        assert_eq!(mem.read_u16(0x1000_0020).unwrap(), 0x20);
+        assert_eq!(mem.read_u64(0x8000_0000).unwrap(), 2);
+        mem.tick();
+        mem.tick();
+        assert_eq!(*ticks.borrow(), 2);
+    }
+
+    #[test]
+    fn ram_regions_are_writable_memory() {
+        let mut mem = Physical::new();
+        mem.push_zeroed(config(0x8000_1000..0x8000_2000));
+        let text = Config {
+            protect: Protect::READ | Protect::EXECUTE,
+            ..config(0x8000_0000..0x8000_1000)
+        };
+        mem.push_owned(text, vec![0; 0x1000]);
+        mem.push_device(config(0x1000_0000..0x1000_0100), Box::new(Recorder::default()));
+        let regions = mem.ram_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0], 0x8000_1000..0x8000_2000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Section region overlapped")]
+    fn overlapping_sections_are_rejected() {
+        let mut mem = Physical::new();
+        mem.push_zeroed(config(0x1000..0x2000));
+        mem.push_zeroed(config(0x1FFF..0x3000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Section region overlapped")]
+    fn contained_sections_are_rejected() {
+        let mut mem = Physical::new();
+        mem.push_zeroed(config(0x1000..0x2000));
+        mem.push_device(config(0x1800..0x1900), Box::new(Recorder::default()));
+    }
+}
@@ -1,16 +1,56 @@
 use core::ops::Range;
 use core::mem::MaybeUninit;
+use crate::error::Result;
+use crate::mem64::Device;
 use crate::size::Usize;
-use crate::plugin::MemoryExtVTable;
+use crate::plugin::{self, MemResult, MemoryExtVTable};
+use thiserror::Error;
+
+// Physical memory as seen by a hart. Addresses are physical addresses after
+// translation; values are returned in host byte order
+pub trait Bus {
+    fn read_u8(&self, addr: u64) -> Result<u8>;
+    fn read_u16(&self, addr: u64) -> Result<u16>;
+    fn read_u32(&self, addr: u64) -> Result<u32>;
+    fn read_u64(&self, addr: u64) -> Result<u64>;
+    fn fetch_ins_u16(&self, addr: u64) -> Result<u16>;
+    fn write_u8(&mut self, addr: u64, n: u8) -> Result<()>;
+    fn write_u16(&mut self, addr: u64, n: u16) -> Result<()>;
+    fn write_u32(&mut self, addr: u64, n: u32) -> Result<()>;
+    fn write_u64(&mut self, addr: u64, n: u64) -> Result<()>;
+    fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64);
+    fn take_reservation(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool;
+
+    // Advances memory-mapped devices by one cycle
+    fn tick(&mut self) {}
+
+    fn read_i8(&self, addr: u64) -> Result<i8> {
+        self.read_u8(addr).map(|x| x as i8)
+    }
+
+    fn read_i16(&self, addr: u64) -> Result<i16> {
+        self.read_u16(addr).map(|x| x as i16)
+    }
+
+    fn read_i32(&self, addr: u64) -> Result<i32> {
+        self.read_u32(addr).map(|x| x as i32)
+    }
+
+    fn read_i64(&self, addr: u64) -> Result<i64> {
+        self.read_u64(addr).map(|x| x as i64)
+    }
+}
 
 pub struct MemorySet<'a> {
     sections: Vec<Section<'a>>,
+    reservations: Reservations,
 }
 
 impl<'a> MemorySet<'a> {
     pub fn new() -> MemorySet<'a> {
         MemorySet {
             sections: Vec::new(),
+            reservations: Reservations::default(),
         }
     }
 
@@ -43,76 +83,216 @@ impl<'a> MemorySet<'a> {
     }
 
     pub fn push_extension(&mut self, vtable: Box<MemoryExtVTable>) {
-        // todo: check overlap
-        self.sections.push(Section::new_extension(vtable));
+        let section = Section::new_extension(vtable);
+        if !self.check_overlap(&section.range()) {
+            panic!("Section region overlapped")
+        }
+        self.sections.push(section);
     }
 
     fn check_overlap(&self, new_range: &Range<Usize>) -> bool {
-        let start = new_range.start;
-        let end = new_range.end;
+        let start = new_range.start.to_u64();
+        let end = new_range.end.to_u64();
         for section in &self.sections {
-            let existing_range = match section {
-                Section::Buffer(config, _) => config.range.clone(),
-                Section::Extension(extension) => extension.get_range(),
-            };
-            if existing_range.contains(&start) || existing_range.contains(&end) 
-            || new_range.contains(&existing_range.start) || new_range.contains(&existing_range.end) {
+            let range = section.range();
+            if start < range.end.to_u64() && range.start.to_u64() < end {
                 return false;
             }
         }
         true
     }
+
+    fn choose_section(&self, addr: u64) -> Result<&Section<'a>> {
+        self.sections.iter().find(|section| section.contains(addr))
+            .ok_or_else(|| MemError::NoMemory { addr }.into())
+    }
+
+    fn choose_section_mut(&mut self, addr: u64) -> Result<&mut Section<'a>> {
+        self.sections.iter_mut().find(|section| section.contains(addr))
+            .ok_or_else(|| MemError::NoMemory { addr }.into())
+    }
+
+    pub fn read_nbytes(&self, addr: u64, nbytes: u32) -> Result<u64> {
+        self.choose_section(addr)?.read_nbytes(addr, nbytes, Access::Read)
+    }
+
+    pub fn exec_nbytes(&self, addr: u64, nbytes: u32) -> Result<u64> {
+        self.choose_section(addr)?.read_nbytes(addr, nbytes, Access::Execute)
+    }
+
+    pub fn write_nbytes(&mut self, addr: u64, nbytes: u32, n: u64) -> Result<()> {
+        self.choose_section_mut(addr)?.write_nbytes(addr, nbytes, n)?;
+        self.reservations.invalidate(addr, nbytes as u64);
+        Ok(())
+    }
+
+    pub fn exec_u16(&self, addr: u64) -> Result<u16> {
+        self.exec_nbytes(addr, 2).map(|n| n as u16)
+    }
+
+    pub fn exec_u32(&self, addr: u64) -> Result<u32> {
+        self.exec_nbytes(addr, 4).map(|n| n as u32)
+    }
 }
 
-struct Extension {
+impl<'a> Bus for MemorySet<'a> {
+    fn read_u8(&self, addr: u64) -> Result<u8> {
+        self.read_nbytes(addr, 1).map(|n| n as u8)
+    }
+
+    fn read_u16(&self, addr: u64) -> Result<u16> {
+        self.read_nbytes(addr, 2).map(|n| n as u16)
+    }
+
+    fn read_u32(&self, addr: u64) -> Result<u32> {
+        self.read_nbytes(addr, 4).map(|n| n as u32)
+    }
+
+    fn read_u64(&self, addr: u64) -> Result<u64> {
+        self.read_nbytes(addr, 8)
+    }
+
+    fn fetch_ins_u16(&self, addr: u64) -> Result<u16> {
+        self.exec_u16(addr)
+    }
+
+    fn write_u8(&mut self, addr: u64, n: u8) -> Result<()> {
+        self.write_nbytes(addr, 1, n as u64)
+    }
+
+    fn write_u16(&mut self, addr: u64, n: u16) -> Result<()> {
+        self.write_nbytes(addr, 2, n as u64)
+    }
+
+    fn write_u32(&mut self, addr: u64, n: u32) -> Result<()> {
+        self.write_nbytes(addr, 4, n as u64)
+    }
+
+    fn write_u64(&mut self, addr: u64, n: u64) -> Result<()> {
+        self.write_nbytes(addr, 8, n)
+    }
+
+    fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64) {
+        self.reservations.reserve(hart_id, addr, nbytes)
+    }
+
+    fn take_reservation(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool {
+        self.reservations.take(hart_id, addr, nbytes)
+    }
+}
+
+// Reservation sets for LR/SC, kept by each bus. Every hart holds at most one
+// reservation; any store overlapping a reserved range, from whichever hart,
+// invalidates it
+#[derive(Debug, Default)]
+pub(crate) struct Reservations {
+    sets: Vec<Reservation>,
+}
+
+#[derive(Debug)]
+struct Reservation {
+    hart_id: usize,
+    range: Range<u64>,
+}
+
+impl Reservations {
+    pub fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64) {
+        self.sets.retain(|r| r.hart_id != hart_id);
+        self.sets.push(Reservation {
+            hart_id,
+            range: addr..addr.wrapping_add(nbytes),
+        });
+    }
+
+    // SC always clears the reservation of this hart, whether it succeeds or not
+    pub fn take(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool {
+        match self.sets.iter().position(|r| r.hart_id == hart_id) {
+            Some(idx) => {
+                let range = self.sets.swap_remove(idx).range;
+                range.start <= addr && addr.wrapping_add(nbytes) <= range.end
+            }
+            None => false,
+        }
+    }
+
+    pub fn invalidate(&mut self, addr: u64, nbytes: u64) {
+        let end = addr.wrapping_add(nbytes);
+        self.sets.retain(|r| r.range.end <= addr || end <= r.range.start);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Execute,
+}
+
+// A memory extension instance created through the C vtable of a plugin. Its
+// range is asked for once, when it is created
+pub struct Extension {
     vtable: Box<MemoryExtVTable>,
     instance: *mut (),
+    range: Range<Usize>,
+    // the physical address the extension is mounted at, for fault addresses
+    base: u64,
 }
 
 impl Extension {
-    fn get_range(&self) -> Range<Usize> {
-        let mut addr_len_bytes: MaybeUninit<u32> = MaybeUninit::uninit();
-        let mut buf_from: MaybeUninit<[u8; 8]> = MaybeUninit::uninit();
-        let mut buf_to: MaybeUninit<[u8; 8]> = MaybeUninit::uninit();
-        (self.vtable.get_range)(
-            self.instance, addr_len_bytes.as_mut_ptr(), 
-            buf_from.as_mut_ptr() as _, buf_to.as_mut_ptr() as _
-        );
-        let addr_len_bytes: u32 = unsafe { addr_len_bytes.assume_init() };
-        let addr_from = match addr_len_bytes {
-            4 => (Usize::U32(u32::from_ne_bytes(unsafe { *(buf_from.as_ptr() as *const [u8; 4]) }))),
-            8 => (Usize::U64(u64::from_ne_bytes(unsafe { buf_from.assume_init() }))),
-            _ => panic!("invalid addr_len_bytes")
-        };
-        let addr_to = match addr_len_bytes {
-            4 => (Usize::U32(u32::from_ne_bytes(unsafe { *(buf_from.as_ptr() as *const [u8; 4]) }))),
-            8 => (Usize::U64(u64::from_ne_bytes(unsafe { buf_from.assume_init() }))),
-            _ => panic!("invalid addr_len_bytes")
-        };
-        addr_from..addr_to
+    pub fn new(vtable: Box<MemoryExtVTable>) -> Extension {
+        let instance = (vtable.memory_new)();
+        let range = ext_get_range(&vtable, instance);
+        let base = range.start.to_u64();
+        Extension { vtable, instance, range, base }
     }
 
-    fn read_u8(&self, offset: Usize) -> Result<u8> {
-        let (ptr, len) = ext_usize_to_ptr_len(&offset);
-        let mut val: MaybeUninit<u8> = MaybeUninit::uninit();
-        let ans = (self.vtable.read_u8)(self.instance, len, ptr, val.as_mut_ptr());
-        ext_map_result(ans).map(|_| unsafe { val.assume_init() })
+    // Mounts the extension as a device at `base` instead of the start of its range
+    pub fn with_base(mut self, base: u64) -> Extension {
+        self.base = base;
+        self
+    }
+
+    // the address range the extension asks to be mapped at
+    pub fn get_range(&self) -> Range<Usize> {
+        self.range.clone()
     }
 
-    fn exec_u8(&self, offset: Usize) -> Result<u8> {
+    // offsets are relative to the start of the range of the extension; values are
+    // exchanged as little endian byte arrays
+    fn read_nbytes(&self, offset: Usize, nbytes: u32, access: Access, addr: u64) -> Result<u64> {
         let (ptr, len) = ext_usize_to_ptr_len(&offset);
-        let mut val: MaybeUninit<u8> = MaybeUninit::uninit();
-        let ans = (self.vtable.exec_u8)(self.instance, len, ptr, val.as_mut_ptr());
-        ext_map_result(ans).map(|_| unsafe { val.assume_init() })
+        let mut buf = [0u8; 8];
+        let f = match access {
+            Access::Read => self.vtable.read_nbytes,
+            Access::Execute => self.vtable.exec_nbytes,
+        };
+        let ans = f(self.instance, len, ptr, buf.as_mut_ptr(), nbytes, plugin::Endian::Little);
+        ext_map_result(ans, addr).map(|_| u64::from_le_bytes(buf))
     }
 
-    fn write_u8(&self, offset: Usize, val: u8) -> Result<()> {
+    fn write_nbytes(&self, offset: Usize, nbytes: u32, val: u64, addr: u64) -> Result<()> {
         let (ptr, len) = ext_usize_to_ptr_len(&offset);
-        let ans = (self.vtable.write_u8)(self.instance, len, ptr, &val as *const _);
-        ext_map_result(ans)
+        let buf = val.to_le_bytes();
+        let ans = (self.vtable.write_nbytes)(
+            self.instance, len, ptr, buf.as_ptr(), nbytes, plugin::Endian::Little,
+        );
+        ext_map_result(ans, addr)
     }
 }
 
+fn ext_get_range(vtable: &MemoryExtVTable, instance: *mut ()) -> Range<Usize> {
+    let mut addr_len_bytes: MaybeUninit<u32> = MaybeUninit::uninit();
+    let mut buf_from: MaybeUninit<[u8; 8]> = MaybeUninit::uninit();
+    let mut buf_to: MaybeUninit<[u8; 8]> = MaybeUninit::uninit();
+    (vtable.get_range)(
+        instance, addr_len_bytes.as_mut_ptr(),
+        buf_from.as_mut_ptr() as _, buf_to.as_mut_ptr() as _
+    );
+    let addr_len_bytes: u32 = unsafe { addr_len_bytes.assume_init() };
+    let addr_from = ext_ptr_to_usize(buf_from.as_ptr() as _, addr_len_bytes);
+    let addr_to = ext_ptr_to_usize(buf_to.as_ptr() as _, addr_len_bytes);
+    addr_from..addr_to
+}
+
 fn ext_usize_to_ptr_len(n: &Usize) -> (*const u8, u32) {
     match n {
         Usize::U32(a) => (a as *const u32 as *const u8, 4),
@@ -120,13 +300,49 @@ fn ext_usize_to_ptr_len(n: &Usize) -> (*const u8, u32) {
     }
 }
 
-fn ext_map_result(a: crate::plugin::MemResult) -> Result<()> {
-    use crate::plugin::MemResult;
+fn ext_ptr_to_usize(ptr: *const u8, len: u32) -> Usize {
+    match len {
+        4 => Usize::U32(u32::from_ne_bytes(unsafe { *(ptr as *const [u8; 4]) })),
+        8 => Usize::U64(u64::from_ne_bytes(unsafe { *(ptr as *const [u8; 8]) })),
+        _ => panic!("invalid addr_len_bytes")
+    }
+}
+
+fn ext_map_result(a: MemResult, addr: u64) -> Result<()> {
     match a {
         MemResult::Ok => Ok(()),
-        MemResult::NoMemory => Err(Error::NoMemory),
-        MemResult::CannotExecute => Err(Error::CannotExecute),
-        MemResult::CannotWrite => Err(Error::CannotWrite),
+        MemResult::NoMemory => Err(MemError::NoMemory { addr })?,
+        MemResult::CannotExecute => Err(MemError::CannotExecute { addr })?,
+        MemResult::CannotWrite => Err(MemError::CannotWrite { addr })?,
+    }
+}
+
+// Lets an extension be mounted into `mem64::Physical` like native devices
+impl Device for Extension {
+    fn read(&self, offset: u64, nbytes: u64) -> Result<u64> {
+        let addr = self.base.wrapping_add(offset);
+        self.read_nbytes(self.offset_usize(offset), nbytes as u32, Access::Read, addr)
+    }
+
+    fn write(&mut self, offset: u64, nbytes: u64, value: u64) -> Result<()> {
+        let addr = self.base.wrapping_add(offset);
+        self.write_nbytes(self.offset_usize(offset), nbytes as u32, value, addr)
+    }
+}
+
+impl Extension {
+    // offsets are passed with the address width of the extension
+    fn offset_usize(&self, offset: u64) -> Usize {
+        match self.range.start {
+            Usize::U32(_) => Usize::U32(offset as u32),
+            Usize::U64(_) => Usize::U64(offset),
+        }
+    }
+}
+
+impl core::fmt::Debug for Extension {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Extension").field("range", &self.range).finish()
     }
 }
 
@@ -136,7 +352,6 @@ impl Drop for Extension {
     }
 }
 
-// #[derive(Debug)]
 enum Section<'a> {
     Buffer(Config, SectionInner<'a>),
     Extension(Extension),
@@ -144,7 +359,8 @@ enum Section<'a> {
 
 impl<'a> Section<'a> {
     fn new_zeroed(config: Config) -> Section<'a> {
-        Section::Buffer(config, SectionInner::Owned(Vec::new()))
+        let len = range_len(&config.range);
+        Section::Buffer(config, SectionInner::Owned(vec![0; len]))
     }
 
     fn new_slice(config: Config, slice: &[u8]) -> Section {
@@ -158,22 +374,121 @@ impl<'a> Section<'a> {
         Section::Buffer(config, SectionInner::BorrowedMut(slice))
     }
 
-    fn new_owned(config: Config, owned: Vec<u8>) -> Section<'a> {
+    // the buffer is zero extended to the whole range, e.g. for .bss after .data
+    fn new_owned(config: Config, mut owned: Vec<u8>) -> Section<'a> {
+        let len = range_len(&config.range);
+        if owned.len() < len {
+            owned.resize(len, 0);
+        }
         Section::Buffer(config, SectionInner::Owned(owned))
     }
 
     fn new_extension(vtable: Box<MemoryExtVTable>) -> Section<'a> {
-        let instance = (vtable.memory_new)();
-        Section::Extension(Extension { vtable, instance })
+        Section::Extension(Extension::new(vtable))
+    }
+
+    fn range(&self) -> Range<Usize> {
+        match self {
+            Section::Buffer(config, _) => config.range.clone(),
+            Section::Extension(extension) => extension.range.clone(),
+        }
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        let range = self.range();
+        range.start.to_u64() <= addr && addr < range.end.to_u64()
+    }
+
+    fn offset(&self, addr: u64) -> Usize {
+        match self.range().start {
+            Usize::U32(start) => Usize::U32((addr - start as u64) as u32),
+            Usize::U64(start) => Usize::U64(addr - start),
+        }
+    }
+
+    fn read_nbytes(&self, addr: u64, nbytes: u32, access: Access) -> Result<u64> {
+        let offset = self.offset(addr);
+        match self {
+            Section::Buffer(config, inner) => {
+                if let Access::Execute = access {
+                    if !config.protect.contains(Protect::EXECUTE) {
+                        return Err(MemError::CannotExecute { addr })?;
+                    }
+                }
+                let bytes = inner.get(offset.to_u64() as usize, nbytes as usize)
+                    .ok_or(MemError::NoMemory { addr })?;
+                Ok(from_bytes(bytes, config.endian))
+            }
+            Section::Extension(extension) => extension.read_nbytes(offset, nbytes, access, addr),
+        }
+    }
+
+    fn write_nbytes(&mut self, addr: u64, nbytes: u32, n: u64) -> Result<()> {
+        let offset = self.offset(addr);
+        match self {
+            Section::Buffer(config, inner) => {
+                if !config.protect.contains(Protect::WRITE) {
+                    return Err(MemError::CannotWrite { addr })?;
+                }
+                let endian = config.endian;
+                let bytes = inner.get_mut(offset.to_u64() as usize, nbytes as usize, addr)?;
+                to_bytes(bytes, n, endian);
+                Ok(())
+            }
+            Section::Extension(extension) => extension.write_nbytes(offset, nbytes, n, addr),
+        }
     }
 }
 
-// #[derive(Debug)]
 enum SectionInner<'a> {
     Borrowed(&'a [u8]),
     BorrowedMut(&'a mut [u8]),
     Owned(Vec<u8>),
-    Extension(Extension),
+}
+
+impl<'a> SectionInner<'a> {
+    fn get(&self, offset: usize, nbytes: usize) -> Option<&[u8]> {
+        let buf: &[u8] = match self {
+            SectionInner::Borrowed(slice) => slice,
+            SectionInner::BorrowedMut(slice) => slice,
+            SectionInner::Owned(vec) => vec,
+        };
+        buf.get(offset..offset.checked_add(nbytes)?)
+    }
+
+    fn get_mut(&mut self, offset: usize, nbytes: usize, addr: u64) -> Result<&mut [u8]> {
+        let buf: &mut [u8] = match self {
+            // read-only slices cannot be written, whatever their protection
+            SectionInner::Borrowed(_) => return Err(MemError::CannotWrite { addr }.into()),
+            SectionInner::BorrowedMut(slice) => slice,
+            SectionInner::Owned(vec) => vec,
+        };
+        let end = offset.checked_add(nbytes).ok_or(MemError::NoMemory { addr })?;
+        Ok(buf.get_mut(offset..end).ok_or(MemError::NoMemory { addr })?)
+    }
+}
+
+fn from_bytes(bytes: &[u8], endian: Endian) -> u64 {
+    let fold = |ans: u64, &byte: &u8| ans << 8 | byte as u64;
+    match endian {
+        Endian::Big => bytes.iter().fold(0, fold),
+        Endian::Little => bytes.iter().rev().fold(0, fold),
+    }
+}
+
+fn to_bytes(bytes: &mut [u8], n: u64, endian: Endian) {
+    let len = bytes.len();
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let shift = match endian {
+            Endian::Big => 8 * (len - 1 - i),
+            Endian::Little => 8 * i,
+        };
+        *byte = (n >> shift) as u8;
+    }
+}
+
+fn range_len(range: &Range<Usize>) -> usize {
+    (range.end.to_u64() - range.start.to_u64()) as usize
 }
 
 #[derive(Clone, Debug)]
@@ -196,11 +511,259 @@ bitflags::bitflags! {
     }
 }
 
+#[derive(Error, Debug)]
 #[non_exhaustive]
-pub enum Error {
-    NoMemory,
-    CannotWrite,
-    CannotExecute,
+pub enum MemError {
+    #[error("Memory address 0x{addr:016X} cannot be written")]
+    CannotWrite { addr: u64 },
+    #[error("Memory address 0x{addr:016X} cannot be executed")]
+    CannotExecute { addr: u64 },
+    #[error("No memory bound for address 0x{addr:016X}")]
+    NoMemory { addr: u64 },
 }
 
-type Result<T> = core::result::Result<T, Error>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::plugin::{Endian as ExtEndian, PluginError, MEMORY_EXT, MEMORY_EXT_API_VERSION};
+    use core::ptr::NonNull;
+    use std::cell::Cell;
+
+    thread_local! {
+        // calls of the range callback made by the current test
+        static GET_RANGE_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn config(range: Range<u64>, protect: Protect, endian: Endian) -> Config {
+        Config {
+            range: Usize::U64(range.start)..Usize::U64(range.end),
+            protect,
+            endian,
+        }
+    }
+
+    fn mem_error(ans: Result<impl core::fmt::Debug>) -> MemError {
+        match ans {
+            Err(Error::Memory(err)) => err,
+            other => panic!("expected a memory error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sections_keep_their_byte_order() {
+        let mut mem = MemorySet::new();
+        mem.push_owned(config(0x1000..0x1010, Protect::WRITE, Endian::Little), vec![1, 2]);
+        mem.push_zeroed(config(0x2000..0x2010, Protect::WRITE, Endian::Big));
+        // owned buffers are zero extended to their range
+        assert_eq!(mem.read_u32(0x1000).unwrap(), 0x0201);
+        assert_eq!(mem.read_u64(0x1008).unwrap(), 0);
+        mem.write_u32(0x2000, 0x0102_0304).unwrap();
+        assert_eq!(mem.read_u8(0x2000).unwrap(), 1);
+        assert_eq!(mem.read_u16(0x2002).unwrap(), 0x0304);
+        assert_eq!(mem.read_i8(0x2003).unwrap(), 4);
+    }
+
+    #[test]
+    fn protection_and_unmapped_addresses() {
+        let code = [0x13, 0x00, 0x00, 0x00];
+        let mut mem = MemorySet::new();
+        mem.push_slice(config(0x1000..0x1004, Protect::EXECUTE, Endian::Little), &code);
+        mem.push_zeroed(config(0x2000..0x2008, Protect::WRITE, Endian::Little));
+        assert_eq!(mem.fetch_ins_u16(0x1000).unwrap(), 0x13);
+        assert_eq!(mem.read_u32(0x1000).unwrap(), 0x13);
+        match mem_error(mem.write_u8(0x1000, 0)) {
+            MemError::CannotWrite { addr } => assert_eq!(addr, 0x1000),
+            other => panic!("{:?}", other),
+        }
+        match mem_error(mem.fetch_ins_u16(0x2000)) {
+            MemError::CannotExecute { addr } => assert_eq!(addr, 0x2000),
+            other => panic!("{:?}", other),
+        }
+        match mem_error(mem.read_u8(0x3000)) {
+            MemError::NoMemory { addr } => assert_eq!(addr, 0x3000),
+            other => panic!("{:?}", other),
+        }
+        // an access running past the end of its section
+        match mem_error(mem.read_u64(0x2004)) {
+            MemError::NoMemory { addr } => assert_eq!(addr, 0x2004),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn stores_clear_overlapping_reservations() {
+        let mut mem = MemorySet::new();
+        mem.push_zeroed(config(0x1000..0x1100, Protect::WRITE, Endian::Little));
+        mem.reserve(0, 0x1000, 8);
+        mem.reserve(1, 0x1010, 4);
+        mem.write_u32(0x1014, 1).unwrap();
+        mem.write_u8(0x1007, 1).unwrap();
+        assert!(!mem.take_reservation(0, 0x1000, 8));
+        assert!(mem.take_reservation(1, 0x1010, 4));
+        // a reservation is taken once
+        assert!(!mem.take_reservation(1, 0x1010, 4));
+        // and only covers the reserved bytes
+        mem.reserve(0, 0x1000, 4);
+        assert!(!mem.take_reservation(0, 0x1000, 8));
+    }
+
+    // a plugin device of 16 byte wide registers at 0x1000_0000
+    extern "C" fn test_new() -> *mut () {
+        Box::into_raw(Box::new([0u8; 16])) as *mut ()
+    }
+
+    extern "C" fn test_unref(this: *mut ()) {
+        drop(unsafe { Box::from_raw(this as *mut [u8; 16]) })
+    }
+
+    extern "C" fn test_get_range(
+        _this: *mut (), addr_len_bytes: *mut u32, addr_from: *mut u8, addr_to: *mut u8,
+    ) {
+        GET_RANGE_CALLS.with(|calls| calls.set(calls.get() + 1));
+        unsafe {
+            *addr_len_bytes = 8;
+            *(addr_from as *mut [u8; 8]) = 0x1000_0000u64.to_ne_bytes();
+            *(addr_to as *mut [u8; 8]) = 0x1000_0010u64.to_ne_bytes();
+        }
+    }
+
+    fn test_offset(addr_len_bytes: u32, offset: *const u8) -> usize {
+        assert_eq!(addr_len_bytes, 8);
+        u64::from_ne_bytes(unsafe { *(offset as *const [u8; 8]) }) as usize
+    }
+
+    extern "C" fn test_read_u8(
+        this: *mut (), addr_len_bytes: u32, offset: *const u8, val_out: *mut u8,
+    ) -> MemResult {
+        test_read_nbytes(this, addr_len_bytes, offset, val_out, 1, ExtEndian::Little)
+    }
+
+    extern "C" fn test_write_u8(
+        this: *mut (), addr_len_bytes: u32, offset: *const u8, val_in: *const u8,
+    ) -> MemResult {
+        test_write_nbytes(this, addr_len_bytes, offset, val_in, 1, ExtEndian::Little)
+    }
+
+    extern "C" fn test_exec_nbytes(
+        _this: *mut (), _addr_len_bytes: u32, _offset: *const u8,
+        _val_out: *mut u8, _nbytes: u32, _endian: ExtEndian,
+    ) -> MemResult {
+        MemResult::CannotExecute
+    }
+
+    extern "C" fn test_exec_u8(
+        this: *mut (), addr_len_bytes: u32, offset: *const u8, val_out: *mut u8,
+    ) -> MemResult {
+        test_exec_nbytes(this, addr_len_bytes, offset, val_out, 1, ExtEndian::Little)
+    }
+
+    extern "C" fn test_read_nbytes(
+        this: *mut (), addr_len_bytes: u32, offset: *const u8,
+        val_out: *mut u8, nbytes: u32, _endian: ExtEndian,
+    ) -> MemResult {
+        let regs = unsafe { &*(this as *const [u8; 16]) };
+        let offset = test_offset(addr_len_bytes, offset);
+        match regs.get(offset..offset + nbytes as usize) {
+            Some(src) => {
+                let dst = unsafe { core::slice::from_raw_parts_mut(val_out, nbytes as usize) };
+                dst.copy_from_slice(src);
+                MemResult::Ok
+            }
+            None => MemResult::NoMemory,
+        }
+    }
+
+    extern "C" fn test_write_nbytes(
+        this: *mut (), addr_len_bytes: u32, offset: *const u8,
+        val_in: *const u8, nbytes: u32, _endian: ExtEndian,
+    ) -> MemResult {
+        let regs = unsafe { &mut *(this as *mut [u8; 16]) };
+        let offset = test_offset(addr_len_bytes, offset);
+        match regs.get_mut(offset..offset + nbytes as usize) {
+            Some(dst) => {
+                let src = unsafe { core::slice::from_raw_parts(val_in, nbytes as usize) };
+                dst.copy_from_slice(src);
+                MemResult::Ok
+            }
+            None => MemResult::NoMemory,
+        }
+    }
+
+    fn test_vtable(api_version: u32) -> MemoryExtVTable {
+        MemoryExtVTable {
+            api_version,
+            memory_new: test_new,
+            memory_unref: test_unref,
+            get_range: test_get_range,
+            read_u8: test_read_u8,
+            exec_u8: test_exec_u8,
+            write_u8: test_write_u8,
+            read_nbytes: test_read_nbytes,
+            exec_nbytes: test_exec_nbytes,
+            write_nbytes: test_write_nbytes,
+        }
+    }
+
+    #[test]
+    fn extension_as_device() {
+        let mut extension = Extension::new(Box::new(test_vtable(MEMORY_EXT_API_VERSION)));
+        assert_eq!(extension.get_range(), Usize::U64(0x1000_0000)..Usize::U64(0x1000_0010));
+        extension.write(4, 4, 0x0403_0201).unwrap();
+        assert_eq!(extension.read(4, 2).unwrap(), 0x0201);
+        assert_eq!(extension.read(0, 8).unwrap(), 0x0403_0201_0000_0000);
+        // faults report the physical address the extension is mounted at
+        match extension.read(12, 8) {
+            Err(Error::Memory(MemError::NoMemory { addr })) => assert_eq!(addr, 0x1000_000C),
+            other => panic!("{:?}", other),
+        }
+        let mut extension = extension.with_base(0x2000_0000);
+        match extension.write(12, 8, 0) {
+            Err(Error::Memory(MemError::NoMemory { addr })) => assert_eq!(addr, 0x2000_000C),
+            other => panic!("{:?}", other),
+        }
+        // the range is asked for once
+        assert_eq!(GET_RANGE_CALLS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn extension_sections() {
+        let mut mem = MemorySet::new();
+        mem.push_extension(Box::new(test_vtable(MEMORY_EXT_API_VERSION)));
+        mem.write_u32(0x1000_0004, 0x0403_0201).unwrap();
+        assert_eq!(mem.read_u16(0x1000_0005).unwrap(), 0x0302);
+        match mem_error(mem.read_u64(0x1000_000C)) {
+            MemError::NoMemory { addr } => assert_eq!(addr, 0x1000_000C),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(GET_RANGE_CALLS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn plugin_memory_ext_checks_api_version() {
+        let plugin = crate::plugin::Builder::new().build();
+        assert!(plugin.memory_ext().unwrap().is_none());
+        let vtable = Box::leak(Box::new(test_vtable(MEMORY_EXT_API_VERSION + 1)));
+        let plugin = crate::plugin::Builder::new()
+            .insert(MEMORY_EXT, NonNull::from(vtable).cast())
+            .build();
+        match plugin.memory_ext() {
+            Err(Error::Plugin(PluginError::ApiVersion { found, .. })) => {
+                assert_eq!(found, MEMORY_EXT_API_VERSION + 1)
+            }
+            Err(other) => panic!("{:?}", other),
+            Ok(_) => panic!("plugin accepted"),
+        }
+    }
+
+    #[test]
+    fn missing_plugin_library() {
+        match crate::plugin::load("/nonexistent/libemu6-plugin.so") {
+            Err(Error::Plugin(PluginError::Open { path, .. })) => {
+                assert_eq!(path, "/nonexistent/libemu6-plugin.so")
+            }
+            Err(other) => panic!("{:?}", other),
+            Ok(_) => panic!("plugin loaded"),
+        }
+    }
+}
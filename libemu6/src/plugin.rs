@@ -1,12 +1,122 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use core::ptr::NonNull;
+use crate::error::Result;
+use thiserror::Error;
+
+// Plugins are shared libraries exporting this symbol as a `PluginEntry`
+pub const PLUGIN_ENTRY: &str = "emu6_plugin_entry";
+
+// Returns the features of the plugin. Only C types cross the library boundary,
+// so plugins may be built with any compiler
+pub type PluginEntry = unsafe extern "C" fn() -> *const PluginTable;
+
+// Vtables of the features a plugin provides, null for those it does not. The
+// table and vtables must stay valid while the library is loaded
+#[repr(C)]
+pub struct PluginTable {
+    pub memory_ext: *const MemoryExtVTable,
+    pub instruction_ext: *const InstructionExtVTable,
+}
 
 #[derive(Clone)]
 pub struct Plugin {
     features: Arc<HashMap<&'static str, NonNull<()>>>,
 }
 
+impl Plugin {
+    pub fn feature(&self, feature: &str) -> Option<NonNull<()>> {
+        self.features.get(feature).copied()
+    }
+
+    // the memory extension vtable of this plugin, if it provides one in a
+    // supported api version
+    pub fn memory_ext(&self) -> Result<Option<&MemoryExtVTable>> {
+        let vtable = match self.feature(MEMORY_EXT) {
+            Some(ptr) => unsafe { ptr.cast::<MemoryExtVTable>().as_ref() },
+            None => return Ok(None),
+        };
+        if vtable.api_version != MEMORY_EXT_API_VERSION {
+            return Err(PluginError::ApiVersion {
+                feature: MEMORY_EXT,
+                found: vtable.api_version,
+                expected: MEMORY_EXT_API_VERSION,
+            })?;
+        }
+        Ok(Some(vtable))
+    }
+
+    // the instruction extension vtable of this plugin, if it provides one in a
+    // supported api version
+    pub fn instruction_ext(&self) -> Result<Option<&InstructionExtVTable>> {
+        let vtable = match self.feature(INSTRUCTION_EXT) {
+            Some(ptr) => unsafe { ptr.cast::<InstructionExtVTable>().as_ref() },
+            None => return Ok(None),
+        };
+        if vtable.api_version != INSTRUCTION_EXT_API_VERSION {
+            return Err(PluginError::ApiVersion {
+                feature: INSTRUCTION_EXT,
+                found: vtable.api_version,
+                expected: INSTRUCTION_EXT_API_VERSION,
+            })?;
+        }
+        Ok(Some(vtable))
+    }
+}
+
+// Loads a plugin from a shared library. The library stays loaded until the
+// process exits, as the plugin may hand out pointers into it at any time
+#[cfg(unix)]
+pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Plugin> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    let path = path.as_ref();
+    let open_error = |reason: String| PluginError::Open { path: path.display().to_string(), reason };
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| open_error(e.to_string()))?;
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        let reason = unsafe { CStr::from_ptr(libc::dlerror()) };
+        return Err(open_error(reason.to_string_lossy().into_owned()).into());
+    }
+    let symbol = CString::new(PLUGIN_ENTRY).unwrap();
+    let entry = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+    if entry.is_null() {
+        return Err(PluginError::MissingEntry { path: path.display().to_string() }.into());
+    }
+    let entry: PluginEntry = unsafe { core::mem::transmute(entry) };
+    let table = match unsafe { entry().as_ref() } {
+        Some(table) => table,
+        None => return Err(open_error("entry returned no feature table".to_string()).into()),
+    };
+    let mut builder = Builder::new();
+    if let Some(vtable) = NonNull::new(table.memory_ext as *mut MemoryExtVTable) {
+        builder = builder.insert(MEMORY_EXT, vtable.cast());
+    }
+    if let Some(vtable) = NonNull::new(table.instruction_ext as *mut InstructionExtVTable) {
+        builder = builder.insert(INSTRUCTION_EXT, vtable.cast());
+    }
+    Ok(builder.build())
+}
+
+#[cfg(not(unix))]
+pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Plugin> {
+    Err(PluginError::Open {
+        path: path.as_ref().display().to_string(),
+        reason: "plugins are not supported on this platform".to_string(),
+    })?
+}
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("Cannot load plugin {path}: {reason}")]
+    Open { path: String, reason: String },
+    #[error("Plugin {path} does not export an entry")]
+    MissingEntry { path: String },
+    #[error("Plugin feature {feature} has api version {found}, expected {expected}")]
+    ApiVersion { feature: &'static str, found: u32, expected: u32 },
+}
+
 pub struct Builder {
     features: HashMap<&'static str, NonNull<()>>
 }
@@ -36,14 +146,17 @@ pub enum MemResult {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 pub enum Endian {
     Big = 0,
     Little = 1,
 }
 
 pub const MEMORY_EXT: &'static str = "memory-ext";
+pub const MEMORY_EXT_API_VERSION: u32 = 1;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct MemoryExtVTable {
     pub api_version: u32,
     pub memory_new: extern "C" fn() -> *mut (),
@@ -77,3 +190,77 @@ pub struct MemoryExtVTable {
         val_in: *const u8, nbytes: u32, endian: Endian, 
     ) -> MemResult,
 }
+
+pub const INSTRUCTION_EXT: &str = "instruction-ext";
+pub const INSTRUCTION_EXT_API_VERSION: u32 = 1;
+
+// Instructions the standard decoder rejects are offered to an instruction
+// extension when `ins & mask == value` for one of its patterns
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InsPattern {
+    pub mask: u32,
+    pub value: u32,
+}
+
+#[non_exhaustive]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsResult {
+    Ok = 0,
+    // the instruction is illegal, e.g. a reserved funct value or a privilege violation
+    IllegalInstruction = 1,
+    // a callback raised an exception; the extension should return this at once
+    Trap = 2,
+}
+
+impl InsResult {
+    // results come from plugins as plain bytes, which may be out of range
+    pub fn from_u8(n: u8) -> Option<InsResult> {
+        match n {
+            0 => Some(InsResult::Ok),
+            1 => Some(InsResult::IllegalInstruction),
+            2 => Some(InsResult::Trap),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct InstructionExtVTable {
+    pub api_version: u32,
+    pub patterns: *const InsPattern,
+    pub patterns_len: usize,
+    // decodes a 32-bit instruction into an opaque payload; returns false if the
+    // instruction is not one of the extension
+    pub decode: extern "C" fn(ins: u32, xlen_bytes: u32, payload_out: *mut u64) -> bool,
+    // returns an `InsResult` as a byte
+    pub execute: extern "C" fn(payload: u64, context: *mut InsContext) -> u8,
+}
+
+// the patterns are never written through, so plugins may keep the vtable in a static
+unsafe impl Sync for InstructionExtVTable {}
+
+// The hart state an instruction extension executes against. Register values
+// are zero extended to 64 bits and truncated to XLEN on writes; memory
+// addresses are virtual, and accesses are translated and checked like loads
+// and stores of the hart
+#[repr(C)]
+pub struct InsContext {
+    pub this: *mut (),
+    pub xlen_bytes: u32,
+    pub pc: u64,
+    // the address of the next instruction, pc + 4 unless the extension jumps
+    pub next_pc: u64,
+    pub read_x: extern "C" fn(this: *mut (), idx: u32) -> u64,
+    pub write_x: extern "C" fn(this: *mut (), idx: u32, val: u64),
+    pub read_csr: extern "C" fn(this: *mut (), csr: u32, val_out: *mut u64) -> InsResult,
+    pub write_csr: extern "C" fn(this: *mut (), csr: u32, val: u64) -> InsResult,
+    pub read_mem: extern "C" fn(
+        this: *mut (), addr: u64, nbytes: u32, val_out: *mut u64,
+    ) -> InsResult,
+    pub write_mem: extern "C" fn(
+        this: *mut (), addr: u64, nbytes: u32, val: u64,
+    ) -> InsResult,
+}
@@ -1,10 +1,23 @@
+mod custom;
+mod disasm;
 mod exec;
 mod fetch;
+mod float;
 mod imm;
+mod mmu;
+mod pmp;
 mod regfile;
+mod trap;
 
+pub use custom::{Custom, InsExtension};
+pub use disasm::{csr_from_name, csr_name, csr_names, disassemble, f_name, x_from_name, x_name};
 pub use exec::{ExecError, Execute};
-pub use fetch::{Fetch, FetchError, Instruction};
+pub use fetch::{Fetch, Instruction, RV32I, RV64I, RVZicsr};
+pub use mmu::{Access, Translate, Watch, Watchpoints};
+pub use regfile::{
+    CSR_MCOUNTEREN, CSR_MEDELEG, CSR_MIDELEG, CSR_MSTATUS, CSR_SCOUNTEREN, MSTATUS_FS_INITIAL,
+};
+pub use trap::{Exception, Interrupt, Trap};
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum Xlen {
@@ -13,6 +26,14 @@ pub enum Xlen {
     X128,
 }
 
+// privilege levels, encoded as in mstatus.MPP
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
 use crate::size::Usize;
 use core::result::Result;
 
@@ -0,0 +1,191 @@
+use super::mmu::Mmu;
+use super::regfile::{Csr, XReg};
+use super::{Privilege, Xlen};
+use crate::error::{Error, Result};
+use crate::plugin::{InsContext, InsPattern, InsResult, InstructionExtVTable};
+use crate::size::Usize;
+
+// A vendor instruction extension provided by a plugin
+#[derive(Clone, Copy)]
+pub struct InsExtension {
+    vtable: InstructionExtVTable,
+}
+
+impl InsExtension {
+    pub fn new(vtable: &InstructionExtVTable) -> InsExtension {
+        InsExtension { vtable: *vtable }
+    }
+
+    fn patterns(&self) -> &[InsPattern] {
+        if self.vtable.patterns.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.vtable.patterns, self.vtable.patterns_len) }
+    }
+
+    pub(crate) fn decode(&self, ins: u32, xlen: Xlen) -> Option<Custom> {
+        if !self.patterns().iter().any(|p| ins & p.mask == p.value) {
+            return None;
+        }
+        let mut payload = 0;
+        if !(self.vtable.decode)(ins, xlen_bytes(xlen), &mut payload) {
+            return None;
+        }
+        Some(Custom {
+            extension: *self,
+            ins,
+            payload,
+        })
+    }
+}
+
+impl core::fmt::Debug for InsExtension {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("InsExtension")
+            .field("patterns", &self.patterns())
+            .finish()
+    }
+}
+
+// A decoded vendor instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Custom {
+    extension: InsExtension,
+    pub ins: u32,
+    pub payload: u64,
+}
+
+struct Context<'c, 'm, 'a> {
+    x: &'c mut XReg,
+    csr: &'c mut Csr,
+    mem: &'c mut Mmu<'m, 'a>,
+    privilege: Privilege,
+    xlen: Xlen,
+    // the exception raised by a callback, returned once the extension is done
+    error: Option<Error>,
+}
+
+impl<'c, 'm, 'a> Context<'c, 'm, 'a> {
+    fn usize(&self, val: u64) -> Usize {
+        match self.xlen {
+            Xlen::X32 => Usize::U32(val as u32),
+            _ => Usize::U64(val),
+        }
+    }
+
+    fn result<T>(&mut self, ans: Result<T>, out: impl FnOnce(T)) -> InsResult {
+        match ans {
+            Ok(val) => {
+                out(val);
+                InsResult::Ok
+            }
+            Err(e) => {
+                self.error = Some(e);
+                InsResult::Trap
+            }
+        }
+    }
+}
+
+pub(crate) fn exec_custom(
+    ins: Custom,
+    x: &mut XReg,
+    csr: &mut Csr,
+    mem: &mut Mmu<'_, '_>,
+    privilege: Privilege,
+    xlen: Xlen,
+    pc: Usize,
+) -> Result<Usize> {
+    let mut context = Context {
+        x,
+        csr,
+        mem,
+        privilege,
+        xlen,
+        error: None,
+    };
+    let mut ins_context = InsContext {
+        this: &mut context as *mut Context as *mut (),
+        xlen_bytes: xlen_bytes(xlen),
+        pc: pc.to_u64(),
+        next_pc: (pc + 4).to_u64(),
+        read_x,
+        write_x,
+        read_csr,
+        write_csr,
+        read_mem,
+        write_mem,
+    };
+    let ans = (ins.extension.vtable.execute)(ins.payload, &mut ins_context);
+    if let Some(e) = context.error.take() {
+        return Err(e);
+    }
+    match InsResult::from_u8(ans) {
+        Some(InsResult::Ok) => Ok(context.usize(ins_context.next_pc)),
+        _ => Err(context.csr.illegal().into()),
+    }
+}
+
+// `this` points to the context of the instruction being executed, which
+// outlives each callback
+extern "C" fn read_x(this: *mut (), idx: u32) -> u64 {
+    let context = unsafe { &*(this as *const Context) };
+    context.x.r_usize(idx as u8 & 0x1F).to_u64()
+}
+
+extern "C" fn write_x(this: *mut (), idx: u32, val: u64) {
+    let context = unsafe { &mut *(this as *mut Context) };
+    let val = context.usize(val);
+    context.x.w_usize(idx as u8 & 0x1F, val)
+}
+
+extern "C" fn read_csr(this: *mut (), csr: u32, val_out: *mut u64) -> InsResult {
+    let context = unsafe { &mut *(this as *mut Context) };
+    let csr = csr as u16;
+    let privilege = context.privilege;
+    let ans = context
+        .csr
+        .check_privilege(csr, privilege)
+        .and_then(|_| context.csr.r_usize(csr));
+    context.result(ans, |val| unsafe { *val_out = val.to_u64() })
+}
+
+extern "C" fn write_csr(this: *mut (), csr: u32, val: u64) -> InsResult {
+    let context = unsafe { &mut *(this as *mut Context) };
+    let csr = csr as u16;
+    let privilege = context.privilege;
+    let val = context.usize(val);
+    let ans = context
+        .csr
+        .check_privilege(csr, privilege)
+        .and_then(|_| context.csr.w_usize(csr, val));
+    context.result(ans, |_| {})
+}
+
+extern "C" fn read_mem(this: *mut (), addr: u64, nbytes: u32, val_out: *mut u64) -> InsResult {
+    let context = unsafe { &mut *(this as *mut Context) };
+    if !matches!(nbytes, 1 | 2 | 4 | 8) {
+        return InsResult::IllegalInstruction;
+    }
+    let addr = context.usize(addr);
+    let ans = context.mem.read_uint(addr, nbytes as u64);
+    context.result(ans, |val| unsafe { *val_out = val })
+}
+
+extern "C" fn write_mem(this: *mut (), addr: u64, nbytes: u32, val: u64) -> InsResult {
+    let context = unsafe { &mut *(this as *mut Context) };
+    if !matches!(nbytes, 1 | 2 | 4 | 8) {
+        return InsResult::IllegalInstruction;
+    }
+    let addr = context.usize(addr);
+    let ans = context.mem.write_uint(addr, val, nbytes as u64);
+    context.result(ans, |_| {})
+}
+
+fn xlen_bytes(xlen: Xlen) -> u32 {
+    match xlen {
+        Xlen::X32 => 4,
+        Xlen::X64 => 8,
+        Xlen::X128 => 16,
+    }
+}
@@ -0,0 +1,550 @@
+use super::fetch::*;
+use super::imm::Imm;
+use super::regfile::*;
+use super::Xlen;
+use crate::size::Isize;
+
+const X_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const F_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+// CSRs implemented by the hart, by their assembler names
+const CSR_NAMES: &[(&str, u16)] = &[
+    ("fflags", CSR_FFLAGS),
+    ("frm", CSR_FRM),
+    ("fcsr", CSR_FCSR),
+    ("sstatus", CSR_SSTATUS),
+    ("sie", CSR_SIE),
+    ("stvec", CSR_STVEC),
+    ("scounteren", CSR_SCOUNTEREN),
+    ("sscratch", CSR_SSCRATCH),
+    ("sepc", CSR_SEPC),
+    ("scause", CSR_SCAUSE),
+    ("stval", CSR_STVAL),
+    ("sip", CSR_SIP),
+    ("satp", CSR_SATP),
+    ("mvendorid", CSR_MVENDORID),
+    ("marchid", CSR_MARCHID),
+    ("mimpid", CSR_MIMPID),
+    ("mhartid", CSR_MHARTID),
+    ("mstatus", CSR_MSTATUS),
+    ("misa", CSR_MISA),
+    ("medeleg", CSR_MEDELEG),
+    ("mideleg", CSR_MIDELEG),
+    ("mie", CSR_MIE),
+    ("mtvec", CSR_MTVEC),
+    ("mcounteren", CSR_MCOUNTEREN),
+    ("mstatush", CSR_MSTATUSH),
+    ("mscratch", CSR_MSCRATCH),
+    ("mepc", CSR_MEPC),
+    ("mcause", CSR_MCAUSE),
+    ("mtval", CSR_MTVAL),
+    ("mip", CSR_MIP),
+];
+
+pub fn x_name(idx: u8) -> &'static str {
+    X_NAMES[idx as usize & 0x1F]
+}
+
+pub fn f_name(idx: u8) -> &'static str {
+    F_NAMES[idx as usize & 0x1F]
+}
+
+// Looks up an integer register by ABI name, "fp", or "xN"
+pub fn x_from_name(name: &str) -> Option<u8> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(idx) = name.strip_prefix('x').and_then(|n| n.parse::<u8>().ok()) {
+        return if idx < 32 { Some(idx) } else { None };
+    }
+    X_NAMES.iter().position(|&n| n == name).map(|idx| idx as u8)
+}
+
+// Assembler names of the CSRs, including pmpcfgN and pmpaddrN
+pub fn csr_names() -> impl Iterator<Item = (String, u16)> {
+    let named = CSR_NAMES.iter().map(|&(name, csr)| (name.to_string(), csr));
+    let pmpcfg = (0..16).map(|i| (format!("pmpcfg{}", i), CSR_PMPCFG0 + i));
+    let pmpaddr = (0..64).map(|i| (format!("pmpaddr{}", i), CSR_PMPADDR0 + i));
+    named.chain(pmpcfg).chain(pmpaddr)
+}
+
+pub fn csr_name(csr: u16) -> String {
+    csr_names()
+        .find(|&(_, addr)| addr == csr)
+        .map(|(name, _)| name)
+        .unwrap_or_else(|| format!("{:#x}", csr))
+}
+
+// Looks up a CSR by assembler name or number
+pub fn csr_from_name(name: &str) -> Option<u16> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(csr) = name.parse::<u16>() {
+        return Some(csr);
+    }
+    csr_names().find(|(n, _)| n == name).map(|(_, csr)| csr)
+}
+
+// Formats an instruction in assembler syntax; `pc` is the address of the
+// instruction, used to print the targets of jumps and branches
+pub fn disassemble(ins: &Instruction, pc: u64) -> String {
+    match ins {
+        Instruction::RV32I(ins) => rv32i(ins, pc),
+        Instruction::RV64I(ins) => rv64i(ins),
+        Instruction::RVC(ins) => rvc(ins, pc),
+        Instruction::RVZicsr(ins) => rvzicsr(ins),
+        Instruction::RVPriv(ins) => rvpriv(ins),
+        Instruction::RVM(ins) => rvm(ins),
+        Instruction::RVA(ins) => rva(ins),
+        Instruction::RVF(ins) => rvf(ins),
+        Instruction::RVD(ins) => rvd(ins),
+        Instruction::Custom(ins) => format!(".insn 0x{:08x}", ins.ins),
+    }
+}
+
+fn simm(imm: Imm) -> i64 {
+    match imm.sext(Xlen::X64) {
+        Isize::I32(a) => a as i64,
+        Isize::I64(a) => a,
+    }
+}
+
+fn target(pc: u64, imm: Imm) -> String {
+    format!("{:#x}", pc.wrapping_add(simm(imm) as u64))
+}
+
+fn x(idx: u8) -> &'static str {
+    x_name(idx)
+}
+
+fn f(idx: u8) -> &'static str {
+    f_name(idx)
+}
+
+fn i_alu(op: &str, i: &IType) -> String {
+    format!("{} {}, {}, {}", op, x(i.rd), x(i.rs1), simm(i.imm))
+}
+
+fn i_shift(op: &str, i: &IType) -> String {
+    format!("{} {}, {}, {}", op, x(i.rd), x(i.rs1), i.imm.low_u32() & 0x3F)
+}
+
+fn load(op: &str, rd: &str, i: &IType) -> String {
+    format!("{} {}, {}({})", op, rd, simm(i.imm), x(i.rs1))
+}
+
+fn store(op: &str, rs2: &str, s: &SType) -> String {
+    format!("{} {}, {}({})", op, rs2, simm(s.imm), x(s.rs1))
+}
+
+fn branch(op: &str, b: &BType, pc: u64) -> String {
+    format!("{} {}, {}, {}", op, x(b.rs1), x(b.rs2), target(pc, b.imm))
+}
+
+fn r_alu(op: &str, r: &RType) -> String {
+    format!("{} {}, {}, {}", op, x(r.rd), x(r.rs1), x(r.rs2))
+}
+
+fn rv32i(ins: &RV32I, pc: u64) -> String {
+    use RV32I::*;
+    match ins {
+        Lui(u) => format!("lui {}, {:#x}", x(u.rd), u.imm.low_u32() >> 12),
+        Auipc(u) => format!("auipc {}, {:#x}", x(u.rd), u.imm.low_u32() >> 12),
+        Jal(j) => format!("jal {}, {}", x(j.rd), target(pc, j.imm)),
+        Jalr(i) => format!("jalr {}, {}({})", x(i.rd), simm(i.imm), x(i.rs1)),
+        Beq(b) => branch("beq", b, pc),
+        Bne(b) => branch("bne", b, pc),
+        Blt(b) => branch("blt", b, pc),
+        Bge(b) => branch("bge", b, pc),
+        Bltu(b) => branch("bltu", b, pc),
+        Bgeu(b) => branch("bgeu", b, pc),
+        Lb(i) => load("lb", x(i.rd), i),
+        Lh(i) => load("lh", x(i.rd), i),
+        Lw(i) => load("lw", x(i.rd), i),
+        Lbu(i) => load("lbu", x(i.rd), i),
+        Lhu(i) => load("lhu", x(i.rd), i),
+        Sb(s) => store("sb", x(s.rs2), s),
+        Sh(s) => store("sh", x(s.rs2), s),
+        Sw(s) => store("sw", x(s.rs2), s),
+        Addi(i) => i_alu("addi", i),
+        Slti(i) => i_alu("slti", i),
+        Sltiu(i) => i_alu("sltiu", i),
+        Xori(i) => i_alu("xori", i),
+        Ori(i) => i_alu("ori", i),
+        Andi(i) => i_alu("andi", i),
+        Slli(i) => i_shift("slli", i),
+        Srli(i) => i_shift("srli", i),
+        Srai(i) => i_shift("srai", i),
+        Add(r) => r_alu("add", r),
+        Sub(r) => r_alu("sub", r),
+        Sll(r) => r_alu("sll", r),
+        Slt(r) => r_alu("slt", r),
+        Sltu(r) => r_alu("sltu", r),
+        Xor(r) => r_alu("xor", r),
+        Srl(r) => r_alu("srl", r),
+        Sra(r) => r_alu("sra", r),
+        Or(r) => r_alu("or", r),
+        And(r) => r_alu("and", r),
+        Fence(i) => {
+            let bits = i.imm.low_u32();
+            format!("fence {}, {}", fence_set(bits >> 4), fence_set(bits))
+        }
+        Ecall(_) => "ecall".to_string(),
+        Ebreak(_) => "ebreak".to_string(),
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let ans: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| bits & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if ans.is_empty() {
+        "0".to_string()
+    } else {
+        ans
+    }
+}
+
+fn rv64i(ins: &RV64I) -> String {
+    use RV64I::*;
+    match ins {
+        Lwu(i) => load("lwu", x(i.rd), i),
+        Ld(i) => load("ld", x(i.rd), i),
+        Sd(s) => store("sd", x(s.rs2), s),
+        Sll(r) => r_alu("sll", r),
+        Srl(r) => r_alu("srl", r),
+        Sra(r) => r_alu("sra", r),
+        Slli(i) => i_shift("slli", i),
+        Srli(i) => i_shift("srli", i),
+        Srai(i) => i_shift("srai", i),
+        Addiw(i) => i_alu("addiw", i),
+        Slliw(i) => i_shift("slliw", i),
+        Srliw(i) => i_shift("srliw", i),
+        Sraiw(i) => i_shift("sraiw", i),
+        Addw(r) => r_alu("addw", r),
+        Subw(r) => r_alu("subw", r),
+        Sllw(r) => r_alu("sllw", r),
+        Srlw(r) => r_alu("srlw", r),
+        Sraw(r) => r_alu("sraw", r),
+    }
+}
+
+fn rvc(ins: &RVC, pc: u64) -> String {
+    use RVC::*;
+    let cl = |op: &str, rd: &str, c: &CLType| format!("{} {}, {}({})", op, rd, simm(c.imm), x(c.rs1));
+    let cs = |op: &str, rs2: &str, c: &CSType| format!("{} {}, {}({})", op, rs2, simm(c.imm), x(c.rs1));
+    let ci = |op: &str, c: &CIType| format!("{} {}, {}", op, x(c.rdrs1), simm(c.imm));
+    let ci_shift = |op: &str, c: &CIType| format!("{} {}, {}", op, x(c.rdrs1), c.imm.low_u32() & 0x3F);
+    let ci_sp = |op: &str, rd: &str, c: &CIType| format!("{} {}, {}(sp)", op, rd, simm(c.imm));
+    let css = |op: &str, rs2: &str, c: &CSSType| format!("{} {}, {}(sp)", op, rs2, simm(c.imm));
+    let ca = |op: &str, c: &CAType| format!("{} {}, {}", op, x(c.rdrs1), x(c.rs2));
+    match ins {
+        Caddi4spn(c) => format!("c.addi4spn {}, sp, {}", x(c.rd), c.uimm.low32()),
+        Cfld(c) => cl("c.fld", f(c.rd), c),
+        Clq(c) => cl("c.lq", x(c.rd), c),
+        Clw(c) => cl("c.lw", x(c.rd), c),
+        Cflw(c) => cl("c.flw", f(c.rd), c),
+        Cld(c) => cl("c.ld", x(c.rd), c),
+        Cfsd(c) => cs("c.fsd", f(c.rs2), c),
+        Csq(c) => cs("c.sq", x(c.rs2), c),
+        Csw(c) => cs("c.sw", x(c.rs2), c),
+        Cfsw(c) => cs("c.fsw", f(c.rs2), c),
+        Csd(c) => cs("c.sd", x(c.rs2), c),
+        Cnop(_) => "c.nop".to_string(),
+        Caddi(c) => ci("c.addi", c),
+        Cjal(c) => format!("c.jal {}", target(pc, c.target)),
+        Caddiw(c) => ci("c.addiw", c),
+        Cli(c) => ci("c.li", c),
+        Caddi16sp(c) => format!("c.addi16sp sp, {}", simm(c.imm)),
+        Clui(c) => format!("c.lui {}, {:#x}", x(c.rdrs1), (c.imm.low_u32() >> 12) & 0xFFFFF),
+        Csrli(c) | Csrli64(c) => ci_shift("c.srli", c),
+        Csrai(c) | Csrai64(c) => ci_shift("c.srai", c),
+        Candi(c) => ci("c.andi", c),
+        Csub(c) => ca("c.sub", c),
+        Cxor(c) => ca("c.xor", c),
+        Cor(c) => ca("c.or", c),
+        Cand(c) => ca("c.and", c),
+        Csubw(c) => ca("c.subw", c),
+        Caddw(c) => ca("c.addw", c),
+        Cj(c) => format!("c.j {}", target(pc, c.target)),
+        Cbeqz(c) => format!("c.beqz {}, {}", x(c.rs1), target(pc, c.off)),
+        Cbnez(c) => format!("c.bnez {}, {}", x(c.rs1), target(pc, c.off)),
+        Cslli(c) | Cslli64(c) => ci_shift("c.slli", c),
+        Cfldsp(c) => ci_sp("c.fldsp", f(c.rdrs1), c),
+        Clqsp(c) => ci_sp("c.lqsp", x(c.rdrs1), c),
+        Clwsp(c) => ci_sp("c.lwsp", x(c.rdrs1), c),
+        Cflwsp(c) => ci_sp("c.flwsp", f(c.rdrs1), c),
+        Cldsp(c) => ci_sp("c.ldsp", x(c.rdrs1), c),
+        Cjr(c) => format!("c.jr {}", x(c.rdrs1)),
+        Cmv(c) => format!("c.mv {}, {}", x(c.rdrs1), x(c.rs2)),
+        Cebreak(_) => "c.ebreak".to_string(),
+        Cjalr(c) => format!("c.jalr {}", x(c.rdrs1)),
+        Cadd(c) => format!("c.add {}, {}", x(c.rdrs1), x(c.rs2)),
+        Cfsdsp(c) => css("c.fsdsp", f(c.rs2), c),
+        Csqsp(c) => css("c.sqsp", x(c.rs2), c),
+        Cswsp(c) => css("c.swsp", x(c.rs2), c),
+        Cfswsp(c) => css("c.fswsp", f(c.rs2), c),
+        Csdsp(c) => css("c.sdsp", x(c.rs2), c),
+    }
+}
+
+fn rvzicsr(ins: &RVZicsr) -> String {
+    use RVZicsr::*;
+    let r = |op: &str, c: &CsrRType| format!("{} {}, {}, {}", op, x(c.rd), csr_name(c.csr), x(c.rs1));
+    let i = |op: &str, c: &CsrIType| format!("{} {}, {}, {}", op, x(c.rd), csr_name(c.csr), c.uimm.low32());
+    match ins {
+        Csrrw(c) => r("csrrw", c),
+        Csrrs(c) => r("csrrs", c),
+        Csrrc(c) => r("csrrc", c),
+        Csrrwi(c) => i("csrrwi", c),
+        Csrrsi(c) => i("csrrsi", c),
+        Csrrci(c) => i("csrrci", c),
+    }
+}
+
+fn rvpriv(ins: &RVPriv) -> String {
+    match ins {
+        RVPriv::Sret(_) => "sret".to_string(),
+        RVPriv::Mret(_) => "mret".to_string(),
+        RVPriv::Wfi(_) => "wfi".to_string(),
+        RVPriv::SfenceVma(r) => format!("sfence.vma {}, {}", x(r.rs1), x(r.rs2)),
+    }
+}
+
+fn rvm(ins: &RVM) -> String {
+    use RVM::*;
+    let (op, r) = match ins {
+        Mul(r) => ("mul", r),
+        Mulh(r) => ("mulh", r),
+        Mulhsu(r) => ("mulhsu", r),
+        Mulhu(r) => ("mulhu", r),
+        Div(r) => ("div", r),
+        Divu(r) => ("divu", r),
+        Rem(r) => ("rem", r),
+        Remu(r) => ("remu", r),
+        Mulw(r) => ("mulw", r),
+        Divw(r) => ("divw", r),
+        Divuw(r) => ("divuw", r),
+        Remw(r) => ("remw", r),
+        Remuw(r) => ("remuw", r),
+    };
+    r_alu(op, r)
+}
+
+fn rva(ins: &RVA) -> String {
+    use RVA::*;
+    let (op, a) = match ins {
+        LrW(a) => ("lr.w", a),
+        ScW(a) => ("sc.w", a),
+        AmoswapW(a) => ("amoswap.w", a),
+        AmoaddW(a) => ("amoadd.w", a),
+        AmoxorW(a) => ("amoxor.w", a),
+        AmoandW(a) => ("amoand.w", a),
+        AmoorW(a) => ("amoor.w", a),
+        AmominW(a) => ("amomin.w", a),
+        AmomaxW(a) => ("amomax.w", a),
+        AmominuW(a) => ("amominu.w", a),
+        AmomaxuW(a) => ("amomaxu.w", a),
+        LrD(a) => ("lr.d", a),
+        ScD(a) => ("sc.d", a),
+        AmoswapD(a) => ("amoswap.d", a),
+        AmoaddD(a) => ("amoadd.d", a),
+        AmoxorD(a) => ("amoxor.d", a),
+        AmoandD(a) => ("amoand.d", a),
+        AmoorD(a) => ("amoor.d", a),
+        AmominD(a) => ("amomin.d", a),
+        AmomaxD(a) => ("amomax.d", a),
+        AmominuD(a) => ("amominu.d", a),
+        AmomaxuD(a) => ("amomaxu.d", a),
+    };
+    let order = match (a.aq, a.rl) {
+        (true, true) => ".aqrl",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (false, false) => "",
+    };
+    if let LrW(_) | LrD(_) = ins {
+        format!("{}{} {}, ({})", op, order, x(a.rd), x(a.rs1))
+    } else {
+        format!("{}{} {}, {}, ({})", op, order, x(a.rd), x(a.rs2), x(a.rs1))
+    }
+}
+
+// operand kinds of floating point instructions in RType encoding
+enum FpOps {
+    Fff,
+    Ff,
+    Xff,
+    Xf,
+    Fx,
+}
+
+fn fp_r(op: &str, kind: FpOps, r: &RType) -> String {
+    match kind {
+        FpOps::Fff => format!("{} {}, {}, {}", op, f(r.rd), f(r.rs1), f(r.rs2)),
+        FpOps::Ff => format!("{} {}, {}", op, f(r.rd), f(r.rs1)),
+        FpOps::Xff => format!("{} {}, {}, {}", op, x(r.rd), f(r.rs1), f(r.rs2)),
+        FpOps::Xf => format!("{} {}, {}", op, x(r.rd), f(r.rs1)),
+        FpOps::Fx => format!("{} {}, {}", op, f(r.rd), x(r.rs1)),
+    }
+}
+
+fn fp_r4(op: &str, r: &R4Type) -> String {
+    format!("{} {}, {}, {}, {}", op, f(r.rd), f(r.rs1), f(r.rs2), f(r.rs3))
+}
+
+fn rvf(ins: &RVF) -> String {
+    use FpOps::*;
+    use RVF::*;
+    match ins {
+        Flw(i) => load("flw", f(i.rd), i),
+        Fsw(s) => store("fsw", f(s.rs2), s),
+        Fmadds(r) => fp_r4("fmadd.s", r),
+        Fmsubs(r) => fp_r4("fmsub.s", r),
+        Fnmadds(r) => fp_r4("fnmadd.s", r),
+        Fnmsubs(r) => fp_r4("fnmsub.s", r),
+        Fadds(r) => fp_r("fadd.s", Fff, r),
+        Fsubs(r) => fp_r("fsub.s", Fff, r),
+        Fmuls(r) => fp_r("fmul.s", Fff, r),
+        Fdivs(r) => fp_r("fdiv.s", Fff, r),
+        Fsqrts(r) => fp_r("fsqrt.s", Ff, r),
+        Fsgnjs(r) => fp_r("fsgnj.s", Fff, r),
+        Fsgnjns(r) => fp_r("fsgnjn.s", Fff, r),
+        Fsgnjxs(r) => fp_r("fsgnjx.s", Fff, r),
+        Fmins(r) => fp_r("fmin.s", Fff, r),
+        Fmaxs(r) => fp_r("fmax.s", Fff, r),
+        Fcvtws(r) => fp_r("fcvt.w.s", Xf, r),
+        Fcvtwus(r) => fp_r("fcvt.wu.s", Xf, r),
+        Fmvxw(r) => fp_r("fmv.x.w", Xf, r),
+        Feqs(r) => fp_r("feq.s", Xff, r),
+        Flts(r) => fp_r("flt.s", Xff, r),
+        Fles(r) => fp_r("fle.s", Xff, r),
+        Fclasss(r) => fp_r("fclass.s", Xf, r),
+        Fcvtsw(r) => fp_r("fcvt.s.w", Fx, r),
+        Fcvtswu(r) => fp_r("fcvt.s.wu", Fx, r),
+        Fmvwx(r) => fp_r("fmv.w.x", Fx, r),
+        Fcvtls(r) => fp_r("fcvt.l.s", Xf, r),
+        Fcvtlus(r) => fp_r("fcvt.lu.s", Xf, r),
+        Fcvtsl(r) => fp_r("fcvt.s.l", Fx, r),
+        Fcvtslu(r) => fp_r("fcvt.s.lu", Fx, r),
+    }
+}
+
+fn rvd(ins: &RVD) -> String {
+    use FpOps::*;
+    use RVD::*;
+    match ins {
+        Fld(i) => load("fld", f(i.rd), i),
+        Fsd(s) => store("fsd", f(s.rs2), s),
+        Fmaddd(r) => fp_r4("fmadd.d", r),
+        Fmsubd(r) => fp_r4("fmsub.d", r),
+        Fnmsubd(r) => fp_r4("fnmsub.d", r),
+        Fnmaddd(r) => fp_r4("fnmadd.d", r),
+        Faddd(r) => fp_r("fadd.d", Fff, r),
+        Fsubd(r) => fp_r("fsub.d", Fff, r),
+        Fmuld(r) => fp_r("fmul.d", Fff, r),
+        Fdivd(r) => fp_r("fdiv.d", Fff, r),
+        Fsqrtd(r) => fp_r("fsqrt.d", Ff, r),
+        Fsgnjd(r) => fp_r("fsgnj.d", Fff, r),
+        Fsgnjnd(r) => fp_r("fsgnjn.d", Fff, r),
+        Fsgnjxd(r) => fp_r("fsgnjx.d", Fff, r),
+        Fmind(r) => fp_r("fmin.d", Fff, r),
+        Fmaxd(r) => fp_r("fmax.d", Fff, r),
+        Fcvtsd(r) => fp_r("fcvt.s.d", Ff, r),
+        Fcvtds(r) => fp_r("fcvt.d.s", Ff, r),
+        Feqd(r) => fp_r("feq.d", Xff, r),
+        Fltd(r) => fp_r("flt.d", Xff, r),
+        Fled(r) => fp_r("fle.d", Xff, r),
+        Fclassd(r) => fp_r("fclass.d", Xf, r),
+        Fcvtwd(r) => fp_r("fcvt.w.d", Xf, r),
+        Fcvtwud(r) => fp_r("fcvt.wu.d", Xf, r),
+        Fcvtdw(r) => fp_r("fcvt.d.w", Fx, r),
+        Fcvtdwu(r) => fp_r("fcvt.d.wu", Fx, r),
+        Fcvtld(r) => fp_r("fcvt.l.d", Xf, r),
+        Fcvtlud(r) => fp_r("fcvt.lu.d", Xf, r),
+        Fmvxd(r) => fp_r("fmv.x.d", Xf, r),
+        Fcvtdl(r) => fp_r("fcvt.d.l", Fx, r),
+        Fcvtdlu(r) => fp_r("fcvt.d.lu", Fx, r),
+        Fmvdx(r) => fp_r("fmv.d.x", Fx, r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem64::{Config, Endian, Physical, Protect};
+    use crate::riscv::{Privilege, Translate};
+    use crate::size::Usize;
+
+    const BASE: u64 = 0x8000_0000;
+
+    // Disassembles the instructions in `code`, placed at BASE
+    fn disassemble_all(code: &[u8]) -> Vec<String> {
+        let mut mem = Physical::new();
+        let config = Config {
+            range: BASE..(BASE + code.len() as u64),
+            protect: Protect::READ | Protect::EXECUTE,
+            endian: Endian::Little,
+        };
+        mem.push_owned(config, code.to_vec());
+        let translate = Translate::new(&Csr::new(Xlen::X64, 0), Privilege::Machine, Xlen::X64);
+        let mut fetch = Fetch::new(Xlen::X64);
+        let mut pc = BASE;
+        let mut ans = Vec::new();
+        while pc < BASE + code.len() as u64 {
+            let ins = fetch.fetch(&mem, Usize::U64(pc), &translate).unwrap();
+            ans.push(disassemble(&ins, pc));
+            pc += if code[(pc - BASE) as usize] & 0b11 == 0b11 { 4 } else { 2 };
+        }
+        ans
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        let words: [u32; 7] = [
+            0xFFF5_8513, 0xFE05_1CE3, 0x0101_3283, 0x3005_1073, 0x02C5_8553, 0x0CB6_252F,
+            0x0035_951B,
+        ];
+        let mut code: Vec<u8> = words.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+        code.extend_from_slice(&[0x05, 0x05]);
+        let expected = [
+            "addi a0, a1, -1",
+            "bne a0, zero, 0x7ffffffc",
+            "ld t0, 16(sp)",
+            "csrrw zero, mstatus, a0",
+            "fadd.d fa0, fa1, fa2",
+            "amoswap.w.aq a0, a1, (a2)",
+            "slliw a0, a1, 3",
+            "c.addi a0, 1",
+        ];
+        assert_eq!(disassemble_all(&code), expected);
+    }
+
+    #[test]
+    fn register_and_csr_names() {
+        assert_eq!(x_from_name("a0"), Some(10));
+        assert_eq!(x_from_name("fp"), Some(8));
+        assert_eq!(x_from_name("x31"), Some(31));
+        assert_eq!(x_from_name("x32"), None);
+        assert_eq!(f_name(10), "fa0");
+        assert_eq!(csr_from_name("mstatus"), Some(CSR_MSTATUS));
+        assert_eq!(csr_from_name("pmpaddr3"), Some(CSR_PMPADDR0 + 3));
+        assert_eq!(csr_from_name("0x340"), Some(CSR_MSCRATCH));
+        assert_eq!(csr_from_name("nosuchcsr"), None);
+        assert_eq!(csr_name(CSR_MEPC), "mepc");
+        assert_eq!(csr_name(0x7C0), "0x7c0");
+    }
+}
@@ -1,70 +1,212 @@
 use super::fetch::*;
+use super::float;
+use super::custom::exec_custom;
+use super::mmu::{Access, Mmu, Translate, Watchpoints};
 use super::imm::{Imm, Uimm};
 use super::regfile::{Csr, XReg, FReg};
 use super::*;
-use crate::error::Result;
-use crate::mem64::Physical;
+use crate::error::{Error, Result};
+use crate::memory::Bus;
 use crate::size::{Isize, Usize};
+use rustc_apfloat::ieee::{Double, Single};
+use rustc_apfloat::{Float, FloatConvert, StatusAnd};
 use thiserror::Error;
 
 #[derive(Error, Clone, Debug)]
 pub enum ExecError {
     #[error("extension is not supported")]
     ExtensionNotSupported,
-}
-
-fn pc_to_mem_addr(pc: Usize) -> u64 {
-    match pc {
-        Usize::U32(a) => a as u64,
-        Usize::U64(a) => a,
-    }
+    #[error("invalid floating point rounding mode 0b{rm:03b}")]
+    InvalidRoundingMode { rm: u8 },
 }
 
 pub struct Execute<'a> {
-    data_mem: &'a mut Physical<'a>,
+    data_mem: &'a mut (dyn Bus + 'a),
     x: Box<XReg>,
     f: Box<FReg>,
     csr: Box<Csr>,
     xlen: Xlen,
+    hart_id: usize,
+    privilege: Privilege,
+    watchpoints: Watchpoints,
 }
 
 impl<'a> core::fmt::Debug for Execute<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Execute")
             .field("x", &self.x)
+            .field("f", &self.f)
             .field("xlen", &self.xlen)
+            .field("privilege", &self.privilege)
             .finish()
     }
 }
 
 impl<'a> Execute<'a> {
-    pub fn new(data_mem: &'a mut Physical<'a>, xlen: Xlen) -> Execute<'a> {
+    pub fn new(data_mem: &'a mut (dyn Bus + 'a), xlen: Xlen) -> Execute<'a> {
         Execute {
             data_mem,
             x: Box::new(XReg::new_zeroed(xlen)),
             f: Box::new(FReg::new_zeroed()),
-            csr: Box::new(Csr::new(xlen)),
+            csr: Box::new(Csr::new(xlen, 0)),
             xlen,
+            hart_id: 0,
+            privilege: Privilege::Machine,
+            watchpoints: Watchpoints::default(),
         }
     }
 
-    // returns next PC value
-    #[rustfmt::skip]
+    // returns next PC value; synchronous exceptions are returned as `Error::Trap`
+    // and leave the hart state untouched, so the caller decides whether to service
+    // them itself or to enter the guest trap handler with `handle_trap`
     pub fn execute(&mut self, ins: Instruction, pc: Usize) -> Result<Usize> {
+        let is_float = is_float(&ins);
+        if is_float && !self.csr.fs_enabled() {
+            return Err(Trap::new(Exception::IllegalInstruction, self.x.r_usize(0)).into());
+        }
+        let next_pc = match self.execute_ins(ins, pc) {
+            // instructions of extensions or modes not supported are illegal
+            Err(Error::Exec(_)) => {
+                return Err(Trap::new(Exception::IllegalInstruction, self.x.r_usize(0)))?
+            }
+            ans => ans?,
+        };
+        if is_float {
+            self.csr.set_fs_dirty();
+        }
+        self.csr.retire();
+        Ok(next_pc)
+    }
+
+    // enters the trap handler, switching privilege; returns the handler address
+    pub fn handle_trap(&mut self, trap: Trap, pc: Usize) -> Usize {
+        let (next_pc, privilege) =
+            self.csr.trap_entry(trap.cause.code(), false, pc, trap.tval, self.privilege);
+        self.privilege = privilege;
+        next_pc
+    }
+
+    // sets a pending bit driven by an interrupt controller
+    pub fn set_interrupt_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        self.csr.set_pending(interrupt, pending)
+    }
+
+    // Takes the highest priority interrupt which is pending and enabled, before
+    // executing the instruction at `pc`; returns the handler address
+    pub fn take_interrupt(&mut self, pc: Usize) -> Option<Usize> {
+        let interrupt = self.csr.pending_interrupt(self.privilege)?;
+        let tval = self.x.r_usize(0);
+        let (next_pc, privilege) =
+            self.csr.trap_entry(interrupt.code(), true, pc, tval, self.privilege);
+        self.privilege = privilege;
+        Some(next_pc)
+    }
+
+    // mtime of the interrupt controller, read through the time CSR
+    pub fn set_time(&mut self, time: u64) {
+        self.csr.set_time(time)
+    }
+
+    // Lets lower privilege levels access all memory, as firmware does before
+    // entering its payload
+    pub fn open_pmp(&mut self) {
+        self.csr.open_pmp()
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    // switches privilege without a trap, as firmware does to enter its payload
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    // address translation state for instruction fetch and data accesses
+    pub fn translate(&self) -> Translate {
+        Translate::new(&self.csr, self.privilege, self.xlen)
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    // whether the extension is present in misa
+    pub fn has_extension(&self, ext: char) -> bool {
+        self.csr.has_extension(ext)
+    }
+
+    // data watchpoints checked on loads, stores and atomics of executed instructions
+    pub fn watchpoints(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    // Register and memory accessors for debuggers; CSRs are accessed as from
+    // machine mode, and memory through the current address translation
+    pub fn read_x(&self, idx: u8) -> Usize {
+        self.x.r_usize(idx)
+    }
+
+    pub fn write_x(&mut self, idx: u8, val: Usize) {
+        self.x.w_usize(idx, val)
+    }
+
+    pub fn read_f(&self, idx: u8) -> u64 {
+        self.f.r_u64(idx)
+    }
+
+    pub fn write_f(&mut self, idx: u8, val: u64) {
+        self.f.w_u64_boxed(idx, val)
+    }
+
+    pub fn read_csr(&self, csr: u16) -> Result<Usize> {
+        self.csr.r_usize(csr)
+    }
+
+    pub fn write_csr(&mut self, csr: u16, val: Usize) -> Result<()> {
+        self.csr.w_usize(csr, val)
+    }
+
+    // The memory this hart owns for its lifetime; fetches borrow it per step
+    pub fn bus(&self) -> &(dyn Bus + 'a) {
+        self.data_mem
+    }
+
+    pub fn bus_mut(&mut self) -> &mut (dyn Bus + 'a) {
+        self.data_mem
+    }
+
+    pub fn read_mem(&mut self, vaddr: Usize, nbytes: u64) -> Result<u64> {
+        let translate = self.translate();
+        Mmu::new(self.data_mem, translate).read_uint(vaddr, nbytes)
+    }
+
+    pub fn write_mem(&mut self, vaddr: Usize, val: u64, nbytes: u64) -> Result<()> {
+        let translate = self.translate();
+        Mmu::new(self.data_mem, translate).write_uint(vaddr, val, nbytes)
+    }
+
+    #[rustfmt::skip]
+    fn execute_ins(&mut self, ins: Instruction, pc: Usize) -> Result<Usize> {
         let xlen = self.xlen;
+        let has_f32 = self.csr.has_extension('F');
+        let has_f64 = self.csr.has_extension('D');
+        let translate = self.translate();
+        let data_mem = &mut Mmu::new(self.data_mem, translate).with_watchpoints(&self.watchpoints);
         let next_pc = match ins {
             Instruction::RV32I(ins) => exec_rv32i(
                 ins,
                 &mut self.x,
-                &mut self.data_mem,
+                data_mem,
                 pc,
+                self.privilege,
                 |imm| imm.sext(xlen),
             )?,
             Instruction::RV64I(ins) => {
                 exec_rv64i(
                     ins,
                     &mut self.x,
-                    &mut self.data_mem,
+                    data_mem,
                     |imm| imm.sext(xlen),
                     || xlen == Xlen::X64 || xlen == Xlen::X128,
                 )?;
@@ -75,6 +217,7 @@ impl<'a> Execute<'a> {
                     ins,
                     &mut self.x,
                     &mut self.csr,
+                    self.privilege,
                     |uimm| uimm.zext(xlen)
                 )?;
                 pc + 4
@@ -82,16 +225,66 @@ impl<'a> Execute<'a> {
             Instruction::RVC(ins) => exec_rvc(
                 ins, 
                 &mut self.x,
-                &mut self.data_mem,
+                &mut self.f,
+                data_mem,
                 pc, 
-                |imm| imm.sext(xlen),
-                |uimm| uimm.zext(xlen),
-                || xlen == Xlen::X64 || xlen == Xlen::X128,
-                || xlen == Xlen::X128,
-                || true, // todo: read from CSR
-                || true // todo: read from CSR
+                RvcHart { xlen, has_f32, has_f64 },
+            )?,
+            Instruction::RVPriv(ins) => exec_rvpriv(ins, &mut self.csr, &mut self.privilege, pc)?,
+            Instruction::RVM(ins) => {
+                exec_rvm(
+                    ins,
+                    &mut self.x,
+                    || xlen == Xlen::X64 || xlen == Xlen::X128,
+                )?;
+                pc + 4
+            },
+            Instruction::RVA(ins) => {
+                exec_rva(
+                    ins,
+                    &mut self.x,
+                    data_mem,
+                    self.hart_id,
+                    || xlen == Xlen::X64 || xlen == Xlen::X128,
+                )?;
+                pc + 4
+            },
+            // floating point extensions turned off in misa
+            Instruction::RVF(_) if !has_f32 => return Err(ExecError::ExtensionNotSupported.into()),
+            Instruction::RVD(_) if !has_f64 => return Err(ExecError::ExtensionNotSupported.into()),
+            Instruction::RVF(ins) => {
+                exec_rvf(
+                    ins,
+                    &mut self.x,
+                    &mut self.f,
+                    &mut self.csr,
+                    data_mem,
+                    |imm| imm.sext(xlen),
+                    || xlen == Xlen::X64 || xlen == Xlen::X128,
+                )?;
+                pc + 4
+            },
+            Instruction::RVD(ins) => {
+                exec_rvd(
+                    ins,
+                    &mut self.x,
+                    &mut self.f,
+                    &mut self.csr,
+                    data_mem,
+                    |imm| imm.sext(xlen),
+                    || xlen == Xlen::X64 || xlen == Xlen::X128,
+                )?;
+                pc + 4
+            },
+            Instruction::Custom(ins) => exec_custom(
+                ins,
+                &mut self.x,
+                &mut self.csr,
+                data_mem,
+                self.privilege,
+                xlen,
+                pc,
             )?,
-            Instruction::RVF(_ins) => todo!()
         };
         Ok(next_pc)
     }
@@ -108,8 +301,9 @@ fn shamt32r(data: Usize) -> u32 {
 fn exec_rv32i<'a, SEXT: Fn(Imm) -> Isize>(
     ins: RV32I,
     x: &mut XReg,
-    data_mem: &mut Physical<'a>,
+    data_mem: &mut Mmu<'_, 'a>,
     pc: Usize,
+    privilege: Privilege,
     sext: SEXT,
 ) -> Result<Usize> {
     use RV32I::*;
@@ -156,40 +350,40 @@ fn exec_rv32i<'a, SEXT: Fn(Imm) -> Isize>(
             }
         }
         Lb(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_i8(addr)?;
             x.w_sext8(i.rd, data);
         }
         Lh(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_i16(addr)?;
             x.w_sext16(i.rd, data);
         }
         Lw(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_i32(addr)?;
             x.w_sext32(i.rd, data);
         }
         Lbu(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_u8(addr)?;
             x.w_zext8(i.rd, data);
         }
         Lhu(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_u16(addr)?;
             x.w_zext16(i.rd, data);
         }
         Sb(s) => data_mem.write_u8(
-            pc_to_mem_addr(x.r_usize(s.rs1) + sext(s.imm)),
+            x.r_usize(s.rs1) + sext(s.imm),
             x.r_u8(s.rs2),
         )?,
         Sh(s) => data_mem.write_u16(
-            pc_to_mem_addr(x.r_usize(s.rs1) + sext(s.imm)),
+            x.r_usize(s.rs1) + sext(s.imm),
             x.r_u16(s.rs2),
         )?,
         Sw(s) => data_mem.write_u32(
-            pc_to_mem_addr(x.r_usize(s.rs1) + sext(s.imm)),
+            x.r_usize(s.rs1) + sext(s.imm),
             x.r_u32(s.rs2),
         )?,
         Addi(i) => x.w_usize(i.rd, x.r_usize(i.rs1) + sext(i.imm)),
@@ -262,9 +456,17 @@ fn exec_rv32i<'a, SEXT: Fn(Imm) -> Isize>(
         And(r) => {
             x.w_usize(r.rd, x.r_usize(r.rs1) & x.r_usize(r.rs2));
         }
-        Fence(_) => todo!(),
-        Ecall(_) => todo!(),
-        Ebreak(_) => todo!("ebreak"),
+        // there is only one hart, whose accesses are always performed in program order
+        Fence(_) => {}
+        Ecall(_) => {
+            let cause = match privilege {
+                Privilege::User => Exception::EnvironmentCallFromUMode,
+                Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+                Privilege::Machine => Exception::EnvironmentCallFromMMode,
+            };
+            return Err(Trap::new(cause, x.r_usize(0)).into());
+        }
+        Ebreak(_) => return Err(Trap::new(Exception::Breakpoint, pc))?,
     }
     Ok(next_pc)
 }
@@ -280,27 +482,27 @@ fn shamt64r(data: Usize) -> u32 {
 fn exec_rv64i<'a, SEXT: Fn(Imm) -> Isize, X64: Fn() -> bool>(
     ins: RV64I,
     x: &mut XReg,
-    data_mem: &mut Physical<'a>,
+    data_mem: &mut Mmu<'_, 'a>,
     sext: SEXT,
     has_x64: X64,
 ) -> Result<()> {
     if !has_x64() { 
-        return Err(ExecError::ExtensionNotSupported)?;
+        return Err(ExecError::ExtensionNotSupported.into());
     }
     use RV64I::*;
     match ins {
         Lwu(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_u32(addr)?;
             x.w_zext32(i.rd, data);
         }
         Ld(i) => {
-            let addr = pc_to_mem_addr(x.r_usize(i.rs1) + sext(i.imm));
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
             let data = data_mem.read_i64(addr)?;
             x.w_sext64(i.rd, data);
         }
         Sd(s) => data_mem.write_u64(
-            pc_to_mem_addr(x.r_usize(s.rs1) + sext(s.imm)),
+            x.r_usize(s.rs1) + sext(s.imm),
             x.r_u64(s.rs2),
         )?,
         Slli(i) => x.w_usize(i.rd, x.r_usize(i.rs1) << shamt64(i.imm)),
@@ -325,7 +527,7 @@ fn exec_rv64i<'a, SEXT: Fn(Imm) -> Isize, X64: Fn() -> bool>(
         }
         Srliw(i) => {
             let val = x.r_u32(i.rs1).checked_shr(shamt32(i.imm)).unwrap_or(0);
-            x.w_sext32(i.rd, i32::from_ne_bytes(val.to_be_bytes()))
+            x.w_sext32(i.rd, val as i32)
         }
         Sraiw(i) => {
             let val = x.r_i32(i.rs1).checked_shr(shamt32(i.imm)).unwrap_or(0);
@@ -345,7 +547,7 @@ fn exec_rv64i<'a, SEXT: Fn(Imm) -> Isize, X64: Fn() -> bool>(
                 .r_u32(r.rs1)
                 .checked_shr(shamt32r(x.r_usize(r.rs2)))
                 .unwrap_or(0);
-            x.w_sext32(r.rd, i32::from_ne_bytes(val.to_be_bytes()))
+            x.w_sext32(r.rd, val as i32)
         }
         Sraw(r) => {
             let val = x
@@ -362,81 +564,694 @@ fn exec_rvzicsr<ZEXT: Fn(Uimm) -> Usize>(
     ins: RVZicsr,
     x: &mut XReg,
     csr: &mut Csr,
+    privilege: Privilege,
     zext: ZEXT,
 ) -> Result<()> {
     use RVZicsr::*;
-    // if r.rd!=0 or r.rs1 != 0 => prevent side effects
+    let addr = match ins {
+        Csrrw(r) | Csrrs(r) | Csrrc(r) => r.csr,
+        Csrrwi(i) | Csrrsi(i) | Csrrci(i) => i.csr,
+    };
+    csr.check_privilege(addr, privilege)?;
+    // if r.rd!=0 or r.rs1 != 0 => prevent side effects; the CSR is written before
+    // rd, so that a failing write leaves rd unchanged
     match ins {
         Csrrw(r) => {
-            if r.rd != 0 {
-                x.w_usize(r.rd, csr.r_usize(r.csr));
+            let old = if r.rd != 0 { Some(csr.r_usize(r.csr)?) } else { None };
+            csr.w_usize(r.csr, x.r_usize(r.rs1))?;
+            if let Some(old) = old {
+                x.w_usize(r.rd, old);
             }
-            csr.w_usize(r.csr, x.r_usize(r.rs1));
         }
         Csrrs(r) => {
-            x.w_usize(r.rd, csr.r_usize(r.csr));
+            let old = csr.r_usize(r.csr)?;
             if r.rs1 != 0 {
-                csr.w_usize(r.csr, csr.r_usize(r.csr) | x.r_usize(r.rs1));
+                csr.w_usize(r.csr, old | x.r_usize(r.rs1))?;
             }
+            x.w_usize(r.rd, old);
         }
         Csrrc(r) => {
-            x.w_usize(r.rd, csr.r_usize(r.csr));
+            let old = csr.r_usize(r.csr)?;
             if r.rs1 != 0 {
-                csr.w_usize(r.csr, csr.r_usize(r.csr) & !x.r_usize(r.rs1));
+                csr.w_usize(r.csr, old & !x.r_usize(r.rs1))?;
             }
+            x.w_usize(r.rd, old);
         }
         Csrrwi(i) => {
-            if i.rd != 0 {
-                x.w_usize(i.rd, csr.r_usize(i.csr));
+            let old = if i.rd != 0 { Some(csr.r_usize(i.csr)?) } else { None };
+            csr.w_usize(i.csr, zext(i.uimm))?;
+            if let Some(old) = old {
+                x.w_usize(i.rd, old);
             }
-            csr.w_usize(i.csr, zext(i.uimm));
         }
         Csrrsi(i) => {
-            x.w_usize(i.rd, csr.r_usize(i.csr));
+            let old = csr.r_usize(i.csr)?;
             if i.uimm != 0 {
-                csr.w_usize(i.csr, csr.r_usize(i.csr) | zext(i.uimm));
+                csr.w_usize(i.csr, old | zext(i.uimm))?;
             }
+            x.w_usize(i.rd, old);
         }
         Csrrci(i) => {
-            x.w_usize(i.rd, csr.r_usize(i.csr));
+            let old = csr.r_usize(i.csr)?;
             if i.uimm != 0 {
-                csr.w_usize(i.csr, csr.r_usize(i.csr) & !zext(i.uimm));
+                csr.w_usize(i.csr, old & !zext(i.uimm))?;
             }
+            x.w_usize(i.rd, old);
         }
     }
     Ok(())
 }
 
+fn exec_rvpriv(ins: RVPriv, csr: &mut Csr, privilege: &mut Privilege, pc: Usize) -> Result<Usize> {
+    use RVPriv::*;
+    let next_pc = match ins {
+        Mret(_) if *privilege != Privilege::Machine => return Err(csr.illegal())?,
+        Mret(_) => {
+            let (next_pc, next_privilege) = csr.mret();
+            *privilege = next_privilege;
+            next_pc
+        }
+        Sret(_) if *privilege == Privilege::User => return Err(csr.illegal())?,
+        Sret(_) if *privilege == Privilege::Supervisor && csr.tsr() => return Err(csr.illegal())?,
+        Sret(_) => {
+            let (next_pc, next_privilege) = csr.sret();
+            *privilege = next_privilege;
+            next_pc
+        }
+        Wfi(_) if *privilege == Privilege::User => return Err(csr.illegal())?,
+        Wfi(_) if *privilege == Privilege::Supervisor && csr.tw() => return Err(csr.illegal())?,
+        // returning at once is a legal implementation; the run loop takes interrupts
+        // before the next instruction anyway
+        Wfi(_) => pc + 4,
+        SfenceVma(_) if *privilege == Privilege::User => return Err(csr.illegal())?,
+        SfenceVma(_) if *privilege == Privilege::Supervisor && csr.tvm() => {
+            return Err(csr.illegal())?
+        }
+        // there is no address translation cache to flush
+        SfenceVma(_) => pc + 4,
+    };
+    Ok(next_pc)
+}
+
+fn is_float(ins: &Instruction) -> bool {
+    use RVC::*;
+    matches!(
+        ins,
+        Instruction::RVF(_)
+            | Instruction::RVD(_)
+            | Instruction::RVC(Cfld(_))
+            | Instruction::RVC(Cflw(_))
+            | Instruction::RVC(Cfsd(_))
+            | Instruction::RVC(Cfsw(_))
+            | Instruction::RVC(Cfldsp(_))
+            | Instruction::RVC(Cflwsp(_))
+            | Instruction::RVC(Cfsdsp(_))
+            | Instruction::RVC(Cfswsp(_))
+    )
+}
+
+fn exec_rvm<X64: Fn() -> bool>(ins: RVM, x: &mut XReg, has_x64: X64) -> Result<()> {
+    use RVM::*;
+    match ins {
+        Mul(r) => x.w_usize(r.rd, match (x.r_usize(r.rs1), x.r_usize(r.rs2)) {
+            (Usize::U32(a), Usize::U32(b)) => Usize::U32(a.wrapping_mul(b)),
+            (Usize::U64(a), Usize::U64(b)) => Usize::U64(a.wrapping_mul(b)),
+            _ => unreachable!(),
+        }),
+        Mulh(r) => x.w_isize(r.rd, match (x.r_isize(r.rs1), x.r_isize(r.rs2)) {
+            (Isize::I32(a), Isize::I32(b)) => Isize::I32(((a as i64 * b as i64) >> 32) as i32),
+            (Isize::I64(a), Isize::I64(b)) => Isize::I64(((a as i128 * b as i128) >> 64) as i64),
+            _ => unreachable!(),
+        }),
+        Mulhsu(r) => x.w_isize(r.rd, match (x.r_isize(r.rs1), x.r_usize(r.rs2)) {
+            (Isize::I32(a), Usize::U32(b)) => Isize::I32(((a as i64 * b as i64) >> 32) as i32),
+            (Isize::I64(a), Usize::U64(b)) => {
+                // the product of i64 and u64 always fits in i128 without overflow
+                Isize::I64(((a as i128 * b as i128) >> 64) as i64)
+            }
+            _ => unreachable!(),
+        }),
+        Mulhu(r) => x.w_usize(r.rd, match (x.r_usize(r.rs1), x.r_usize(r.rs2)) {
+            (Usize::U32(a), Usize::U32(b)) => Usize::U32(((a as u64 * b as u64) >> 32) as u32),
+            (Usize::U64(a), Usize::U64(b)) => Usize::U64(((a as u128 * b as u128) >> 64) as u64),
+            _ => unreachable!(),
+        }),
+        // division by zero and signed overflow never trap; see RISC-V spec, table 7.1
+        Div(r) => x.w_isize(r.rd, match (x.r_isize(r.rs1), x.r_isize(r.rs2)) {
+            (Isize::I32(_), Isize::I32(0)) => Isize::I32(-1),
+            (Isize::I32(a), Isize::I32(b)) => Isize::I32(a.wrapping_div(b)),
+            (Isize::I64(_), Isize::I64(0)) => Isize::I64(-1),
+            (Isize::I64(a), Isize::I64(b)) => Isize::I64(a.wrapping_div(b)),
+            _ => unreachable!(),
+        }),
+        Divu(r) => x.w_usize(r.rd, match (x.r_usize(r.rs1), x.r_usize(r.rs2)) {
+            (Usize::U32(_), Usize::U32(0)) => Usize::U32(u32::MAX),
+            (Usize::U32(a), Usize::U32(b)) => Usize::U32(a / b),
+            (Usize::U64(_), Usize::U64(0)) => Usize::U64(u64::MAX),
+            (Usize::U64(a), Usize::U64(b)) => Usize::U64(a / b),
+            _ => unreachable!(),
+        }),
+        Rem(r) => x.w_isize(r.rd, match (x.r_isize(r.rs1), x.r_isize(r.rs2)) {
+            (Isize::I32(a), Isize::I32(0)) => Isize::I32(a),
+            (Isize::I32(a), Isize::I32(b)) => Isize::I32(a.wrapping_rem(b)),
+            (Isize::I64(a), Isize::I64(0)) => Isize::I64(a),
+            (Isize::I64(a), Isize::I64(b)) => Isize::I64(a.wrapping_rem(b)),
+            _ => unreachable!(),
+        }),
+        Remu(r) => x.w_usize(r.rd, match (x.r_usize(r.rs1), x.r_usize(r.rs2)) {
+            (Usize::U32(a), Usize::U32(0)) => Usize::U32(a),
+            (Usize::U32(a), Usize::U32(b)) => Usize::U32(a % b),
+            (Usize::U64(a), Usize::U64(0)) => Usize::U64(a),
+            (Usize::U64(a), Usize::U64(b)) => Usize::U64(a % b),
+            _ => unreachable!(),
+        }),
+        Mulw(_) | Divw(_) | Divuw(_) | Remw(_) | Remuw(_) if !has_x64() => {
+            return Err(ExecError::ExtensionNotSupported.into());
+        }
+        Mulw(r) => x.w_sext32(r.rd, x.r_i32(r.rs1).wrapping_mul(x.r_i32(r.rs2))),
+        Divw(r) => {
+            let val = match (x.r_i32(r.rs1), x.r_i32(r.rs2)) {
+                (_, 0) => -1,
+                (a, b) => a.wrapping_div(b),
+            };
+            x.w_sext32(r.rd, val)
+        }
+        Divuw(r) => {
+            let val = match (x.r_u32(r.rs1), x.r_u32(r.rs2)) {
+                (_, 0) => u32::MAX,
+                (a, b) => a / b,
+            };
+            x.w_sext32(r.rd, i32::from_ne_bytes(val.to_ne_bytes()))
+        }
+        Remw(r) => {
+            let val = match (x.r_i32(r.rs1), x.r_i32(r.rs2)) {
+                (a, 0) => a,
+                (a, b) => a.wrapping_rem(b),
+            };
+            x.w_sext32(r.rd, val)
+        }
+        Remuw(r) => {
+            let val = match (x.r_u32(r.rs1), x.r_u32(r.rs2)) {
+                (a, 0) => a,
+                (a, b) => a % b,
+            };
+            x.w_sext32(r.rd, i32::from_ne_bytes(val.to_ne_bytes()))
+        }
+    }
+    Ok(())
+}
+
+fn exec_rva<'a, X64: Fn() -> bool>(
+    ins: RVA,
+    x: &mut XReg,
+    data_mem: &mut Mmu<'_, 'a>,
+    hart_id: usize,
+    has_x64: X64,
+) -> Result<()> {
+    use RVA::*;
+    // aq and rl bits need no special handling; accesses from this hart are always
+    // performed in program order
+    match ins {
+        LrW(a) => {
+            let addr = lr_addr(data_mem, x.r_usize(a.rs1), 4)?;
+            let data = data_mem.read_i32(x.r_usize(a.rs1))?;
+            data_mem.reserve(hart_id, addr, 4);
+            x.w_sext32(a.rd, data);
+        }
+        ScW(a) => {
+            let addr = amo_addr(data_mem, x.r_usize(a.rs1), 4)?;
+            if data_mem.take_reservation(hart_id, addr, 4) {
+                data_mem.write_u32(x.r_usize(a.rs1), x.r_u32(a.rs2))?;
+                x.w_zext8(a.rd, 0);
+            } else {
+                x.w_zext8(a.rd, 1);
+            }
+        }
+        AmoswapW(a) => amo_w(a, x, data_mem, |_, b| b)?,
+        AmoaddW(a) => amo_w(a, x, data_mem, |a, b| a.wrapping_add(b))?,
+        AmoxorW(a) => amo_w(a, x, data_mem, |a, b| a ^ b)?,
+        AmoandW(a) => amo_w(a, x, data_mem, |a, b| a & b)?,
+        AmoorW(a) => amo_w(a, x, data_mem, |a, b| a | b)?,
+        AmominW(a) => amo_w(a, x, data_mem, |a, b| (a as i32).min(b as i32) as u32)?,
+        AmomaxW(a) => amo_w(a, x, data_mem, |a, b| (a as i32).max(b as i32) as u32)?,
+        AmominuW(a) => amo_w(a, x, data_mem, |a, b| a.min(b))?,
+        AmomaxuW(a) => amo_w(a, x, data_mem, |a, b| a.max(b))?,
+        LrD(_) | ScD(_) | AmoswapD(_) | AmoaddD(_) | AmoxorD(_) | AmoandD(_) | AmoorD(_)
+        | AmominD(_) | AmomaxD(_) | AmominuD(_) | AmomaxuD(_)
+            if !has_x64() =>
+        {
+            return Err(ExecError::ExtensionNotSupported.into());
+        }
+        LrD(a) => {
+            let addr = lr_addr(data_mem, x.r_usize(a.rs1), 8)?;
+            let data = data_mem.read_i64(x.r_usize(a.rs1))?;
+            data_mem.reserve(hart_id, addr, 8);
+            x.w_sext64(a.rd, data);
+        }
+        ScD(a) => {
+            let addr = amo_addr(data_mem, x.r_usize(a.rs1), 8)?;
+            if data_mem.take_reservation(hart_id, addr, 8) {
+                data_mem.write_u64(x.r_usize(a.rs1), x.r_u64(a.rs2))?;
+                x.w_zext8(a.rd, 0);
+            } else {
+                x.w_zext8(a.rd, 1);
+            }
+        }
+        AmoswapD(a) => amo_d(a, x, data_mem, |_, b| b)?,
+        AmoaddD(a) => amo_d(a, x, data_mem, |a, b| a.wrapping_add(b))?,
+        AmoxorD(a) => amo_d(a, x, data_mem, |a, b| a ^ b)?,
+        AmoandD(a) => amo_d(a, x, data_mem, |a, b| a & b)?,
+        AmoorD(a) => amo_d(a, x, data_mem, |a, b| a | b)?,
+        AmominD(a) => amo_d(a, x, data_mem, |a, b| (a as i64).min(b as i64) as u64)?,
+        AmomaxD(a) => amo_d(a, x, data_mem, |a, b| (a as i64).max(b as i64) as u64)?,
+        AmominuD(a) => amo_d(a, x, data_mem, |a, b| a.min(b))?,
+        AmomaxuD(a) => amo_d(a, x, data_mem, |a, b| a.max(b))?,
+    }
+    Ok(())
+}
+
+// atomic memory operations must be naturally aligned; returns physical address
+fn lr_addr(data_mem: &Mmu, addr: Usize, nbytes: u32) -> Result<u64> {
+    if !addr.low_u32().is_multiple_of(nbytes) {
+        return Err(Trap::new(Exception::LoadAddressMisaligned, addr).into());
+    }
+    data_mem.translate(addr, nbytes as u64, Access::Load)
+}
+
+fn amo_addr(data_mem: &Mmu, addr: Usize, nbytes: u32) -> Result<u64> {
+    if !addr.low_u32().is_multiple_of(nbytes) {
+        return Err(Trap::new(Exception::StoreAddressMisaligned, addr).into());
+    }
+    data_mem.translate(addr, nbytes as u64, Access::Store)
+}
+
+fn amo_w(a: AType, x: &mut XReg, data_mem: &mut Mmu, op: fn(u32, u32) -> u32) -> Result<()> {
+    amo_addr(data_mem, x.r_usize(a.rs1), 4)?;
+    let b = x.r_u32(a.rs2);
+    let data = data_mem.amo_u32(x.r_usize(a.rs1), |a| op(a, b))?;
+    x.w_sext32(a.rd, i32::from_ne_bytes(data.to_ne_bytes()));
+    Ok(())
+}
+
+fn amo_d(a: AType, x: &mut XReg, data_mem: &mut Mmu, op: fn(u64, u64) -> u64) -> Result<()> {
+    amo_addr(data_mem, x.r_usize(a.rs1), 8)?;
+    let b = x.r_u64(a.rs2);
+    let data = data_mem.amo_u64(x.r_usize(a.rs1), |a| op(a, b))?;
+    x.w_sext64(a.rd, i64::from_ne_bytes(data.to_ne_bytes()));
+    Ok(())
+}
+
+fn r_f32(f: &FReg, idx: u8) -> Single {
+    Single::from_bits(f.r_u32_boxed(idx) as u128)
+}
+
+fn w_f32(f: &mut FReg, csr: &mut Csr, idx: u8, ans: StatusAnd<Single>) {
+    csr.accrue_fflags(float::fflags(ans.status));
+    f.w_u32_boxed(idx, float::canonical_nan(ans.value).to_bits() as u32);
+}
+
+fn exec_rvf<'a, SEXT: Fn(Imm) -> Isize, X64: Fn() -> bool>(
+    ins: RVF,
+    x: &mut XReg,
+    f: &mut FReg,
+    csr: &mut Csr,
+    data_mem: &mut Mmu<'_, 'a>,
+    sext: SEXT,
+    has_x64: X64,
+) -> Result<()> {
+    use RVF::*;
+    match ins {
+        Flw(i) => {
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
+            let data = data_mem.read_u32(addr)?;
+            f.w_u32_boxed(i.rd, data);
+        }
+        Fsw(s) => data_mem.write_u32(
+            x.r_usize(s.rs1) + sext(s.imm),
+            f.r_u32(s.rs2),
+        )?,
+        Fmadds(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f32(f, r.rs1), r_f32(f, r.rs2), r_f32(f, r.rs3));
+            w_f32(f, csr, r.rd, float::fused(a, b, c, round))
+        }
+        Fmsubs(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f32(f, r.rs1), r_f32(f, r.rs2), r_f32(f, r.rs3));
+            w_f32(f, csr, r.rd, float::fused(a, b, -c, round))
+        }
+        Fnmsubs(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f32(f, r.rs1), r_f32(f, r.rs2), r_f32(f, r.rs3));
+            w_f32(f, csr, r.rd, float::fused(-a, b, c, round))
+        }
+        Fnmadds(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f32(f, r.rs1), r_f32(f, r.rs2), r_f32(f, r.rs3));
+            w_f32(f, csr, r.rd, float::fused(-a, b, -c, round))
+        }
+        Fadds(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f32(f, r.rs1).add_r(r_f32(f, r.rs2), round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fsubs(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f32(f, r.rs1).sub_r(r_f32(f, r.rs2), round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fmuls(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f32(f, r.rs1).mul_r(r_f32(f, r.rs2), round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fdivs(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f32(f, r.rs1).div_r(r_f32(f, r.rs2), round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fsqrts(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            w_f32(f, csr, r.rd, float::sqrt(r_f32(f, r.rs1), round))
+        }
+        // sign injection never raises exceptions and keeps NaN payloads
+        Fsgnjs(r) => {
+            let (a, b) = (f.r_u32_boxed(r.rs1), f.r_u32_boxed(r.rs2));
+            f.w_u32_boxed(r.rd, (a & 0x7FFFFFFF) | (b & 0x80000000))
+        }
+        Fsgnjns(r) => {
+            let (a, b) = (f.r_u32_boxed(r.rs1), f.r_u32_boxed(r.rs2));
+            f.w_u32_boxed(r.rd, (a & 0x7FFFFFFF) | (!b & 0x80000000))
+        }
+        Fsgnjxs(r) => {
+            let (a, b) = (f.r_u32_boxed(r.rs1), f.r_u32_boxed(r.rs2));
+            f.w_u32_boxed(r.rd, a ^ (b & 0x80000000))
+        }
+        Fmins(r) => {
+            let ans = float::min_max(r_f32(f, r.rs1), r_f32(f, r.rs2), false);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fmaxs(r) => {
+            let ans = float::min_max(r_f32(f, r.rs1), r_f32(f, r.rs2), true);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fcvtws(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f32(f, r.rs1), round, true, 32);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext32(r.rd, ans.value as i32)
+        }
+        // the 32-bit result is sign extended even if the conversion is unsigned
+        Fcvtwus(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f32(f, r.rs1), round, false, 32);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext32(r.rd, ans.value as u32 as i32)
+        }
+        Fmvxw(r) => x.w_sext32(r.rd, f.r_u32(r.rs1) as i32),
+        Feqs(r) => {
+            let ans = float::eq(r_f32(f, r.rs1), r_f32(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Flts(r) => {
+            let ans = float::lt(r_f32(f, r.rs1), r_f32(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Fles(r) => {
+            let ans = float::le(r_f32(f, r.rs1), r_f32(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Fclasss(r) => x.w_zext16(r.rd, float::classify(r_f32(f, r.rs1)) as u16),
+        Fcvtsw(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_i32(r.rs1) as i128, round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fcvtswu(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u32(r.rs1) as i128, round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fmvwx(r) => f.w_u32_boxed(r.rd, x.r_u32(r.rs1)),
+        Fcvtls(_) | Fcvtlus(_) | Fcvtsl(_) | Fcvtslu(_) if !has_x64() => {
+            return Err(ExecError::ExtensionNotSupported.into());
+        }
+        Fcvtls(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f32(f, r.rs1), round, true, 64);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext64(r.rd, ans.value as i64)
+        }
+        Fcvtlus(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f32(f, r.rs1), round, false, 64);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext64(r.rd, ans.value as u64 as i64)
+        }
+        Fcvtsl(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u64(r.rs1) as i64 as i128, round);
+            w_f32(f, csr, r.rd, ans)
+        }
+        Fcvtslu(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u64(r.rs1) as i128, round);
+            w_f32(f, csr, r.rd, ans)
+        }
+    }
+    Ok(())
+}
+
+fn r_f64(f: &FReg, idx: u8) -> Double {
+    Double::from_bits(f.r_u64_boxed(idx) as u128)
+}
+
+fn w_f64(f: &mut FReg, csr: &mut Csr, idx: u8, ans: StatusAnd<Double>) {
+    csr.accrue_fflags(float::fflags(ans.status));
+    f.w_u64_boxed(idx, float::canonical_nan(ans.value).to_bits() as u64);
+}
+
+fn exec_rvd<'a, SEXT: Fn(Imm) -> Isize, X64: Fn() -> bool>(
+    ins: RVD,
+    x: &mut XReg,
+    f: &mut FReg,
+    csr: &mut Csr,
+    data_mem: &mut Mmu<'_, 'a>,
+    sext: SEXT,
+    has_x64: X64,
+) -> Result<()> {
+    use RVD::*;
+    match ins {
+        Fld(i) => {
+            let addr = x.r_usize(i.rs1) + sext(i.imm);
+            let data = data_mem.read_u64(addr)?;
+            f.w_u64_boxed(i.rd, data);
+        }
+        Fsd(s) => data_mem.write_u64(
+            x.r_usize(s.rs1) + sext(s.imm),
+            f.r_u64(s.rs2),
+        )?,
+        Fmaddd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f64(f, r.rs1), r_f64(f, r.rs2), r_f64(f, r.rs3));
+            w_f64(f, csr, r.rd, float::fused(a, b, c, round))
+        }
+        Fmsubd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f64(f, r.rs1), r_f64(f, r.rs2), r_f64(f, r.rs3));
+            w_f64(f, csr, r.rd, float::fused(a, b, -c, round))
+        }
+        Fnmsubd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f64(f, r.rs1), r_f64(f, r.rs2), r_f64(f, r.rs3));
+            w_f64(f, csr, r.rd, float::fused(-a, b, c, round))
+        }
+        Fnmaddd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let (a, b, c) = (r_f64(f, r.rs1), r_f64(f, r.rs2), r_f64(f, r.rs3));
+            w_f64(f, csr, r.rd, float::fused(-a, b, -c, round))
+        }
+        Faddd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f64(f, r.rs1).add_r(r_f64(f, r.rs2), round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fsubd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f64(f, r.rs1).sub_r(r_f64(f, r.rs2), round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fmuld(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f64(f, r.rs1).mul_r(r_f64(f, r.rs2), round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fdivd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = r_f64(f, r.rs1).div_r(r_f64(f, r.rs2), round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fsqrtd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            w_f64(f, csr, r.rd, float::sqrt(r_f64(f, r.rs1), round))
+        }
+        Fsgnjd(r) => {
+            let (a, b) = (f.r_u64_boxed(r.rs1), f.r_u64_boxed(r.rs2));
+            f.w_u64_boxed(r.rd, (a & !(1 << 63)) | (b & (1 << 63)))
+        }
+        Fsgnjnd(r) => {
+            let (a, b) = (f.r_u64_boxed(r.rs1), f.r_u64_boxed(r.rs2));
+            f.w_u64_boxed(r.rd, (a & !(1 << 63)) | (!b & (1 << 63)))
+        }
+        Fsgnjxd(r) => {
+            let (a, b) = (f.r_u64_boxed(r.rs1), f.r_u64_boxed(r.rs2));
+            f.w_u64_boxed(r.rd, a ^ (b & (1 << 63)))
+        }
+        Fmind(r) => {
+            let ans = float::min_max(r_f64(f, r.rs1), r_f64(f, r.rs2), false);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fmaxd(r) => {
+            let ans = float::min_max(r_f64(f, r.rs1), r_f64(f, r.rs2), true);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fcvtsd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let mut loses_info = false;
+            let ans: StatusAnd<Single> = r_f64(f, r.rs1).convert_r(round, &mut loses_info);
+            w_f32(f, csr, r.rd, ans)
+        }
+        // every single-precision value is exactly representable in double precision
+        Fcvtds(r) => {
+            let mut loses_info = false;
+            let ans: StatusAnd<Double> = r_f32(f, r.rs1).convert(&mut loses_info);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Feqd(r) => {
+            let ans = float::eq(r_f64(f, r.rs1), r_f64(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Fltd(r) => {
+            let ans = float::lt(r_f64(f, r.rs1), r_f64(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Fled(r) => {
+            let ans = float::le(r_f64(f, r.rs1), r_f64(f, r.rs2));
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_zext8(r.rd, ans.value as u8)
+        }
+        Fclassd(r) => x.w_zext16(r.rd, float::classify(r_f64(f, r.rs1)) as u16),
+        Fcvtwd(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f64(f, r.rs1), round, true, 32);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext32(r.rd, ans.value as i32)
+        }
+        Fcvtwud(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f64(f, r.rs1), round, false, 32);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext32(r.rd, ans.value as u32 as i32)
+        }
+        // conversions from 32-bit integers are always exact
+        Fcvtdw(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_i32(r.rs1) as i128, round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fcvtdwu(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u32(r.rs1) as i128, round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fcvtld(_) | Fcvtlud(_) | Fmvxd(_) | Fcvtdl(_) | Fcvtdlu(_) | Fmvdx(_) if !has_x64() => {
+            return Err(ExecError::ExtensionNotSupported.into());
+        }
+        Fcvtld(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f64(f, r.rs1), round, true, 64);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext64(r.rd, ans.value as i64)
+        }
+        Fcvtlud(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::to_int(r_f64(f, r.rs1), round, false, 64);
+            csr.accrue_fflags(float::fflags(ans.status));
+            x.w_sext64(r.rd, ans.value as u64 as i64)
+        }
+        Fmvxd(r) => x.w_sext64(r.rd, f.r_u64(r.rs1) as i64),
+        Fcvtdl(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u64(r.rs1) as i64 as i128, round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fcvtdlu(r) => {
+            let round = float::round_mode(r.funct3, csr.frm())?;
+            let ans = float::from_int(x.r_u64(r.rs1) as i128, round);
+            w_f64(f, csr, r.rd, ans)
+        }
+        Fmvdx(r) => f.w_u64_boxed(r.rd, x.r_u64(r.rs1)),
+    }
+    Ok(())
+}
+
 const X1_RA: u8 = 1;
 const X2_SP: u8 = 2;
 
-fn exec_rvc<
-    'a,
-    SEXT: Fn(Imm) -> Isize,
-    ZEXT: Fn(Uimm) -> Usize,
-    X64: Fn() -> bool,
-    X128: Fn() -> bool,
-    F32: Fn() -> bool,
-    F64: Fn() -> bool,
->(
+// What compressed instructions depend on: their encodings vary with XLEN and
+// with the floating point extensions the hart implements
+struct RvcHart {
+    xlen: Xlen,
+    has_f32: bool,
+    has_f64: bool,
+}
+
+impl RvcHart {
+    fn sext(&self, imm: Imm) -> Isize {
+        imm.sext(self.xlen)
+    }
+
+    fn zext(&self, uimm: Uimm) -> Usize {
+        uimm.zext(self.xlen)
+    }
+
+    fn has_x64(&self) -> bool {
+        self.xlen == Xlen::X64 || self.xlen == Xlen::X128
+    }
+
+    fn has_x128(&self) -> bool {
+        self.xlen == Xlen::X128
+    }
+
+    fn has_f32(&self) -> bool {
+        self.has_f32
+    }
+
+    fn has_f64(&self) -> bool {
+        self.has_f64
+    }
+}
+
+fn exec_rvc<'a>(
     ins: RVC,
     x: &mut XReg,
-    data_mem: &mut Physical<'a>,
+    f: &mut FReg,
+    data_mem: &mut Mmu<'_, 'a>,
     pc: Usize,
-    sext: SEXT,
-    zext: ZEXT,
-    has_x64: X64,
-    has_x128: X128,
-    has_f32: F32,
-    has_f64: F64,
+    hart: RvcHart,
 ) -> Result<Usize> {
     let shamt_c = |imm: Imm| -> Result<u32> {
-        if has_x128() {
+        if hart.has_x128() {
             todo!("RV128I")
         }
         let s64 = imm.low_u32() & 0b111111;
-        if !has_x64() && s64 >= 0b100000 {
-            return Err(ExecError::ExtensionNotSupported)?;
+        if !hart.has_x64() && s64 >= 0b100000 {
+            return Err(ExecError::ExtensionNotSupported.into());
         };
         Ok(s64)
     };
@@ -444,101 +1259,111 @@ fn exec_rvc<
     let mut next_pc = pc + 2;
     // if r.rd!=0 or r.rs1 != 0 => prevent side effects
     match ins {
-        Caddi4spn(ciw) => x.w_usize(ciw.rd, x.r_usize(X2_SP) + zext(ciw.uimm)),
-        Cfld(_cl) => {
-            if has_x128() || !has_f64() { // RV32DC or RV64DC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Caddi4spn(ciw) => x.w_usize(ciw.rd, x.r_usize(X2_SP) + hart.zext(ciw.uimm)),
+        Cfld(cl) => {
+            if hart.has_x128() || !hart.has_f64() { // RV32DC or RV64DC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("D extension")
+            let addr = x.r_usize(cl.rs1) + hart.sext(cl.imm);
+            let data = data_mem.read_u64(addr)?;
+            f.w_u64_boxed(cl.rd, data);
         },
         Clq(_cl) => {
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
         Clw(cl) => {
-            let addr = pc_to_mem_addr(x.r_usize(cl.rs1) + sext(cl.imm));
+            let addr = x.r_usize(cl.rs1) + hart.sext(cl.imm);
             let data = data_mem.read_i32(addr)?;
             x.w_sext32(cl.rd, data);
         },
-        Cflw(_clt) => {
-            if !has_f32() || has_x64() || has_x128() { // RV32FC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cflw(cl) => {
+            if !hart.has_f32() || hart.has_x64() || hart.has_x128() { // RV32FC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("F extension")
+            let addr = x.r_usize(cl.rs1) + hart.sext(cl.imm);
+            let data = data_mem.read_u32(addr)?;
+            f.w_u32_boxed(cl.rd, data);
         },
         Cld(cl) => {
-            if !has_x64() && !has_x128() { // RV64C or RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x64() && !hart.has_x128() { // RV64C or RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            let addr = pc_to_mem_addr(x.r_usize(cl.rs1) + sext(cl.imm));
+            let addr = x.r_usize(cl.rs1) + hart.sext(cl.imm);
             let data = data_mem.read_i64(addr)?;
             x.w_sext64(cl.rd, data);
         },
-        Cfsd(_cs) => {
-            if has_x128() || !has_f64() { // RV32DC or RV64DC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cfsd(cs) => {
+            if hart.has_x128() || !hart.has_f64() { // RV32DC or RV64DC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("D extension")
+            data_mem.write_u64(
+                x.r_usize(cs.rs1) + hart.sext(cs.imm),
+                f.r_u64(cs.rs2),
+            )?
         },
         Csq(_cs) => {
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
         Csw(cs) => data_mem.write_u32(
-            pc_to_mem_addr(x.r_usize(cs.rs1) + sext(cs.imm)),
+            x.r_usize(cs.rs1) + hart.sext(cs.imm),
             x.r_u32(cs.rs2),
         )?,
-        Cfsw(_cs) => {
-            if !has_f32() || has_x64() || has_x128() { // RV32FC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cfsw(cs) => {
+            if !hart.has_f32() || hart.has_x64() || hart.has_x128() { // RV32FC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("F extension")
+            data_mem.write_u32(
+                x.r_usize(cs.rs1) + hart.sext(cs.imm),
+                f.r_u32(cs.rs2),
+            )?
         },
         Csd(cs) => {
-            if !has_x64() && !has_x128() { // RV64C or RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x64() && !hart.has_x128() { // RV64C or RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             data_mem.write_u64(
-                pc_to_mem_addr(x.r_usize(cs.rs1) + sext(cs.imm)),
+                x.r_usize(cs.rs1) + hart.sext(cs.imm),
                 x.r_u64(cs.rs2),
             )?
         },
         Cnop(_) => { /* nop */ }, 
         Caddi(ci) => {
-            x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) + sext(ci.imm))
+            x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) + hart.sext(ci.imm))
         },
         Cjal(cj) => {
-            if has_x64() || has_x128() { // RV32C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if hart.has_x64() || hart.has_x128() { // RV32C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             x.w_usize(X1_RA, next_pc);
-            next_pc = pc + sext(cj.target);
+            next_pc = pc + hart.sext(cj.target);
         },
         Caddiw(ci) => {
-            if !has_x64() && !has_x128() { // RV64C or RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x64() && !hart.has_x128() { // RV64C or RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             x.w_sext32(ci.rdrs1, x.r_i32(ci.rdrs1).wrapping_add(ci.imm.low_i32()))
         },
         Cli(ci) => {
-            x.w_isize(ci.rdrs1, sext(ci.imm))
+            x.w_isize(ci.rdrs1, hart.sext(ci.imm))
         },
         Caddi16sp(ci) => {
-            x.w_usize(X2_SP, x.r_usize(X2_SP) + sext(ci.imm))
+            x.w_usize(X2_SP, x.r_usize(X2_SP) + hart.sext(ci.imm))
         },
         Clui(ci) => {
-            x.w_isize(ci.rdrs1, sext(ci.imm))
+            x.w_isize(ci.rdrs1, hart.sext(ci.imm))
         },
         Csrli(ci) => {
             x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) >> shamt_c(ci.imm)?);
         },
         Csrli64(_ci) => { // c.srlid?
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
@@ -546,12 +1371,12 @@ fn exec_rvc<
             x.w_isize(ci.rdrs1, x.r_isize(ci.rdrs1) >> shamt_c(ci.imm)?);
         },
         Csrai64(_ci) => { // c.sraid?
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
-        Candi(ci) =>  x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) & sext(ci.imm)),
+        Candi(ci) =>  x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) & hart.sext(ci.imm)),
         Csub(ca) => {
             x.w_usize(ca.rdrs1, x.r_usize(ca.rdrs1) - x.r_usize(ca.rs2));
         },
@@ -570,60 +1395,64 @@ fn exec_rvc<
         Caddw(ca) => {
             x.w_sext32(ca.rdrs1, x.r_i32(ca.rdrs1).wrapping_add(x.r_i32(ca.rs2)))
         },
-        Cj(cj) => next_pc = pc + sext(cj.target),
+        Cj(cj) => next_pc = pc + hart.sext(cj.target),
         Cbeqz(cb) => {
             if x.r_usize(cb.rs1) == x.r_usize(0) {
-                next_pc = pc + sext(cb.off)
+                next_pc = pc + hart.sext(cb.off)
             }
         },
         Cbnez(cb) => {
             if x.r_usize(cb.rs1) != x.r_usize(0) {
-                next_pc = pc + sext(cb.off)
+                next_pc = pc + hart.sext(cb.off)
             }
         },
         Cslli(ci) => {
             x.w_usize(ci.rdrs1, x.r_usize(ci.rdrs1) << shamt_c(ci.imm)?);
         },
         Cslli64(_ci) => { // c.sllid?
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
-        Cfldsp(_ci) => {
-            if has_x128() || !has_f64() { // RV32DC or RV64DC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cfldsp(ci) => {
+            if hart.has_x128() || !hart.has_f64() { // RV32DC or RV64DC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("D extension")
+            let addr = x.r_usize(X2_SP) + hart.sext(ci.imm);
+            let data = data_mem.read_u64(addr)?;
+            f.w_u64_boxed(ci.rdrs1, data);
         },
         Clqsp(_ci) => {
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
         Clwsp(ci) => {
-            let addr = pc_to_mem_addr(x.r_usize(X2_SP) + sext(ci.imm));
+            let addr = x.r_usize(X2_SP) + hart.sext(ci.imm);
             let data = data_mem.read_i32(addr)?;
             x.w_sext32(ci.rdrs1, data);
         },
-        Cflwsp(_ci) => {
-            if !has_f32() || has_x64() || has_x128() { // RV32FC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cflwsp(ci) => {
+            if !hart.has_f32() || hart.has_x64() || hart.has_x128() { // RV32FC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("F extension")
+            let addr = x.r_usize(X2_SP) + hart.sext(ci.imm);
+            let data = data_mem.read_u32(addr)?;
+            f.w_u32_boxed(ci.rdrs1, data);
         },
         Cldsp(ci) => {
-            if !has_x64() || !has_x128() { // RV64C or RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x64() && !hart.has_x128() { // RV64C or RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            let addr = pc_to_mem_addr(x.r_usize(X2_SP) + sext(ci.imm));
+            let addr = x.r_usize(X2_SP) + hart.sext(ci.imm);
             let data = data_mem.read_i64(addr)?;
             x.w_sext64(ci.rdrs1, data);
         },
         Cjr(cr) => next_pc = x.r_usize(cr.rdrs1),
         Cmv(cr) => x.w_usize(cr.rdrs1, x.r_usize(cr.rs2)),
-        Cebreak(_cr) => todo!("ebreak"),
+        Cebreak(_cr) => return Err(Trap::new(Exception::Breakpoint, pc))?,
         Cjalr(cr) => {
             x.w_usize(X1_RA, next_pc);
             next_pc = x.r_usize(cr.rdrs1);
@@ -631,37 +1460,683 @@ fn exec_rvc<
         Cadd(cr) => {
             x.w_usize(cr.rdrs1, x.r_usize(cr.rdrs1) + x.r_usize(cr.rs2));
         },
-        Cfsdsp(_css) => {
-            if has_x128() || !has_f64() { // RV32DC or RV64DC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cfsdsp(css) => {
+            if hart.has_x128() || !hart.has_f64() { // RV32DC or RV64DC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("D extension")
+            data_mem.write_u64(
+                x.r_usize(X2_SP) + hart.sext(css.imm),
+                f.r_u64(css.rs2),
+            )?
         },
         Csqsp(_css) => {
-            if !has_x128() { // RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x128() { // RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             todo!("RV128I")
         },
         Cswsp(css) => data_mem.write_u32(
-            pc_to_mem_addr(x.r_usize(X2_SP) + sext(css.imm)),
+            x.r_usize(X2_SP) + hart.sext(css.imm),
             x.r_u32(css.rs2),
         )?,
-        Cfswsp(_css) => {
-            if !has_f32() || has_x64() || has_x128() { // RV32FC
-                return Err(ExecError::ExtensionNotSupported)?;
+        Cfswsp(css) => {
+            if !hart.has_f32() || hart.has_x64() || hart.has_x128() { // RV32FC
+                return Err(ExecError::ExtensionNotSupported.into());
             }
-            todo!("F extension")
+            data_mem.write_u32(
+                x.r_usize(X2_SP) + hart.sext(css.imm),
+                f.r_u32(css.rs2),
+            )?
         },
         Csdsp(css) => {
-            if !has_x64() || !has_x128() { // RV64C or RV128C
-                return Err(ExecError::ExtensionNotSupported)?;
+            if !hart.has_x64() && !hart.has_x128() { // RV64C or RV128C
+                return Err(ExecError::ExtensionNotSupported.into());
             }
             data_mem.write_u64(
-                pc_to_mem_addr(x.r_usize(X2_SP) + sext(css.imm)),
+                x.r_usize(X2_SP) + hart.sext(css.imm),
                 x.r_u64(css.rs2),
             )?;
         },
     }
     Ok(next_pc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::mem64::{Config, Endian, Physical, Protect};
+    use crate::plugin::{InsContext, InsPattern, InsResult, InstructionExtVTable};
+    use crate::riscv::InsExtension;
+    use crate::riscv::regfile::{
+        CSR_FFLAGS, CSR_MCAUSE, CSR_MCOUNTEREN, CSR_MEDELEG, CSR_MEPC, CSR_MIE, CSR_MISA,
+        CSR_MSTATUS, CSR_MTVAL, CSR_MTVEC, CSR_SCAUSE, CSR_SCOUNTEREN, CSR_SEPC, CSR_STVAL,
+        CSR_STVEC,
+    };
+
+    const BASE: u64 = 0x8000_0000;
+    // scratch memory after the code
+    const DATA: u64 = BASE + 0x1000;
+    // invalid operation and inexact in fflags
+    const FFLAGS_NV: u64 = 1 << 4;
+    const FFLAGS_NX: u64 = 1 << 0;
+    const S_ONE: u32 = 0x3F80_0000;
+    const S_SNAN: u32 = 0x7F80_0001;
+    const S_QNAN: u32 = 0x7FC0_0000;
+    const D_ONE: u64 = 0x3FF0_0000_0000_0000;
+    const D_SNAN: u64 = 0x7FF0_0000_0000_0001;
+    const D_QNAN: u64 = 0x7FF8_0000_0000_0000;
+    const MISA_F: u64 = 1 << 5;
+    const MISA_D: u64 = 1 << 3;
+    const MSTATUS_MIE: u64 = 1 << 3;
+    const MSTATUS_MPP: u64 = 0b11 << 11;
+    const MSTATUS_FS_INITIAL: u64 = 1 << 13;
+
+    struct Hart {
+        exec: Execute<'static>,
+        pc: Usize,
+        extensions: Vec<InsExtension>,
+    }
+
+    impl Hart {
+        // An RV64 hart in machine mode with `code` at BASE, followed by
+        // zeroed memory up to BASE + 0x2000
+        fn new(code: &[u32]) -> Hart {
+            let mut bytes: Vec<u8> = code.iter().flat_map(|ins| ins.to_le_bytes()).collect();
+            bytes.resize(0x2000, 0);
+            let config = Config {
+                range: BASE..(BASE + 0x2000),
+                protect: Protect::READ | Protect::WRITE | Protect::EXECUTE,
+                endian: Endian::Little,
+            };
+            let mem = Box::leak(Box::new(Physical::new()));
+            mem.push_owned(config, bytes);
+            Hart {
+                exec: Execute::new(mem, Xlen::X64),
+                pc: Usize::U64(BASE),
+                extensions: Vec::new(),
+            }
+        }
+
+        fn step(&mut self) -> Result<()> {
+            let translate = self.exec.translate();
+            let mut fetch = Fetch::new(Xlen::X64);
+            for extension in &self.extensions {
+                fetch.push_extension(*extension);
+            }
+            let ins = fetch.fetch(self.exec.bus(), self.pc, &translate)?;
+            self.pc = self.exec.execute(ins, self.pc)?;
+            Ok(())
+        }
+
+        // Executes `n` instructions, stopping at the first trap
+        fn run(&mut self, n: usize) -> Result<()> {
+            (0..n).try_for_each(|_| self.step())
+        }
+
+        fn x(&self, idx: u8) -> u64 {
+            match self.exec.x.r_usize(idx) {
+                Usize::U64(val) => val,
+                Usize::U32(val) => val as u64,
+            }
+        }
+
+        fn set_x(&mut self, idx: u8, val: u64) {
+            self.exec.x.w_usize(idx, Usize::U64(val));
+        }
+
+        // single precision value of a register, or the canonical NaN if it
+        // is not NaN-boxed
+        fn f(&self, idx: u8) -> u32 {
+            self.exec.f.r_u32_boxed(idx)
+        }
+
+        fn set_f(&mut self, idx: u8, val: u32) {
+            self.exec.f.w_u32_boxed(idx, val);
+        }
+
+        fn d(&self, idx: u8) -> u64 {
+            self.exec.f.r_u64_boxed(idx)
+        }
+
+        fn set_d(&mut self, idx: u8, val: u64) {
+            self.exec.f.w_u64_boxed(idx, val);
+        }
+
+        fn csr(&self, csr: u16) -> u64 {
+            match self.exec.csr.r_usize(csr).unwrap() {
+                Usize::U64(val) => val,
+                Usize::U32(val) => val as u64,
+            }
+        }
+
+        fn set_csr(&mut self, csr: u16, val: u64) {
+            self.exec.csr.w_usize(csr, Usize::U64(val)).unwrap();
+        }
+
+        // Drops to `privilege` with all memory accessible, as firmware does
+        // before entering its payload
+        fn enter(&mut self, privilege: Privilege) {
+            self.exec.open_pmp();
+            self.exec.privilege = privilege;
+        }
+
+        fn fflags(&self) -> u64 {
+            self.csr(CSR_FFLAGS)
+        }
+
+        // Executes the next instruction, which must raise an exception;
+        // returns its cause and trap value
+        fn trap(&mut self) -> (Exception, Usize) {
+            match self.step() {
+                Err(Error::Trap(trap)) => (trap.cause, trap.tval),
+                other => panic!("expected a trap, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn shift_right_logical_word() {
+        // srliw a0, a1, 4; srlw a2, a1, a3; srliw a4, a5, 0
+        let code: Vec<u8> = [0x0045_D51Bu32, 0x00D5_D63B, 0x0007_D71B]
+            .iter()
+            .flat_map(|ins| ins.to_le_bytes())
+            .collect();
+        let config = Config {
+            range: 0x8000_0000..0x8000_000C,
+            protect: Protect::READ | Protect::EXECUTE,
+            endian: Endian::Little,
+        };
+        let mem = Box::leak(Box::new(Physical::new()));
+        mem.push_owned(config, code);
+        let mut exec = Execute::new(mem, Xlen::X64);
+        exec.x.w_usize(11, Usize::U64(0xFFFF_FFFF_8765_4321));
+        exec.x.w_usize(13, Usize::U64(36));
+        exec.x.w_usize(15, Usize::U64(0x8000_0000));
+        for pc in (0x8000_0000..0x8000_000C).step_by(4) {
+            let pc = Usize::U64(pc);
+            let ins = Fetch::new(Xlen::X64).fetch(exec.bus(), pc, &exec.translate()).unwrap();
+            exec.execute(ins, pc).unwrap();
+        }
+        assert_eq!(exec.x.r_usize(10), Usize::U64(0x0876_5432));
+        assert_eq!(exec.x.r_usize(12), Usize::U64(0x0876_5432));
+        assert_eq!(exec.x.r_usize(14), Usize::U64(0xFFFF_FFFF_8000_0000));
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        // div a0, a1, a2; divu a3, a1, a2; rem a4, a1, a2; remu a5, a1, a2;
+        // divw a6, a1, a2
+        let code = [0x02C5_C533, 0x02C5_D6B3, 0x02C5_E733, 0x02C5_F7B3, 0x02C5_C83B];
+        let mut hart = Hart::new(&code);
+        hart.set_x(11, 1234);
+        hart.run(5).unwrap();
+        assert_eq!(hart.x(10), u64::MAX);
+        assert_eq!(hart.x(13), u64::MAX);
+        assert_eq!(hart.x(14), 1234);
+        assert_eq!(hart.x(15), 1234);
+        assert_eq!(hart.x(16), u64::MAX);
+    }
+
+    #[test]
+    fn divide_overflow() {
+        // div a0, a1, a2; rem a4, a1, a2
+        let mut hart = Hart::new(&[0x02C5_C533, 0x02C5_E733]);
+        hart.set_x(11, i64::MIN as u64);
+        hart.set_x(12, -1i64 as u64);
+        hart.run(2).unwrap();
+        assert_eq!(hart.x(10), i64::MIN as u64);
+        assert_eq!(hart.x(14), 0);
+        // divw a0, a1, a2; remw a3, a1, a2
+        let mut hart = Hart::new(&[0x02C5_C53B, 0x02C5_E6BB]);
+        hart.set_x(11, i32::MIN as u64);
+        hart.set_x(12, -1i64 as u64);
+        hart.run(2).unwrap();
+        assert_eq!(hart.x(10), i32::MIN as u64);
+        assert_eq!(hart.x(13), 0);
+    }
+
+    #[test]
+    fn multiply_high() {
+        // mulh a0, a1, a2; mulhu a3, a1, a2; mulhsu a4, a1, a2
+        let mut hart = Hart::new(&[0x02C5_9533, 0x02C5_B6B3, 0x02C5_A733]);
+        hart.set_x(11, -2i64 as u64);
+        hart.set_x(12, 3);
+        hart.run(3).unwrap();
+        assert_eq!(hart.x(10), u64::MAX);
+        assert_eq!(hart.x(13), 2);
+        assert_eq!(hart.x(14), u64::MAX);
+    }
+
+    #[test]
+    fn store_conditional_after_load_reserved() {
+        // lr.d a0, (a1); sc.d a3, a2, (a1)
+        let mut hart = Hart::new(&[0x1005_B52F, 0x18C5_B6AF]);
+        hart.exec.data_mem.write_u64(DATA, 7).unwrap();
+        hart.set_x(11, DATA);
+        hart.set_x(12, 9);
+        hart.run(2).unwrap();
+        assert_eq!(hart.x(10), 7);
+        assert_eq!(hart.x(13), 0);
+        assert_eq!(hart.exec.data_mem.read_u64(DATA).unwrap(), 9);
+        // a second SC finds the reservation taken by the first
+        hart = Hart::new(&[0x1005_B52F, 0x18C5_B6AF, 0x18C5_B6AF]);
+        hart.set_x(11, DATA);
+        hart.run(3).unwrap();
+        assert_eq!(hart.x(13), 1);
+    }
+
+    #[test]
+    fn store_clears_reservation() {
+        // lr.w a0, (a1); sc.w a4, a2, (a1)
+        let code = [0x1005_A52F, 0x18C5_A72F];
+        // a store next to the reserved word keeps the reservation
+        let mut hart = Hart::new(&code);
+        hart.set_x(11, DATA);
+        hart.set_x(12, 9);
+        hart.run(1).unwrap();
+        hart.exec.data_mem.write_u32(DATA + 4, 5).unwrap();
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(14), 0);
+        // one to the reserved word, from another hart or a device, clears it
+        hart = Hart::new(&code);
+        hart.set_x(11, DATA);
+        hart.set_x(12, 9);
+        hart.run(1).unwrap();
+        hart.exec.data_mem.write_u32(DATA, 5).unwrap();
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(14), 1);
+        assert_eq!(hart.exec.data_mem.read_u32(DATA).unwrap(), 5);
+    }
+
+    #[test]
+    fn atomic_memory_operations() {
+        // amoadd.d a5, a2, (a1); amoswap.w a5, a2, (a1)
+        let mut hart = Hart::new(&[0x00C5_B7AF, 0x08C5_A7AF]);
+        hart.exec.data_mem.write_u64(DATA, 0xFFFF_FFFF).unwrap();
+        hart.set_x(11, DATA);
+        hart.set_x(12, 1);
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(15), 0xFFFF_FFFF);
+        assert_eq!(hart.exec.data_mem.read_u64(DATA).unwrap(), 0x1_0000_0000);
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(15), 0);
+        assert_eq!(hart.exec.data_mem.read_u64(DATA).unwrap(), 0x1_0000_0001);
+    }
+
+    #[test]
+    fn single_min_max_signaling_nan() {
+        // fmin.s fa0, fa1, fa2; fmax.s fa3, fa1, fa2
+        let code = [0x28C5_8553, 0x28C5_96D3];
+        // a signaling NaN is ignored like a quiet one, but raises invalid operation
+        let mut hart = Hart::new(&code);
+        hart.set_f(11, S_SNAN);
+        hart.set_f(12, S_ONE);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), S_ONE);
+        assert_eq!(hart.f(13), S_ONE);
+        assert_eq!(hart.fflags(), FFLAGS_NV);
+        // a quiet NaN raises nothing
+        let mut hart = Hart::new(&code);
+        hart.set_f(11, S_QNAN | 1);
+        hart.set_f(12, S_ONE);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), S_ONE);
+        assert_eq!(hart.fflags(), 0);
+        // two NaNs give the canonical NaN
+        let mut hart = Hart::new(&code);
+        hart.set_f(11, S_SNAN);
+        hart.set_f(12, S_QNAN | 1);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), S_QNAN);
+        assert_eq!(hart.f(13), S_QNAN);
+        // -0 is less than +0
+        let mut hart = Hart::new(&code);
+        hart.set_f(11, 0);
+        hart.set_f(12, 0x8000_0000);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), 0x8000_0000);
+        assert_eq!(hart.f(13), 0);
+    }
+
+    #[test]
+    fn single_nan_boxing() {
+        // fmv.w.x fa0, a1; fadd.s fa4, fa1, fa2; fmv.x.w a0, fa0
+        let mut hart = Hart::new(&[0xF005_8553, 0x00C5_F753, 0xE005_0553]);
+        hart.set_x(11, 0x8000_0000_BF80_0000);
+        // fa1 is zero from reset, which is not NaN-boxed and reads as the
+        // canonical NaN
+        hart.set_f(12, S_ONE);
+        hart.run(3).unwrap();
+        assert_eq!(hart.f(10), 0xBF80_0000);
+        assert_eq!(hart.f(14), S_QNAN);
+        assert_eq!(hart.x(10), 0xFFFF_FFFF_BF80_0000);
+    }
+
+    #[test]
+    fn single_rounding_modes() {
+        // fadd.s fa0, fa1, fa2, rdn; fadd.s fa3, fa1, fa2, rup
+        let mut hart = Hart::new(&[0x00C5_A553, 0x00C5_B6D3]);
+        // 1 + 2^-25 lies between 1 and the next single
+        hart.set_f(11, S_ONE);
+        hart.set_f(12, 0x3300_0000);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), S_ONE);
+        assert_eq!(hart.f(13), S_ONE + 1);
+        assert_eq!(hart.fflags(), FFLAGS_NX);
+        // fcvt.w.s a0, fa1, rtz; fcvt.w.s a1, fa1, rne
+        let mut hart = Hart::new(&[0xC005_9553, 0xC005_85D3]);
+        hart.set_f(11, 0xBFC0_0000);
+        hart.run(2).unwrap();
+        assert_eq!(hart.x(10), -1i64 as u64);
+        assert_eq!(hart.x(11), -2i64 as u64);
+    }
+
+    #[test]
+    fn double_min_max_signaling_nan() {
+        // fmin.d fa0, fa1, fa2; fmax.d fa3, fa1, fa2
+        let code = [0x2AC5_8553, 0x2AC5_96D3];
+        let mut hart = Hart::new(&code);
+        hart.set_d(11, D_SNAN);
+        hart.set_d(12, D_ONE);
+        hart.run(2).unwrap();
+        assert_eq!(hart.d(10), D_ONE);
+        assert_eq!(hart.d(13), D_ONE);
+        assert_eq!(hart.fflags(), FFLAGS_NV);
+        let mut hart = Hart::new(&code);
+        hart.set_d(11, D_QNAN | 1);
+        hart.set_d(12, D_SNAN);
+        hart.run(2).unwrap();
+        assert_eq!(hart.d(10), D_QNAN);
+        assert_eq!(hart.d(13), D_QNAN);
+    }
+
+    #[test]
+    fn convert_between_precisions() {
+        // fcvt.s.d fa0, fa1; fcvt.d.s fa3, fa2
+        let mut hart = Hart::new(&[0x4015_F553, 0x4206_06D3]);
+        hart.set_d(11, D_ONE);
+        hart.set_f(12, S_SNAN);
+        hart.run(2).unwrap();
+        assert_eq!(hart.f(10), S_ONE);
+        assert_eq!(hart.d(13), D_QNAN);
+        assert_eq!(hart.fflags(), FFLAGS_NV);
+    }
+
+    #[test]
+    fn misaligned_atomics_trap() {
+        // lr.d a0, (a1); amoadd.d a5, a2, (a1)
+        let mut hart = Hart::new(&[0x1005_B52F, 0x00C5_B7AF]);
+        hart.set_x(11, DATA + 4);
+        assert_eq!(hart.trap(), (Exception::LoadAddressMisaligned, Usize::U64(DATA + 4)));
+        hart.pc = Usize::U64(BASE + 4);
+        assert_eq!(hart.trap(), (Exception::StoreAddressMisaligned, Usize::U64(DATA + 4)));
+    }
+
+    #[test]
+    fn environment_call_and_breakpoint() {
+        // fence; ecall; ebreak
+        let mut hart = Hart::new(&[0x0FF0_000F, 0x0000_0073, 0x0010_0073]);
+        hart.run(1).unwrap();
+        assert_eq!(hart.pc, Usize::U64(BASE + 4));
+        let ecall = (Exception::EnvironmentCallFromMMode, Usize::U64(0));
+        assert_eq!(hart.trap(), ecall);
+        // the trap leaves the hart untouched
+        assert_eq!(hart.pc, Usize::U64(BASE + 4));
+        hart.pc = Usize::U64(BASE + 8);
+        assert_eq!(hart.trap(), (Exception::Breakpoint, Usize::U64(BASE + 8)));
+    }
+
+    #[test]
+    fn float_off_is_illegal() {
+        // fadd.s fa4, fa1, fa2
+        let mut hart = Hart::new(&[0x00C5_F753]);
+        hart.set_csr(CSR_MSTATUS, 0);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.set_csr(CSR_MSTATUS, MSTATUS_FS_INITIAL);
+        hart.run(1).unwrap();
+    }
+
+    #[test]
+    fn misa_turns_float_extensions_off() {
+        // fadd.d fa4, fa1, fa2; fadd.s fa4, fa1, fa2
+        let code = [0x02C5_F753, 0x00C5_F753];
+        let mut hart = Hart::new(&code);
+        let misa = hart.csr(CSR_MISA);
+        assert_eq!(misa & (MISA_F | MISA_D), MISA_F | MISA_D);
+        // without D only double precision is illegal
+        hart.set_csr(CSR_MISA, misa & !MISA_D);
+        assert!(!hart.exec.has_extension('D'));
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.pc = Usize::U64(BASE + 4);
+        hart.run(1).unwrap();
+        // D depends on F, so clearing F alone turns off both
+        let mut hart = Hart::new(&code);
+        hart.set_csr(CSR_MISA, misa & !MISA_F);
+        assert_eq!(hart.csr(CSR_MISA) & (MISA_F | MISA_D), 0);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.pc = Usize::U64(BASE + 4);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        // and both can be turned on again
+        hart.set_csr(CSR_MISA, misa);
+        hart.pc = Usize::U64(BASE);
+        hart.run(2).unwrap();
+    }
+
+    #[test]
+    fn illegal_instruction_trap_value() {
+        // an instruction of the custom-0 space with no extension to decode it
+        let mut hart = Hart::new(&[0x1234_500B]);
+        assert_eq!(hart.trap(), (Exception::IllegalInstruction, Usize::U64(0x1234_500B)));
+    }
+
+    #[test]
+    fn csr_access_checks_privilege() {
+        // csrr a0, mstatus; csrr a0, sstatus
+        let code = [0x3000_2573, 0x1000_2573];
+        let mut hart = Hart::new(&code);
+        hart.enter(Privilege::Supervisor);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.pc = Usize::U64(BASE + 4);
+        hart.run(1).unwrap();
+        let mut hart = Hart::new(&code[1..]);
+        hart.enter(Privilege::User);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        // csrr a0, mvendorid; csrw mvendorid, a1
+        let mut hart = Hart::new(&[0xF110_2573, 0xF115_9073]);
+        hart.run(1).unwrap();
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+    }
+
+    #[test]
+    fn counters() {
+        // rdinstret a5; rdtime a5; rdcycle a5
+        let code = [0xC020_27F3, 0xC010_27F3, 0xC000_27F3];
+        let mut hart = Hart::new(&code);
+        hart.exec.set_time(0x1_2345_6789);
+        hart.run(3).unwrap();
+        assert_eq!(hart.x(15), 2);
+        hart.pc = Usize::U64(BASE + 4);
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(15), 0x1_2345_6789);
+        // below M-mode each counter needs its bit in mcounteren, and in
+        // scounteren as well for U-mode
+        let mut hart = Hart::new(&code[1..]);
+        hart.enter(Privilege::Supervisor);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.set_csr(CSR_MCOUNTEREN, 0b010);
+        hart.run(1).unwrap();
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        let mut hart = Hart::new(&code[1..]);
+        hart.set_csr(CSR_MCOUNTEREN, 0b111);
+        hart.enter(Privilege::User);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+        hart.set_csr(CSR_SCOUNTEREN, 0b011);
+        hart.run(2).unwrap();
+        // counters are read-only
+        assert!(hart.exec.write_csr(0xC01, Usize::U64(0)).is_err());
+    }
+
+    #[test]
+    fn trap_entry_and_machine_return() {
+        // mret at BASE and at the handler
+        let mut code = [0; 0x41];
+        code[0] = 0x3020_0073;
+        code[0x40] = 0x3020_0073;
+        let mut hart = Hart::new(&code);
+        hart.set_csr(CSR_MTVEC, BASE + 0x100);
+        hart.enter(Privilege::Supervisor);
+        let trap = Trap::new(Exception::IllegalInstruction, Usize::U64(0x1234));
+        hart.pc = hart.exec.handle_trap(trap, Usize::U64(BASE));
+        assert_eq!(hart.pc, Usize::U64(BASE + 0x100));
+        assert_eq!(hart.exec.privilege(), Privilege::Machine);
+        assert_eq!(hart.csr(CSR_MEPC), BASE);
+        assert_eq!(hart.csr(CSR_MCAUSE), 2);
+        assert_eq!(hart.csr(CSR_MTVAL), 0x1234);
+        assert_eq!(hart.csr(CSR_MSTATUS) & MSTATUS_MPP, 0b01 << 11);
+        hart.run(1).unwrap();
+        assert_eq!(hart.pc, Usize::U64(BASE));
+        assert_eq!(hart.exec.privilege(), Privilege::Supervisor);
+        // mret below machine mode
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+    }
+
+    #[test]
+    fn environment_call_cause_follows_privilege() {
+        // ecall
+        let causes = [
+            (Privilege::Machine, Exception::EnvironmentCallFromMMode),
+            (Privilege::Supervisor, Exception::EnvironmentCallFromSMode),
+            (Privilege::User, Exception::EnvironmentCallFromUMode),
+        ];
+        for &(privilege, cause) in causes.iter() {
+            let mut hart = Hart::new(&[0x0000_0073]);
+            hart.enter(privilege);
+            assert_eq!(hart.trap(), (cause, Usize::U64(0)));
+        }
+    }
+
+    #[test]
+    fn delegated_traps_enter_supervisor() {
+        // ecall; sret
+        let mut hart = Hart::new(&[0x0000_0073, 0x1020_0073]);
+        hart.set_csr(CSR_MTVEC, BASE + 0x100);
+        hart.set_csr(CSR_STVEC, BASE + 0x200);
+        // delegate environment calls from user mode
+        hart.set_csr(CSR_MEDELEG, 1 << 8);
+        hart.enter(Privilege::User);
+        let (cause, tval) = hart.trap();
+        let next_pc = hart.exec.handle_trap(Trap::new(cause, tval), hart.pc);
+        assert_eq!(next_pc, Usize::U64(BASE + 0x200));
+        assert_eq!(hart.exec.privilege(), Privilege::Supervisor);
+        assert_eq!(hart.csr(CSR_SCAUSE), 8);
+        assert_eq!(hart.csr(CSR_SEPC), BASE);
+        assert_eq!(hart.csr(CSR_STVAL), 0);
+        // a cause that is not delegated goes to machine mode
+        hart.enter(Privilege::User);
+        let trap = Trap::new(Exception::IllegalInstruction, Usize::U64(0));
+        let next_pc = hart.exec.handle_trap(trap, Usize::U64(BASE + 4));
+        assert_eq!(next_pc, Usize::U64(BASE + 0x100));
+        assert_eq!(hart.exec.privilege(), Privilege::Machine);
+        assert_eq!(hart.csr(CSR_MCAUSE), 2);
+        assert_eq!(hart.csr(CSR_SCAUSE), 8);
+        // traps from machine mode are never delegated
+        hart.set_csr(CSR_MEDELEG, 1 << 11);
+        let trap = Trap::new(Exception::EnvironmentCallFromMMode, Usize::U64(0));
+        let next_pc = hart.exec.handle_trap(trap, Usize::U64(BASE));
+        assert_eq!(next_pc, Usize::U64(BASE + 0x100));
+        assert_eq!(hart.csr(CSR_MCAUSE), 11);
+        // sret from user mode
+        hart.enter(Privilege::User);
+        hart.pc = Usize::U64(BASE + 4);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+    }
+
+    #[test]
+    fn timer_interrupt_needs_enable() {
+        let mut hart = Hart::new(&[]);
+        hart.set_csr(CSR_MTVEC, BASE + 0x100);
+        hart.exec.set_interrupt_pending(Interrupt::MachineTimer, true);
+        assert_eq!(hart.exec.take_interrupt(Usize::U64(BASE)), None);
+        hart.set_csr(CSR_MIE, 1 << 7);
+        assert_eq!(hart.exec.take_interrupt(Usize::U64(BASE)), None);
+        // machine interrupts are always enabled below machine mode
+        hart.enter(Privilege::User);
+        assert_eq!(hart.exec.take_interrupt(Usize::U64(BASE)), Some(Usize::U64(BASE + 0x100)));
+        assert_eq!(hart.csr(CSR_MCAUSE), 1 << 63 | 7);
+        assert_eq!(hart.csr(CSR_MEPC), BASE);
+        // and in machine mode with mstatus.MIE, which trap entry has cleared
+        assert_eq!(hart.exec.take_interrupt(Usize::U64(BASE + 0x100)), None);
+        hart.set_csr(CSR_MSTATUS, MSTATUS_MIE);
+        assert_eq!(hart.exec.take_interrupt(Usize::U64(BASE)), Some(Usize::U64(BASE + 0x100)));
+    }
+
+    // custom-0 with funct3 = 0: rd = mem64[rs1] + rs2, skipping the next
+    // instruction if the sum is zero; rd = zero returns a result out of range
+    const ADD_LOAD_PATTERNS: [InsPattern; 1] = [InsPattern { mask: 0x707F, value: 0x0B }];
+
+    extern "C" fn add_load_decode(ins: u32, _xlen_bytes: u32, payload_out: *mut u64) -> bool {
+        unsafe { *payload_out = ins as u64 };
+        true
+    }
+
+    extern "C" fn add_load_execute(payload: u64, context: *mut InsContext) -> u8 {
+        let context = unsafe { &mut *context };
+        let ins = payload as u32;
+        let (rd, rs1, rs2) = ((ins >> 7) & 0x1F, (ins >> 15) & 0x1F, (ins >> 20) & 0x1F);
+        if rd == 0 {
+            return 7;
+        }
+        let addr = (context.read_x)(context.this, rs1);
+        let mut val = 0;
+        if (context.read_mem)(context.this, addr, 8, &mut val) != InsResult::Ok {
+            return InsResult::Trap as u8;
+        }
+        let sum = val.wrapping_add((context.read_x)(context.this, rs2));
+        (context.write_x)(context.this, rd, sum);
+        if sum == 0 {
+            context.next_pc = context.pc + 8;
+        }
+        InsResult::Ok as u8
+    }
+
+    fn add_load_extension() -> InsExtension {
+        InsExtension::new(&InstructionExtVTable {
+            api_version: crate::plugin::INSTRUCTION_EXT_API_VERSION,
+            patterns: ADD_LOAD_PATTERNS.as_ptr(),
+            patterns_len: ADD_LOAD_PATTERNS.len(),
+            decode: add_load_decode,
+            execute: add_load_execute,
+        })
+    }
+
+    #[test]
+    fn instruction_extension() {
+        let mut hart = Hart::new(&[
+            0x00C5_868B, // custom-0 x13, x11, x12
+            0x00C5_868B, // custom-0 x13, x11, x12
+            0x0000_0013, // nop, skipped
+            0x00C5_968B, // custom-0 funct3 = 1, not an instruction of the extension
+            0x00C5_868B, // custom-0 x13, x11, x12
+            0x00C5_800B, // custom-0 x0, x11, x12
+        ]);
+        hart.extensions.push(add_load_extension());
+        hart.exec.data_mem.write_u64(DATA, 5).unwrap();
+        hart.set_x(11, DATA);
+        hart.set_x(12, 2);
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(13), 7);
+        hart.set_x(12, 5u64.wrapping_neg());
+        hart.run(1).unwrap();
+        assert_eq!(hart.x(13), 0);
+        assert_eq!(hart.pc, Usize::U64(BASE + 12));
+        assert_eq!(hart.trap(), (Exception::IllegalInstruction, Usize::U64(0x00C5_968B)));
+        // exceptions of callbacks are raised by the instruction
+        hart.pc = Usize::U64(BASE + 16);
+        hart.set_x(11, 0x10);
+        assert_eq!(hart.trap(), (Exception::LoadAccessFault, Usize::U64(0x10)));
+        // results out of range make the instruction illegal
+        hart.pc = Usize::U64(BASE + 20);
+        assert_eq!(hart.trap().0, Exception::IllegalInstruction);
+    }
+}
@@ -1,58 +1,78 @@
 use super::imm::{Imm, Uimm};
-use super::Xlen;
+use super::custom::{Custom, InsExtension};
+use super::mmu::{Access, Translate};
+use super::{Exception, Trap, Xlen};
 use crate::error::Result;
-use crate::mem64::Physical;
+use crate::memory::Bus;
 use crate::size::Usize;
-use thiserror::Error;
 
-fn pc_to_mem_addr(pc: Usize) -> u64 {
-    match pc {
-        Usize::U32(a) => a as u64,
-        Usize::U64(a) => a,
-    }
-}
-
-pub struct Fetch<'a> {
-    mem: &'a Physical<'a>,
+pub struct Fetch {
     xlen: Xlen,
+    extensions: Vec<InsExtension>,
 }
 
-impl<'a> Fetch<'a> {
-    pub fn new(mem: &'a Physical<'a>, xlen: Xlen) -> Self {
-        Fetch { mem, xlen }
+impl Fetch {
+    pub fn new(xlen: Xlen) -> Self {
+        Fetch {
+            xlen,
+            extensions: Vec::new(),
+        }
     }
 
-    pub fn fetch(&mut self, mut pc: Usize) -> Result<Instruction> {
-        let addr = pc;
-        let ins = self.next_u16(&mut pc)?;
+    // Vendor extensions only see instructions the standard decoder rejects, in
+    // the order they were added
+    pub fn push_extension(&mut self, extension: InsExtension) {
+        self.extensions.push(extension);
+    }
+
+    // Illegal encodings raise an illegal instruction exception with the instruction
+    // bits as trap value; failing to read the instruction is an access fault.
+    // Each 16-bit parcel is translated on its own, as it may lie on another page
+    pub fn fetch(
+        &mut self,
+        mem: &dyn Bus,
+        mut pc: Usize,
+        translate: &Translate,
+    ) -> Result<Instruction> {
+        let ins = next_u16(mem, &mut pc, translate)?;
         if ins & 0b11 != 0b11 {
-            return resolve_u16(ins, self.xlen)
-                    .map_err(|_| FetchError::IllegalInstruction16 { addr, ins }.into());
+            return resolve_u16(ins, self.xlen).map_err(|_| self.illegal(ins as u32).into());
         }
         if ins & 0b11100 != 0b11100 {
-            let ins = (ins as u32) + ((self.next_u16(&mut pc)? as u32) << 16);
+            let ins = (ins as u32) + ((next_u16(mem, &mut pc, translate)? as u32) << 16);
             return resolve_u32(ins, self.xlen)
-                    .map_err(|_| FetchError::IllegalInstruction32 { addr, ins }.into());
+                .or_else(|_| self.resolve_custom(ins))
+                .map_err(|_| self.illegal(ins).into());
         }
-        Err(FetchError::InstructionLength { addr })?
+        // instructions longer than 32 bits are not supported
+        Err(self.illegal(ins as u32))?
+    }
+
+    fn resolve_custom(&self, ins: u32) -> core::result::Result<Instruction, ()> {
+        self.extensions
+            .iter()
+            .find_map(|extension| extension.decode(ins, self.xlen))
+            .map(Instruction::Custom)
+            .ok_or(())
     }
 
-    fn next_u16(&mut self, pc: &mut Usize) -> Result<u16> {
-        let addr = pc_to_mem_addr(*pc);
-        let ans = self.mem.fetch_ins_u16(addr);
-        *pc += 2;
-        ans
+    fn illegal(&self, ins: u32) -> Trap {
+        let tval = match self.xlen {
+            Xlen::X32 => Usize::U32(ins),
+            _ => Usize::U64(ins as u64),
+        };
+        Trap::new(Exception::IllegalInstruction, tval)
     }
 }
 
-#[derive(Error, Clone, Debug)]
-pub enum FetchError {
-    #[error("Illegal 16-bit instruction 0x{ins:04X} at address: 0x{addr:X} ")]
-    IllegalInstruction16 { addr: Usize, ins: u16 },
-    #[error("Illegal 32-bit instruction 0x{ins:08X} at address: 0x{addr:X} ")]
-    IllegalInstruction32 { addr: Usize, ins: u32 },
-    #[error("Illegal instruction at address: 0x{addr:X}; length over 32-bit is not supported")]
-    InstructionLength { addr: Usize },
+fn next_u16(mem: &dyn Bus, pc: &mut Usize, translate: &Translate) -> Result<u16> {
+    let addr = translate.translate(mem, *pc, Access::Fetch)?;
+    translate.check(addr, 2, *pc, Access::Fetch)?;
+    let ans = mem
+        .fetch_ins_u16(addr)
+        .map_err(|_| Trap::new(Exception::InstructionAccessFault, *pc).into());
+    *pc += 2;
+    ans
 }
 
 const OPCODE_C0: u16 = 0b00;
@@ -79,14 +99,14 @@ fn resolve_u16(ins: u16, xlen: Xlen) -> core::result::Result<Instruction, ()> {
     let nzuimm540 = ((ins >> 2) & 0b11111) | (((ins >> 12) & 0b1) << 5);
     let nzimm540 = nzuimm540;
     let imm540 = nzuimm540;
-    let imm114981067315 = (((ins >> 3) & 0b11) << 1)
-        | (((ins >> 11) & 0b1) << 3)
-        | (((ins >> 2) & 0b1) << 4)
-        | (((ins >> 7) & 0b1) << 5)
-        | (((ins >> 6) & 0b1) << 6)
+    let imm114981067315 = (((ins >> 3) & 0b111) << 1)
+        | (((ins >> 11) & 0b1) << 4)
+        | (((ins >> 2) & 0b1) << 5)
+        | (((ins >> 7) & 0b1) << 6)
+        | (((ins >> 6) & 0b1) << 7)
         | (((ins >> 9) & 0b11) << 8)
-        | (((ins >> 8) & 0b1) << 9)
-        | (((ins >> 11) & 0b1) << 10);
+        | (((ins >> 8) & 0b1) << 10)
+        | (((ins >> 12) & 0b1) << 11);
     let nzimm946875 = (((ins >> 12) & 0b1) << 9)
         | (((ins >> 6) & 0b1) << 4)
         | (((ins >> 5) & 0b1) << 6)
@@ -427,6 +447,7 @@ const OPCODE_AUIPC: u32     = 0b001_0111;
 const OPCODE_OP_IMM32: u32  = 0b001_1011;
 const OPCODE_STORE: u32     = 0b010_0011;
 const OPCODE_STORE_FP: u32  = 0b010_0111;
+const OPCODE_AMO: u32       = 0b010_1111;
 const OPCODE_OP: u32        = 0b011_0011;
 const OPCODE_LUI: u32       = 0b011_0111;
 const OPCODE_OP_32: u32     = 0b011_1011;
@@ -475,6 +496,17 @@ const FUNCT7_OP_SRA: u8 = 0b010_0000;
 const FUNCT7_OP_ADD: u8 = 0b000_0000;
 const FUNCT7_OP_SUB: u8 = 0b010_0000;
 
+const FUNCT7_OP_MULDIV: u8 = 0b000_0001;
+
+const FUNCT3_OP_MUL: u8 = 0b000;
+const FUNCT3_OP_MULH: u8 = 0b001;
+const FUNCT3_OP_MULHSU: u8 = 0b010;
+const FUNCT3_OP_MULHU: u8 = 0b011;
+const FUNCT3_OP_DIV: u8 = 0b100;
+const FUNCT3_OP_DIVU: u8 = 0b101;
+const FUNCT3_OP_REM: u8 = 0b110;
+const FUNCT3_OP_REMU: u8 = 0b111;
+
 const FUNCT3_SYSTEM_PRIV: u8 = 0b000;
 const FUNCT3_SYSTEM_CSRRW: u8 = 0b001;
 const FUNCT3_SYSTEM_CSRRS: u8 = 0b010;
@@ -485,12 +517,30 @@ const FUNCT3_SYSTEM_CSRRCI: u8 = 0b111;
 
 const FUNCT12_SYSTEM_ECALL: u32 = 0b000;
 const FUNCT12_SYSTEM_EBREAK: u32 = 0b001;
+const FUNCT7_SYSTEM_SFENCE_VMA: u8 = 0b000_1001;
+const FUNCT12_SYSTEM_SRET: u32 = 0b0001_0000_0010;
+const FUNCT12_SYSTEM_MRET: u32 = 0b0011_0000_0010;
+const FUNCT12_SYSTEM_WFI: u32 = 0b0001_0000_0101;
 
 const FUNCT3_MISC_MEM_FENCE: u8 = 0b000;
 
 const FUNCT3_WIDTH_W: u8 = 0b010;
+const FUNCT3_WIDTH_D: u8 = 0b011;
+
+const FUNCT5_AMO_ADD: u8 = 0b00000;
+const FUNCT5_AMO_SWAP: u8 = 0b00001;
+const FUNCT5_AMO_LR: u8 = 0b00010;
+const FUNCT5_AMO_SC: u8 = 0b00011;
+const FUNCT5_AMO_XOR: u8 = 0b00100;
+const FUNCT5_AMO_OR: u8 = 0b01000;
+const FUNCT5_AMO_AND: u8 = 0b01100;
+const FUNCT5_AMO_MIN: u8 = 0b10000;
+const FUNCT5_AMO_MAX: u8 = 0b10100;
+const FUNCT5_AMO_MINU: u8 = 0b11000;
+const FUNCT5_AMO_MAXU: u8 = 0b11100;
 
 const FUNCT2_FMT_S: u8 = 0b00;
+const FUNCT2_FMT_D: u8 = 0b01;
 
 const FUNCT_RS3_FP_ADD: u8 = 0b00000;
 const FUNCT_RS3_FP_SUB: u8 = 0b00001;
@@ -498,6 +548,7 @@ const FUNCT_RS3_FP_MUL: u8 = 0b00010;
 const FUNCT_RS3_FP_DIV: u8 = 0b00011;
 const FUNCT_RS3_FP_SGNJ: u8 = 0b00100;
 const FUNCT_RS3_FP_MIN_MAX: u8 = 0b00101;
+const FUNCT_RS3_FP_FCVTFF: u8 = 0b01000; // fcvt.s.d, fcvt.d.s
 const FUNCT_RS3_FP_SQRT: u8 = 0b01011;
 const FUNCT_RS3_FP_CMP: u8 = 0b10100;
 const FUNCT_RS3_FP_FCVTX: u8 = 0b11000; // fcvt.{w|l}[u].s, fcvt.int.fmt
@@ -521,8 +572,11 @@ const FUNCT_RS2_CVT_WU: u8 = 0b00001;
 const FUNCT_RS2_CVT_L: u8 = 0b00010;
 const FUNCT_RS2_CVT_LU: u8 = 0b00011;
 
+const FUNCT_RS2_CVT_S: u8 = 0b00000;
+const FUNCT_RS2_CVT_D: u8 = 0b00001;
+
 fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
-    use {self::RVZicsr::*, self::RV32I::*, self::RV64I::*, self::RVF::*};
+    use {self::RVZicsr::*, self::RVPriv::*, self::RV32I::*, self::RV64I::*, self::RVM::*, self::RVA::*, self::RVF::*, self::RVD::*};
     let opcode = ins & 0b111_1111;
     let rd = ((ins >> 7) & 0b1_1111) as u8;
     let rs1 = ((ins >> 15) & 0b1_1111) as u8;
@@ -532,6 +586,7 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
     let funct12 = (ins >> 20) & 0b1111_1111_1111;
     let rs3 = ((ins >> 27) & 0b1_1111) as u8;
     let funct2 = ((ins >> 25) & 0b11) as u8;
+    let funct5 = ((ins >> 27) & 0b1_1111) as u8;
     let imm_i = {
         let val = (ins >> 20) & 0b1111_1111_1111;
         Imm::new(val, 12)
@@ -604,6 +659,14 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
         funct3,
         funct2,
     };
+    let a_type = AType {
+        rd,
+        rs1,
+        rs2,
+        funct3,
+        aq: ins & (1 << 26) != 0,
+        rl: ins & (1 << 25) != 0,
+    };
     let ans = match opcode {
         OPCODE_LUI => Lui(u_type).into(),
         OPCODE_AUIPC => Auipc(u_type).into(),
@@ -647,6 +710,10 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
                 FUNCT12_SYSTEM_EBREAK if funct3 == FUNCT3_SYSTEM_PRIV && rs1 == 0 && rd == 0 => {
                     Ebreak(i_type).into()
                 }
+                FUNCT12_SYSTEM_SRET if rs1 == 0 && rd == 0 => Sret(i_type).into(),
+                FUNCT12_SYSTEM_MRET if rs1 == 0 && rd == 0 => Mret(i_type).into(),
+                FUNCT12_SYSTEM_WFI if rs1 == 0 && rd == 0 => Wfi(i_type).into(),
+                _ if funct7 == FUNCT7_SYSTEM_SFENCE_VMA && rd == 0 => SfenceVma(r_type).into(),
                 _ => Err(())?,
             },
             FUNCT3_SYSTEM_CSRRW => Csrrw(csr_r_type).into(),
@@ -681,6 +748,17 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             },
             _ => Err(())?,
         },
+        OPCODE_OP if funct7 == FUNCT7_OP_MULDIV => match funct3 {
+            FUNCT3_OP_MUL => Mul(r_type).into(),
+            FUNCT3_OP_MULH => Mulh(r_type).into(),
+            FUNCT3_OP_MULHSU => Mulhsu(r_type).into(),
+            FUNCT3_OP_MULHU => Mulhu(r_type).into(),
+            FUNCT3_OP_DIV => Div(r_type).into(),
+            FUNCT3_OP_DIVU => Divu(r_type).into(),
+            FUNCT3_OP_REM => Rem(r_type).into(),
+            FUNCT3_OP_REMU => Remu(r_type).into(),
+            _ => Err(())?,
+        },
         OPCODE_OP => match funct3 {
             FUNCT3_OP_ADD_SUB => match funct7 {
                 FUNCT7_OP_ADD => Add(r_type).into(),
@@ -715,6 +793,14 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             },
             _ => Err(())?,
         },
+        OPCODE_OP_32 if xlen == Xlen::X64 && funct7 == FUNCT7_OP_MULDIV => match funct3 {
+            FUNCT3_OP_MUL => Mulw(r_type).into(),
+            FUNCT3_OP_DIV => Divw(r_type).into(),
+            FUNCT3_OP_DIVU => Divuw(r_type).into(),
+            FUNCT3_OP_REM => Remw(r_type).into(),
+            FUNCT3_OP_REMU => Remuw(r_type).into(),
+            _ => Err(())?,
+        },
         OPCODE_OP_32 if xlen == Xlen::X64 => match funct3 {
             FUNCT3_OP_ADD_SUB => match funct7 {
                 FUNCT7_OP_ADD => Addw(r_type).into(),
@@ -729,58 +815,102 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             },
             _ => Err(())?,
         },
+        OPCODE_AMO => match (funct3, funct5) {
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_LR) if rs2 == 0 => LrW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_SC) => ScW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_SWAP) => AmoswapW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_ADD) => AmoaddW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_XOR) => AmoxorW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_AND) => AmoandW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_OR) => AmoorW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_MIN) => AmominW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_MAX) => AmomaxW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_MINU) => AmominuW(a_type).into(),
+            (FUNCT3_WIDTH_W, FUNCT5_AMO_MAXU) => AmomaxuW(a_type).into(),
+            (FUNCT3_WIDTH_D, _) if xlen == Xlen::X32 => Err(())?,
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_LR) if rs2 == 0 => LrD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_SC) => ScD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_SWAP) => AmoswapD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_ADD) => AmoaddD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_XOR) => AmoxorD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_AND) => AmoandD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_OR) => AmoorD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_MIN) => AmominD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_MAX) => AmomaxD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_MINU) => AmominuD(a_type).into(),
+            (FUNCT3_WIDTH_D, FUNCT5_AMO_MAXU) => AmomaxuD(a_type).into(),
+            _ => Err(())?,
+        },
         OPCODE_LOAD_FP => match funct3 {
             FUNCT3_WIDTH_W => Flw(i_type).into(),
+            FUNCT3_WIDTH_D => Fld(i_type).into(),
             _ => Err(())?
         },
         OPCODE_STORE_FP => match funct3 {
             FUNCT3_WIDTH_W => Fsw(s_type).into(),
+            FUNCT3_WIDTH_D => Fsd(s_type).into(),
             _ => Err(())?
         },
         OPCODE_FMADD => match funct2 {
             FUNCT2_FMT_S => Fmadds(r4_type).into(),
+            FUNCT2_FMT_D => Fmaddd(r4_type).into(),
             _ => Err(())?
         },
         OPCODE_FMSUB => match funct2 {
             FUNCT2_FMT_S => Fmsubs(r4_type).into(),
+            FUNCT2_FMT_D => Fmsubd(r4_type).into(),
             _ => Err(())?
         },
         OPCODE_FNMSUB => match funct2 {
             FUNCT2_FMT_S => Fnmsubs(r4_type).into(),
+            FUNCT2_FMT_D => Fnmsubd(r4_type).into(),
             _ => Err(())?
         },
         OPCODE_FNMADD => match funct2 {
             FUNCT2_FMT_S => Fnmadds(r4_type).into(),
+            FUNCT2_FMT_D => Fnmaddd(r4_type).into(),
             _ => Err(())?
         },
         OPCODE_FP => match rs3 {
             FUNCT_RS3_FP_ADD => match funct2 {
                 FUNCT2_FMT_S => Fadds(r_type).into(),
+                FUNCT2_FMT_D => Faddd(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_SUB => match funct2 {
                 FUNCT2_FMT_S => Fsubs(r_type).into(),
+                FUNCT2_FMT_D => Fsubd(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_MUL => match funct2 {
                 FUNCT2_FMT_S => Fmuls(r_type).into(),
+                FUNCT2_FMT_D => Fmuld(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_DIV => match funct2 {
                 FUNCT2_FMT_S => Fdivs(r_type).into(),
+                FUNCT2_FMT_D => Fdivd(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_SQRT if rs2 == 0 => match funct2 {
                 FUNCT2_FMT_S => Fsqrts(r_type).into(),
+                FUNCT2_FMT_D => Fsqrtd(r_type).into(),
+                _ => Err(())?
+            },
+            FUNCT_RS3_FP_FCVTFF => match (funct2, rs2) {
+                (FUNCT2_FMT_S, FUNCT_RS2_CVT_D) => Fcvtsd(r_type).into(),
+                (FUNCT2_FMT_D, FUNCT_RS2_CVT_S) => Fcvtds(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_MIN_MAX => match funct3 {
                 FUNCT3_FP_MIN => match funct2 {
                     FUNCT2_FMT_S => Fmins(r_type).into(),
+                    FUNCT2_FMT_D => Fmind(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT3_FP_MAX => match funct2 {
                     FUNCT2_FMT_S => Fmaxs(r_type).into(),
+                    FUNCT2_FMT_D => Fmaxd(r_type).into(),
                     _ => Err(())?
                 },
                 _ => Err(())?
@@ -788,14 +918,17 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             FUNCT_RS3_FP_SGNJ => match funct3 {
                 FUNCT3_FP_SGNJ => match funct2 {
                     FUNCT2_FMT_S => Fsgnjs(r_type).into(),
+                    FUNCT2_FMT_D => Fsgnjd(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT3_FP_SGNJN => match funct2 {
                     FUNCT2_FMT_S => Fsgnjns(r_type).into(),
+                    FUNCT2_FMT_D => Fsgnjnd(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT3_FP_SGNJX => match funct2 {
                     FUNCT2_FMT_S => Fsgnjxs(r_type).into(),
+                    FUNCT2_FMT_D => Fsgnjxd(r_type).into(),
                     _ => Err(())?
                 },
                 _ => Err(())?
@@ -803,14 +936,17 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             FUNCT_RS3_FP_CMP => match funct3 {
                 FUNCT3_FP_EQ => match funct2 {
                     FUNCT2_FMT_S => Feqs(r_type).into(),
+                    FUNCT2_FMT_D => Feqd(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT3_FP_LT => match funct2 {
                     FUNCT2_FMT_S => Flts(r_type).into(),
+                    FUNCT2_FMT_D => Fltd(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT3_FP_LE => match funct2 {
                     FUNCT2_FMT_S => Fles(r_type).into(),
+                    FUNCT2_FMT_D => Fled(r_type).into(),
                     _ => Err(())?
                 },
                 _ => Err(())?
@@ -819,18 +955,22 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             FUNCT_RS3_FP_FCVTX => match rs2 {
                 FUNCT_RS2_CVT_W => match funct2 {
                     FUNCT2_FMT_S => Fcvtws(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtwd(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_WU => match funct2 {
                     FUNCT2_FMT_S => Fcvtwus(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtwud(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_L if xlen != Xlen::X32 => match funct2 {
                     FUNCT2_FMT_S => Fcvtls(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtld(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_LU if xlen != Xlen::X32 => match funct2 {
                     FUNCT2_FMT_S => Fcvtlus(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtlud(r_type).into(),
                     _ => Err(())?
                 },
                 _ => Err(())?
@@ -839,18 +979,22 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             FUNCT_RS3_FP_XCVTF => match rs2 {
                 FUNCT_RS2_CVT_W => match funct2 {
                     FUNCT2_FMT_S => Fcvtsw(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtdw(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_WU => match funct2 {
                     FUNCT2_FMT_S => Fcvtswu(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtdwu(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_L if xlen != Xlen::X32 => match funct2 {
                     FUNCT2_FMT_S => Fcvtsl(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtdl(r_type).into(),
                     _ => Err(())?
                 },
                 FUNCT_RS2_CVT_LU if xlen != Xlen::X32 => match funct2 {
                     FUNCT2_FMT_S => Fcvtslu(r_type).into(),
+                    FUNCT2_FMT_D => Fcvtdlu(r_type).into(),
                     _ => Err(())?
                 },
                 _ => Err(())?
@@ -858,15 +1002,18 @@ fn resolve_u32(ins: u32, xlen: Xlen) -> core::result::Result<Instruction, ()> {
             // fmv.x.w
             FUNCT_RS3_FP_FMVX_CLASS if rs2 == 0 && funct3 == 0 => match funct2 {
                 FUNCT2_FMT_S => Fmvxw(r_type).into(),
+                FUNCT2_FMT_D if xlen != Xlen::X32 => Fmvxd(r_type).into(),
                 _ => Err(())?
             },
             FUNCT_RS3_FP_FMVX_CLASS if rs2 == 0 && funct3 == 1 => match funct2 {
                 FUNCT2_FMT_S => Fclasss(r_type).into(),
+                FUNCT2_FMT_D => Fclassd(r_type).into(),
                 _ => Err(())?
             },
             // fmv.w.x
             FUNCT_RS3_FP_XMVF if rs2 == 0 && funct3 == 0 => match funct2 {
                 FUNCT2_FMT_S => Fmvwx(r_type).into(),
+                FUNCT2_FMT_D if xlen != Xlen::X32 => Fmvdx(r_type).into(),
                 _ => Err(())?
             },
             _ => Err(())?
@@ -882,7 +1029,12 @@ pub enum Instruction {
     RV64I(RV64I),
     RVC(RVC),
     RVZicsr(RVZicsr),
+    RVPriv(RVPriv),
+    RVM(RVM),
+    RVA(RVA),
     RVF(RVF),
+    RVD(RVD),
+    Custom(Custom),
 }
 
 impl From<RV32I> for Instruction {
@@ -909,12 +1061,36 @@ impl From<RVZicsr> for Instruction {
     }
 }
 
+impl From<RVPriv> for Instruction {
+    fn from(src: RVPriv) -> Instruction {
+        Instruction::RVPriv(src)
+    }
+}
+
+impl From<RVM> for Instruction {
+    fn from(src: RVM) -> Instruction {
+        Instruction::RVM(src)
+    }
+}
+
+impl From<RVA> for Instruction {
+    fn from(src: RVA) -> Instruction {
+        Instruction::RVA(src)
+    }
+}
+
 impl From<RVF> for Instruction {
     fn from(src: RVF) -> Instruction {
         Instruction::RVF(src)
     }
 }
 
+impl From<RVD> for Instruction {
+    fn from(src: RVD) -> Instruction {
+        Instruction::RVD(src)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum RV32I {
     Lui(UType),
@@ -1165,6 +1341,15 @@ pub enum RVZicsr {
     Csrrci(CsrIType),
 }
 
+// Privileged instructions
+#[derive(Debug, Clone, Copy)]
+pub enum RVPriv {
+    Sret(IType),
+    Mret(IType),
+    Wfi(IType),
+    SfenceVma(RType),
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct CsrRType {
     pub rd: u8,
@@ -1180,6 +1365,63 @@ pub struct CsrIType {
     pub csr: u16,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum RVM {
+    // RV32M
+    Mul(RType),
+    Mulh(RType),
+    Mulhsu(RType),
+    Mulhu(RType),
+    Div(RType),
+    Divu(RType),
+    Rem(RType),
+    Remu(RType),
+    // RV64M
+    Mulw(RType),
+    Divw(RType),
+    Divuw(RType),
+    Remw(RType),
+    Remuw(RType),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RVA {
+    // RV32A
+    LrW(AType),
+    ScW(AType),
+    AmoswapW(AType),
+    AmoaddW(AType),
+    AmoxorW(AType),
+    AmoandW(AType),
+    AmoorW(AType),
+    AmominW(AType),
+    AmomaxW(AType),
+    AmominuW(AType),
+    AmomaxuW(AType),
+    // RV64A
+    LrD(AType),
+    ScD(AType),
+    AmoswapD(AType),
+    AmoaddD(AType),
+    AmoxorD(AType),
+    AmoandD(AType),
+    AmoorD(AType),
+    AmominD(AType),
+    AmomaxD(AType),
+    AmominuD(AType),
+    AmomaxuD(AType),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AType {
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub funct3: u8,
+    pub aq: bool,
+    pub rl: bool,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum RVF {
     // RV32F 
@@ -1216,6 +1458,44 @@ pub enum RVF {
     Fcvtslu(RType),
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum RVD {
+    // RV32D
+    Fld(IType),
+    Fsd(SType),
+    Fmaddd(R4Type),
+    Fmsubd(R4Type),
+    Fnmsubd(R4Type),
+    Fnmaddd(R4Type),
+    Faddd(RType),
+    Fsubd(RType),
+    Fmuld(RType),
+    Fdivd(RType),
+    Fsqrtd(RType),
+    Fsgnjd(RType),
+    Fsgnjnd(RType),
+    Fsgnjxd(RType),
+    Fmind(RType),
+    Fmaxd(RType),
+    Fcvtsd(RType),
+    Fcvtds(RType),
+    Feqd(RType),
+    Fltd(RType),
+    Fled(RType),
+    Fclassd(RType),
+    Fcvtwd(RType),
+    Fcvtwud(RType),
+    Fcvtdw(RType),
+    Fcvtdwu(RType),
+    // RV64D
+    Fcvtld(RType),
+    Fcvtlud(RType),
+    Fmvxd(RType),
+    Fcvtdl(RType),
+    Fcvtdlu(RType),
+    Fmvdx(RType),
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct R4Type {
     pub rd: u8,
@@ -0,0 +1,221 @@
+use super::exec::ExecError;
+use crate::error::Result;
+use rustc_apfloat::{ExpInt, Float, Round, Status, StatusAnd};
+
+// rounding mode encodings, shared by the instruction rm field and the frm CSR
+const RM_RNE: u8 = 0b000;
+const RM_RTZ: u8 = 0b001;
+const RM_RDN: u8 = 0b010;
+const RM_RUP: u8 = 0b011;
+const RM_RMM: u8 = 0b100;
+const RM_DYN: u8 = 0b111;
+
+// accrued exception flags in fflags
+const FFLAGS_NV: u32 = 0b10000;
+const FFLAGS_DZ: u32 = 0b01000;
+const FFLAGS_OF: u32 = 0b00100;
+const FFLAGS_UF: u32 = 0b00010;
+const FFLAGS_NX: u32 = 0b00001;
+
+pub fn round_mode(rm: u8, frm: u32) -> Result<Round> {
+    let rm = if rm == RM_DYN { frm as u8 } else { rm };
+    let round = match rm {
+        RM_RNE => Round::NearestTiesToEven,
+        RM_RTZ => Round::TowardZero,
+        RM_RDN => Round::TowardNegative,
+        RM_RUP => Round::TowardPositive,
+        RM_RMM => Round::NearestTiesToAway,
+        _ => return Err(ExecError::InvalidRoundingMode { rm })?,
+    };
+    Ok(round)
+}
+
+pub fn fflags(status: Status) -> u32 {
+    let mut ans = 0;
+    if status.contains(Status::INVALID_OP) {
+        ans |= FFLAGS_NV;
+    }
+    if status.contains(Status::DIV_BY_ZERO) {
+        ans |= FFLAGS_DZ;
+    }
+    if status.contains(Status::OVERFLOW) {
+        ans |= FFLAGS_OF;
+    }
+    if status.contains(Status::UNDERFLOW) {
+        ans |= FFLAGS_UF;
+    }
+    if status.contains(Status::INEXACT) {
+        ans |= FFLAGS_NX;
+    }
+    ans
+}
+
+// RISC-V does not propagate NaN payloads; every generated NaN is the canonical one,
+// which is the positive quiet NaN with an all-zero payload
+pub fn canonical_nan<F: Float>(a: F) -> F {
+    if a.is_nan() {
+        F::NAN
+    } else {
+        a
+    }
+}
+
+pub fn fused<F: Float>(a: F, b: F, c: F, round: Round) -> StatusAnd<F> {
+    // inf * 0 + qNaN is invalid even if the addend is a quiet NaN
+    if ((a.is_infinite() && b.is_zero()) || (a.is_zero() && b.is_infinite())) && c.is_nan() {
+        return Status::INVALID_OP.and(F::NAN);
+    }
+    a.mul_add_r(b, c, round)
+}
+
+pub fn sqrt<F: Float>(a: F, round: Round) -> StatusAnd<F> {
+    if a.is_nan() {
+        let status = if a.is_signaling() { Status::INVALID_OP } else { Status::OK };
+        return status.and(F::NAN);
+    }
+    if a.is_zero() || a.is_pos_infinity() {
+        return Status::OK.and(a);
+    }
+    if a.is_negative() {
+        return Status::INVALID_OP.and(F::NAN);
+    }
+    // a = sig * 2^exp where sig is an integer of PRECISION bits; make exp even, then
+    // widen sig by an even number of bits so that its integer square root holds two
+    // guard bits beyond PRECISION, plus one sticky bit for the remainder
+    let mut exp: ExpInt = 0;
+    let frac = a.frexp(&mut exp);
+    let mut sig = frac.scalbn(F::PRECISION as ExpInt).to_u128(128).value;
+    let mut exp = exp - F::PRECISION as ExpInt;
+    if exp % 2 != 0 {
+        sig <<= 1;
+        exp -= 1;
+    }
+    let widen = ((F::PRECISION + 6) / 2 * 2) as ExpInt;
+    let (root, rem) = isqrt(sig << widen);
+    let sticky = if rem != 0 { 1 } else { 0 };
+    let ans = F::from_u128_r(root | sticky, round);
+    // results of square roots are always normal, so scaling is exact
+    ans.map(|v| v.scalbn((exp - widen) / 2))
+}
+
+// returns (root, remainder)
+fn isqrt(n: u128) -> (u128, u128) {
+    let mut rem = n;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    (root, rem)
+}
+
+// fmin and fmax as defined by IEEE 754-2019 minimumNumber and maximumNumber
+pub fn min_max<F: Float>(a: F, b: F, is_max: bool) -> StatusAnd<F> {
+    let status = if a.is_signaling() || b.is_signaling() {
+        Status::INVALID_OP
+    } else {
+        Status::OK
+    };
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::NAN,
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            // -0.0 is considered less than +0.0
+            let a_lt_b = if a.is_zero() && b.is_zero() {
+                a.is_negative() && !b.is_negative()
+            } else {
+                a < b
+            };
+            if a_lt_b ^ is_max {
+                a
+            } else {
+                b
+            }
+        }
+    };
+    status.and(value)
+}
+
+// feq is a quiet comparison, it only signals on signaling NaNs
+pub fn eq<F: Float>(a: F, b: F) -> StatusAnd<bool> {
+    let status = if a.is_signaling() || b.is_signaling() {
+        Status::INVALID_OP
+    } else {
+        Status::OK
+    };
+    status.and(a.partial_cmp(&b) == Some(core::cmp::Ordering::Equal))
+}
+
+// flt and fle are signaling comparisons
+pub fn lt<F: Float>(a: F, b: F) -> StatusAnd<bool> {
+    if a.is_nan() || b.is_nan() {
+        return Status::INVALID_OP.and(false);
+    }
+    Status::OK.and(a < b)
+}
+
+pub fn le<F: Float>(a: F, b: F) -> StatusAnd<bool> {
+    if a.is_nan() || b.is_nan() {
+        return Status::INVALID_OP.and(false);
+    }
+    Status::OK.and(a <= b)
+}
+
+pub fn classify<F: Float>(a: F) -> u32 {
+    let neg = a.is_negative();
+    let bit = if a.is_nan() {
+        if a.is_signaling() { 8 } else { 9 }
+    } else if a.is_infinite() {
+        if neg { 0 } else { 7 }
+    } else if a.is_zero() {
+        if neg { 3 } else { 4 }
+    } else if a.is_denormal() {
+        if neg { 2 } else { 5 }
+    } else if neg {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+// Converts to an integer of `width` bits. NaNs and values out of range saturate and
+// raise the invalid flag, without setting the inexact flag
+pub fn to_int<F: Float>(a: F, round: Round, signed: bool, width: usize) -> StatusAnd<i128> {
+    let (min, max): (i128, i128) = if signed {
+        (-(1 << (width - 1)), (1 << (width - 1)) - 1)
+    } else {
+        (0, (1 << width) - 1)
+    };
+    if a.is_nan() {
+        return Status::INVALID_OP.and(max);
+    }
+    let StatusAnd { status, value } = a.round_to_integral(round);
+    if value.is_infinite() {
+        return Status::INVALID_OP.and(if value.is_negative() { min } else { max });
+    }
+    // the value is already integral; huge values saturate to i128 bounds here
+    let mut is_exact = true;
+    let n = value.to_i128_r(128, Round::TowardZero, &mut is_exact).value;
+    if n < min {
+        Status::INVALID_OP.and(min)
+    } else if n > max {
+        Status::INVALID_OP.and(max)
+    } else {
+        (status & Status::INEXACT).and(n)
+    }
+}
+
+pub fn from_int<F: Float>(a: i128, round: Round) -> StatusAnd<F> {
+    F::from_i128_r(a, round)
+}
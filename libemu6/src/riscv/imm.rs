@@ -64,8 +64,12 @@ impl Imm {
         self.data & MASK32[self.valid_bits as usize]
     }
 
+    // the immediate sign-extended to 32 bits
     pub fn low_i32(&self) -> i32 {
-        i32::from_ne_bytes(u32::to_ne_bytes(self.low_u32()))
+        match self.sext(Xlen::X32) {
+            Isize::I32(ans) => ans,
+            _ => unreachable!(),
+        }
     }
 }
 
@@ -101,3 +105,16 @@ impl core::cmp::PartialEq<u32> for Uimm {
         self.low32() == *rhs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_i32_sign_extends() {
+        assert_eq!(Imm::new(0xFFF, 12).low_i32(), -1);
+        assert_eq!(Imm::new(0x800, 12).low_i32(), -2048);
+        assert_eq!(Imm::new(0x7FF, 12).low_i32(), 2047);
+        assert_eq!(Imm::new(0x20, 6).low_i32(), -32);
+    }
+}
@@ -0,0 +1,587 @@
+use super::pmp::Pmp;
+use super::regfile::Csr;
+use super::{Exception, Privilege, Trap, Xlen};
+use crate::error::Result;
+use crate::memory::Bus;
+use crate::size::Usize;
+use core::cell::Cell;
+
+const PAGE_SIZE: u64 = 4096;
+
+// page table entry bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+// bits 63:54 of Sv39/48/57 entries are reserved, or used by extensions not supported
+const PTE_RESERVED_64: u64 = 0x3FF << 54;
+
+// satp MODE field values
+const SATP_MODE_BARE: u64 = 0;
+const SATP32_MODE_SV32: u64 = 1;
+const SATP64_MODE_SV39: u64 = 8;
+const SATP64_MODE_SV48: u64 = 9;
+const SATP64_MODE_SV57: u64 = 10;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Access {
+    Fetch,
+    Load,
+    // also used by atomic memory operations and store-conditionals
+    Store,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum Mode {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl Mode {
+    // returns (levels, PTE size in bytes, VPN bits per level)
+    fn layout(self) -> (u32, u64, u32) {
+        match self {
+            Mode::Bare => (0, 0, 0),
+            Mode::Sv32 => (2, 4, 10),
+            Mode::Sv39 => (3, 8, 9),
+            Mode::Sv48 => (4, 8, 9),
+            Mode::Sv57 => (5, 8, 9),
+        }
+    }
+}
+
+// Returns whether `mode` is a valid satp MODE value for this xlen
+pub(crate) fn satp_mode_supported(xlen: Xlen, mode: u64) -> bool {
+    match xlen {
+        Xlen::X32 => mode == SATP_MODE_BARE || mode == SATP32_MODE_SV32,
+        _ => matches!(
+            mode,
+            SATP_MODE_BARE | SATP64_MODE_SV39 | SATP64_MODE_SV48 | SATP64_MODE_SV57
+        ),
+    }
+}
+
+// Address translation state of a hart, taken from satp and mstatus.
+//
+// There is no TLB; every access walks the page table, so SFENCE.VMA has nothing to
+// flush. Accessed and dirty bits are not updated by hardware: accessing a page whose
+// A bit is clear, or storing to a page whose D bit is clear, raises a page fault and
+// the supervisor software sets the bits.
+#[derive(Clone, Copy, Debug)]
+pub struct Translate {
+    mode: Mode,
+    root: u64,
+    fetch_privilege: Privilege,
+    data_privilege: Privilege,
+    sum: bool,
+    mxr: bool,
+    pmp: Pmp,
+}
+
+impl Translate {
+    pub(crate) fn new(csr: &Csr, privilege: Privilege, xlen: Xlen) -> Translate {
+        let satp = csr.satp();
+        let (mode, ppn) = match xlen {
+            Xlen::X32 => (satp >> 31, satp & 0x3FFFFF),
+            _ => (satp >> 60, satp & 0xFFF_FFFFFFFF),
+        };
+        let mode = match (xlen, mode) {
+            (Xlen::X32, SATP32_MODE_SV32) => Mode::Sv32,
+            (Xlen::X64, SATP64_MODE_SV39) => Mode::Sv39,
+            (Xlen::X64, SATP64_MODE_SV48) => Mode::Sv48,
+            (Xlen::X64, SATP64_MODE_SV57) => Mode::Sv57,
+            _ => Mode::Bare,
+        };
+        Translate {
+            mode,
+            root: ppn * PAGE_SIZE,
+            fetch_privilege: privilege,
+            data_privilege: csr.data_privilege(privilege),
+            sum: csr.sum(),
+            mxr: csr.mxr(),
+            pmp: csr.pmp(),
+        }
+    }
+
+    // Translates a virtual address into physical address
+    pub fn translate(&self, mem: &dyn Bus, vaddr: Usize, access: Access) -> Result<u64> {
+        let va = match vaddr {
+            Usize::U32(a) => a as u64,
+            Usize::U64(a) => a,
+        };
+        if self.is_bare(access) {
+            return Ok(va);
+        }
+        let privilege = self.privilege(access);
+        let page_fault = || {
+            let cause = match access {
+                Access::Fetch => Exception::InstructionPageFault,
+                Access::Load => Exception::LoadPageFault,
+                Access::Store => Exception::StorePageFault,
+            };
+            Trap::new(cause, vaddr)
+        };
+        let (levels, pte_size, vpn_bits) = self.mode.layout();
+        let va_bits = 12 + levels * vpn_bits;
+        // Sv39 and above require the unused upper bits to equal the highest bit
+        if self.mode != Mode::Sv32 {
+            let upper = ((va as i64) >> (va_bits - 1)) as u64;
+            if upper != 0 && upper != u64::MAX {
+                return Err(page_fault())?;
+            }
+        }
+        let mut table = self.root;
+        let mut level = levels;
+        let (pte, level) = loop {
+            if level == 0 {
+                return Err(page_fault())?;
+            }
+            level -= 1;
+            let vpn = (va >> (12 + level * vpn_bits)) & ((1 << vpn_bits) - 1);
+            let pte_addr = table + vpn * pte_size;
+            // failing to read the page table is an access fault of the original access;
+            // page table accesses are checked by PMP as supervisor loads
+            if !self.pmp.check(pte_addr, pte_size, Access::Load, Privilege::Supervisor) {
+                return Err(access_fault(access, vaddr))?;
+            }
+            let pte = match pte_size {
+                4 => mem.read_u32(pte_addr).map(|pte| pte as u64),
+                _ => mem.read_u64(pte_addr),
+            };
+            let pte = pte.map_err(|_| access_fault(access, vaddr))?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(page_fault())?;
+            }
+            if pte_size == 8 && pte & PTE_RESERVED_64 != 0 {
+                return Err(page_fault())?;
+            }
+            if pte & (PTE_R | PTE_X) != 0 {
+                break (pte, level);
+            }
+            // pointer to the next level; A, D and U are reserved in non-leaf entries
+            if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                return Err(page_fault())?;
+            }
+            table = pte_ppn(pte, pte_size) * PAGE_SIZE;
+        };
+        let permitted = match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (self.mxr && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        };
+        let user_page = pte & PTE_U != 0;
+        let privilege_ok = match privilege {
+            Privilege::User => user_page,
+            // with SUM supervisor may load and store user pages, but never execute them
+            _ => !user_page || (self.sum && access != Access::Fetch),
+        };
+        if !permitted || !privilege_ok {
+            return Err(page_fault())?;
+        }
+        let ppn = pte_ppn(pte, pte_size);
+        let offset_bits = 12 + level * vpn_bits;
+        // superpages must be aligned to their size
+        if ppn & ((1 << (level * vpn_bits)) - 1) != 0 {
+            return Err(page_fault())?;
+        }
+        if pte & PTE_A == 0 || (access == Access::Store && pte & PTE_D == 0) {
+            return Err(page_fault())?;
+        }
+        Ok(((ppn * PAGE_SIZE) & !((1 << offset_bits) - 1)) | (va & ((1 << offset_bits) - 1)))
+    }
+
+    // Checks physical memory protection for `nbytes` bytes at `addr`, translated
+    // from `vaddr`; failing accesses raise access faults
+    pub fn check(&self, addr: u64, nbytes: u64, vaddr: Usize, access: Access) -> Result<()> {
+        let privilege = self.privilege(access);
+        if !self.pmp.check(addr, nbytes, access, privilege) {
+            return Err(access_fault(access, vaddr))?;
+        }
+        Ok(())
+    }
+
+    fn privilege(&self, access: Access) -> Privilege {
+        match access {
+            Access::Fetch => self.fetch_privilege,
+            _ => self.data_privilege,
+        }
+    }
+
+    fn is_bare(&self, access: Access) -> bool {
+        self.mode == Mode::Bare || self.privilege(access) == Privilege::Machine
+    }
+}
+
+fn pte_ppn(pte: u64, pte_size: u64) -> u64 {
+    match pte_size {
+        4 => (pte >> 10) & 0x3FFFFF,
+        _ => (pte >> 10) & 0xFFF_FFFFFFFF,
+    }
+}
+
+pub(crate) fn access_fault(access: Access, vaddr: Usize) -> Trap {
+    let cause = match access {
+        Access::Fetch => Exception::InstructionAccessFault,
+        Access::Load => Exception::LoadAccessFault,
+        Access::Store => Exception::StoreAccessFault,
+    };
+    Trap::new(cause, vaddr)
+}
+
+// Kinds of data accesses a watchpoint triggers on
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+// Virtual address ranges watched by a debugger, and the first access which hit
+// one of them since the hit was last taken
+#[derive(Default, Debug)]
+pub struct Watchpoints {
+    ranges: Vec<(u64, u64, Watch)>,
+    hit: Cell<Option<(Watch, u64)>>,
+}
+
+impl Watchpoints {
+    pub fn insert(&mut self, addr: u64, len: u64, kind: Watch) {
+        self.ranges.push((addr, len, kind))
+    }
+
+    // returns false if there is no such watchpoint
+    pub fn remove(&mut self, addr: u64, len: u64, kind: Watch) -> bool {
+        match self.ranges.iter().position(|&w| w == (addr, len, kind)) {
+            Some(idx) => {
+                self.ranges.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // returns the kind of the watchpoint hit, and the watched address accessed
+    pub fn take_hit(&mut self) -> Option<(Watch, u64)> {
+        self.hit.take()
+    }
+
+    fn check(&self, vaddr: Usize, nbytes: u64, read: bool, write: bool) {
+        if self.ranges.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        let start = match vaddr {
+            Usize::U32(a) => a as u64,
+            Usize::U64(a) => a,
+        };
+        for &(addr, len, kind) in &self.ranges {
+            let matches = match kind {
+                Watch::Read => read,
+                Watch::Write => write,
+                Watch::Access => true,
+            };
+            if matches && start < addr.wrapping_add(len) && addr < start.wrapping_add(nbytes) {
+                self.hit.set(Some((kind, start.max(addr))));
+                return;
+            }
+        }
+    }
+}
+
+// Data memory as seen by a hart, addressed with virtual addresses. Failing
+// accesses raise page faults or access faults
+pub struct Mmu<'m, 'a> {
+    mem: &'m mut (dyn Bus + 'a),
+    translate: Translate,
+    watchpoints: Option<&'m Watchpoints>,
+}
+
+impl<'m, 'a> Mmu<'m, 'a> {
+    pub fn new(mem: &'m mut (dyn Bus + 'a), translate: Translate) -> Mmu<'m, 'a> {
+        Mmu {
+            mem,
+            translate,
+            watchpoints: None,
+        }
+    }
+
+    // records accesses of the hart which hit any of `watchpoints`
+    pub fn with_watchpoints(mut self, watchpoints: &'m Watchpoints) -> Mmu<'m, 'a> {
+        self.watchpoints = Some(watchpoints);
+        self
+    }
+
+    fn watch(&self, vaddr: Usize, nbytes: u64, read: bool, write: bool) {
+        if let Some(watchpoints) = self.watchpoints {
+            watchpoints.check(vaddr, nbytes, read, write)
+        }
+    }
+
+    // Translates and checks an access; returns the physical address
+    pub fn translate(&self, vaddr: Usize, nbytes: u64, access: Access) -> Result<u64> {
+        let addr = self.translate.translate(self.mem, vaddr, access)?;
+        self.translate.check(addr, nbytes, vaddr, access)?;
+        Ok(addr)
+    }
+
+    pub fn reserve(&mut self, hart_id: usize, addr: u64, nbytes: u64) {
+        self.mem.reserve(hart_id, addr, nbytes)
+    }
+
+    pub fn take_reservation(&mut self, hart_id: usize, addr: u64, nbytes: u64) -> bool {
+        self.mem.take_reservation(hart_id, addr, nbytes)
+    }
+
+    pub fn read_u8(&self, vaddr: Usize) -> Result<u8> {
+        self.read_uint(vaddr, 1).map(|n| n as u8)
+    }
+
+    pub fn read_i8(&self, vaddr: Usize) -> Result<i8> {
+        self.read_u8(vaddr).map(|n| n as i8)
+    }
+
+    pub fn read_u16(&self, vaddr: Usize) -> Result<u16> {
+        self.read_uint(vaddr, 2).map(|n| n as u16)
+    }
+
+    pub fn read_i16(&self, vaddr: Usize) -> Result<i16> {
+        self.read_u16(vaddr).map(|n| n as i16)
+    }
+
+    pub fn read_u32(&self, vaddr: Usize) -> Result<u32> {
+        self.read_uint(vaddr, 4).map(|n| n as u32)
+    }
+
+    pub fn read_i32(&self, vaddr: Usize) -> Result<i32> {
+        self.read_u32(vaddr).map(|n| n as i32)
+    }
+
+    pub fn read_u64(&self, vaddr: Usize) -> Result<u64> {
+        self.read_uint(vaddr, 8)
+    }
+
+    pub fn read_i64(&self, vaddr: Usize) -> Result<i64> {
+        self.read_u64(vaddr).map(|n| n as i64)
+    }
+
+    pub fn write_u8(&mut self, vaddr: Usize, n: u8) -> Result<()> {
+        self.write_uint(vaddr, n as u64, 1)
+    }
+
+    pub fn write_u16(&mut self, vaddr: Usize, n: u16) -> Result<()> {
+        self.write_uint(vaddr, n as u64, 2)
+    }
+
+    pub fn write_u32(&mut self, vaddr: Usize, n: u32) -> Result<()> {
+        self.write_uint(vaddr, n as u64, 4)
+    }
+
+    pub fn write_u64(&mut self, vaddr: Usize, n: u64) -> Result<()> {
+        self.write_uint(vaddr, n, 8)
+    }
+
+    // Read-modify-write of atomic memory operations; returns the old value.
+    // Faults are reported as store faults
+    pub fn amo_u32(&mut self, vaddr: Usize, op: impl FnOnce(u32) -> u32) -> Result<u32> {
+        let addr = self.translate(vaddr, 4, Access::Store)?;
+        let fault = |_| access_fault(Access::Store, vaddr);
+        let data = self.mem.read_u32(addr).map_err(fault)?;
+        self.mem.write_u32(addr, op(data)).map_err(fault)?;
+        self.watch(vaddr, 4, true, true);
+        Ok(data)
+    }
+
+    pub fn amo_u64(&mut self, vaddr: Usize, op: impl FnOnce(u64) -> u64) -> Result<u64> {
+        let addr = self.translate(vaddr, 8, Access::Store)?;
+        let fault = |_| access_fault(Access::Store, vaddr);
+        let data = self.mem.read_u64(addr).map_err(fault)?;
+        self.mem.write_u64(addr, op(data)).map_err(fault)?;
+        self.watch(vaddr, 8, true, true);
+        Ok(data)
+    }
+
+    // Misaligned accesses crossing a page boundary are split into byte accesses,
+    // each of which is translated on its own
+    pub(crate) fn read_uint(&self, vaddr: Usize, nbytes: u64) -> Result<u64> {
+        let fault = |_| access_fault(Access::Load, vaddr);
+        if !self.crosses_page(vaddr, nbytes, Access::Load) {
+            let addr = self.translate(vaddr, nbytes, Access::Load)?;
+            let ans = match nbytes {
+                1 => self.mem.read_u8(addr).map(|n| n as u64),
+                2 => self.mem.read_u16(addr).map(|n| n as u64),
+                4 => self.mem.read_u32(addr).map(|n| n as u64),
+                _ => self.mem.read_u64(addr),
+            };
+            let ans = ans.map_err(fault)?;
+            self.watch(vaddr, nbytes, true, false);
+            return Ok(ans);
+        }
+        let mut ans = 0;
+        for i in 0..nbytes {
+            let addr = self.translate(vaddr + i as u32, 1, Access::Load)?;
+            ans |= (self.mem.read_u8(addr).map_err(fault)? as u64) << (8 * i);
+        }
+        self.watch(vaddr, nbytes, true, false);
+        Ok(ans)
+    }
+
+    pub(crate) fn write_uint(&mut self, vaddr: Usize, n: u64, nbytes: u64) -> Result<()> {
+        let fault = |_| access_fault(Access::Store, vaddr);
+        if !self.crosses_page(vaddr, nbytes, Access::Store) {
+            let addr = self.translate(vaddr, nbytes, Access::Store)?;
+            let ans = match nbytes {
+                1 => self.mem.write_u8(addr, n as u8),
+                2 => self.mem.write_u16(addr, n as u16),
+                4 => self.mem.write_u32(addr, n as u32),
+                _ => self.mem.write_u64(addr, n),
+            };
+            ans.map_err(fault)?;
+            self.watch(vaddr, nbytes, false, true);
+            return Ok(());
+        }
+        // translate all bytes first, so that a fault leaves memory untouched
+        let mut addrs = [0u64; 8];
+        for i in 0..nbytes {
+            addrs[i as usize] = self.translate(vaddr + i as u32, 1, Access::Store)?;
+        }
+        for i in 0..nbytes {
+            self.mem.write_u8(addrs[i as usize], (n >> (8 * i)) as u8).map_err(fault)?;
+        }
+        self.watch(vaddr, nbytes, false, true);
+        Ok(())
+    }
+
+    fn crosses_page(&self, vaddr: Usize, nbytes: u64, access: Access) -> bool {
+        let offset = match vaddr {
+            Usize::U32(a) => a as u64,
+            Usize::U64(a) => a,
+        } % PAGE_SIZE;
+        !self.translate.is_bare(access) && offset + nbytes > PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::mem64::{Config, Endian, Physical, Protect};
+    use crate::riscv::regfile::{CSR_PMPADDR0, CSR_PMPCFG0, CSR_SATP};
+
+    const RAM: u64 = 0x8000_0000;
+    // root, second and last level tables of Sv39
+    const ROOT: u64 = RAM + 0x1000;
+    const L1: u64 = RAM + 0x2000;
+    const L0: u64 = RAM + 0x3000;
+    const VA: u64 = 0x4000_0000;
+
+    fn pte(pa: u64, flags: u64) -> u64 {
+        (pa >> 12) << 10 | flags | PTE_V
+    }
+
+    // Maps the pages from VA: the first read-write, the second read-only, the
+    // third not at all and the fourth without its accessed bit
+    fn memory() -> Physical<'static> {
+        let mut mem = Physical::new();
+        let config = Config {
+            range: RAM..(RAM + 0x10000),
+            protect: Protect::READ | Protect::WRITE,
+            endian: Endian::Little,
+        };
+        mem.push_zeroed(config);
+        let entries = [
+            (ROOT + 8, pte(L1, 0)),
+            (L1, pte(L0, 0)),
+            (L0, pte(RAM + 0x8000, PTE_R | PTE_W | PTE_A | PTE_D)),
+            (L0 + 8, pte(RAM + 0x9000, PTE_R | PTE_A)),
+            (L0 + 24, pte(RAM + 0xB000, PTE_R | PTE_W)),
+        ];
+        for &(addr, pte) in entries.iter() {
+            mem.write_u64(addr, pte).unwrap();
+        }
+        mem
+    }
+
+    // Sv39 translation with PMP granting all memory
+    fn sv39(root: u64, privilege: Privilege) -> Translate {
+        let mut csr = Csr::new(Xlen::X64, 0);
+        csr.w_usize(CSR_PMPADDR0, Usize::U64(u64::MAX)).unwrap();
+        csr.w_usize(CSR_PMPCFG0, Usize::U64(0x1F)).unwrap();
+        let satp = SATP64_MODE_SV39 << 60 | root >> 12;
+        csr.w_usize(CSR_SATP, Usize::U64(satp)).unwrap();
+        Translate::new(&csr, privilege, Xlen::X64)
+    }
+
+    fn fault(ans: Result<u64>) -> (Exception, Usize) {
+        match ans {
+            Err(Error::Trap(trap)) => (trap.cause, trap.tval),
+            other => panic!("expected a trap, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn translate_mapped_pages() {
+        let mem = memory();
+        let translate = sv39(ROOT, Privilege::Supervisor);
+        let va = |offset| Usize::U64(VA + offset);
+        assert_eq!(translate.translate(&mem, va(0x123), Access::Load).unwrap(), RAM + 0x8123);
+        assert_eq!(translate.translate(&mem, va(0x008), Access::Store).unwrap(), RAM + 0x8008);
+        assert_eq!(translate.translate(&mem, va(0x1FF8), Access::Load).unwrap(), RAM + 0x9FF8);
+        // M-mode accesses are not translated
+        let translate = sv39(ROOT, Privilege::Machine);
+        assert_eq!(translate.translate(&mem, va(0x123), Access::Load).unwrap(), VA + 0x123);
+    }
+
+    #[test]
+    fn page_fault_cause_and_tval() {
+        let mem = memory();
+        let translate = sv39(ROOT, Privilege::Supervisor);
+        let fault_at = |va, access| fault(translate.translate(&mem, Usize::U64(va), access));
+        let page_fault = |cause, va| (cause, Usize::U64(va));
+        // not mapped
+        assert_eq!(
+            fault_at(VA + 0x2010, Access::Load),
+            page_fault(Exception::LoadPageFault, VA + 0x2010)
+        );
+        // read only
+        assert_eq!(
+            fault_at(VA + 0x1008, Access::Store),
+            page_fault(Exception::StorePageFault, VA + 0x1008)
+        );
+        // not executable
+        assert_eq!(
+            fault_at(VA + 0x4, Access::Fetch),
+            page_fault(Exception::InstructionPageFault, VA + 0x4)
+        );
+        // accessed bit clear
+        assert_eq!(
+            fault_at(VA + 0x3000, Access::Load),
+            page_fault(Exception::LoadPageFault, VA + 0x3000)
+        );
+        // bits above 38 must copy bit 38
+        assert_eq!(
+            fault_at(0x0000_0040_0000_0000, Access::Load),
+            page_fault(Exception::LoadPageFault, 0x0000_0040_0000_0000)
+        );
+        // supervisor pages are not accessible from U-mode
+        let translate = sv39(ROOT, Privilege::User);
+        assert_eq!(
+            fault(translate.translate(&mem, Usize::U64(VA), Access::Load)),
+            page_fault(Exception::LoadPageFault, VA)
+        );
+    }
+
+    #[test]
+    fn unreadable_page_table_is_access_fault() {
+        let mem = memory();
+        let translate = sv39(0x9000_0000, Privilege::Supervisor);
+        assert_eq!(
+            fault(translate.translate(&mem, Usize::U64(VA + 0x10), Access::Store)),
+            (Exception::StoreAccessFault, Usize::U64(VA + 0x10))
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use super::mmu::Access;
+use super::{Privilege, Xlen};
+
+const PMP_ENTRIES: usize = 64;
+
+// pmpcfg fields, one byte per entry
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+// address matching modes in the A field
+const PMP_A_OFF: u8 = 0b00 << 3;
+const PMP_A_TOR: u8 = 0b01 << 3;
+const PMP_A_NA4: u8 = 0b10 << 3;
+const PMP_A_NAPOT: u8 = 0b11 << 3;
+
+// Physical memory protection entries of a hart
+#[derive(Clone, Copy, Debug)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Pmp {
+        Pmp {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    // pmpcfg registers hold 4 entries on RV32 and 8 entries on RV64, where the
+    // odd-numbered registers do not exist
+    pub fn cfg_exists(idx: usize, xlen: Xlen) -> bool {
+        idx < PMP_ENTRIES / 4 && (xlen == Xlen::X32 || idx & 1 == 0)
+    }
+
+    pub fn r_cfg(&self, idx: usize, xlen: Xlen) -> u64 {
+        let (first, count) = cfg_entries(idx, xlen);
+        (0..count).fold(0, |ans, i| ans | (self.cfg[first + i] as u64) << (8 * i))
+    }
+
+    pub fn w_cfg(&mut self, idx: usize, val: u64, xlen: Xlen) {
+        let (first, count) = cfg_entries(idx, xlen);
+        for i in 0..count {
+            let entry = first + i;
+            // writes to locked entries are ignored
+            if self.cfg[entry] & PMP_L != 0 {
+                continue;
+            }
+            let mut cfg = (val >> (8 * i)) as u8 & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+            // R=0 and W=1 is reserved
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+    }
+
+    // one NAPOT entry over all memory with read, write and execute permission
+    pub fn open_all(&mut self, xlen: Xlen) {
+        self.w_addr(0, u64::MAX, xlen);
+        self.w_cfg(0, (PMP_A_NAPOT | PMP_R | PMP_W | PMP_X) as u64, xlen);
+    }
+
+    pub fn r_addr(&self, entry: usize) -> u64 {
+        self.addr[entry]
+    }
+
+    pub fn w_addr(&mut self, entry: usize, val: u64, xlen: Xlen) {
+        // a locked entry also locks the address below it when it is the top of a range
+        let locked = self.cfg[entry] & PMP_L != 0;
+        let locked_tor = entry + 1 < PMP_ENTRIES
+            && self.cfg[entry + 1] & PMP_L != 0
+            && self.cfg[entry + 1] & PMP_A == PMP_A_TOR;
+        if locked || locked_tor {
+            return;
+        }
+        // bits 33:2 of a 34-bit address on RV32, bits 55:2 of a 56-bit address on RV64
+        self.addr[entry] = match xlen {
+            Xlen::X32 => val & 0xFFFF_FFFF,
+            _ => val & 0x3F_FFFF_FFFF_FFFF,
+        };
+    }
+
+    // Checks an access of `nbytes` bytes at physical address `addr`. The entry with
+    // the lowest number matching any byte decides; it must match all the bytes.
+    // M-mode accesses are only checked by locked entries, and succeed if no entry
+    // matches; S-mode and U-mode accesses fail if no entry matches. Accesses
+    // wrapping around the end of the address space always fail
+    pub fn check(&self, addr: u64, nbytes: u64, access: Access, privilege: Privilege) -> bool {
+        let end = match addr.checked_add(nbytes) {
+            Some(end) => end,
+            None => return false,
+        };
+        for entry in 0..PMP_ENTRIES {
+            let (lo, hi) = match self.range(entry) {
+                Some(range) => range,
+                None => continue,
+            };
+            if addr >= hi || end <= lo {
+                continue;
+            }
+            if addr < lo || end > hi {
+                return false;
+            }
+            let cfg = self.cfg[entry];
+            if privilege == Privilege::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let perm = match access {
+                Access::Fetch => PMP_X,
+                Access::Load => PMP_R,
+                Access::Store => PMP_W,
+            };
+            return cfg & perm != 0;
+        }
+        privilege == Privilege::Machine
+    }
+
+    // byte address range [lo, hi) matched by an entry
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[entry];
+        match self.cfg[entry] & PMP_A {
+            PMP_A_OFF => None,
+            PMP_A_TOR => {
+                let lo = if entry == 0 { 0 } else { self.addr[entry - 1] << 2 };
+                Some((lo, addr << 2))
+            }
+            PMP_A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_A_NAPOT => {
+                // the number of trailing ones encodes the size, at least 8 bytes
+                let ones = addr.trailing_ones();
+                let lo = (addr & !((1 << ones) - 1)) << 2;
+                Some((lo, lo + (1 << (ones + 3))))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+// returns (first entry, number of entries) held by a pmpcfg register
+fn cfg_entries(idx: usize, xlen: Xlen) -> (usize, usize) {
+    match xlen {
+        Xlen::X32 => (idx * 4, 4),
+        _ => (idx * 4, 8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // entries as pmpcfg0 holds them on RV64, lowest entry in the lowest byte
+    fn entries(cfg: u64, addrs: &[u64]) -> Pmp {
+        let mut pmp = Pmp::new();
+        for (entry, &addr) in addrs.iter().enumerate() {
+            pmp.w_addr(entry, addr, Xlen::X64);
+        }
+        pmp.w_cfg(0, cfg, Xlen::X64);
+        pmp
+    }
+
+    #[test]
+    fn napot_boundaries() {
+        // 4 KiB at 0x8000_0000, read only
+        let pmp = entries((PMP_A_NAPOT | PMP_R) as u64, &[(0x8000_0000 >> 2) | 0x1FF]);
+        let check = |addr, nbytes, access| pmp.check(addr, nbytes, access, Privilege::Supervisor);
+        assert!(check(0x8000_0000, 8, Access::Load));
+        assert!(check(0x8000_0FF8, 8, Access::Load));
+        assert!(!check(0x8000_0FFC, 8, Access::Load));
+        assert!(!check(0x8000_1000, 1, Access::Load));
+        assert!(!check(0x7FFF_FFFF, 1, Access::Load));
+        assert!(!check(0x8000_0000, 8, Access::Store));
+        assert!(!check(0x8000_0000, 2, Access::Fetch));
+        // the smallest NAPOT region is 8 bytes
+        let pmp = entries((PMP_A_NAPOT | PMP_R) as u64, &[0x8000_0000 >> 2]);
+        assert!(pmp.check(0x8000_0004, 4, Access::Load, Privilege::User));
+        assert!(!pmp.check(0x8000_0008, 1, Access::Load, Privilege::User));
+    }
+
+    #[test]
+    fn tor_boundaries() {
+        // entry 1 covers [0x8000_0000, 0x8000_1000) with the address of entry 0
+        // as its bottom; entry 0 itself is off
+        let cfg = ((PMP_A_TOR | PMP_R | PMP_W) as u64) << 8;
+        let pmp = entries(cfg, &[0x8000_0000 >> 2, 0x8000_1000 >> 2]);
+        let check = |addr, nbytes| pmp.check(addr, nbytes, Access::Store, Privilege::User);
+        assert!(check(0x8000_0000, 8));
+        assert!(check(0x8000_0FFF, 1));
+        assert!(!check(0x7FFF_FFFF, 1));
+        assert!(!check(0x8000_1000, 1));
+        assert!(!check(0x8000_0FFE, 4));
+        // a TOR entry 0 starts at address zero
+        let pmp = entries((PMP_A_TOR | PMP_R) as u64, &[0x1000 >> 2]);
+        assert!(pmp.check(0, 8, Access::Load, Privilege::User));
+        assert!(!pmp.check(0x1000, 1, Access::Load, Privilege::User));
+    }
+
+    #[test]
+    fn machine_mode_checks_locked_entries_only() {
+        let pmp = entries((PMP_A_NA4 | PMP_R) as u64, &[0x1000 >> 2]);
+        assert!(pmp.check(0x1000, 4, Access::Store, Privilege::Machine));
+        assert!(pmp.check(0x2000, 4, Access::Store, Privilege::Machine));
+        assert!(!pmp.check(0x2000, 4, Access::Load, Privilege::Supervisor));
+        let pmp = entries((PMP_A_NA4 | PMP_R | PMP_L) as u64, &[0x1000 >> 2]);
+        assert!(pmp.check(0x1000, 4, Access::Load, Privilege::Machine));
+        assert!(!pmp.check(0x1000, 4, Access::Store, Privilege::Machine));
+    }
+
+    #[test]
+    fn lowest_matching_entry_decides() {
+        // a read-only NA4 entry in front of a NAPOT entry granting everything
+        let cfg = (PMP_A_NA4 | PMP_R) as u64 | ((PMP_A_NAPOT | PMP_R | PMP_W | PMP_X) as u64) << 8;
+        let pmp = entries(cfg, &[0x1000 >> 2, u64::MAX]);
+        assert!(!pmp.check(0x1000, 4, Access::Store, Privilege::User));
+        assert!(pmp.check(0x1004, 4, Access::Store, Privilege::User));
+    }
+
+    #[test]
+    fn accesses_at_the_end_of_the_address_space() {
+        let pmp = Pmp::new();
+        assert!(pmp.check(u64::MAX - 7, 7, Access::Load, Privilege::Machine));
+        assert!(!pmp.check(u64::MAX - 7, 8, Access::Load, Privilege::Machine));
+        assert!(!pmp.check(u64::MAX - 7, 16, Access::Store, Privilege::Machine));
+    }
+
+    #[test]
+    fn locked_entries_ignore_writes() {
+        let mut pmp = entries((PMP_A_NA4 | PMP_R | PMP_L) as u64, &[0x1000 >> 2]);
+        pmp.w_cfg(0, (PMP_A_NAPOT | PMP_R | PMP_W) as u64, Xlen::X64);
+        pmp.w_addr(0, 0x2000 >> 2, Xlen::X64);
+        assert_eq!(pmp.r_cfg(0, Xlen::X64), (PMP_A_NA4 | PMP_R | PMP_L) as u64);
+        assert_eq!(pmp.r_addr(0), 0x1000 >> 2);
+    }
+}
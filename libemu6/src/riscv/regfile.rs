@@ -1,4 +1,6 @@
-use super::Xlen;
+use super::pmp::Pmp;
+use super::{mmu, Exception, Interrupt, Privilege, Trap, Xlen};
+use crate::error::Result;
 use crate::size::{Isize, Usize};
 
 pub struct XReg {
@@ -180,52 +182,627 @@ impl FReg {
     }
 }
 
+// Values narrower than the register are NaN-boxed: all upper bits are ones. Reading
+// a value which is not properly NaN-boxed gives the canonical NaN.
+const CANONICAL_NAN_F32: u32 = 0x7FC00000;
+const CANONICAL_NAN_F64: u64 = 0x7FF8000000000000;
+
+impl FReg {
+    pub fn r_u32(&self, idx: u8) -> u32 {
+        (self.f[idx as usize] & 0xFFFFFFFF) as u32
+    }
+    pub fn r_u32_boxed(&self, idx: u8) -> u32 {
+        let data = self.f[idx as usize];
+        if data >> 32 == u128::MAX >> 32 {
+            (data & 0xFFFFFFFF) as u32
+        } else {
+            CANONICAL_NAN_F32
+        }
+    }
+    pub fn w_u32_boxed(&mut self, idx: u8, val: u32) {
+        self.f[idx as usize] = (u128::MAX << 32) | val as u128;
+    }
+    pub fn r_u64(&self, idx: u8) -> u64 {
+        (self.f[idx as usize] & 0xFFFFFFFF_FFFFFFFF) as u64
+    }
+    pub fn r_u64_boxed(&self, idx: u8) -> u64 {
+        let data = self.f[idx as usize];
+        if data >> 64 == u128::MAX >> 64 {
+            (data & 0xFFFFFFFF_FFFFFFFF) as u64
+        } else {
+            CANONICAL_NAN_F64
+        }
+    }
+    pub fn w_u64_boxed(&mut self, idx: u8, val: u64) {
+        self.f[idx as usize] = (u128::MAX << 64) | val as u128;
+    }
+}
+
+impl core::fmt::Debug for FReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.f.iter()).finish()
+    }
+}
+
 // -- ISA spec definded CSRs
 // Floating point CSRs
-const CSR_FFLAGS: u16 = 0x001;
-const CSR_FRM: u16 = 0x002;
-const CSR_FCSR: u16 = 0x003;
+pub(crate) const CSR_FFLAGS: u16 = 0x001;
+pub(crate) const CSR_FRM: u16 = 0x002;
+pub(crate) const CSR_FCSR: u16 = 0x003;
 // Counters and timers
-// const CSR_CYCLE: u16 = 0xC00;
-// const CSR_TIME: u16 = 0xC01;
-// const CSR_INSTRET: u16 = 0xC02;
-// const CSR_CYCLEH: u16 = 0xC80;
-// const CSR_TIMEH: u16 = 0xC81;
-// const CSR_INSTRETH: u16 = 0xC82;
+pub(crate) const CSR_CYCLE: u16 = 0xC00;
+pub(crate) const CSR_TIME: u16 = 0xC01;
+pub(crate) const CSR_INSTRET: u16 = 0xC02;
+pub(crate) const CSR_CYCLEH: u16 = 0xC80;
+pub(crate) const CSR_TIMEH: u16 = 0xC81;
+pub(crate) const CSR_INSTRETH: u16 = 0xC82;
+// Supervisor trap setup
+pub(crate) const CSR_SSTATUS: u16 = 0x100;
+pub(crate) const CSR_SIE: u16 = 0x104;
+pub(crate) const CSR_STVEC: u16 = 0x105;
+pub const CSR_SCOUNTEREN: u16 = 0x106;
+// Supervisor trap handling
+pub(crate) const CSR_SSCRATCH: u16 = 0x140;
+pub(crate) const CSR_SEPC: u16 = 0x141;
+pub(crate) const CSR_SCAUSE: u16 = 0x142;
+pub(crate) const CSR_STVAL: u16 = 0x143;
+pub(crate) const CSR_SIP: u16 = 0x144;
+// Supervisor protection and translation
+pub(crate) const CSR_SATP: u16 = 0x180;
+// Machine information registers
+pub(crate) const CSR_MVENDORID: u16 = 0xF11;
+pub(crate) const CSR_MARCHID: u16 = 0xF12;
+pub(crate) const CSR_MIMPID: u16 = 0xF13;
+pub(crate) const CSR_MHARTID: u16 = 0xF14;
+// Machine trap setup
+pub const CSR_MSTATUS: u16 = 0x300;
+pub(crate) const CSR_MISA: u16 = 0x301;
+pub const CSR_MEDELEG: u16 = 0x302;
+pub const CSR_MIDELEG: u16 = 0x303;
+pub(crate) const CSR_MIE: u16 = 0x304;
+pub(crate) const CSR_MTVEC: u16 = 0x305;
+pub const CSR_MCOUNTEREN: u16 = 0x306;
+pub(crate) const CSR_MSTATUSH: u16 = 0x310;
+// Machine trap handling
+pub(crate) const CSR_MSCRATCH: u16 = 0x340;
+pub(crate) const CSR_MEPC: u16 = 0x341;
+pub(crate) const CSR_MCAUSE: u16 = 0x342;
+pub(crate) const CSR_MTVAL: u16 = 0x343;
+pub(crate) const CSR_MIP: u16 = 0x344;
+// Machine memory protection
+pub(crate) const CSR_PMPCFG0: u16 = 0x3A0;
+pub(crate) const CSR_PMPCFG15: u16 = 0x3AF;
+pub(crate) const CSR_PMPADDR0: u16 = 0x3B0;
+pub(crate) const CSR_PMPADDR63: u16 = 0x3EF;
+
+// mstatus fields
+const MSTATUS_SIE: u64 = 1 << 1;
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_SPIE: u64 = 1 << 5;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_SPP: u64 = 1 << 8;
+const MSTATUS_MPP: u64 = 0b11 << 11;
+const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+const MSTATUS_FS_DIRTY: u64 = 0b11 << 13;
+const MSTATUS_MPRV: u64 = 1 << 17;
+const MSTATUS_SUM: u64 = 1 << 18;
+const MSTATUS_MXR: u64 = 1 << 19;
+const MSTATUS_TVM: u64 = 1 << 20;
+const MSTATUS_TW: u64 = 1 << 21;
+const MSTATUS_TSR: u64 = 1 << 22;
+const MSTATUS_UXL: u64 = 0b11 << 32;
+// fields of mstatus visible through sstatus
+const SSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_UXL;
+
+// interrupt bits in mip and mie
+const MIP_SSIP: u64 = 1 << 1;
+const MIP_MSIP: u64 = 1 << 3;
+const MIP_STIP: u64 = 1 << 5;
+const MIP_MTIP: u64 = 1 << 7;
+const MIP_SEIP: u64 = 1 << 9;
+const MIP_MEIP: u64 = 1 << 11;
+const MIP_S_MASK: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MIP_M_MASK: u64 = MIP_MSIP | MIP_MTIP | MIP_MEIP;
+
+// interrupts in decreasing priority order
+const INTERRUPT_PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoftware,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoftware,
+    Interrupt::SupervisorTimer,
+];
+
+// environment calls from M-mode are never delegated
+const MEDELEG_MASK: u64 = 0xB3FF;
+
+// extensions implemented by this hart, as in misa
+const MISA_EXTENSIONS: &str = "ACDFIMSU";
+// the floating point extensions can be turned off by clearing their misa bits
+const MISA_F: u64 = 1 << (b'F' - b'A');
+const MISA_D: u64 = 1 << (b'D' - b'A');
 
 pub struct Csr {
     fcsr: u32,
     xlen: Xlen,
+    hart_id: usize,
+    mstatus: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mtvec: u64,
+    mcounteren: u64,
+    mie: u64,
+    mip: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    stvec: u64,
+    scounteren: u64,
+    sscratch: u64,
+    sepc: u64,
+    scause: u64,
+    stval: u64,
+    satp: u64,
+    // retired instructions, also counted as cycles
+    instret: u64,
+    // mtime of the interrupt controller
+    time: u64,
+    // enabled extensions, one bit for each letter as in misa
+    extensions: u64,
+    pmp: Pmp,
 }
 
 impl Csr {
-    pub fn new(xlen: Xlen) -> Csr {
-        Csr { xlen, fcsr: 0 }
+    pub fn new(xlen: Xlen, hart_id: usize) -> Csr {
+        // UXL and SXL are read-only; they equal MXL
+        let xl = match xlen {
+            Xlen::X64 => (2 << 32) | (2 << 34),
+            _ => 0,
+        };
+        Csr {
+            fcsr: 0,
+            xlen,
+            hart_id,
+            // floating point unit is enabled on reset, so programs loaded directly
+            // without a firmware could use floating point instructions
+            mstatus: xl | MSTATUS_FS_INITIAL,
+            medeleg: 0,
+            mideleg: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mie: 0,
+            mip: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            instret: 0,
+            time: 0,
+            extensions: MISA_EXTENSIONS
+                .bytes()
+                .fold(0, |ans, ext| ans | (1 << (ext - b'A'))),
+            pmp: Pmp::new(),
+        }
     }
 
-    pub fn r_usize(&self, csr: u16) -> Usize {
-        match csr {
-            CSR_FFLAGS => self.u32_to_usize(self.fcsr & 0b11111),
-            CSR_FRM => self.u32_to_usize((self.fcsr >> 5) & 0b111),
-            CSR_FCSR => self.u32_to_usize(self.fcsr & 0b11111111),
-            _ => todo!(),
+    // CSR address bits [9:8] encode the lowest privilege level allowed to access it;
+    // supervisor accesses to satp trap when mstatus.TVM is set
+    pub fn check_privilege(&self, csr: u16, privilege: Privilege) -> Result<()> {
+        let required = (csr >> 8) & 0b11;
+        if required > privilege as u16 {
+            return Err(self.illegal())?;
+        }
+        if csr == CSR_SATP && privilege == Privilege::Supervisor && self.tvm() {
+            return Err(self.illegal())?;
         }
+        // below M-mode, a counter needs its bit in mcounteren, and in scounteren for U-mode
+        if let CSR_CYCLE..=CSR_INSTRET | CSR_CYCLEH..=CSR_INSTRETH = csr {
+            let enabled = match privilege {
+                Privilege::Machine => u64::MAX,
+                Privilege::Supervisor => self.mcounteren,
+                Privilege::User => self.mcounteren & self.scounteren,
+            };
+            if enabled & (1 << (csr & 0x1F)) == 0 {
+                return Err(self.illegal())?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn w_usize(&mut self, csr: u16, a: Usize) {
+    // Accessing a CSR which does not exist raises an illegal instruction exception
+    pub fn r_usize(&self, csr: u16) -> Result<Usize> {
+        let ans = match csr {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR if !self.fs_enabled() => return Err(self.illegal())?,
+            CSR_FFLAGS => (self.fcsr & 0b11111) as u64,
+            CSR_FRM => ((self.fcsr >> 5) & 0b111) as u64,
+            CSR_FCSR => (self.fcsr & 0b11111111) as u64,
+            CSR_CYCLE | CSR_INSTRET => self.instret,
+            CSR_TIME => self.time,
+            CSR_CYCLEH | CSR_INSTRETH if self.xlen == Xlen::X32 => self.instret >> 32,
+            CSR_TIMEH if self.xlen == Xlen::X32 => self.time >> 32,
+            CSR_SSTATUS => self.mstatus() & (SSTATUS_MASK | self.sd_bit()),
+            CSR_SIE => self.mie & self.mideleg,
+            CSR_STVEC => self.stvec,
+            CSR_SCOUNTEREN => self.scounteren,
+            CSR_SSCRATCH => self.sscratch,
+            CSR_SEPC => self.sepc,
+            CSR_SCAUSE => self.scause,
+            CSR_STVAL => self.stval,
+            CSR_SIP => self.mip & self.mideleg,
+            CSR_SATP => self.satp,
+            CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID => 0,
+            CSR_MHARTID => self.hart_id as u64,
+            CSR_MSTATUS => self.mstatus(),
+            CSR_MSTATUSH if self.xlen == Xlen::X32 => 0,
+            CSR_MISA => self.misa(),
+            CSR_MEDELEG => self.medeleg,
+            CSR_MIDELEG => self.mideleg,
+            CSR_MIE => self.mie,
+            CSR_MTVEC => self.mtvec,
+            CSR_MCOUNTEREN => self.mcounteren,
+            CSR_MSCRATCH => self.mscratch,
+            CSR_MEPC => self.mepc,
+            CSR_MCAUSE => self.mcause,
+            CSR_MTVAL => self.mtval,
+            CSR_MIP => self.mip,
+            CSR_PMPCFG0..=CSR_PMPCFG15 if Pmp::cfg_exists((csr - CSR_PMPCFG0) as usize, self.xlen) => {
+                self.pmp.r_cfg((csr - CSR_PMPCFG0) as usize, self.xlen)
+            }
+            CSR_PMPADDR0..=CSR_PMPADDR63 => self.pmp.r_addr((csr - CSR_PMPADDR0) as usize),
+            _ => return Err(self.illegal())?,
+        };
+        Ok(Usize::from_u64(ans, self.xlen))
+    }
+
+    // Writes to read-only CSRs, i.e. address bits [11:10] are 0b11, are illegal
+    pub fn w_usize(&mut self, csr: u16, a: Usize) -> Result<()> {
+        if csr >> 10 == 0b11 {
+            return Err(self.illegal())?;
+        }
+        let a = a.to_u64();
         match csr {
-            CSR_FFLAGS => self.fcsr = (self.fcsr & !0b11111) | a.low_u32() & 0b11111,
-            CSR_FRM => self.fcsr = (self.fcsr & !0b11100000) | ((a.low_u32() & 0b111) << 5),
-            CSR_FCSR => self.fcsr = a.low_u32() & 0b11111111,
-            _ => todo!(),
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR if !self.fs_enabled() => return Err(self.illegal())?,
+            CSR_FFLAGS => self.fcsr = (self.fcsr & !0b11111) | (a as u32) & 0b11111,
+            CSR_FRM => self.fcsr = (self.fcsr & !0b11100000) | (((a as u32) & 0b111) << 5),
+            CSR_FCSR => self.fcsr = (a as u32) & 0b11111111,
+            CSR_SSTATUS => {
+                let mask = SSTATUS_MASK & !MSTATUS_UXL;
+                self.mstatus = (self.mstatus & !mask) | (a & mask);
+            }
+            CSR_SIE => self.mie = (self.mie & !self.mideleg) | (a & self.mideleg & MIP_S_MASK),
+            CSR_STVEC => self.stvec = legalize_tvec(a),
+            CSR_SCOUNTEREN => self.scounteren = a & 0b111,
+            CSR_SSCRATCH => self.sscratch = a,
+            CSR_SEPC => self.sepc = a & !1,
+            CSR_SCAUSE => self.scause = a,
+            CSR_STVAL => self.stval = a,
+            // only the supervisor software interrupt is writable from supervisor level
+            CSR_SIP => {
+                let mask = self.mideleg & MIP_SSIP;
+                self.mip = (self.mip & !mask) | (a & mask);
+            }
+            CSR_SATP => self.w_satp(a),
+            CSR_MSTATUS => {
+                let mask = MSTATUS_SIE
+                    | MSTATUS_MIE
+                    | MSTATUS_SPIE
+                    | MSTATUS_MPIE
+                    | MSTATUS_SPP
+                    | MSTATUS_FS
+                    | MSTATUS_MPRV
+                    | MSTATUS_SUM
+                    | MSTATUS_MXR
+                    | MSTATUS_TVM
+                    | MSTATUS_TW
+                    | MSTATUS_TSR;
+                // MPP is WARL, the reserved encoding keeps the previous value
+                let mpp = if (a & MSTATUS_MPP) >> 11 == 0b10 {
+                    self.mstatus & MSTATUS_MPP
+                } else {
+                    a & MSTATUS_MPP
+                };
+                self.mstatus = (self.mstatus & !(mask | MSTATUS_MPP)) | (a & mask) | mpp;
+            }
+            // mstatush holds no implemented fields
+            CSR_MSTATUSH if self.xlen == Xlen::X32 => {}
+            // only F and D can be turned off; D depends on F, so enabling D
+            // without F turns off both
+            CSR_MISA => {
+                let mut ext = a & (MISA_F | MISA_D);
+                if ext & MISA_F == 0 {
+                    ext = 0;
+                }
+                self.extensions = (self.extensions & !(MISA_F | MISA_D)) | ext;
+            }
+            CSR_MEDELEG => self.medeleg = a & MEDELEG_MASK,
+            CSR_MIDELEG => self.mideleg = a & MIP_S_MASK,
+            CSR_MIE => self.mie = a & (MIP_S_MASK | MIP_M_MASK),
+            CSR_MTVEC => self.mtvec = legalize_tvec(a),
+            CSR_MCOUNTEREN => self.mcounteren = a & 0b111,
+            CSR_MSCRATCH => self.mscratch = a,
+            // IALIGN is 16 as the C extension is implemented
+            CSR_MEPC => self.mepc = a & !1,
+            CSR_MCAUSE => self.mcause = a,
+            CSR_MTVAL => self.mtval = a,
+            // machine level pending bits are only driven by devices
+            CSR_MIP => self.mip = (self.mip & !MIP_S_MASK) | (a & MIP_S_MASK),
+            CSR_PMPCFG0..=CSR_PMPCFG15 if Pmp::cfg_exists((csr - CSR_PMPCFG0) as usize, self.xlen) => {
+                self.pmp.w_cfg((csr - CSR_PMPCFG0) as usize, a, self.xlen)
+            }
+            CSR_PMPADDR0..=CSR_PMPADDR63 => {
+                self.pmp.w_addr((csr - CSR_PMPADDR0) as usize, a, self.xlen)
+            }
+            _ => return Err(self.illegal())?,
+        }
+        match csr {
+            CSR_FFLAGS | CSR_FRM | CSR_FCSR => self.set_fs_dirty(),
+            _ => {}
         }
+        Ok(())
     }
 
-    fn u32_to_usize(&self, a: u32) -> Usize {
+    pub fn frm(&self) -> u32 {
+        (self.fcsr >> 5) & 0b111
+    }
+
+    pub fn accrue_fflags(&mut self, flags: u32) {
+        self.fcsr |= flags & 0b11111;
+    }
+
+    pub fn has_extension(&self, ext: char) -> bool {
+        ext.is_ascii_uppercase() && self.extensions & (1 << (ext as u8 - b'A')) != 0
+    }
+
+    // floating point instructions are illegal when mstatus.FS is Off or the F
+    // extension is turned off in misa
+    pub fn fs_enabled(&self) -> bool {
+        self.mstatus & MSTATUS_FS != 0 && self.extensions & MISA_F != 0
+    }
+
+    pub fn set_fs_dirty(&mut self) {
+        self.mstatus |= MSTATUS_FS_DIRTY;
+    }
+
+    // timeout wait; WFI in supervisor mode is illegal when set
+    pub fn tw(&self) -> bool {
+        self.mstatus & MSTATUS_TW != 0
+    }
+
+    // trap SRET; SRET in supervisor mode is illegal when set
+    pub fn tsr(&self) -> bool {
+        self.mstatus & MSTATUS_TSR != 0
+    }
+
+    pub fn retire(&mut self) {
+        self.instret = self.instret.wrapping_add(1);
+    }
+
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    // Makes PMP entry 0 a region over all memory, as firmware does before
+    // entering a lower privilege level
+    pub fn open_pmp(&mut self) {
+        self.pmp.open_all(self.xlen);
+    }
+
+    // pending bits driven by devices, as opposed to those written by software
+    pub fn set_pending(&mut self, interrupt: Interrupt, pending: bool) {
+        let mask = 1 << interrupt.code();
+        if pending {
+            self.mip |= mask;
+        } else {
+            self.mip &= !mask;
+        }
+    }
+
+    // Returns the interrupt to take at `privilege`, if any. Interrupts handled in
+    // M-mode come first; those delegated to S-mode are never taken in M-mode
+    pub fn pending_interrupt(&self, privilege: Privilege) -> Option<Interrupt> {
+        let pending = self.mip & self.mie;
+        let m_enabled = privilege < Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let s_enabled = privilege < Privilege::Supervisor
+            || (privilege == Privilege::Supervisor && self.mstatus & MSTATUS_SIE != 0);
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !self.mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & self.mideleg;
+        }
+        let is_delegated = |i: &&Interrupt| self.mideleg & (1 << i.code()) != 0;
+        let to_m = INTERRUPT_PRIORITY.iter().filter(|i| !is_delegated(i));
+        let to_s = INTERRUPT_PRIORITY.iter().filter(is_delegated);
+        to_m.chain(to_s).copied().find(|i| enabled & (1 << i.code()) != 0)
+    }
+
+    pub fn satp(&self) -> u64 {
+        self.satp
+    }
+
+    pub fn pmp(&self) -> Pmp {
+        self.pmp
+    }
+
+    // privilege used for loads and stores; with MPRV set, M-mode accesses memory
+    // with the privilege in MPP
+    pub fn data_privilege(&self, privilege: Privilege) -> Privilege {
+        if privilege != Privilege::Machine || self.mstatus & MSTATUS_MPRV == 0 {
+            return privilege;
+        }
+        match (self.mstatus & MSTATUS_MPP) >> 11 {
+            0b00 => Privilege::User,
+            0b01 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+
+    // permit supervisor user memory access
+    pub fn sum(&self) -> bool {
+        self.mstatus & MSTATUS_SUM != 0
+    }
+
+    // make executable readable
+    pub fn mxr(&self) -> bool {
+        self.mstatus & MSTATUS_MXR != 0
+    }
+
+    // trap virtual memory; satp accesses and SFENCE.VMA in S-mode are illegal when set
+    pub fn tvm(&self) -> bool {
+        self.mstatus & MSTATUS_TVM != 0
+    }
+
+    // Enters the trap handler of M-mode, or of S-mode if the trap is delegated and
+    // not taken from M-mode; returns the handler address and the new privilege.
+    // Interrupts in vectored mode jump to BASE + 4 * cause
+    pub fn trap_entry(
+        &mut self,
+        code: u32,
+        is_interrupt: bool,
+        epc: Usize,
+        tval: Usize,
+        privilege: Privilege,
+    ) -> (Usize, Privilege) {
+        let deleg = if is_interrupt { self.mideleg } else { self.medeleg };
+        let to_s = privilege != Privilege::Machine && (deleg >> code) & 1 != 0;
+        let mut cause = code as u64;
+        if is_interrupt {
+            cause |= match self.xlen {
+                Xlen::X32 => 1 << 31,
+                _ => 1 << 63,
+            };
+        }
+        let tvec = if to_s {
+            self.sepc = epc.to_u64();
+            self.scause = cause;
+            self.stval = tval.to_u64();
+            let sie = self.mstatus & MSTATUS_SIE != 0;
+            self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if sie {
+                self.mstatus |= MSTATUS_SPIE;
+            }
+            if privilege == Privilege::Supervisor {
+                self.mstatus |= MSTATUS_SPP;
+            }
+            self.stvec
+        } else {
+            self.mepc = epc.to_u64();
+            self.mcause = cause;
+            self.mtval = tval.to_u64();
+            let mie = self.mstatus & MSTATUS_MIE != 0;
+            self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            if mie {
+                self.mstatus |= MSTATUS_MPIE;
+            }
+            self.mstatus |= (privilege as u64) << 11;
+            self.mtvec
+        };
+        let base = tvec & !0b11;
+        let target = if is_interrupt && tvec & 0b11 == 1 {
+            base + 4 * code as u64
+        } else {
+            base
+        };
+        let privilege = if to_s { Privilege::Supervisor } else { Privilege::Machine };
+        (Usize::from_u64(target, self.xlen), privilege)
+    }
+
+    // MRET; returns the address and privilege to resume execution with
+    pub fn mret(&mut self) -> (Usize, Privilege) {
+        let privilege = match (self.mstatus & MSTATUS_MPP) >> 11 {
+            0b00 => Privilege::User,
+            0b01 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        };
+        let mpie = self.mstatus & MSTATUS_MPIE != 0;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        if mpie {
+            self.mstatus |= MSTATUS_MIE;
+        }
+        // MPIE is set to 1, MPP to the least privileged mode, which is U
+        self.mstatus |= MSTATUS_MPIE;
+        if privilege != Privilege::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+        (Usize::from_u64(self.mepc, self.xlen), privilege)
+    }
+
+    // SRET; returns the address and privilege to resume execution with
+    pub fn sret(&mut self) -> (Usize, Privilege) {
+        let privilege = if self.mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        let spie = self.mstatus & MSTATUS_SPIE != 0;
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPP);
+        if spie {
+            self.mstatus |= MSTATUS_SIE;
+        }
+        // SPP is never M, so MPRV is always cleared
+        self.mstatus |= MSTATUS_SPIE;
+        self.mstatus &= !MSTATUS_MPRV;
+        (Usize::from_u64(self.sepc, self.xlen), privilege)
+    }
+
+    fn w_satp(&mut self, a: u64) {
+        // writes selecting an unsupported mode have no effect
+        let mode = match self.xlen {
+            Xlen::X32 => a >> 31,
+            _ => a >> 60,
+        };
+        if mmu::satp_mode_supported(self.xlen, mode) {
+            self.satp = a;
+        }
+    }
+
+    fn mstatus(&self) -> u64 {
+        self.mstatus | self.sd_bit()
+    }
+
+    // SD summarizes whether FS is dirty
+    fn sd_bit(&self) -> u64 {
+        if self.mstatus & MSTATUS_FS != MSTATUS_FS_DIRTY {
+            return 0;
+        }
         match self.xlen {
-            Xlen::X32 => Usize::U32(a),
-            Xlen::X64 => Usize::U64(a as u64),
-            Xlen::X128 => panic!("unsupported xlen"),
+            Xlen::X32 => 1 << 31,
+            _ => 1 << 63,
         }
     }
+
+    fn misa(&self) -> u64 {
+        let mxl = match self.xlen {
+            Xlen::X32 => 1 << 30,
+            Xlen::X64 => 2 << 62,
+            Xlen::X128 => panic!("unsupported xlen"),
+        };
+        mxl | self.extensions
+    }
+
+    // illegal instruction exception, with zero as trap value
+    pub fn illegal(&self) -> Trap {
+        Trap::new(Exception::IllegalInstruction, Usize::from_u64(0, self.xlen))
+    }
+}
+
+// direct and vectored modes are supported; reserved modes are ignored
+fn legalize_tvec(a: u64) -> u64 {
+    if a & 0b11 > 1 {
+        a & !0b11
+    } else {
+        a
+    }
 }
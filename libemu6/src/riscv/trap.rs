@@ -0,0 +1,83 @@
+use crate::size::Usize;
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned,
+    LoadAccessFault,
+    StoreAddressMisaligned,
+    StoreAccessFault,
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+}
+
+impl Exception {
+    // exception code as written into the cause register
+    pub fn code(self) -> u32 {
+        use Exception::*;
+        match self {
+            InstructionAddressMisaligned => 0,
+            InstructionAccessFault => 1,
+            IllegalInstruction => 2,
+            Breakpoint => 3,
+            LoadAddressMisaligned => 4,
+            LoadAccessFault => 5,
+            StoreAddressMisaligned => 6,
+            StoreAccessFault => 7,
+            EnvironmentCallFromUMode => 8,
+            EnvironmentCallFromSMode => 9,
+            EnvironmentCallFromMMode => 11,
+            InstructionPageFault => 12,
+            LoadPageFault => 13,
+            StorePageFault => 15,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    // interrupt code as written into the cause register, also the bit in mip and mie
+    pub fn code(self) -> u32 {
+        use Interrupt::*;
+        match self {
+            SupervisorSoftware => 1,
+            MachineSoftware => 3,
+            SupervisorTimer => 5,
+            MachineTimer => 7,
+            SupervisorExternal => 9,
+            MachineExternal => 11,
+        }
+    }
+}
+
+// A synchronous exception raised by an instruction; the instruction has no other
+// architectural effect. `tval` holds the faulting address or other trap value
+#[derive(Error, Clone, Copy, Debug)]
+#[error("Exception {cause:?} raised with trap value 0x{tval:X}")]
+pub struct Trap {
+    pub cause: Exception,
+    pub tval: Usize,
+}
+
+impl Trap {
+    pub fn new(cause: Exception, tval: Usize) -> Trap {
+        Trap { cause, tval }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::riscv::Xlen;
+
 #[derive(Clone, Copy, Ord, Eq, PartialEq)]
 pub enum Usize {
     U32(u32),
@@ -11,6 +13,21 @@ impl Usize {
             Usize::U64(a) => (a & 0xFFFFFFFF) as u32,
         }
     }
+
+    pub fn to_u64(self) -> u64 {
+        match self {
+            Usize::U32(a) => a as u64,
+            Usize::U64(a) => a,
+        }
+    }
+
+    // truncates to 32 bits on RV32
+    pub fn from_u64(n: u64, xlen: Xlen) -> Usize {
+        match xlen {
+            Xlen::X32 => Usize::U32(n as u32),
+            _ => Usize::U64(n),
+        }
+    }
 }
 
 impl core::fmt::Debug for Usize {
@@ -87,16 +104,9 @@ impl core::ops::Add<Isize> for Usize {
     type Output = Usize;
     fn add(self, rhs: Isize) -> Self::Output {
         match (self, rhs) {
-            (Usize::U32(a), Isize::I32(b)) => Usize::U32(if b > 0 {
-                a.wrapping_add(b as u32)
-            } else {
-                a.wrapping_add(b.abs() as u32)
-            }),
-            (Usize::U64(a), Isize::I64(b)) => Usize::U64(if b > 0 {
-                a.wrapping_add(b as u64)
-            } else {
-                a.wrapping_add(b.abs() as u64)
-            }),
+            // two's complement addition also handles negative offsets
+            (Usize::U32(a), Isize::I32(b)) => Usize::U32(a.wrapping_add(b as u32)),
+            (Usize::U64(a), Isize::I64(b)) => Usize::U64(a.wrapping_add(b as u64)),
             _ => panic!("Not the same type"),
         }
     }
@@ -273,3 +283,22 @@ impl core::ops::Shr<u32> for Isize {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_negative_offset() {
+        assert_eq!(Usize::U32(0x1000) + Isize::I32(-4), Usize::U32(0xFFC));
+        assert_eq!(Usize::U64(0x1000) + Isize::I64(-4), Usize::U64(0xFFC));
+        assert_eq!(Usize::U32(0) + Isize::I32(-1), Usize::U32(u32::MAX));
+        assert_eq!(Usize::U64(8) + Isize::I64(i64::MIN), Usize::U64(0x8000_0000_0000_0008));
+    }
+
+    #[test]
+    fn add_positive_offset() {
+        assert_eq!(Usize::U32(u32::MAX) + Isize::I32(1), Usize::U32(0));
+        assert_eq!(Usize::U64(0x1000) + Isize::I64(0x7FF), Usize::U64(0x17FF));
+    }
+}